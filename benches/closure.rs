@@ -0,0 +1,75 @@
+//! The closure backend against the tree-walking interpreter it is meant to
+//! replace. Each program is parsed and compiled once; only evaluation is
+//! timed.
+//!
+//! ```bash
+//! cargo bench --bench closure
+//! ```
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use calculator::closure::Compiler as ClosureCompiler;
+use calculator::val::Val;
+use calculator::{Compile, Interpreter, optimize, parser};
+
+const PROGRAMS: [(&str, &str); 3] = [
+    (
+        "fib",
+        "fn fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2); fib(20)",
+    ),
+    (
+        "int arithmetic",
+        "fn f(a, b) = (a * b + a // 3 - b % 7) * (a - b) + (a + b) * (a + 1) - b * b
+         fn sum(lo, hi) = if lo == hi then f(lo % 97, 5) else sum(lo, (lo + hi) // 2) + sum((lo + hi) // 2 + 1, hi)
+         sum(1, 5000)",
+    ),
+    (
+        "float arithmetic",
+        "fn sqrt(x, g, n) = if n == 0 then g else sqrt(x, (g + x / g) / 2, n - 1)
+         fn sum(lo, hi) = if lo == hi then sqrt(lo * 1.0, 1.0, 12) else sum(lo, (lo + hi) // 2) + sum((lo + hi) // 2 + 1, hi)
+         sum(1, 1000)",
+    ),
+];
+
+fn main() {
+    for (name, source) in PROGRAMS {
+        let ast = optimize(parser::parse(source).unwrap());
+        let program = ClosureCompiler::from_ast(ast.clone()).unwrap();
+
+        report(name, "interp", || {
+            let ast = ast.clone();
+            let start = Instant::now();
+            let result = Interpreter::from_ast(black_box(ast)).unwrap();
+            (start.elapsed(), result)
+        });
+        report(name, "closure", || {
+            let start = Instant::now();
+            let result = black_box(&program).eval(&[]).unwrap();
+            (start.elapsed(), result)
+        });
+    }
+}
+
+/// Repeats `run` for at least two seconds and five runs and prints the mean.
+fn report(name: &str, engine: &str, mut run: impl FnMut() -> (Duration, Val)) {
+    let mut runs = 0u32;
+    let mut elapsed = Duration::ZERO;
+    let mut result = None;
+    while elapsed < Duration::from_secs(2) || runs < 5 {
+        let (time, val) = run();
+        elapsed += time;
+        result = Some(val);
+        runs += 1;
+    }
+    let mean = elapsed / runs;
+    println!(
+        "{:<18} {:<8} {:>10.3} ms/run  {:>8.1} runs/s  ({} runs, result {})",
+        name,
+        engine,
+        mean.as_secs_f64() * 1000.0,
+        runs as f64 / elapsed.as_secs_f64(),
+        runs,
+        result.unwrap()
+    );
+}
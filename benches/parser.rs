@@ -0,0 +1,54 @@
+//! Parsing speed of the pest grammar against the hand-written Pratt parser,
+//! on the same sources. Both build the same AST, so only parsing is timed.
+//!
+//! ```bash
+//! cargo bench --bench parser
+//! ```
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use calculator::{parser, pratt};
+
+const SOURCES: [(&str, &str); 3] = [
+    (
+        "statements",
+        "let a = 1; let b = 2.5; fn f(x, y) = x * y + 1; f(a, b) - a // 2 % 3",
+    ),
+    (
+        "deep nesting",
+        "((((((((((1 + 2) * 3) - 4) / 5) ^ 2) + 6) * 7) - 8) // 9) % 10) == ((((1))))",
+    ),
+    (
+        "long chain",
+        "1 + 2 * 3 - 4 / 5 + 6 ^ 2 ^ 1 - -7 * 8 < 9 && 10 >= 11 || !true != false == (12 <= 13)",
+    ),
+];
+const RUNS: u32 = 20_000;
+
+fn main() {
+    for (name, source) in SOURCES {
+        assert_eq!(pratt::parse(source), parser::parse_with_pest(source));
+        let pest = time(|| parser::parse_with_pest(black_box(source)).unwrap().len());
+        let pratt = time(|| pratt::parse(black_box(source)).unwrap().len());
+        println!(
+            "{:<14} pest {:>8.3} µs  pratt {:>8.3} µs  ({:.1}x)",
+            name,
+            per_run(pest),
+            per_run(pratt),
+            pest.as_secs_f64() / pratt.as_secs_f64()
+        );
+    }
+}
+
+fn time(mut parse: impl FnMut() -> usize) -> Duration {
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(parse());
+    }
+    start.elapsed()
+}
+
+fn per_run(elapsed: Duration) -> f64 {
+    elapsed.as_secs_f64() * 1e6 / RUNS as f64
+}
@@ -0,0 +1,57 @@
+//! Evaluating one formula many times with different values for its
+//! variables, on every backend in the build: compiling it again for each
+//! evaluation against compiling it once into a `Program`.
+//!
+//! ```bash
+//! cargo bench --bench program
+//! ```
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use calculator::val::Val;
+use calculator::{Program, Registry};
+
+const FORMULA: &str =
+    "fn sq(v) = v * v; let d = x - y; if d > 0 then sq(d) + x * y else sq(y) // 3";
+const EVALUATIONS: i32 = 20_000;
+
+fn main() {
+    let registry = Registry::new();
+    for backend in registry.iter() {
+        let args = |n: i32| [Val::Int(n % 101), Val::Int(n % 37)];
+
+        let start = Instant::now();
+        let mut sum = 0;
+        for n in 0..EVALUATIONS {
+            let program = Program::new(backend, FORMULA, &["x", "y"]).unwrap();
+            sum += as_int(program.eval(black_box(&args(n))).unwrap());
+        }
+        report(backend.name(), "compile each time", start.elapsed(), sum);
+
+        let start = Instant::now();
+        let mut sum = 0;
+        let program = Program::new(backend, FORMULA, &["x", "y"]).unwrap();
+        for n in 0..EVALUATIONS {
+            sum += as_int(program.eval(black_box(&args(n))).unwrap());
+        }
+        report(backend.name(), "compile once", start.elapsed(), sum);
+    }
+}
+
+fn as_int(val: Val) -> i64 {
+    match val {
+        Val::Int(n) => n as i64,
+        other => panic!("expected an int, got {}", other),
+    }
+}
+
+fn report(backend: &str, mode: &str, elapsed: std::time::Duration, sum: i64) {
+    println!(
+        "{:<8} {:<18} {:>10.3} µs/eval  (sum {})",
+        backend,
+        mode,
+        elapsed.as_secs_f64() * 1e6 / EVALUATIONS as f64,
+        sum
+    );
+}
@@ -0,0 +1,85 @@
+//! Throughput of the dispatch loops of the stack VM and the register VM. Each
+//! program is compiled once and then run repeatedly on a fresh VM; only the
+//! run is timed.
+//!
+//! ```bash
+//! cargo bench --bench vm
+//! ```
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use calculator::register::Compiler as RegisterCompiler;
+use calculator::val::Val;
+use calculator::vm::bytecode::Interpreter as BytecodeCompiler;
+use calculator::{Compile, RegisterVM, VM, optimize, parser};
+
+const PROGRAMS: [(&str, &str); 4] = [
+    (
+        "fib",
+        "fn fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2); fib(22)",
+    ),
+    (
+        "int arithmetic",
+        "fn f(a, b) = (a * b + a // 3 - b % 7) * (a - b) + (a + b) * (a + 1) - b * b
+         fn sum(lo, hi) = if lo == hi then f(lo % 97, 5) else sum(lo, (lo + hi) // 2) + sum((lo + hi) // 2 + 1, hi)
+         sum(1, 20000)",
+    ),
+    (
+        "float arithmetic",
+        "fn sqrt(x, g, n) = if n == 0 then g else sqrt(x, (g + x / g) / 2, n - 1)
+         fn sum(lo, hi) = if lo == hi then sqrt(lo * 1.0, 1.0, 12) else sum(lo, (lo + hi) // 2) + sum((lo + hi) // 2 + 1, hi)
+         sum(1, 5000)",
+    ),
+    (
+        "nested arithmetic",
+        "fn f(a, b) = ((((a + 1) * (b - 2)) - ((a * 3) + (b // 2))) * (((a - b) + 7) - ((b * 2) - (a % 5)))) + ((((a * a) - (b + 3)) + ((a - 4) * (b + 1))) - (((a + b) * 2) - ((b - a) * (a + 2))))
+         fn sum(lo, hi) = if lo == hi then f(lo % 31, lo % 17) else sum(lo, (lo + hi) // 2) + sum((lo + hi) // 2 + 1, hi)
+         sum(1, 20000)",
+    ),
+];
+
+fn main() {
+    for (name, source) in PROGRAMS {
+        let ast = optimize(parser::parse(source).unwrap());
+        let mut bytecode = BytecodeCompiler::from_ast(ast.clone()).unwrap();
+        bytecode.peephole().unwrap();
+        let code = RegisterCompiler::from_ast(ast).unwrap();
+
+        report(name, "stack", || {
+            let mut vm = VM::new(bytecode.clone()).unwrap();
+            let start = Instant::now();
+            black_box(&mut vm).run().unwrap();
+            (start.elapsed(), vm.peek().unwrap())
+        });
+        report(name, "register", || {
+            let mut vm = RegisterVM::new(code.clone());
+            let start = Instant::now();
+            let result = black_box(&mut vm).run().unwrap();
+            (start.elapsed(), result)
+        });
+    }
+}
+
+/// Repeats `run` for at least two seconds and five runs and prints the mean.
+fn report(name: &str, engine: &str, mut run: impl FnMut() -> (Duration, Val)) {
+    let mut runs = 0u32;
+    let mut elapsed = Duration::ZERO;
+    let mut result = None;
+    while elapsed < Duration::from_secs(2) || runs < 5 {
+        let (time, val) = run();
+        elapsed += time;
+        result = Some(val);
+        runs += 1;
+    }
+    let mean = elapsed / runs;
+    println!(
+        "{:<18} {:<8} {:>10.3} ms/run  {:>8.1} runs/s  ({} runs, result {})",
+        name,
+        engine,
+        mean.as_secs_f64() * 1000.0,
+        runs as f64 / elapsed.as_secs_f64(),
+        runs,
+        result.unwrap()
+    );
+}
@@ -1,5 +1,7 @@
 use std::fmt;
+use std::ops::Deref;
 
+use crate::error::Span;
 use crate::val::Val;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -9,9 +11,43 @@ pub enum Operator {
     Minus,
     Multiply,
     Divide,
+    FloorDivide,
+    Modulo,
+    Power,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+    Not,
 }
 // ANCHOR_END: operator
 
+impl Operator {
+    /// `/`, `//` and `%`, which fail when the divisor is the int `0`.
+    pub fn is_division(&self) -> bool {
+        matches!(
+            self,
+            Operator::Divide | Operator::FloorDivide | Operator::Modulo
+        )
+    }
+
+    pub fn is_comparison(&self) -> bool {
+        matches!(
+            self,
+            Operator::Equal
+                | Operator::NotEqual
+                | Operator::Less
+                | Operator::LessEqual
+                | Operator::Greater
+                | Operator::GreaterEqual
+        )
+    }
+}
+
 impl fmt::Display for Operator {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match &self {
@@ -19,6 +55,18 @@ impl fmt::Display for Operator {
             Operator::Minus => write!(f, "-"),
             Operator::Multiply => write!(f, "*"),
             Operator::Divide => write!(f, "/"),
+            Operator::FloorDivide => write!(f, "//"),
+            Operator::Modulo => write!(f, "%"),
+            Operator::Power => write!(f, "^"),
+            Operator::Equal => write!(f, "=="),
+            Operator::NotEqual => write!(f, "!="),
+            Operator::Less => write!(f, "<"),
+            Operator::LessEqual => write!(f, "<="),
+            Operator::Greater => write!(f, ">"),
+            Operator::GreaterEqual => write!(f, ">="),
+            Operator::And => write!(f, "&&"),
+            Operator::Or => write!(f, "||"),
+            Operator::Not => write!(f, "!"),
         }
     }
 }
@@ -27,25 +75,136 @@ impl fmt::Display for Operator {
 // ANCHOR: node
 pub enum Node {
     Val(Val),
+    Ident(String),
+    Let {
+        name: String,
+        value: Box<Spanned<Node>>,
+    },
+    FnDef {
+        name: String,
+        params: Vec<String>,
+        body: Box<Spanned<Node>>,
+    },
+    Call {
+        name: String,
+        args: Vec<Spanned<Node>>,
+    },
     UnaryExpr {
         op: Operator,
-        child: Box<Node>,
+        child: Box<Spanned<Node>>,
     },
     BinaryExpr {
         op: Operator,
-        lhs: Box<Node>,
-        rhs: Box<Node>,
+        lhs: Box<Spanned<Node>>,
+        rhs: Box<Spanned<Node>>,
     },
+    If {
+        cond: Box<Spanned<Node>>,
+        then_branch: Box<Spanned<Node>>,
+        else_branch: Box<Spanned<Node>>,
+    },
+    /// Stands in for a statement that did not parse, in the partial AST that
+    /// [`parse_with_recovery`](crate::parser::parse_with_recovery) returns.
+    /// Backends reject it.
+    Error,
 }
 // ANCHOR_END: node
 
+/// A node of the AST with the span of source it was parsed from. Spans take
+/// no part in comparisons and are not shown by `Debug`, so a tree built by
+/// hand equals the same tree parsed from source.
+#[derive(Clone)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Self { node, span }
+    }
+
+    /// Applies `f` to the node, keeping its span.
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Spanned<U> {
+        Spanned::new(f(self.node), self.span)
+    }
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
+
+/// A node that was not parsed from source, such as one built by a test, has
+/// an empty span at offset 0.
+impl From<Node> for Spanned<Node> {
+    fn from(node: Node) -> Self {
+        Spanned::new(node, Span::default())
+    }
+}
+
+impl From<Node> for Box<Spanned<Node>> {
+    fn from(node: Node) -> Self {
+        Box::new(node.into())
+    }
+}
+
+impl<T: PartialEq> PartialEq for Spanned<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.node == other.node
+    }
+}
+
+impl<T: Eq> Eq for Spanned<T> {}
+
+impl PartialEq<Node> for Spanned<Node> {
+    fn eq(&self, other: &Node) -> bool {
+        self.node == *other
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Spanned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.node.fmt(f)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Spanned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.node.fmt(f)
+    }
+}
+
 impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
         match &self {
-            Node::Val(Val::Int(n)) => write!(f, "{}", n),
+            Node::Val(val) => write!(f, "{}", val),
+            Node::Ident(name) => write!(f, "{}", name),
+            Node::Let { name, value } => write!(f, "let {} = {}", name, value),
+            Node::FnDef { name, params, body } => {
+                write!(f, "fn {}({}) = {}", name, params.join(", "), body)
+            }
+            Node::Call { name, args } => {
+                write!(f, "{}(", name)?;
+                for (idx, arg) in args.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
             Node::UnaryExpr { op, child } => write!(f, "{}{}", op, child),
             Node::BinaryExpr { op, lhs, rhs } => write!(f, "{} {} {}", lhs, op, rhs),
-            Node::Val(Val::Float(n)) => write!(f, "{}", n),
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => write!(f, "if {} then {} else {}", cond, then_branch, else_branch),
+            Node::Error => write!(f, "<error>"),
         }
     }
 }
@@ -0,0 +1,171 @@
+use std::marker::PhantomData;
+use std::time::Instant;
+
+use crate::program::{Executable, Prepare};
+use crate::trace::{self, Category, Level};
+use crate::val::Val;
+use crate::{Closure, Compile, Interpreter, Node, RegisterVM, Result, Spanned, VM};
+
+/// An execution engine that can be chosen at runtime, for example with
+/// `calc --backend vm`.
+///
+/// Every method returns the result of the last statement. Only the
+/// interpreter and the stack VM can return the result of each statement, with
+/// [`Interpreter::eval_statements`] and [`VM::eval_statements`].
+pub trait Backend {
+    /// Short name used to select the backend.
+    fn name(&self) -> &'static str;
+
+    /// One-line description shown by `calc backends`.
+    fn description(&self) -> &'static str;
+
+    fn eval_ast(&self, ast: Vec<Spanned<Node>>) -> Result<Val>;
+
+    fn eval(&self, source: &str) -> Result<Val>;
+
+    /// Compiles a program in which `variables` are free, to be run many times
+    /// with different values for them. See [`crate::Program`].
+    fn prepare(&self, ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Box<dyn Executable>>;
+}
+
+/// Adapts any [`Compile`] implementation that produces a `Val`, and can
+/// [`Prepare`] a program, to a [`Backend`].
+struct Engine<T> {
+    name: &'static str,
+    description: &'static str,
+    compiler: PhantomData<fn() -> T>,
+}
+
+impl<T> Engine<T> {
+    fn new(name: &'static str, description: &'static str) -> Self {
+        Self {
+            name,
+            description,
+            compiler: PhantomData,
+        }
+    }
+
+    /// Runs `run`, tracing its result and how long it took.
+    fn traced(&self, run: impl FnOnce() -> Result<Val>) -> Result<Val> {
+        let start = Instant::now();
+        let result = run();
+        trace::emit(Category::Exec, Level::Info, || match &result {
+            Ok(val) => format!("{}: {} in {:?}", self.name, val, start.elapsed()),
+            Err(err) => format!("{}: error: {} in {:?}", self.name, err, start.elapsed()),
+        });
+        result
+    }
+}
+
+impl<T: Compile<Output = Val> + Prepare> Backend for Engine<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn description(&self) -> &'static str {
+        self.description
+    }
+
+    fn eval_ast(&self, ast: Vec<Spanned<Node>>) -> Result<Val> {
+        self.traced(|| T::from_ast(ast))
+    }
+
+    fn eval(&self, source: &str) -> Result<Val> {
+        self.traced(|| T::from_source(source))
+    }
+
+    fn prepare(&self, ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Box<dyn Executable>> {
+        T::prepare(ast, variables)
+    }
+}
+
+/// Every backend compiled into this build. The first one is the default.
+pub struct Registry {
+    backends: Vec<Box<dyn Backend>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        let backends: Vec<Box<dyn Backend>> = vec![
+            Box::new(Engine::<Interpreter>::new(
+                "interp",
+                "tree-walking interpreter",
+            )),
+            Box::new(Engine::<VM>::new("vm", "bytecode compiler and stack VM")),
+            Box::new(Engine::<RegisterVM>::new(
+                "reg",
+                "register allocator and register VM",
+            )),
+            Box::new(Engine::<Closure>::new(
+                "closure",
+                "AST compiled to Rust closures",
+            )),
+            #[cfg(feature = "jit")]
+            Box::new(Engine::<crate::Jit>::new("jit", "LLVM JIT compiler")),
+        ];
+        Self { backends }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Backend> {
+        self.iter().find(|backend| backend.name() == name)
+    }
+
+    pub fn default_backend(&self) -> &dyn Backend {
+        self.backends[0].as_ref()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &dyn Backend> {
+        self.backends.iter().map(|backend| backend.as_ref())
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.iter().map(|backend| backend.name()).collect()
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{CalcError, Span};
+    use crate::parser::parse_with_recovery;
+
+    #[test]
+    fn registry() {
+        let registry = Registry::new();
+        assert_eq!(registry.default_backend().name(), "interp");
+        assert!(
+            registry
+                .names()
+                .starts_with(&["interp", "vm", "reg", "closure"])
+        );
+        assert!(registry.get("nope").is_none());
+        for backend in registry.iter() {
+            assert_eq!(
+                backend.eval("fn sq(x) = x * x; sq(3) + 1").unwrap(),
+                Val::Int(10),
+                "Failed on backend: {}",
+                backend.name()
+            );
+            assert!(backend.eval("1 +").is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_unparsed_statements() {
+        let recovered = parse_with_recovery("let x = 2\nx +\nx");
+        for backend in Registry::new().iter() {
+            assert_eq!(
+                backend.eval_ast(recovered.ast.clone()),
+                Err(CalcError::unparsed().at(Span::new(10, 13))),
+                "Failed on backend: {}",
+                backend.name()
+            );
+        }
+    }
+}
@@ -1,26 +1,47 @@
-#![allow(unused_imports, unused_variables)]
-
+use clap::Parser;
 use rustyline::error::ReadlineError;
 use rustyline::{DefaultEditor, Result};
 
-use cfg_if::cfg_if;
+use calculator::trace::{self, Filter};
+use calculator::{Diagnostic, Registry, Renderer, parser};
 
-use calculator::Compile;
-cfg_if! {
-    if #[cfg(feature = "jit")] {
-        use calculator::Jit as Engine;
-    }
-    else if #[cfg(feature = "interpreter")] {
-        use calculator::Interpreter as Engine;
-    }
-    else if #[cfg(feature = "vm")]{
-        use calculator::vm::bytecode::Interpreter as Engine;
-        use calculator::VM;
-    }
+#[derive(Parser)]
+struct Args {
+    /// Execution backend to use
+    #[arg(short, long, value_name = "NAME")]
+    backend: Option<String>,
+
+    /// Run lines as written, without constant folding and simplification
+    #[arg(long)]
+    no_opt: bool,
+
+    /// Print what happens to each line to stderr, such as 'bytecode,ir'
+    #[arg(long, value_name = "CATEGORIES")]
+    trace: Option<Filter>,
 }
 
 // ANCHOR: repl
 fn main() -> Result<()> {
+    let args = Args::parse();
+    if let Some(filter) = args.trace {
+        trace::set_tracer(filter, |event| eprintln!("{}", event));
+    }
+    let registry = Registry::new();
+    let backend = match &args.backend {
+        Some(name) => match registry.get(name) {
+            Some(backend) => backend,
+            None => {
+                eprintln!(
+                    "unknown backend '{}', available backends: {}",
+                    name,
+                    registry.names().join(", ")
+                );
+                std::process::exit(1);
+            }
+        },
+        None => registry.default_backend(),
+    };
+
     let mut rl = DefaultEditor::new()?;
     println!("Calculator prompt. Expressions are line evaluated.");
     loop {
@@ -31,21 +52,18 @@ fn main() -> Result<()> {
                 if line.is_empty() {
                     continue;
                 }
-                cfg_if! {
-                    if #[cfg(any(feature = "jit", feature = "interpreter"))] {
-                        match Engine::from_source(line) {
-                            Ok(result) => println!("{}", result),
-                            Err(e) => eprintln!("{}", e),
-                        };
-                    }
-                    else if #[cfg(feature = "vm")] {
-                        let byte_code = Engine::from_source(line);
-                        println!("byte code: {:?}", byte_code);
-                        let mut vm = VM::new(byte_code);
-                        vm.run();
-                        println!("{}", vm.pop_last());
-                    }
-                }
+                let result = if args.no_opt {
+                    parser::parse(line).and_then(|ast| backend.eval_ast(ast))
+                } else {
+                    backend.eval(line)
+                };
+                match result {
+                    Ok(result) => println!("{}", result),
+                    Err(e) => eprint!(
+                        "{}",
+                        Renderer::stderr().render(&Diagnostic::from(&e), "<repl>", line)
+                    ),
+                };
             }
             Err(ReadlineError::Interrupted) => {
                 println!("CTRL-C");
@@ -0,0 +1,417 @@
+//! Compiles the AST once into nested Rust closures, one per node, which call
+//! each other directly instead of matching on the tree every time it runs.
+//! Names are resolved while compiling: a variable becomes an index into the
+//! globals or into the current call's arguments, and a call an index into
+//! the function table, so running the program does no lookups by name.
+//!
+//! A program can leave some variables free and be run many times with
+//! different values for them, without parsing or compiling it again:
+//!
+//! ```
+//! use calculator::closure::Compiler;
+//! use calculator::{parser, val::Val};
+//!
+//! let ast = parser::parse("let d = x - y; d * d").unwrap();
+//! let program = Compiler::compile(ast, &["x", "y"]).unwrap();
+//! assert_eq!(program.eval(&[Val::Int(5), Val::Int(2)]), Ok(Val::Int(9)));
+//! assert_eq!(program.eval(&[Val::Int(1), Val::Int(4)]), Ok(Val::Int(9)));
+//! ```
+
+use std::collections::HashMap;
+
+use crate::compiler::MAX_CALL_DEPTH;
+use crate::error::{CalcError, RuntimeError};
+use crate::program::{Executable, Prepare, check_variables};
+use crate::val::Val;
+use crate::{Compile, Node, Operator, Result, Spanned};
+
+type Expr = Box<dyn Fn(&mut Env<'_>) -> Result<Val>>;
+
+/// State of one evaluation, passed to every closure.
+struct Env<'a> {
+    functions: &'a [Expr],
+    globals: Vec<Val>, // free variables followed by `let` bindings
+    stack: Vec<Val>,   // arguments of every active call
+    base: usize,       // index of the current call's first argument
+    depth: usize,
+}
+
+/// A program compiled to closures.
+pub struct Compiled {
+    statements: Vec<Expr>,
+    functions: Vec<Expr>,
+    variables: usize,
+    globals: usize,
+}
+
+impl Compiled {
+    /// Runs the program, returning the value of its last statement. `args`
+    /// holds the values of the free variables, in the order they were given
+    /// to [`Compiler::compile`].
+    pub fn eval(&self, args: &[Val]) -> Result<Val> {
+        if args.len() != self.variables {
+            return Err(CalcError::type_error(format!(
+                "program expects {} variables but got {}",
+                self.variables,
+                args.len()
+            )));
+        }
+        let mut globals = Vec::with_capacity(self.globals);
+        globals.extend_from_slice(args);
+        globals.resize(self.globals, Val::Int(0));
+        let mut env = Env {
+            functions: &self.functions,
+            globals,
+            stack: Vec::new(),
+            base: 0,
+            depth: 0,
+        };
+        let mut result = None;
+        for statement in &self.statements {
+            result = Some(statement(&mut env)?);
+        }
+        result.ok_or_else(|| CalcError::type_error("cannot evaluate an empty program"))
+    }
+}
+
+pub struct Compiler {
+    globals: HashMap<String, usize>,            // index of each global
+    functions: HashMap<String, (usize, usize)>, // index into the function table and arity
+    locals: Option<Vec<String>>,                // parameters of the function being compiled
+}
+
+impl Compile for Compiler {
+    type Output = Compiled;
+
+    fn from_ast(ast: Vec<Spanned<Node>>) -> Result<Self::Output> {
+        Compiler::compile(ast, &[])
+    }
+}
+
+impl Compiler {
+    /// Compiles a program in which `variables` may be read without being
+    /// bound by `let`. Their values are passed to [`Compiled::eval`].
+    pub fn compile(ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Compiled> {
+        let mut compiler = Compiler {
+            globals: HashMap::new(),
+            functions: HashMap::new(),
+            locals: None,
+        };
+        check_variables(variables)?;
+        for (idx, name) in variables.iter().enumerate() {
+            compiler.globals.insert(name.to_string(), idx);
+        }
+        // function definitions are hoisted so they can be called from anywhere
+        let (definitions, statements): (Vec<_>, Vec<_>) = ast
+            .into_iter()
+            .partition(|node| matches!(node.node, Node::FnDef { .. }));
+        for (idx, node) in definitions.iter().enumerate() {
+            if let Node::FnDef { name, params, .. } = &node.node {
+                compiler.functions.insert(name.clone(), (idx, params.len()));
+            }
+        }
+
+        let statements = statements
+            .into_iter()
+            .map(|node| compiler.compile_node(node))
+            .collect::<Result<Vec<_>>>()?;
+        let mut functions = Vec::with_capacity(definitions.len());
+        for node in definitions {
+            if let Node::FnDef { params, body, .. } = node.node {
+                compiler.locals = Some(params);
+                functions.push(compiler.compile_node(*body)?);
+                compiler.locals = None;
+            }
+        }
+        Ok(Compiled {
+            statements,
+            functions,
+            variables: variables.len(),
+            globals: compiler.globals.len(),
+        })
+    }
+
+    /// Compiles `node` to a closure. Errors raised by the node itself, while
+    /// compiling or while running, point at its span.
+    fn compile_node(&mut self, node: Spanned<Node>) -> Result<Expr> {
+        let span = node.span;
+        let at = move |err: CalcError| err.at(span);
+        let expr: Expr = match node.node {
+            Node::Val(val) => Box::new(move |_| Ok(val)),
+            Node::Ident(name) => match &self.locals {
+                Some(locals) => match locals.iter().position(|local| *local == name) {
+                    Some(idx) => Box::new(move |env| Ok(env.stack[env.base + idx])),
+                    None => return Err(undefined_variable(&name).at(span)),
+                },
+                None => match self.globals.get(&name) {
+                    Some(&idx) => Box::new(move |env| Ok(env.globals[idx])),
+                    None => return Err(undefined_variable(&name).at(span)),
+                },
+            },
+            Node::Let { name, value } => {
+                if self.locals.is_some() {
+                    return Err(CalcError::type_error(format!(
+                        "`let {}` can only be used at the top level",
+                        name
+                    ))
+                    .at(span));
+                }
+                // the value is compiled before the name is bound so that
+                // `let x = x + 1` refers to the previous binding
+                let value = self.compile_node(*value)?;
+                let next = self.globals.len();
+                let idx = *self.globals.entry(name).or_insert(next);
+                Box::new(move |env| {
+                    let val = value(env)?;
+                    env.globals[idx] = val;
+                    Ok(val)
+                })
+            }
+            Node::FnDef { name, .. } => {
+                return Err(CalcError::type_error(format!(
+                    "function `{}` can only be defined at the top level",
+                    name
+                ))
+                .at(span));
+            }
+            Node::Call { name, args } => {
+                let Some(&(idx, arity)) = self.functions.get(&name) else {
+                    return Err(
+                        CalcError::type_error(format!("undefined function `{}`", name)).at(span),
+                    );
+                };
+                if arity != args.len() {
+                    return Err(CalcError::type_error(format!(
+                        "function `{}` expects {} arguments but got {}",
+                        name,
+                        arity,
+                        args.len()
+                    ))
+                    .at(span));
+                }
+                let args = args
+                    .into_iter()
+                    .map(|arg| self.compile_node(arg))
+                    .collect::<Result<Vec<_>>>()?;
+                Box::new(move |env| {
+                    let base = env.stack.len();
+                    for arg in &args {
+                        let val = arg(env)?;
+                        env.stack.push(val);
+                    }
+                    if env.depth >= MAX_CALL_DEPTH {
+                        return Err(CalcError::runtime(RuntimeError::CallDepthExceeded).at(span));
+                    }
+                    let caller = std::mem::replace(&mut env.base, base);
+                    env.depth += 1;
+                    let ret = (env.functions[idx])(env);
+                    env.depth -= 1;
+                    env.base = caller;
+                    env.stack.truncate(base);
+                    ret
+                })
+            }
+            Node::UnaryExpr { op, child } => {
+                let child = self.compile_node(*child)?;
+                Box::new(move |env| child(env)?.unary(op).map_err(at))
+            }
+            // `&&` and `||` short-circuit, so the rhs is only evaluated when needed
+            Node::BinaryExpr {
+                op: op @ (Operator::And | Operator::Or),
+                lhs,
+                rhs,
+            } => {
+                let lhs = self.compile_node(*lhs)?;
+                let rhs = self.compile_node(*rhs)?;
+                let short_circuit = op == Operator::Or;
+                Box::new(move |env| {
+                    if lhs(env)?.expect_bool().map_err(at)? == short_circuit {
+                        Ok(Val::Bool(short_circuit))
+                    } else {
+                        Ok(Val::Bool(rhs(env)?.expect_bool().map_err(at)?))
+                    }
+                })
+            }
+            Node::BinaryExpr { op, lhs, rhs } => {
+                let lhs = self.compile_node(*lhs)?;
+                let rhs = self.compile_node(*rhs)?;
+                // one closure per operator, so that each only has the fast
+                // path for its own operator
+                macro_rules! binary {
+                    ($($op:ident),*) => {
+                        match op {
+                            $(Operator::$op => Box::new(move |env| {
+                                lhs(env)?
+                                    .fast_binary(Operator::$op, rhs(env)?)
+                                    .map_err(at)
+                            }),)*
+                            _ => {
+                                return Err(CalcError::internal(format!(
+                                    "`{}` is not a binary operator",
+                                    op
+                                )));
+                            }
+                        }
+                    };
+                }
+                binary!(
+                    Plus,
+                    Minus,
+                    Multiply,
+                    Divide,
+                    FloorDivide,
+                    Modulo,
+                    Power,
+                    Equal,
+                    NotEqual,
+                    Less,
+                    LessEqual,
+                    Greater,
+                    GreaterEqual
+                )
+            }
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let cond = self.compile_node(*cond)?;
+                let then_branch = self.compile_node(*then_branch)?;
+                let else_branch = self.compile_node(*else_branch)?;
+                Box::new(move |env| {
+                    if cond(env)?.expect_bool().map_err(at)? {
+                        then_branch(env)
+                    } else {
+                        else_branch(env)
+                    }
+                })
+            }
+            Node::Error => return Err(CalcError::unparsed().at(span)),
+        };
+        Ok(expr)
+    }
+}
+
+fn undefined_variable(name: &str) -> CalcError {
+    CalcError::type_error(format!("undefined variable `{}`", name))
+}
+
+/// The closure backend: compiles the program to closures and runs it once.
+pub struct Closure;
+
+impl Compile for Closure {
+    type Output = Val;
+
+    fn from_ast(ast: Vec<Spanned<Node>>) -> Result<Self::Output> {
+        Compiler::from_ast(ast)?.eval(&[])
+    }
+}
+
+impl Prepare for Closure {
+    fn prepare(ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Box<dyn Executable>> {
+        Ok(Box::new(Compiler::compile(ast, variables)?))
+    }
+}
+
+impl Executable for Compiled {
+    fn eval(&self, args: &[Val]) -> Result<Val> {
+        Compiled::eval(self, args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn eval(source: &str) -> Result<Val> {
+        Closure::from_ast(parse(source).unwrap())
+    }
+
+    #[test]
+    fn programs() {
+        let tests = [
+            ("1 + 2 * 3", Val::Int(7)),
+            ("-7 // 2 + 7 % -2", Val::Int(-5)),
+            ("7 / 2", Val::Float(3.5.into())),
+            ("let x = 2; let x = x * x + x; x", Val::Int(6)),
+            ("1 < 2 && !(2.5 >= 3) || false", Val::Bool(true)),
+            (
+                "fn fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2); fib(15)",
+                Val::Int(610),
+            ),
+            (
+                "fn add(a, b) = a + b; fn f(x) = add(x, 1) * add(x * 2, add(x, 3)); f(2)",
+                Val::Int(27),
+            ),
+        ];
+        for (source, expected) in tests {
+            assert_eq!(eval(source), Ok(expected), "Failed on input: {}", source);
+        }
+    }
+
+    #[test]
+    fn free_variables() {
+        let ast =
+            parse("fn sq(v) = v * v; let r = sq(x) + sq(y); if r > 10 then r else -r").unwrap();
+        let program = Compiler::compile(ast, &["x", "y"]).unwrap();
+        assert_eq!(program.eval(&[Val::Int(3), Val::Int(4)]), Ok(Val::Int(25)));
+        assert_eq!(program.eval(&[Val::Int(1), Val::Int(2)]), Ok(Val::Int(-5)));
+        assert_eq!(
+            program.eval(&[Val::Float(0.5.into()), Val::Int(0)]),
+            Ok(Val::Float((-0.25).into()))
+        );
+        assert_eq!(
+            program.eval(&[Val::Int(1)]).unwrap_err().to_string(),
+            "program expects 2 variables but got 1"
+        );
+        assert_eq!(
+            program
+                .eval(&[Val::Bool(true), Val::Int(1)])
+                .unwrap_err()
+                .to_string(),
+            "cannot apply `*` to bool and bool"
+        );
+        // a free variable can be rebound like any other
+        let program = Compiler::compile(parse("let x = x + 1; x * 2").unwrap(), &["x"]).unwrap();
+        assert_eq!(program.eval(&[Val::Int(4)]), Ok(Val::Int(10)));
+    }
+
+    #[test]
+    fn errors() {
+        let err = |source| eval(source).unwrap_err().to_string();
+        assert_eq!(err("let x = 1; y + x"), "undefined variable `y`");
+        assert_eq!(
+            err("let y = 1; fn f(x) = x + y; f(1)"),
+            "undefined variable `y`"
+        );
+        assert_eq!(err("f(1)"), "undefined function `f`");
+        assert_eq!(
+            err("fn f(a, b) = a; f(1)"),
+            "function `f` expects 2 arguments but got 1"
+        );
+        assert_eq!(
+            err("fn f(x) = f(x); f(1)"),
+            "maximum call depth of 256 exceeded"
+        );
+        assert_eq!(err("true && 1"), "expected a bool, found int");
+        assert_eq!(err("1 // 0"), "division by zero");
+        assert_eq!(err("fn f(x) = x"), "cannot evaluate an empty program");
+        assert_eq!(
+            Compiler::compile(parse("x").unwrap(), &["x", "x"])
+                .err()
+                .unwrap()
+                .to_string(),
+            "variable `x` is given more than once"
+        );
+    }
+
+    #[test]
+    fn short_circuit() {
+        let prelude = "fn loop(x) = loop(x)\n";
+        for (source, expected) in [("false && loop(1)", false), ("true || loop(1)", true)] {
+            let val = eval(&format!("{}{}", prelude, source)).unwrap();
+            assert_eq!(val, Val::Bool(expected), "Failed on input: {}", source);
+        }
+    }
+}
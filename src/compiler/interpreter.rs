@@ -1,73 +1,369 @@
 #![allow(clippy::only_used_in_recursion)]
 
-use ordered_float::OrderedFloat;
+use std::collections::{HashMap, HashSet};
 
-use crate::{val::Val, Compile, Node, Operator, Result};
+use crate::compiler::MAX_CALL_DEPTH;
+use crate::error::{CalcError, RuntimeError};
+use crate::program::{Executable, Prepare, check_variables};
+use crate::{Compile, Node, Operator, Result, Spanned, val::Val};
 
 // ANCHOR: interpreter
 pub struct Interpreter;
 
 impl Compile for Interpreter {
-    type Output = Result<Val>;
+    type Output = Val;
 
-    fn from_ast(ast: Vec<Node>) -> Self::Output {
-        let mut ret = Val::Float(ordered_float::OrderedFloat(0f32));
-        let evaluator = Eval::new();
-        for node in ast {
-            ret += evaluator.eval(&node);
-        }
-        Ok(ret)
+    fn from_ast(ast: Vec<Spanned<Node>>) -> Result<Self::Output> {
+        Interpreter::eval_statements(ast)?
+            .pop()
+            .ok_or_else(|| CalcError::type_error("cannot evaluate an empty program"))
+    }
+}
+
+impl Interpreter {
+    /// Evaluates every top-level statement in order, returning the result of each one.
+    /// Function definitions are hoisted and do not produce a result. Names are
+    /// resolved before anything runs, so an undefined name is an error even in
+    /// a branch that is never taken, as in the compiled backends.
+    ///
+    /// Only this backend and the stack VM, with [`VM::eval_statements`](crate::VM::eval_statements),
+    /// return per-statement results; [`Backend`](crate::Backend) and the
+    /// other backends return the last statement's.
+    pub fn eval_statements(ast: Vec<Spanned<Node>>) -> Result<Vec<Val>> {
+        resolve(&ast, &[])?;
+        Eval::new(&ast).eval_statements(&ast)
+    }
+}
+
+/// A program kept as its AST, with the names of its free variables.
+struct Prepared {
+    ast: Vec<Spanned<Node>>,
+    variables: Vec<String>,
+}
+
+impl Prepare for Interpreter {
+    fn prepare(ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Box<dyn Executable>> {
+        resolve(&ast, variables)?;
+        let variables = variables.iter().map(|name| name.to_string()).collect();
+        Ok(Box::new(Prepared { ast, variables }))
+    }
+}
+
+impl Executable for Prepared {
+    fn eval(&self, args: &[Val]) -> Result<Val> {
+        let mut evaluator = Eval::new(&self.ast);
+        evaluator.env = self
+            .variables
+            .iter()
+            .cloned()
+            .zip(args.iter().copied())
+            .collect();
+        evaluator
+            .eval_statements(&self.ast)?
+            .pop()
+            .ok_or_else(|| CalcError::type_error("cannot evaluate an empty program"))
     }
 }
 // ANCHOR_END: interpreter
 
 // ANCHOR: interpreter_recursive
-struct Eval;
+struct Eval<'a> {
+    env: HashMap<String, Val>,
+    // one scope per active call; a function body only sees its own parameters
+    frames: Vec<HashMap<&'a str, Val>>,
+    functions: HashMap<&'a str, (&'a [String], &'a Spanned<Node>)>,
+}
 
-impl Eval {
-    pub fn new() -> Self {
-        Self
+impl<'a> Eval<'a> {
+    pub fn new(ast: &'a [Spanned<Node>]) -> Self {
+        let functions = ast
+            .iter()
+            .filter_map(|node| match &node.node {
+                Node::FnDef { name, params, body } => {
+                    Some((name.as_str(), (params.as_slice(), &**body)))
+                }
+                _ => None,
+            })
+            .collect();
+        Self {
+            env: HashMap::new(),
+            frames: Vec::new(),
+            functions,
+        }
     }
+
+    fn eval_statements(&mut self, ast: &'a [Spanned<Node>]) -> Result<Vec<Val>> {
+        ast.iter()
+            .filter(|node| !matches!(node.node, Node::FnDef { .. }))
+            .map(|node| self.eval(node))
+            .collect()
+    }
+
     // ANCHOR: interpreter_eval
-    pub fn eval(&self, node: &Node) -> Val {
-        match node {
-            Node::Val(Val::Int(n)) => Val::Int(*n),
-            Node::Val(Val::Float(f)) => Val::Float(*f),
-            Node::UnaryExpr { op, child } => {
-                let child = self.eval(child);
-                match op {
-                    Operator::Plus => child,
-                    Operator::Minus => -child,
-                    _ => {
-                        panic!("Cannot apply {op} to the Val");
-                    }
+    /// Evaluates `node`. An error raised by the node itself points at its
+    /// span, while one raised by a child keeps the child's.
+    ///
+    /// Only dispatches, leaving each kind of node to its own method: a call
+    /// recurses through here twice, and in debug builds a frame holding the
+    /// temporaries of every arm would overflow a 2 MiB thread stack before
+    /// the depth reached [`MAX_CALL_DEPTH`].
+    pub fn eval(&mut self, node: &'a Spanned<Node>) -> Result<Val> {
+        match &node.node {
+            Node::Val(val) => Ok(*val),
+            Node::Ident(name) => self.variable(name, node),
+            Node::FnDef { name, .. } => Err(nested_function(name, node)),
+            Node::Call { name, args } => self.call(name, args, node),
+            Node::Let { name, value } => self.let_binding(name, value),
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => self.if_expr(cond, then_branch, else_branch, node),
+            Node::Error => Err(CalcError::unparsed().at(node.span)),
+            Node::UnaryExpr { op, child } => self.unary(*op, child, node),
+            Node::BinaryExpr { op, lhs, rhs } => self.binary(*op, lhs, rhs, node),
+        }
+    }
+    // ANCHOR_END: interpreter_eval
+
+    fn variable(&self, name: &str, node: &Spanned<Node>) -> Result<Val> {
+        let val = match self.frames.last() {
+            Some(frame) => frame.get(name),
+            None => self.env.get(name),
+        };
+        val.copied().ok_or_else(|| {
+            CalcError::type_error(format!("undefined variable `{}`", name)).at(node.span)
+        })
+    }
+
+    fn call(&mut self, name: &str, args: &'a [Spanned<Node>], node: &Spanned<Node>) -> Result<Val> {
+        let (params, body) = self.function(name, args, node)?;
+        let frame = self.bind(params, args)?;
+        self.frames.push(frame);
+        let ret = self.eval(body);
+        self.frames.pop();
+        ret
+    }
+
+    /// Looks up the function a call refers to, checking its arity and that
+    /// the call does not nest too deep.
+    fn function(
+        &self,
+        name: &str,
+        args: &[Spanned<Node>],
+        node: &Spanned<Node>,
+    ) -> Result<(&'a [String], &'a Spanned<Node>)> {
+        let Some(&(params, body)) = self.functions.get(name) else {
+            return Err(
+                CalcError::type_error(format!("undefined function `{}`", name)).at(node.span),
+            );
+        };
+        if params.len() != args.len() {
+            return Err(CalcError::type_error(format!(
+                "function `{}` expects {} arguments but got {}",
+                name,
+                params.len(),
+                args.len()
+            ))
+            .at(node.span));
+        }
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            return Err(CalcError::runtime(RuntimeError::CallDepthExceeded).at(node.span));
+        }
+        Ok((params, body))
+    }
+
+    /// Evaluates the arguments of a call into the scope of its body.
+    fn bind(
+        &mut self,
+        params: &'a [String],
+        args: &'a [Spanned<Node>],
+    ) -> Result<HashMap<&'a str, Val>> {
+        let mut frame = HashMap::new();
+        for (param, arg) in params.iter().zip(args) {
+            frame.insert(param.as_str(), self.eval(arg)?);
+        }
+        Ok(frame)
+    }
+
+    fn let_binding(&mut self, name: &str, value: &'a Spanned<Node>) -> Result<Val> {
+        let val = self.eval(value)?;
+        self.env.insert(name.to_string(), val);
+        Ok(val)
+    }
+
+    fn if_expr(
+        &mut self,
+        cond: &'a Spanned<Node>,
+        then_branch: &'a Spanned<Node>,
+        else_branch: &'a Spanned<Node>,
+        node: &Spanned<Node>,
+    ) -> Result<Val> {
+        if self.bool_operand(cond, node)? {
+            self.eval(then_branch)
+        } else {
+            self.eval(else_branch)
+        }
+    }
+
+    /// Evaluates an operand of `node` that must be a bool.
+    fn bool_operand(&mut self, operand: &'a Spanned<Node>, node: &Spanned<Node>) -> Result<bool> {
+        self.eval(operand)?
+            .expect_bool()
+            .map_err(|err| err.at(node.span))
+    }
+
+    fn unary(
+        &mut self,
+        op: Operator,
+        child: &'a Spanned<Node>,
+        node: &Spanned<Node>,
+    ) -> Result<Val> {
+        self.eval(child)?.unary(op).map_err(|err| err.at(node.span))
+    }
+
+    fn binary(
+        &mut self,
+        op: Operator,
+        lhs: &'a Spanned<Node>,
+        rhs: &'a Spanned<Node>,
+        node: &Spanned<Node>,
+    ) -> Result<Val> {
+        // `&&` and `||` short-circuit, so the rhs is only evaluated when needed
+        if let Operator::And | Operator::Or = op {
+            let lhs_ret = self.bool_operand(lhs, node)?;
+            if lhs_ret == (op == Operator::Or) {
+                return Ok(Val::Bool(lhs_ret));
+            }
+            return self.bool_operand(rhs, node).map(Val::Bool);
+        }
+        let lhs_ret = self.eval(lhs)?;
+        let rhs_ret = self.eval(rhs)?;
+        lhs_ret.binary(op, rhs_ret).map_err(|err| err.at(node.span))
+    }
+}
+
+fn nested_function(name: &str, node: &Spanned<Node>) -> CalcError {
+    CalcError::type_error(format!(
+        "function `{}` can only be defined at the top level",
+        name
+    ))
+    .at(node.span)
+}
+
+// ANCHOR_END: interpreter_recursive
+
+/// Checks every name in a program the way the compiled backends do while
+/// compiling it, reporting the first undefined variable or function, wrong
+/// number of arguments or misplaced definition in the same order they would.
+fn resolve(ast: &[Spanned<Node>], variables: &[&str]) -> Result<()> {
+    check_variables(variables)?;
+    let mut resolver = Resolver {
+        globals: variables.iter().copied().collect(),
+        functions: HashMap::new(),
+        locals: None,
+    };
+    for node in ast {
+        if let Node::FnDef { name, params, .. } = &node.node {
+            resolver.functions.insert(name, params.len());
+        }
+    }
+    // statements first, then function bodies, which only see their parameters
+    for node in ast {
+        if !matches!(node.node, Node::FnDef { .. }) {
+            resolver.resolve(node)?;
+        }
+    }
+    for node in ast {
+        if let Node::FnDef { params, body, .. } = &node.node {
+            resolver.locals = Some(params);
+            resolver.resolve(body)?;
+        }
+    }
+    Ok(())
+}
+
+struct Resolver<'a> {
+    globals: HashSet<&'a str>,
+    functions: HashMap<&'a str, usize>, // arity of each function
+    locals: Option<&'a [String]>,       // parameters of the function being resolved
+}
+
+impl<'a> Resolver<'a> {
+    fn resolve(&mut self, node: &'a Spanned<Node>) -> Result<()> {
+        match &node.node {
+            Node::Val(_) => Ok(()),
+            Node::Ident(name) => {
+                let defined = match self.locals {
+                    Some(locals) => locals.contains(name),
+                    None => self.globals.contains(name.as_str()),
+                };
+                if defined {
+                    Ok(())
+                } else {
+                    Err(
+                        CalcError::type_error(format!("undefined variable `{}`", name))
+                            .at(node.span),
+                    )
                 }
             }
-            Node::BinaryExpr { op, lhs, rhs } => {
-                let lhs_ret = self.eval(lhs);
-                let rhs_ret = self.eval(rhs);
-
-                match op {
-                    Operator::Plus => lhs_ret + rhs_ret,
-                    Operator::Minus => lhs_ret - rhs_ret,
-                    Operator::Multiply => lhs_ret * rhs_ret,
-                    Operator::Divide => match (lhs_ret, rhs_ret) {
-                        (Val::Int(l), Val::Int(r)) => Val::Float(OrderedFloat(l as f32 / r as f32)),
-                        (Val::Float(l), Val::Float(r)) => Val::Float(l / r),
-                        (Val::Float(l), Val::Int(r)) => Val::Float(l / OrderedFloat(r as f32)),
-                        (Val::Int(l), Val::Float(r)) => Val::Float(OrderedFloat(l as f32) / r),
-                    },
+            Node::Let { name, value } => {
+                if self.locals.is_some() {
+                    return Err(CalcError::type_error(format!(
+                        "`let {}` can only be used at the top level",
+                        name
+                    ))
+                    .at(node.span));
                 }
+                // the value is resolved before the name is bound, so that
+                // `let x = x + 1` refers to the previous binding
+                self.resolve(value)?;
+                self.globals.insert(name);
+                Ok(())
+            }
+            Node::FnDef { name, .. } => Err(nested_function(name, node)),
+            Node::Call { name, args } => {
+                let Some(&arity) = self.functions.get(name.as_str()) else {
+                    return Err(
+                        CalcError::type_error(format!("undefined function `{}`", name))
+                            .at(node.span),
+                    );
+                };
+                if arity != args.len() {
+                    return Err(CalcError::type_error(format!(
+                        "function `{}` expects {} arguments but got {}",
+                        name,
+                        arity,
+                        args.len()
+                    ))
+                    .at(node.span));
+                }
+                args.iter().try_for_each(|arg| self.resolve(arg))
+            }
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.resolve(cond)?;
+                self.resolve(then_branch)?;
+                self.resolve(else_branch)
+            }
+            Node::Error => Err(CalcError::unparsed().at(node.span)),
+            Node::UnaryExpr { child, .. } => self.resolve(child),
+            Node::BinaryExpr { lhs, rhs, .. } => {
+                self.resolve(lhs)?;
+                self.resolve(rhs)
             }
         }
     }
-    // ANCHOR_END: interpreter_eval
 }
-// ANCHOR_END: interpreter_recursive
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::Span;
+    use crate::parser::parse;
 
     #[test]
     fn basics() {
@@ -85,8 +381,264 @@ mod tests {
             let result = match val {
                 Val::Int(n) => n,
                 Val::Float(f) => f.into_inner() as i32,
+                Val::Bool(_) => panic!("Expected a number"),
             };
             assert_eq!(result, expected, "Failed on input: {}", src);
         }
     }
+
+    #[test]
+    fn let_bindings() {
+        assert_eq!(
+            Interpreter::from_source("let x = 2 * 3; x + 1").unwrap(),
+            Val::Int(7)
+        );
+        assert_eq!(
+            Interpreter::from_source("let x = 2; let y = x * x; y - x").unwrap(),
+            Val::Int(2)
+        );
+        assert_eq!(
+            Interpreter::from_source("let x = 1; let x = x + 1; x").unwrap(),
+            Val::Int(2)
+        );
+    }
+
+    #[test]
+    fn undefined_variable() {
+        let err = Interpreter::from_source("let x = 1; y + x").unwrap_err();
+        assert_eq!(err.to_string(), "undefined variable `y`");
+    }
+
+    #[test]
+    fn multiple_statements() {
+        assert_eq!(
+            Interpreter::from_source("1 + 1; 2 * 5").unwrap(),
+            Val::Int(10)
+        );
+        assert_eq!(
+            Interpreter::from_source("let x = 2\nlet y = 3\n\nx * y\n").unwrap(),
+            Val::Int(6)
+        );
+    }
+
+    #[test]
+    fn statement_results() {
+        let ast = crate::parser::parse("let x = 4; x * 2; 1.5").unwrap();
+        assert_eq!(
+            Interpreter::eval_statements(ast).unwrap(),
+            vec![Val::Int(4), Val::Int(8), Val::Float(1.5.into())]
+        );
+        assert!(Interpreter::from_ast(vec![]).is_err());
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(
+            Interpreter::from_source("fn area(w, h) = w * h; area(3, 4)").unwrap(),
+            Val::Int(12)
+        );
+        assert_eq!(
+            Interpreter::from_source(
+                "fn sq(x) = x * x\nfn hyp2(a, b) = sq(a) + sq(b)\nhyp2(3, 4.0)"
+            )
+            .unwrap(),
+            Val::Float(25.0.into())
+        );
+        // definitions are hoisted and do not produce a result
+        let ast =
+            crate::parser::parse("double(2); fn double(x) = x * 2; let x = 1; double(x)").unwrap();
+        assert_eq!(
+            Interpreter::eval_statements(ast).unwrap(),
+            vec![Val::Int(4), Val::Int(1), Val::Int(2)]
+        );
+    }
+
+    #[test]
+    fn resolves_names_before_running() {
+        let err = |src| Interpreter::from_source(src).unwrap_err();
+        assert_eq!(
+            err("let t = true; if t then 1 else zz"),
+            CalcError::type_error("undefined variable `zz`").at(Span::new(31, 33))
+        );
+        // the name error is reported before the division runs
+        assert_eq!(
+            err("1 / 0; let t = false; t && f(1)").to_string(),
+            "undefined function `f`"
+        );
+        assert_eq!(
+            err("fn f(x) = x; let t = false; if t then f(1, 2) else 0").to_string(),
+            "function `f` expects 1 arguments but got 2"
+        );
+        assert_eq!(
+            err("let t = false; if t then x else 0; let x = 1").to_string(),
+            "undefined variable `x`"
+        );
+        assert_eq!(
+            Interpreter::prepare(parse("x + y").unwrap(), &["x"])
+                .err()
+                .unwrap()
+                .to_string(),
+            "undefined variable `y`"
+        );
+    }
+
+    #[test]
+    fn function_errors() {
+        let err = |src| Interpreter::from_source(src).unwrap_err().to_string();
+        assert_eq!(err("f(1)"), "undefined function `f`");
+        assert_eq!(
+            err("fn f(a, b) = a; f(1)"),
+            "function `f` expects 2 arguments but got 1"
+        );
+        assert_eq!(
+            err("let y = 1; fn f(x) = x + y; f(1)"),
+            "undefined variable `y`"
+        );
+        assert_eq!(
+            err("fn f(x) = f(x); f(1)"),
+            "maximum call depth of 256 exceeded"
+        );
+        assert_eq!(err("fn f(x) = x"), "cannot evaluate an empty program");
+    }
+
+    #[test]
+    fn booleans() {
+        let tests = [
+            ("true", true),
+            ("!true", false),
+            ("1 < 2", true),
+            ("2 <= 2.0", true),
+            ("1.5 > 2", false),
+            ("3 >= 4", false),
+            ("1 == 1.0", true),
+            ("1 != 1", false),
+            ("true == false", false),
+            ("1 < 2 && 2 < 3", true),
+            ("1 > 2 || 2 > 3", false),
+            ("!(1 > 2) && true", true),
+        ];
+
+        for (src, expected) in tests {
+            let val = Interpreter::from_source(src).unwrap();
+            assert_eq!(val, Val::Bool(expected), "Failed on input: {}", src);
+        }
+    }
+
+    #[test]
+    fn if_expressions() {
+        assert_eq!(
+            Interpreter::from_source("let x = -3; if x < 0 then -x else x").unwrap(),
+            Val::Int(3)
+        );
+        assert_eq!(
+            Interpreter::from_source(
+                "fn fact(n) = if n <= 1 then 1 else n * fact(n - 1); fact(10)"
+            )
+            .unwrap(),
+            Val::Int(3628800)
+        );
+        assert_eq!(
+            Interpreter::from_source("if false then 1 else if true then 2 else 3").unwrap(),
+            Val::Int(2)
+        );
+    }
+
+    #[test]
+    fn call_depth_on_a_thread_stack() {
+        // 2 MiB is what `std::thread::spawn` gives a thread, and the test
+        // harness its test threads
+        let sources = [
+            "fn f(x) = if x > 0 then f(x) else 0; f(1)",
+            "fn f(x) = 1 + -f(x) * 2; f(1)",
+            "fn f(x) = x > 0 && f(x); f(1)",
+        ];
+        let errors = std::thread::Builder::new()
+            .stack_size(2 * 1024 * 1024)
+            .spawn(move || sources.map(|src| Interpreter::from_source(src).unwrap_err()))
+            .unwrap()
+            .join()
+            .unwrap();
+        for err in errors {
+            assert_eq!(err.to_string(), "maximum call depth of 256 exceeded");
+        }
+    }
+
+    #[test]
+    fn short_circuit() {
+        // `loop` never returns, so evaluating it would exceed the call depth
+        let prelude = "fn loop(x) = loop(x)\n";
+        for (src, expected) in [
+            ("false && loop(1)", false),
+            ("true || loop(1)", true),
+            ("if true then 1 < 2 else loop(1)", true),
+        ] {
+            let val = Interpreter::from_source(&format!("{}{}", prelude, src)).unwrap();
+            assert_eq!(val, Val::Bool(expected), "Failed on input: {}", src);
+        }
+        assert!(Interpreter::from_source(&format!("{}true && loop(1)", prelude)).is_err());
+    }
+
+    #[test]
+    fn type_errors() {
+        let err = |src| Interpreter::from_source(src).unwrap_err().to_string();
+        assert_eq!(err("true + 1"), "cannot apply `+` to bool and int");
+        assert_eq!(err("-false"), "cannot apply `-` to bool");
+        assert_eq!(err("!1"), "cannot apply `!` to int");
+        assert_eq!(err("true < false"), "cannot apply `<` to bool and bool");
+        assert_eq!(err("1 == true"), "cannot apply `==` to int and bool");
+        assert_eq!(err("if 1 then 2 else 3"), "expected a bool, found int");
+        assert_eq!(err("true && 1"), "expected a bool, found int");
+    }
+
+    #[test]
+    fn division() {
+        let tests = [
+            ("7 / 2", Val::Float(3.5.into())),
+            ("8 / 2", Val::Float(4.0.into())),
+            ("-7 // 2", Val::Int(-4)),
+            ("7 // 2.0", Val::Float(3.0.into())),
+            ("-7 % 2", Val::Int(1)),
+            ("7 % -2", Val::Int(-1)),
+            (
+                "let a = -7; let b = 2; (a // b) * b + a % b == a",
+                Val::Bool(true),
+            ),
+        ];
+        for (src, expected) in tests {
+            assert_eq!(
+                Interpreter::from_source(src).unwrap(),
+                expected,
+                "Failed on input: {}",
+                src
+            );
+        }
+        for src in ["1 / 0", "1 // 0", "1.5 % 0"] {
+            assert_eq!(
+                Interpreter::from_source(src),
+                Err(CalcError::runtime(RuntimeError::DivisionByZero).at(Span::new(0, src.len())))
+            );
+        }
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let err = |src| Interpreter::from_source(src).unwrap_err();
+        assert_eq!(
+            err("2147483647 + 1"),
+            CalcError::runtime(RuntimeError::Overflow).at(Span::new(0, 14))
+        );
+        // errors point at the subexpression that failed, even inside a function
+        assert_eq!(
+            err("fn f(x) = x * x; f(65536)"),
+            CalcError::runtime(RuntimeError::Overflow).at(Span::new(10, 15))
+        );
+        assert_eq!(
+            err("fn f(x) = f(x); f(1)"),
+            CalcError::runtime(RuntimeError::CallDepthExceeded).at(Span::new(10, 14))
+        );
+        assert_eq!(err("1 + undefined").span(), Span::new(4, 13));
+        assert_eq!(err("let t = true; (t && 2) || t").span(), Span::new(15, 21));
+        assert!(matches!(err("x"), CalcError::Type { .. }));
+        assert!(matches!(err("1 +"), CalcError::Parse { .. }));
+    }
 }
@@ -1,222 +1,1486 @@
-use crate::{Compile, Node, Operator, Result, val::Val};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, hash_map};
+use std::fmt;
+
+use crate::compiler::MAX_CALL_DEPTH;
+use crate::error::{CalcError, RuntimeError, Span};
+use crate::program::{Executable, Prepare};
+use crate::trace::{self, Category, Level};
+use crate::{Compile, Node, Operator, Result, Spanned, val::Val};
+use ordered_float::OrderedFloat;
+
 use inkwell::{
-    OptimizationLevel,
+    AddressSpace, FloatPredicate, IntPredicate, OptimizationLevel,
     builder::Builder,
     context::Context,
-    execution_engine::JitFunction,
-    types::{FloatType, IntType},
-    values::{AnyValue, FloatValue, IntValue},
+    execution_engine::{ExecutionEngine, FunctionLookupError, JitFunction},
+    intrinsics::Intrinsic,
+    module::Module,
+    types::{BasicMetadataTypeEnum, BasicType, BasicTypeEnum},
+    values::{
+        BasicMetadataValueEnum, BasicValueEnum, FloatValue, FunctionValue, GlobalValue, IntValue,
+    },
 };
 
-type JitFuncFloat = unsafe extern "C" fn() -> f64;
+// `jit` takes a pointer to the values of the free variables
+type JitFuncFloat = unsafe extern "C" fn(*const u32) -> f32;
+type JitFuncInt = unsafe extern "C" fn(*const u32) -> i32;
+type JitFuncError = unsafe extern "C" fn() -> i32;
 
 pub struct Jit;
 
+// Generated code reports a runtime error by storing its code in the `calc.error`
+// global, and the index of the check that failed in `calc.site`, and returning
+// early; every call site checks the global after the call.
+fn error_code(error: RuntimeError) -> u64 {
+    match error {
+        RuntimeError::DivisionByZero => 1,
+        RuntimeError::Overflow => 2,
+        RuntimeError::CallDepthExceeded => 3,
+        RuntimeError::NegativeExponent => 4,
+    }
+}
+
+fn runtime_error(code: i32) -> Result<RuntimeError> {
+    match code {
+        1 => Ok(RuntimeError::DivisionByZero),
+        2 => Ok(RuntimeError::Overflow),
+        3 => Ok(RuntimeError::CallDepthExceeded),
+        4 => Ok(RuntimeError::NegativeExponent),
+        _ => Err(CalcError::internal(format!("unknown error code {}", code))),
+    }
+}
+
 impl Compile for Jit {
-    type Output = Result<i32>;
+    type Output = Val;
 
-    fn from_ast(ast: Vec<Node>) -> Self::Output {
-        let context = Context::create();
-        let module = context.create_module("calculator");
-        let builder = context.create_builder();
-        let execution_engine = module
-            .create_jit_execution_engine(OptimizationLevel::None)
-            .unwrap();
+    fn from_ast(ast: Vec<Spanned<Node>>) -> Result<Self::Output> {
+        Entry::new(&ast, &[])?.call(&[])
+    }
+}
+
+/// A program compiled for one list of free variable types, together with
+/// the LLVM context its code belongs to.
+struct Entry {
+    // everything below borrows `context`, so it is declared first and dropped
+    // before the context
+    main: Main,
+    error: JitFunction<'static, JitFuncError>,
+    site: JitFunction<'static, JitFuncError>,
+    sites: Vec<Span>, // the span of each check that can fail, by site index
+    _engine: ExecutionEngine<'static>,
+    _module: Module<'static>,
+    _context: Box<Context>,
+}
 
-        // Check if we need float or int
-        let needs_float = ast.iter().any(|node| contains_float(node));
+/// The compiled `jit` entry point, by the type of value it returns.
+enum Main {
+    Int(JitFunction<'static, JitFuncInt>),
+    Float(JitFunction<'static, JitFuncFloat>),
+    Bool(JitFunction<'static, JitFuncInt>),
+}
 
-        if needs_float {
-            let f64_type = context.f64_type();
-            let fn_type = f64_type.fn_type(&[], false);
-            let function = module.add_function("jit", fn_type, None);
-            let basic_block = context.append_basic_block(function, "entry");
+impl Entry {
+    fn new(ast: &[Spanned<Node>], variables: &[(&str, Type)]) -> Result<Self> {
+        let context = Box::new(Context::create());
+        // SAFETY: the context is boxed, so it stays where it is when the box
+        // moves into the entry, and the entry drops everything that borrows
+        // it before the box
+        let context_ref: &'static Context = unsafe { &*(&*context as *const Context) };
+        let mut recursive_builder = RecursiveBuilder::new(context_ref, ast);
+        let return_type = recursive_builder.build_main(ast, variables)?;
+        let sites = recursive_builder.sites;
+        let module = recursive_builder.module;
+        trace::emit(Category::Ir, Level::Info, || {
+            module.print_to_string().to_string()
+        });
+        let engine = module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .map_err(|err| CalcError::internal(format!("cannot create the JIT: {}", err)))?;
 
-            builder.position_at_end(basic_block);
+        let lookup_error = |err: FunctionLookupError| CalcError::internal(err.to_string());
+        // SAFETY: `build_main` gave `jit`, `jit.error` and `jit.site` these signatures
+        let (main, error, site) = unsafe {
+            let main = match return_type {
+                Type::Int => Main::Int(engine.get_function("jit").map_err(lookup_error)?),
+                Type::Float => Main::Float(engine.get_function("jit").map_err(lookup_error)?),
+                Type::Bool => Main::Bool(engine.get_function("jit").map_err(lookup_error)?),
+            };
+            (
+                main,
+                engine.get_function("jit.error").map_err(lookup_error)?,
+                engine.get_function("jit.site").map_err(lookup_error)?,
+            )
+        };
+        Ok(Self {
+            main,
+            error,
+            site,
+            sites,
+            _engine: engine,
+            _module: module,
+            _context: context,
+        })
+    }
 
-            for node in ast {
-                let recursive_builder = RecursiveBuilder::new(&context, &builder);
-                let return_value = recursive_builder.build_float(&node);
-                let _ = builder.build_return(Some(&return_value));
+    /// Runs the program with `args` as the values of its free variables,
+    /// whose types must be the ones it was compiled for.
+    fn call(&self, args: &[Val]) -> Result<Val> {
+        // each argument is passed as the 32 bits of its value
+        let args: Vec<u32> = args
+            .iter()
+            .map(|arg| match *arg {
+                Val::Int(n) => n as u32,
+                Val::Float(x) => x.into_inner().to_bits(),
+                Val::Bool(b) => b as u32,
+            })
+            .collect();
+        let args = args.as_ptr();
+        // SAFETY: `build_main` reads one 32-bit value per free variable
+        unsafe {
+            let result = match &self.main {
+                Main::Int(main) => Val::Int(main.call(args)),
+                Main::Float(main) => Val::Float(OrderedFloat(main.call(args))),
+                Main::Bool(main) => Val::Bool(main.call(args) != 0),
+            };
+            match self.error.call() {
+                0 => Ok(result),
+                code => {
+                    let span = self.sites.get(self.site.call() as usize).copied();
+                    Err(CalcError::runtime(runtime_error(code)?).at(span.unwrap_or_default()))
+                }
             }
+        }
+    }
+}
 
-            println!(
-                "Generated LLVM IR: {}",
-                function.print_to_string().to_string()
-            );
+impl Prepare for Jit {
+    fn prepare(ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Box<dyn Executable>> {
+        Ok(Box::new(Prepared {
+            ast,
+            variables: variables.iter().map(|name| name.to_string()).collect(),
+            entries: RefCell::new(HashMap::new()),
+        }))
+    }
+}
+
+/// A program that is compiled the first time it runs with each combination
+/// of free variable types, since the JIT gives every value a static type.
+struct Prepared {
+    ast: Vec<Spanned<Node>>,
+    variables: Vec<String>,
+    entries: RefCell<HashMap<Vec<Type>, Entry>>,
+}
 
-            unsafe {
-                let jit_function: JitFunction<JitFuncFloat> =
-                    execution_engine.get_function("jit").unwrap();
-                Ok(jit_function.call() as i32)
+impl Executable for Prepared {
+    fn eval(&self, args: &[Val]) -> Result<Val> {
+        let types: Vec<Type> = args.iter().map(Type::of).collect();
+        let mut entries = self.entries.borrow_mut();
+        let entry = match entries.entry(types) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                let variables: Vec<(&str, Type)> = self
+                    .variables
+                    .iter()
+                    .map(String::as_str)
+                    .zip(entry.key().iter().copied())
+                    .collect();
+                let compiled = Entry::new(&self.ast, &variables)?;
+                entry.insert(compiled)
             }
-        } else {
-            let i32_type = context.i32_type();
-            let fn_type = i32_type.fn_type(&[], false);
-            let function = module.add_function("jit", fn_type, None);
-            let basic_block = context.append_basic_block(function, "entry");
+        };
+        entry.call(args)
+    }
+}
 
-            builder.position_at_end(basic_block);
+/// Static type of a JIT value. Every expression gets one at compile time:
+/// ints are `i32`, floats are `f32` like `Val::Float`, and bools are `i1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Type {
+    Int,
+    Float,
+    Bool,
+}
 
-            for node in ast {
-                let recursive_builder = RecursiveBuilder::new(&context, &builder);
-                let return_value = recursive_builder.build_int(&node);
-                let _ = builder.build_return(Some(&return_value));
-            }
+impl Type {
+    fn of(val: &Val) -> Self {
+        match val {
+            Val::Int(_) => Type::Int,
+            Val::Float(_) => Type::Float,
+            Val::Bool(_) => Type::Bool,
+        }
+    }
 
-            println!(
-                "Generated LLVM IR: {}",
-                function.print_to_string().to_string()
-            );
+    // used to name the instance of a function specialised for these argument types
+    fn code(&self) -> char {
+        match self {
+            Type::Int => 'i',
+            Type::Float => 'f',
+            Type::Bool => 'b',
+        }
+    }
+
+    fn unary(op: Operator, ty: Type) -> Result<Type> {
+        match (op, ty) {
+            (Operator::Not, Type::Bool) => Ok(Type::Bool),
+            (Operator::Plus | Operator::Minus, Type::Int | Type::Float) => Ok(ty),
+            _ => Err(CalcError::type_error(format!(
+                "cannot apply `{}` to {}",
+                op, ty
+            ))),
+        }
+    }
 
-            unsafe {
-                let jit_function: JitFunction<unsafe extern "C" fn() -> i32> =
-                    execution_engine.get_function("jit").unwrap();
-                Ok(jit_function.call())
+    fn binary(op: Operator, lhs: Type, rhs: Type) -> Result<Type> {
+        match op {
+            Operator::And | Operator::Or => match (lhs, rhs) {
+                (Type::Bool, Type::Bool) => Ok(Type::Bool),
+                (Type::Bool, other) | (other, _) => Err(CalcError::type_error(format!(
+                    "expected a bool, found {}",
+                    other
+                ))),
+            },
+            Operator::Equal | Operator::NotEqual if lhs == Type::Bool && rhs == Type::Bool => {
+                Ok(Type::Bool)
             }
+            _ if lhs == Type::Bool || rhs == Type::Bool => Err(CalcError::type_error(format!(
+                "cannot apply `{}` to {} and {}",
+                op, lhs, rhs
+            ))),
+            _ if op.is_comparison() => Ok(Type::Bool),
+            Operator::Divide => Ok(Type::Float),
+            _ if lhs == Type::Int && rhs == Type::Int => Ok(Type::Int),
+            _ => Ok(Type::Float),
         }
     }
 }
 
-fn contains_float(node: &Node) -> bool {
-    match node {
-        Node::Val(Val::Float(_)) => true,
-        Node::Val(Val::Int(_)) => false,
-        Node::UnaryExpr { child, .. } => contains_float(child),
-        Node::BinaryExpr { lhs, rhs, .. } => contains_float(lhs) || contains_float(rhs),
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Float => write!(f, "float"),
+            Type::Bool => write!(f, "bool"),
+        }
     }
 }
 
-struct RecursiveBuilder<'a> {
-    context: &'a Context,
-    i32_type: IntType<'a>,
-    f64_type: FloatType<'a>,
-    builder: &'a Builder<'a>,
+#[derive(Clone, Copy)]
+struct TypedValue<'ctx> {
+    ty: Type,
+    value: BasicValueEnum<'ctx>,
+}
+
+// a function specialised for one list of argument types
+type Instance<'a> = (&'a str, Vec<Type>);
+
+struct RecursiveBuilder<'ctx, 'ast> {
+    context: &'ctx Context,
+    module: Module<'ctx>,
+    builder: Builder<'ctx>,
+    definitions: HashMap<&'ast str, (&'ast [String], &'ast Spanned<Node>)>,
+    instances: HashMap<Instance<'ast>, (FunctionValue<'ctx>, Type)>,
+    return_types: HashMap<Instance<'ast>, Type>,
+    // instances whose return type is being inferred, used to spot recursion
+    inferring: HashSet<Instance<'ast>>,
+    vars: HashMap<&'ast str, TypedValue<'ctx>>,
+    error: GlobalValue<'ctx>,
+    site: GlobalValue<'ctx>,
+    call_depth: GlobalValue<'ctx>,
+    // the span of each check that can fail, indexed by the site it stores
+    sites: Vec<Span>,
 }
 
-impl<'a> RecursiveBuilder<'a> {
-    pub fn new(context: &'a Context, builder: &'a Builder<'a>) -> Self {
+impl<'ctx, 'ast> RecursiveBuilder<'ctx, 'ast> {
+    pub fn new(context: &'ctx Context, ast: &'ast [Spanned<Node>]) -> Self {
+        let definitions = ast
+            .iter()
+            .filter_map(|node| match &node.node {
+                Node::FnDef { name, params, body } => {
+                    Some((name.as_str(), (params.as_slice(), &**body)))
+                }
+                _ => None,
+            })
+            .collect();
+        let module = context.create_module("calculator");
+        let global = |name| {
+            let global = module.add_global(context.i32_type(), None, name);
+            global.set_initializer(&context.i32_type().const_zero());
+            global
+        };
+        let error = global("calc.error");
+        let site = global("calc.site");
+        let call_depth = global("calc.depth");
         Self {
             context,
-            i32_type: context.i32_type(),
-            f64_type: context.f64_type(),
-            builder,
+            module,
+            builder: context.create_builder(),
+            definitions,
+            instances: HashMap::new(),
+            return_types: HashMap::new(),
+            inferring: HashSet::new(),
+            vars: HashMap::new(),
+            error,
+            site,
+            call_depth,
+            sites: Vec::new(),
         }
     }
 
-    pub fn build_int(&self, ast: &Node) -> IntValue<'a> {
-        match ast {
-            Node::Val(Val::Int(n)) => self.i32_type.const_int(*n as u64, true),
-            Node::Val(Val::Float(f)) => self.i32_type.const_int(f.0 as i32 as u64, true),
-            Node::UnaryExpr { op, child } => {
-                let child = self.build_int(child);
-                match op {
-                    Operator::Minus => child.const_neg(),
-                    Operator::Plus => child,
-                    _ => panic!("Unsupported unary operator in JIT: {:?}", op),
+    fn llvm_type(&self, ty: Type) -> BasicTypeEnum<'ctx> {
+        match ty {
+            Type::Int => self.context.i32_type().into(),
+            Type::Float => self.context.f32_type().into(),
+            Type::Bool => self.context.bool_type().into(),
+        }
+    }
+
+    /// Builds the `jit` entry point, which evaluates every top-level statement
+    /// and returns the value of the last one. Bools are returned as an `i32`.
+    /// It takes a pointer to the values of `variables`, one 32-bit slot each.
+    pub fn build_main(
+        &mut self,
+        ast: &'ast [Spanned<Node>],
+        variables: &[(&'ast str, Type)],
+    ) -> Result<Type> {
+        let statements: Vec<&'ast Spanned<Node>> = ast
+            .iter()
+            .filter(|node| !matches!(node.node, Node::FnDef { .. }))
+            .collect();
+
+        // the signature of the entry point depends on the type of the last statement
+        let mut env: HashMap<&str, Type> = variables.iter().copied().collect();
+        let mut return_type = None;
+        for node in &statements {
+            return_type = Some(self.infer_known(node, &mut env)?);
+        }
+        let return_type =
+            return_type.ok_or_else(|| CalcError::type_error("cannot evaluate an empty program"))?;
+
+        let i32_type = self.context.i32_type();
+        let args_type = self.context.ptr_type(AddressSpace::default());
+        let fn_type = match return_type {
+            Type::Float => self.context.f32_type().fn_type(&[args_type.into()], false),
+            Type::Int | Type::Bool => i32_type.fn_type(&[args_type.into()], false),
+        };
+        let function = self.module.add_function("jit", fn_type, None);
+        let basic_block = self.context.append_basic_block(function, "entry");
+        self.builder.position_at_end(basic_block);
+
+        // the same code may run again after a run that failed
+        for global in [self.error, self.call_depth] {
+            self.builder
+                .build_store(global.as_pointer_value(), i32_type.const_zero())?;
+        }
+        let args = function.get_nth_param(0).unwrap().into_pointer_value();
+        for (idx, &(name, ty)) in variables.iter().enumerate() {
+            // SAFETY: the caller passes one slot per variable
+            let slot = unsafe {
+                self.builder.build_in_bounds_gep(
+                    i32_type,
+                    args,
+                    &[i32_type.const_int(idx as u64, false)],
+                    name,
+                )?
+            };
+            let bits = self
+                .builder
+                .build_load(i32_type, slot, name)?
+                .into_int_value();
+            let value = match ty {
+                Type::Int => bits.into(),
+                Type::Float => self
+                    .builder
+                    .build_bit_cast(bits, self.context.f32_type(), name)?,
+                Type::Bool => self
+                    .builder
+                    .build_int_truncate(bits, self.context.bool_type(), name)?
+                    .into(),
+            };
+            self.vars.insert(name, TypedValue { ty, value });
+        }
+
+        let mut return_value = None;
+        for node in statements {
+            return_value = Some(self.build(node)?);
+        }
+        let return_value = return_value.unwrap();
+        let return_value: BasicValueEnum = match return_value.ty {
+            Type::Bool => self
+                .builder
+                .build_int_z_extend(
+                    return_value.value.into_int_value(),
+                    self.context.i32_type(),
+                    "bool_to_int",
+                )?
+                .into(),
+            _ => return_value.value,
+        };
+        self.builder.build_return(Some(&return_value))?;
+
+        // lets the caller read `calc.error` and `calc.site` once `jit` has returned
+        for (name, global) in [("jit.error", self.error), ("jit.site", self.site)] {
+            let function = self
+                .module
+                .add_function(name, i32_type.fn_type(&[], false), None);
+            self.builder
+                .position_at_end(self.context.append_basic_block(function, "entry"));
+            let value = self
+                .builder
+                .build_load(i32_type, global.as_pointer_value(), "value")?;
+            self.builder.build_return(Some(&value))?;
+        }
+        Ok(return_type)
+    }
+
+    fn infer_known(
+        &mut self,
+        node: &'ast Spanned<Node>,
+        env: &mut HashMap<&'ast str, Type>,
+    ) -> Result<Type> {
+        self.infer(node, env)?.ok_or_else(|| {
+            CalcError::type_error(format!("cannot infer the type of `{}`", node)).at(node.span)
+        })
+    }
+
+    /// Works out the static type of `node` without generating any code.
+    /// `None` means the type depends on a recursive call whose return type is
+    /// still being inferred; an `if` then takes its type from the other branch.
+    fn infer(
+        &mut self,
+        node: &'ast Spanned<Node>,
+        env: &mut HashMap<&'ast str, Type>,
+    ) -> Result<Option<Type>> {
+        let span = node.span;
+        let at = |err: CalcError| err.at(span);
+        let ty = match &node.node {
+            Node::Val(val) => Type::of(val),
+            Node::Ident(name) => *env.get(name.as_str()).ok_or_else(|| {
+                CalcError::type_error(format!("undefined variable `{}`", name)).at(span)
+            })?,
+            Node::Let { name, value } => {
+                let ty = self.infer_known(value, env)?;
+                env.insert(name, ty);
+                ty
+            }
+            Node::FnDef { name, .. } => {
+                return Err(CalcError::type_error(format!(
+                    "function `{}` can only be defined at the top level",
+                    name
+                ))
+                .at(span));
+            }
+            Node::Error => return Err(CalcError::unparsed().at(span)),
+            Node::Call { name, args } => {
+                let mut arg_types = Vec::with_capacity(args.len());
+                for arg in args {
+                    match self.infer(arg, env)? {
+                        Some(ty) => arg_types.push(ty),
+                        None => return Ok(None),
+                    }
+                }
+                return self.return_type(name, arg_types, span);
+            }
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                if let Some(ty) = self.infer(cond, env)?
+                    && ty != Type::Bool
+                {
+                    return Err(
+                        CalcError::type_error(format!("expected a bool, found {}", ty)).at(span),
+                    );
                 }
+                let then_type = self.infer(then_branch, env)?;
+                let else_type = self.infer(else_branch, env)?;
+                return match (then_type, else_type) {
+                    (Some(a), Some(b)) if a != b => Err(CalcError::type_error(format!(
+                        "`if` branches have different types: {} and {}",
+                        a, b
+                    ))
+                    .at(span)),
+                    (Some(ty), _) | (None, Some(ty)) => Ok(Some(ty)),
+                    (None, None) => Ok(None),
+                };
             }
+            Node::UnaryExpr { op, child } => match self.infer(child, env)? {
+                Some(ty) => Type::unary(*op, ty).map_err(at)?,
+                None => return Ok(None),
+            },
             Node::BinaryExpr { op, lhs, rhs } => {
-                let left = self.build_int(lhs);
-                let right = self.build_int(rhs);
-                match op {
-                    Operator::Plus => self
-                        .builder
-                        .build_int_add(left, right, "plus_temp")
-                        .unwrap(),
-                    Operator::Minus => self
-                        .builder
-                        .build_int_sub(left, right, "minus_temp")
-                        .unwrap(),
-                    Operator::Multiply => {
-                        self.builder.build_int_mul(left, right, "mul_temp").unwrap()
+                let lhs = self.infer(lhs, env)?;
+                let rhs = self.infer(rhs, env)?;
+                match (lhs, rhs) {
+                    (Some(lhs), Some(rhs)) => Type::binary(*op, lhs, rhs).map_err(at)?,
+                    // comparisons and logical operators are bools whatever their operands
+                    _ if op.is_comparison() || matches!(op, Operator::And | Operator::Or) => {
+                        Type::Bool
                     }
-                    Operator::Divide => self
-                        .builder
-                        .build_int_signed_div(left, right, "div_temp")
-                        .unwrap(),
+                    _ => return Ok(None),
                 }
             }
+        };
+        Ok(Some(ty))
+    }
+
+    /// Looks up the function a call at `span` refers to.
+    fn definition(
+        &self,
+        name: &str,
+        argc: usize,
+        span: Span,
+    ) -> Result<(&'ast str, &'ast [String], &'ast Spanned<Node>)> {
+        let (&name, &(params, body)) = self.definitions.get_key_value(name).ok_or_else(|| {
+            CalcError::type_error(format!("undefined function `{}`", name)).at(span)
+        })?;
+        if params.len() != argc {
+            return Err(CalcError::type_error(format!(
+                "function `{}` expects {} arguments but got {}",
+                name,
+                params.len(),
+                argc
+            ))
+            .at(span));
+        }
+        Ok((name, params, body))
+    }
+
+    fn return_type(
+        &mut self,
+        name: &str,
+        arg_types: Vec<Type>,
+        span: Span,
+    ) -> Result<Option<Type>> {
+        let (name, params, body) = self.definition(name, arg_types.len(), span)?;
+        let key = (name, arg_types);
+        if let Some(ty) = self.return_types.get(&key) {
+            return Ok(Some(*ty));
+        }
+        if self.inferring.contains(&key) {
+            return Ok(None);
+        }
+
+        let mut env = params
+            .iter()
+            .map(String::as_str)
+            .zip(key.1.iter().copied())
+            .collect();
+        self.inferring.insert(key.clone());
+        let ty = self.infer(body, &mut env);
+        self.inferring.remove(&key);
+        let ty = ty?.ok_or_else(|| {
+            CalcError::type_error(format!("cannot infer the return type of `{}`", name)).at(span)
+        })?;
+        self.return_types.insert(key, ty);
+        Ok(Some(ty))
+    }
+
+    /// Returns the LLVM function for `name` specialised to `arg_types`,
+    /// generating it the first time it is called with them by the call at `span`.
+    fn instance(
+        &mut self,
+        name: &str,
+        arg_types: Vec<Type>,
+        span: Span,
+    ) -> Result<(FunctionValue<'ctx>, Type)> {
+        let (name, params, body) = self.definition(name, arg_types.len(), span)?;
+        if let Some(instance) = self.instances.get(&(name, arg_types.clone())) {
+            return Ok(*instance);
+        }
+        let ret = self
+            .return_type(name, arg_types.clone(), span)?
+            .ok_or_else(|| {
+                CalcError::type_error(format!("cannot infer the return type of `{}`", name))
+                    .at(span)
+            })?;
+
+        let param_types: Vec<BasicMetadataTypeEnum> = arg_types
+            .iter()
+            .map(|ty| self.llvm_type(*ty).into())
+            .collect();
+        let fn_type = self.llvm_type(ret).fn_type(&param_types, false);
+        // prefixed so user functions can never clash with the `jit` entry point
+        let suffix: String = arg_types.iter().map(Type::code).collect();
+        let function = self
+            .module
+            .add_function(&format!("fn.{}.{}", name, suffix), fn_type, None);
+        // registered before the body is built so recursive calls find it
+        self.instances
+            .insert((name, arg_types.clone()), (function, ret));
+
+        let caller_block = self.builder.get_insert_block();
+        let caller_vars = std::mem::take(&mut self.vars);
+        for (idx, (param, ty)) in params.iter().zip(arg_types).enumerate() {
+            let value = function.get_nth_param(idx as u32).unwrap();
+            self.vars.insert(param, TypedValue { ty, value });
+        }
+        self.builder
+            .position_at_end(self.context.append_basic_block(function, "entry"));
+        let body = self.build(body);
+        self.vars = caller_vars;
+        let body = body?;
+        if body.ty != ret {
+            return Err(CalcError::type_error(format!(
+                "function `{}` returns both {} and {}",
+                name, ret, body.ty
+            ))
+            .at(span));
+        }
+        self.builder.build_return(Some(&body.value))?;
+        if let Some(block) = caller_block {
+            self.builder.position_at_end(block);
         }
+        Ok((function, ret))
     }
 
-    pub fn build_float(&self, ast: &Node) -> FloatValue<'a> {
-        match ast {
-            Node::Val(Val::Int(n)) => self.f64_type.const_float(*n as f64),
-            Node::Val(Val::Float(f)) => self.f64_type.const_float(f.0 as f64),
+    /// Returns zero from the function being built when `cond` is true. With an
+    /// `error` it is recorded first, along with the site of the check, which
+    /// points at `span`; without one it was set by a callee.
+    fn return_if(
+        &mut self,
+        cond: IntValue<'ctx>,
+        error: Option<(RuntimeError, Span)>,
+    ) -> Result<()> {
+        let function = self
+            .builder
+            .get_insert_block()
+            .and_then(|block| block.get_parent())
+            .ok_or_else(|| CalcError::internal("the builder is not inside a function"))?;
+        let fail_block = self.context.append_basic_block(function, "fail");
+        let ok_block = self.context.append_basic_block(function, "ok");
+        self.builder
+            .build_conditional_branch(cond, fail_block, ok_block)?;
+
+        self.builder.position_at_end(fail_block);
+        if let Some((error, span)) = error {
+            let i32_type = self.context.i32_type();
+            let code = i32_type.const_int(error_code(error), false);
+            self.builder
+                .build_store(self.error.as_pointer_value(), code)?;
+            let site = i32_type.const_int(self.sites.len() as u64, false);
+            self.builder
+                .build_store(self.site.as_pointer_value(), site)?;
+            self.sites.push(span);
+        }
+        let return_type = function
+            .get_type()
+            .get_return_type()
+            .ok_or_else(|| CalcError::internal("function has no return type"))?;
+        self.builder.build_return(Some(&return_type.const_zero()))?;
+
+        self.builder.position_at_end(ok_block);
+        Ok(())
+    }
+
+    /// Builds `llvm.s{add,sub,mul}.with.overflow` and fails on overflow,
+    /// pointing at `span`.
+    fn build_checked(
+        &mut self,
+        intrinsic: &str,
+        left: IntValue<'ctx>,
+        right: IntValue<'ctx>,
+        span: Span,
+    ) -> Result<IntValue<'ctx>> {
+        let (value, overflow) = self.build_with_overflow(intrinsic, left, right)?;
+        self.return_if(overflow, Some((RuntimeError::Overflow, span)))?;
+        Ok(value)
+    }
+
+    /// Builds `llvm.s{add,sub,mul}.with.overflow`, returning the wrapped
+    /// result and whether it overflowed.
+    fn build_with_overflow(
+        &self,
+        intrinsic: &str,
+        left: IntValue<'ctx>,
+        right: IntValue<'ctx>,
+    ) -> Result<(IntValue<'ctx>, IntValue<'ctx>)> {
+        let function = Intrinsic::find(intrinsic)
+            .and_then(|i| i.get_declaration(&self.module, &[self.context.i32_type().into()]))
+            .ok_or_else(|| CalcError::internal(format!("missing intrinsic {}", intrinsic)))?;
+        let result = self
+            .builder
+            .build_call(function, &[left.into(), right.into()], "checked_temp")?
+            .try_as_basic_value()
+            .unwrap_basic()
+            .into_struct_value();
+        let value = self
+            .builder
+            .build_extract_value(result, 0, "value")?
+            .into_int_value();
+        let overflow = self
+            .builder
+            .build_extract_value(result, 1, "overflow")?
+            .into_int_value();
+        Ok((value, overflow))
+    }
+
+    /// Builds `left ^ right` for ints by squaring and multiplying, failing
+    /// the way `i32::checked_pow` does.
+    fn build_int_pow(
+        &mut self,
+        left: IntValue<'ctx>,
+        right: IntValue<'ctx>,
+        span: Span,
+    ) -> Result<IntValue<'ctx>> {
+        let i32_type = self.context.i32_type();
+        let zero = i32_type.const_zero();
+        let one = i32_type.const_int(1, false);
+        let is_negative =
+            self.builder
+                .build_int_compare(IntPredicate::SLT, right, zero, "is_negative")?;
+        self.return_if(is_negative, Some((RuntimeError::NegativeExponent, span)))?;
+
+        let entry = self.builder.get_insert_block().unwrap();
+        let function = entry.get_parent().unwrap();
+        let loop_block = self.context.append_basic_block(function, "pow_loop");
+        let exit_block = self.context.append_basic_block(function, "pow_exit");
+        self.builder.build_unconditional_branch(loop_block)?;
+
+        self.builder.position_at_end(loop_block);
+        let acc = self.builder.build_phi(i32_type, "acc")?;
+        let base = self.builder.build_phi(i32_type, "base")?;
+        let exp = self.builder.build_phi(i32_type, "exp")?;
+        let acc_value = acc.as_basic_value().into_int_value();
+        let base_value = base.as_basic_value().into_int_value();
+        let exp_value = exp.as_basic_value().into_int_value();
+
+        // multiply the base into the result when the exponent's low bit is set
+        let bit = self.builder.build_and(exp_value, one, "bit")?;
+        let is_odd = self
+            .builder
+            .build_int_compare(IntPredicate::NE, bit, zero, "is_odd")?;
+        let (product, product_overflow) =
+            self.build_with_overflow("llvm.smul.with.overflow", acc_value, base_value)?;
+        let next_acc = self
+            .builder
+            .build_select(is_odd, product, acc_value, "next_acc")?
+            .into_int_value();
+        let acc_overflow = self
+            .builder
+            .build_and(is_odd, product_overflow, "acc_overflow")?;
+
+        // square the base only while there are bits of the exponent left
+        let done = self
+            .builder
+            .build_int_compare(IntPredicate::ULE, exp_value, one, "done")?;
+        let next_exp = self
+            .builder
+            .build_right_shift(exp_value, one, false, "next_exp")?;
+        let (square, square_overflow) =
+            self.build_with_overflow("llvm.smul.with.overflow", base_value, base_value)?;
+        let not_done = self.builder.build_not(done, "not_done")?;
+        let base_overflow = self
+            .builder
+            .build_and(not_done, square_overflow, "base_overflow")?;
+        let overflow = self
+            .builder
+            .build_or(acc_overflow, base_overflow, "overflow")?;
+        self.return_if(overflow, Some((RuntimeError::Overflow, span)))?;
+
+        let loop_end = self.builder.get_insert_block().unwrap();
+        self.builder
+            .build_conditional_branch(done, exit_block, loop_block)?;
+        acc.add_incoming(&[(&one, entry), (&next_acc, loop_end)]);
+        base.add_incoming(&[(&left, entry), (&square, loop_end)]);
+        exp.add_incoming(&[(&right, entry), (&next_exp, loop_end)]);
+
+        self.builder.position_at_end(exit_block);
+        Ok(next_acc)
+    }
+
+    fn to_float(&self, value: TypedValue<'ctx>) -> Result<FloatValue<'ctx>> {
+        Ok(match value.ty {
+            Type::Int => self.builder.build_signed_int_to_float(
+                value.value.into_int_value(),
+                self.context.f32_type(),
+                "int_to_float",
+            )?,
+            _ => value.value.into_float_value(),
+        })
+    }
+
+    /// Generates the code for `ast`. Errors raised by the node itself, while
+    /// compiling or while running, point at its span.
+    pub fn build(&mut self, ast: &'ast Spanned<Node>) -> Result<TypedValue<'ctx>> {
+        let span = ast.span;
+        let (ty, value): (Type, BasicValueEnum<'ctx>) = match &ast.node {
+            Node::Val(Val::Int(n)) => (
+                Type::Int,
+                self.context.i32_type().const_int(*n as u64, true).into(),
+            ),
+            Node::Val(Val::Float(f)) => (
+                Type::Float,
+                self.context.f32_type().const_float(f.0 as f64).into(),
+            ),
+            Node::Val(Val::Bool(b)) => (
+                Type::Bool,
+                self.context.bool_type().const_int(*b as u64, false).into(),
+            ),
+            Node::Ident(name) => {
+                let var = self.vars.get(name.as_str()).ok_or_else(|| {
+                    CalcError::type_error(format!("undefined variable `{}`", name)).at(span)
+                })?;
+                (var.ty, var.value)
+            }
+            Node::Let { name, value } => {
+                let value = self.build(value)?;
+                self.vars.insert(name, value);
+                (value.ty, value.value)
+            }
+            Node::FnDef { name, .. } => {
+                return Err(CalcError::type_error(format!(
+                    "function `{}` can only be defined at the top level",
+                    name
+                ))
+                .at(span));
+            }
+            Node::Error => return Err(CalcError::unparsed().at(span)),
+            Node::Call { name, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.build(arg))
+                    .collect::<Result<Vec<_>>>()?;
+                let (function, ret) =
+                    self.instance(name, args.iter().map(|arg| arg.ty).collect(), span)?;
+                let args: Vec<BasicMetadataValueEnum> =
+                    args.iter().map(|arg| arg.value.into()).collect();
+
+                let i32_type = self.context.i32_type();
+                let depth_ptr = self.call_depth.as_pointer_value();
+                let depth = self
+                    .builder
+                    .build_load(i32_type, depth_ptr, "depth")?
+                    .into_int_value();
+                let max_depth = i32_type.const_int(MAX_CALL_DEPTH as u64, false);
+                let too_deep = self.builder.build_int_compare(
+                    IntPredicate::SGE,
+                    depth,
+                    max_depth,
+                    "too_deep",
+                )?;
+                self.return_if(too_deep, Some((RuntimeError::CallDepthExceeded, span)))?;
+                let callee_depth =
+                    self.builder
+                        .build_int_add(depth, i32_type.const_int(1, false), "depth")?;
+                self.builder.build_store(depth_ptr, callee_depth)?;
+                let value = self
+                    .builder
+                    .build_call(function, &args, "call_temp")?
+                    .try_as_basic_value()
+                    .unwrap_basic();
+                self.builder.build_store(depth_ptr, depth)?;
+
+                // pass on an error raised inside the callee
+                let error = self
+                    .builder
+                    .build_load(i32_type, self.error.as_pointer_value(), "error")?
+                    .into_int_value();
+                let failed = self.builder.build_int_compare(
+                    IntPredicate::NE,
+                    error,
+                    i32_type.const_zero(),
+                    "failed",
+                )?;
+                self.return_if(failed, None)?;
+                (ret, value)
+            }
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let cond = self.build(cond)?;
+                if cond.ty != Type::Bool {
+                    return Err(CalcError::type_error(format!(
+                        "expected a bool, found {}",
+                        cond.ty
+                    ))
+                    .at(span));
+                }
+                let function = self
+                    .builder
+                    .get_insert_block()
+                    .and_then(|block| block.get_parent())
+                    .unwrap();
+                let then_block = self.context.append_basic_block(function, "then");
+                let else_block = self.context.append_basic_block(function, "else");
+                let merge_block = self.context.append_basic_block(function, "merge");
+                self.builder.build_conditional_branch(
+                    cond.value.into_int_value(),
+                    then_block,
+                    else_block,
+                )?;
+
+                self.builder.position_at_end(then_block);
+                let then_value = self.build(then_branch)?;
+                // the branch may have added blocks of its own
+                let then_end = self.builder.get_insert_block().unwrap();
+                self.builder.build_unconditional_branch(merge_block)?;
+
+                self.builder.position_at_end(else_block);
+                let else_value = self.build(else_branch)?;
+                let else_end = self.builder.get_insert_block().unwrap();
+                self.builder.build_unconditional_branch(merge_block)?;
+
+                if then_value.ty != else_value.ty {
+                    return Err(CalcError::type_error(format!(
+                        "`if` branches have different types: {} and {}",
+                        then_value.ty, else_value.ty
+                    ))
+                    .at(span));
+                }
+                self.builder.position_at_end(merge_block);
+                let phi = self
+                    .builder
+                    .build_phi(self.llvm_type(then_value.ty), "if_temp")?;
+                phi.add_incoming(&[(&then_value.value, then_end), (&else_value.value, else_end)]);
+                (then_value.ty, phi.as_basic_value())
+            }
             Node::UnaryExpr { op, child } => {
-                let child = self.build_float(child);
-                match op {
-                    Operator::Minus => {
-                        // Multiply by -1.0 to negate
-                        let neg_one = self.f64_type.const_float(-1.0);
-                        self.builder
-                            .build_float_mul(child, neg_one, "neg_temp")
-                            .unwrap()
+                let child = self.build(child)?;
+                let ty = Type::unary(*op, child.ty).map_err(|err| err.at(span))?;
+                let value: BasicValueEnum = match (op, ty) {
+                    (Operator::Plus, _) => child.value,
+                    (Operator::Minus, Type::Int) => self
+                        .build_checked(
+                            "llvm.ssub.with.overflow",
+                            self.context.i32_type().const_zero(),
+                            child.value.into_int_value(),
+                            span,
+                        )?
+                        .into(),
+                    (Operator::Minus, _) => self
+                        .builder
+                        .build_float_neg(child.value.into_float_value(), "neg_temp")?
+                        .into(),
+                    (Operator::Not, _) => self
+                        .builder
+                        .build_not(child.value.into_int_value(), "not_temp")?
+                        .into(),
+                    _ => {
+                        return Err(CalcError::internal(format!(
+                            "`{}` is not a unary operator",
+                            op
+                        )));
                     }
-                    Operator::Plus => child,
-                    _ => panic!("Unsupported unary operator in JIT: {:?}", op),
+                };
+                (ty, value)
+            }
+            Node::BinaryExpr {
+                op: op @ (Operator::And | Operator::Or),
+                lhs,
+                rhs,
+            } => {
+                // only evaluate the rhs when the lhs does not decide the result
+                let lhs = self.build(lhs)?;
+                if lhs.ty != Type::Bool {
+                    return Err(CalcError::type_error(format!(
+                        "expected a bool, found {}",
+                        lhs.ty
+                    ))
+                    .at(span));
+                }
+                let lhs_end = self.builder.get_insert_block().unwrap();
+                let function = lhs_end.get_parent().unwrap();
+                let rhs_block = self.context.append_basic_block(function, "rhs");
+                let merge_block = self.context.append_basic_block(function, "merge");
+                let (on_true, on_false) = match op {
+                    Operator::And => (rhs_block, merge_block),
+                    _ => (merge_block, rhs_block),
+                };
+                self.builder.build_conditional_branch(
+                    lhs.value.into_int_value(),
+                    on_true,
+                    on_false,
+                )?;
+
+                self.builder.position_at_end(rhs_block);
+                let rhs = self.build(rhs)?;
+                if rhs.ty != Type::Bool {
+                    return Err(CalcError::type_error(format!(
+                        "expected a bool, found {}",
+                        rhs.ty
+                    ))
+                    .at(span));
                 }
+                let rhs_end = self.builder.get_insert_block().unwrap();
+                self.builder.build_unconditional_branch(merge_block)?;
+
+                self.builder.position_at_end(merge_block);
+                let short_circuit = self
+                    .context
+                    .bool_type()
+                    .const_int((*op == Operator::Or) as u64, false);
+                let phi = self
+                    .builder
+                    .build_phi(self.context.bool_type(), "logic_temp")?;
+                phi.add_incoming(&[(&short_circuit, lhs_end), (&rhs.value, rhs_end)]);
+                (Type::Bool, phi.as_basic_value())
             }
             Node::BinaryExpr { op, lhs, rhs } => {
-                let left = self.build_float(lhs);
-                let right = self.build_float(rhs);
-                match op {
-                    Operator::Plus => self
-                        .builder
-                        .build_float_add(left, right, "plus_temp")
-                        .unwrap(),
-                    Operator::Minus => self
-                        .builder
-                        .build_float_sub(left, right, "minus_temp")
-                        .unwrap(),
-                    Operator::Multiply => self
-                        .builder
-                        .build_float_mul(left, right, "mul_temp")
-                        .unwrap(),
-                    Operator::Divide => self
-                        .builder
-                        .build_float_div(left, right, "div_temp")
-                        .unwrap(),
+                let left = self.build(lhs)?;
+                let right = self.build(rhs)?;
+                let ty = Type::binary(*op, left.ty, right.ty).map_err(|err| err.at(span))?;
+                // like `Val::binary`, only the int `0` is an error; float division
+                // by zero gives an infinity or NaN
+                if op.is_division() && right.ty == Type::Int {
+                    let is_zero = self.builder.build_int_compare(
+                        IntPredicate::EQ,
+                        right.value.into_int_value(),
+                        self.context.i32_type().const_zero(),
+                        "is_zero",
+                    )?;
+                    self.return_if(is_zero, Some((RuntimeError::DivisionByZero, span)))?;
                 }
+                if op.is_comparison() {
+                    (Type::Bool, self.build_comparison(*op, left, right)?.into())
+                } else if ty == Type::Int {
+                    (ty, self.build_int(*op, left, right, span)?.into())
+                } else {
+                    (ty, self.build_float(*op, left, right)?.into())
+                }
+            }
+        };
+        Ok(TypedValue { ty, value })
+    }
+
+    fn build_int(
+        &mut self,
+        op: Operator,
+        left: TypedValue<'ctx>,
+        right: TypedValue<'ctx>,
+        span: Span,
+    ) -> Result<IntValue<'ctx>> {
+        let left = left.value.into_int_value();
+        let right = right.value.into_int_value();
+        match op {
+            Operator::Plus => self.build_checked("llvm.sadd.with.overflow", left, right, span),
+            Operator::Minus => self.build_checked("llvm.ssub.with.overflow", left, right, span),
+            Operator::Multiply => self.build_checked("llvm.smul.with.overflow", left, right, span),
+            Operator::Power => self.build_int_pow(left, right, span),
+            // the divisor was checked for zero by the caller
+            Operator::FloorDivide => {
+                let i32_type = self.context.i32_type();
+                // i32::MIN // -1 does not fit in an i32
+                let is_min = self.builder.build_int_compare(
+                    IntPredicate::EQ,
+                    left,
+                    i32_type.const_int(i32::MIN as u64, true),
+                    "is_min",
+                )?;
+                let is_minus_one = self.builder.build_int_compare(
+                    IntPredicate::EQ,
+                    right,
+                    i32_type.const_all_ones(),
+                    "is_minus_one",
+                )?;
+                let overflow = self.builder.build_and(is_min, is_minus_one, "overflow")?;
+                self.return_if(overflow, Some((RuntimeError::Overflow, span)))?;
+                // `sdiv` truncates towards zero, so step down when the signs
+                // differ and the division is inexact
+                let quotient = self.builder.build_int_signed_div(left, right, "div_temp")?;
+                let remainder = self.builder.build_int_signed_rem(left, right, "rem_temp")?;
+                let adjust = self.needs_adjust(remainder, right)?;
+                let floor = self.builder.build_int_sub(
+                    quotient,
+                    i32_type.const_int(1, false),
+                    "floor_temp",
+                )?;
+                Ok(self
+                    .builder
+                    .build_select(adjust, floor, quotient, "floor_div_temp")?
+                    .into_int_value())
+            }
+            Operator::Modulo => {
+                let i32_type = self.context.i32_type();
+                // `srem` is undefined for i32::MIN % -1, whose result is 0 anyway
+                let is_minus_one = self.builder.build_int_compare(
+                    IntPredicate::EQ,
+                    right,
+                    i32_type.const_all_ones(),
+                    "is_minus_one",
+                )?;
+                let divisor = self
+                    .builder
+                    .build_select(is_minus_one, i32_type.const_int(1, false), right, "divisor")?
+                    .into_int_value();
+                let remainder = self
+                    .builder
+                    .build_int_signed_rem(left, divisor, "rem_temp")?;
+                let adjust = self.needs_adjust(remainder, right)?;
+                let shifted = self.builder.build_int_add(remainder, right, "shift_temp")?;
+                Ok(self
+                    .builder
+                    .build_select(adjust, shifted, remainder, "mod_temp")?
+                    .into_int_value())
             }
+            _ => Err(CalcError::internal(format!(
+                "`{}` is not an arithmetic operator",
+                op
+            ))),
         }
     }
+
+    /// True when an int remainder is nonzero and its sign differs from the
+    /// divisor's, which is when `//` and `%` must round towards negative infinity.
+    fn needs_adjust(
+        &self,
+        remainder: IntValue<'ctx>,
+        divisor: IntValue<'ctx>,
+    ) -> Result<IntValue<'ctx>> {
+        let zero = self.context.i32_type().const_zero();
+        let is_nonzero =
+            self.builder
+                .build_int_compare(IntPredicate::NE, remainder, zero, "is_nonzero")?;
+        let rem_negative =
+            self.builder
+                .build_int_compare(IntPredicate::SLT, remainder, zero, "rem_negative")?;
+        let div_negative =
+            self.builder
+                .build_int_compare(IntPredicate::SLT, divisor, zero, "div_negative")?;
+        let signs_differ = self
+            .builder
+            .build_xor(rem_negative, div_negative, "signs_differ")?;
+        Ok(self.builder.build_and(is_nonzero, signs_differ, "adjust")?)
+    }
+
+    fn build_float(
+        &self,
+        op: Operator,
+        left: TypedValue<'ctx>,
+        right: TypedValue<'ctx>,
+    ) -> Result<FloatValue<'ctx>> {
+        let left = self.to_float(left)?;
+        let right = self.to_float(right)?;
+        let value = match op {
+            Operator::Plus => self.builder.build_float_add(left, right, "plus_temp")?,
+            Operator::Minus => self.builder.build_float_sub(left, right, "minus_temp")?,
+            Operator::Multiply => self.builder.build_float_mul(left, right, "mul_temp")?,
+            Operator::Divide => self.builder.build_float_div(left, right, "div_temp")?,
+            Operator::FloorDivide => {
+                let quotient = self.builder.build_float_div(left, right, "div_temp")?;
+                let function = Intrinsic::find("llvm.floor")
+                    .and_then(|i| {
+                        i.get_declaration(&self.module, &[self.context.f32_type().into()])
+                    })
+                    .ok_or_else(|| CalcError::internal("missing intrinsic llvm.floor"))?;
+                self.builder
+                    .build_call(function, &[quotient.into()], "floor_div_temp")?
+                    .try_as_basic_value()
+                    .unwrap_basic()
+                    .into_float_value()
+            }
+            Operator::Power => {
+                let function = Intrinsic::find("llvm.pow")
+                    .and_then(|i| {
+                        i.get_declaration(&self.module, &[self.context.f32_type().into()])
+                    })
+                    .ok_or_else(|| CalcError::internal("missing intrinsic llvm.pow"))?;
+                self.builder
+                    .build_call(function, &[left.into(), right.into()], "pow_temp")?
+                    .try_as_basic_value()
+                    .unwrap_basic()
+                    .into_float_value()
+            }
+            Operator::Modulo => {
+                // `frem` takes the sign of the dividend; move it to the divisor's
+                let remainder = self.builder.build_float_rem(left, right, "rem_temp")?;
+                let zero = self.context.f32_type().const_zero();
+                let is_nonzero = self.builder.build_float_compare(
+                    FloatPredicate::UNE,
+                    remainder,
+                    zero,
+                    "is_nonzero",
+                )?;
+                let rem_negative = self.builder.build_float_compare(
+                    FloatPredicate::OLT,
+                    remainder,
+                    zero,
+                    "rem_negative",
+                )?;
+                let div_negative = self.builder.build_float_compare(
+                    FloatPredicate::OLT,
+                    right,
+                    zero,
+                    "div_negative",
+                )?;
+                let signs_differ =
+                    self.builder
+                        .build_xor(rem_negative, div_negative, "signs_differ")?;
+                let adjust = self.builder.build_and(is_nonzero, signs_differ, "adjust")?;
+                let shifted = self
+                    .builder
+                    .build_float_add(remainder, right, "shift_temp")?;
+                self.builder
+                    .build_select(adjust, shifted, remainder, "mod_temp")?
+                    .into_float_value()
+            }
+            _ => {
+                return Err(CalcError::internal(format!(
+                    "`{}` is not an arithmetic operator",
+                    op
+                )));
+            }
+        };
+        Ok(value)
+    }
+
+    fn build_comparison(
+        &self,
+        op: Operator,
+        left: TypedValue<'ctx>,
+        right: TypedValue<'ctx>,
+    ) -> Result<IntValue<'ctx>> {
+        let value = if left.ty == right.ty && left.ty != Type::Float {
+            // ints and bools
+            let predicate = match op {
+                Operator::Equal => IntPredicate::EQ,
+                Operator::NotEqual => IntPredicate::NE,
+                Operator::Less => IntPredicate::SLT,
+                Operator::LessEqual => IntPredicate::SLE,
+                Operator::Greater => IntPredicate::SGT,
+                _ => IntPredicate::SGE,
+            };
+            self.builder.build_int_compare(
+                predicate,
+                left.value.into_int_value(),
+                right.value.into_int_value(),
+                "cmp_temp",
+            )?
+        } else {
+            let predicate = match op {
+                Operator::Equal => FloatPredicate::OEQ,
+                Operator::NotEqual => FloatPredicate::UNE,
+                Operator::Less => FloatPredicate::OLT,
+                Operator::LessEqual => FloatPredicate::OLE,
+                Operator::Greater => FloatPredicate::OGT,
+                _ => FloatPredicate::OGE,
+            };
+            let left = self.to_float(left)?;
+            let right = self.to_float(right)?;
+            self.builder
+                .build_float_compare(predicate, left, right, "cmp_temp")?
+        };
+        Ok(value)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::parser::parse;
 
     #[test]
     fn basics() {
-        assert_eq!(Jit::from_source("1 + 2").unwrap(), 3);
-        assert_eq!(Jit::from_source("2 + (2 - 1)").unwrap(), 3);
-        assert_eq!(Jit::from_source("(2 + 3) - 1").unwrap(), 4);
-        assert_eq!(Jit::from_source("1 + ((2 + 3) - (2 + 3))").unwrap(), 1);
-        assert_eq!(Jit::from_source("(1 + 2)").unwrap(), 3);
+        assert_eq!(Jit::from_source("1 + 2").unwrap(), Val::Int(3));
+        assert_eq!(Jit::from_source("2 + (2 - 1)").unwrap(), Val::Int(3));
+        assert_eq!(Jit::from_source("(2 + 3) - 1").unwrap(), Val::Int(4));
+        assert_eq!(
+            Jit::from_source("1 + ((2 + 3) - (2 + 3))").unwrap(),
+            Val::Int(1)
+        );
+        assert_eq!(Jit::from_source("(1 + 2)").unwrap(), Val::Int(3));
     }
 
     #[test]
     fn precedence() {
-        assert_eq!(Jit::from_source("2 + 3 * 4").unwrap(), 14);
-        assert_eq!(Jit::from_source("10 - 4 / 2").unwrap(), 8);
-        assert_eq!(Jit::from_source("2 + 3 - 1").unwrap(), 4);
+        assert_eq!(Jit::from_source("2 + 3 * 4").unwrap(), Val::Int(14));
+        assert_eq!(
+            Jit::from_source("10 - 4 / 2").unwrap(),
+            Val::Float(8.0.into())
+        );
+        assert_eq!(Jit::from_source("10 - 7 // 2 * 2").unwrap(), Val::Int(4));
+        assert_eq!(Jit::from_source("2 + 3 - 1").unwrap(), Val::Int(4));
     }
 
     #[test]
     fn unary() {
-        assert_eq!(Jit::from_source("-5").unwrap(), -5);
-        assert_eq!(Jit::from_source("+3").unwrap(), 3);
-        assert_eq!(Jit::from_source("-1 + 2").unwrap(), 1);
+        assert_eq!(Jit::from_source("-5").unwrap(), Val::Int(-5));
+        assert_eq!(Jit::from_source("+3").unwrap(), Val::Int(3));
+        assert_eq!(Jit::from_source("-1 + 2").unwrap(), Val::Int(1));
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn float_ops() {
-        // Note: Results are truncated to i32
-        assert_eq!(Jit::from_source("3.14 * 2.0").unwrap(), 6);
-        assert_eq!(Jit::from_source("7.0 / 2.0").unwrap(), 3);
-        assert_eq!(Jit::from_source("3.14 * 2.0 + 1.5 / 3.0").unwrap(), 6);
+        assert_eq!(
+            Jit::from_source("3.14 * 2.0").unwrap(),
+            Val::Float(6.28.into())
+        );
+        assert_eq!(
+            Jit::from_source("7.0 / 2.0").unwrap(),
+            Val::Float(3.5.into())
+        );
+        assert_eq!(
+            Jit::from_source("3.14 * 2.0 + 1.5 / 3.0").unwrap(),
+            Val::Float((6.28f32 + 0.5).into())
+        );
     }
-}
 
+    #[test]
+    fn let_bindings() {
+        assert_eq!(
+            Jit::from_source("let x = 2 * 3; x + 1").unwrap(),
+            Val::Int(7)
+        );
+        assert_eq!(
+            Jit::from_source("let x = 1.5; let y = x * 3; y").unwrap(),
+            Val::Float(4.5.into())
+        );
+        assert!(Jit::from_source("let x = 1; y").is_err());
+    }
+
+    #[test]
+    fn multiple_statements() {
+        assert_eq!(Jit::from_source("1 + 1; 2 * 5").unwrap(), Val::Int(10));
+        assert_eq!(
+            Jit::from_source("let x = 2\nlet y = 3\nx * y\n").unwrap(),
+            Val::Int(6)
+        );
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(
+            Jit::from_source("fn area(w, h) = w * h; area(3, 4)").unwrap(),
+            Val::Int(12)
+        );
+        assert_eq!(
+            Jit::from_source("fn sq(x) = x * x\nfn hyp2(a, b) = sq(a) + sq(b)\nhyp2(3, 4.0)")
+                .unwrap(),
+            Val::Float(25.0.into())
+        );
+        assert_eq!(
+            Jit::from_source("double(2); fn double(x) = x * 2; double(5)").unwrap(),
+            Val::Int(10)
+        );
+        assert!(Jit::from_source("f(1)").is_err());
+        assert!(Jit::from_source("fn f(a, b) = a; f(1)").is_err());
+        assert!(Jit::from_source("let y = 1; fn f(x) = x + y; f(1)").is_err());
+    }
+
+    #[test]
+    fn booleans() {
+        assert_eq!(Jit::from_source("1 < 2").unwrap(), Val::Bool(true));
+        assert_eq!(
+            Jit::from_source("2 <= 2.0 && !false").unwrap(),
+            Val::Bool(true)
+        );
+        assert_eq!(
+            Jit::from_source("1 == 1.0 || false").unwrap(),
+            Val::Bool(true)
+        );
+        assert_eq!(Jit::from_source("true != true").unwrap(), Val::Bool(false));
+        assert!(Jit::from_source("true + 1").is_err());
+        assert!(Jit::from_source("true < false").is_err());
+        assert!(Jit::from_source("if 1 then 2 else 3").is_err());
+    }
+
+    #[test]
+    fn if_expressions() {
+        assert_eq!(
+            Jit::from_source("let x = -3; if x < 0 then -x else x").unwrap(),
+            Val::Int(3)
+        );
+        assert_eq!(
+            Jit::from_source("fn fact(n) = if n <= 1 then 1 else n * fact(n - 1); fact(10)")
+                .unwrap(),
+            Val::Int(3628800)
+        );
+        assert_eq!(
+            Jit::from_source("fn fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2); fib(15)")
+                .unwrap(),
+            Val::Int(610)
+        );
+        assert_eq!(
+            Jit::from_source("if false then 1 else if true then 2 else 3").unwrap(),
+            Val::Int(2)
+        );
+        assert!(Jit::from_source("if true then 1 else 2.0").is_err());
+    }
+
+    #[test]
+    fn short_circuit() {
+        // `loop` never returns, so calling it would hang
+        let prelude = "fn loop(x) = if x > 0 then loop(x) else x > 0\n";
+        assert_eq!(
+            Jit::from_source(&format!("{}false && loop(1)", prelude)).unwrap(),
+            Val::Bool(false)
+        );
+        assert_eq!(
+            Jit::from_source(&format!("{}true || loop(1)", prelude)).unwrap(),
+            Val::Bool(true)
+        );
+        // a body that only recurses has no type to infer, unlike on the other
+        // backends, which never need one
+        assert_eq!(
+            Jit::from_ast(parse("fn loop(x) = loop(x)\nfalse && loop(1)").unwrap()),
+            Err(
+                CalcError::type_error("cannot infer the return type of `loop`")
+                    .at(Span::new(30, 37))
+            )
+        );
+    }
+
+    #[test]
+    fn division() {
+        let tests = [
+            ("7 / 2", Val::Float(3.5.into())),
+            ("-7 // 2", Val::Int(-4)),
+            ("7 // -2", Val::Int(-4)),
+            ("-7.0 // 2", Val::Float((-4.0).into())),
+            ("-7 % 2", Val::Int(1)),
+            ("7 % -2", Val::Int(-1)),
+            ("-2147483647 - 1 % -1", Val::Int(-2147483647)),
+            ("let x = -2147483647 - 1; x % -1", Val::Int(0)),
+            ("-7.5 % 2", Val::Float(0.5.into())),
+            ("7.5 % -2.0", Val::Float((-0.5).into())),
+        ];
+        for (src, expected) in tests {
+            assert_eq!(
+                Jit::from_source(src).unwrap(),
+                expected,
+                "Failed on input: {}",
+                src
+            );
+        }
+        for src in ["1 % 0", "1 // 0", "1.5 / 0"] {
+            assert_eq!(
+                Jit::from_source(src),
+                Err(CalcError::runtime(RuntimeError::DivisionByZero).at(Span::new(0, src.len())))
+            );
+        }
+        assert_eq!(
+            Jit::from_source("let x = -2147483647 - 1; x // -1"),
+            Err(CalcError::runtime(RuntimeError::Overflow).at(Span::new(25, 32)))
+        );
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let err = |src| Jit::from_source(src).unwrap_err();
+        assert_eq!(
+            err("1 / 0"),
+            CalcError::runtime(RuntimeError::DivisionByZero).at(Span::new(0, 5))
+        );
+        assert_eq!(
+            err("2147483647 + 1"),
+            CalcError::runtime(RuntimeError::Overflow).at(Span::new(0, 14))
+        );
+        assert_eq!(
+            err("let x = -2147483647 - 1; -x"),
+            CalcError::runtime(RuntimeError::Overflow).at(Span::new(25, 27))
+        );
+        assert_eq!(
+            err("fn f(x) = x * x; f(65536)"),
+            CalcError::runtime(RuntimeError::Overflow).at(Span::new(10, 15))
+        );
+        assert_eq!(
+            err("fn f(x) = if x > 0 then f(x) else 1; f(1)"),
+            CalcError::runtime(RuntimeError::CallDepthExceeded).at(Span::new(24, 28))
+        );
+        assert!(matches!(err("x"), CalcError::Type { .. }));
+    }
+}
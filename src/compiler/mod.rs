@@ -1,4 +1,12 @@
+pub mod closure;
 pub mod interpreter;
 #[cfg(feature = "jit")]
 pub mod jit;
+pub mod register;
 pub mod vm;
+
+/// How many nested function calls a program may make before it is aborted.
+///
+/// Every backend reports this before using up a 2 MiB native stack, the size
+/// of a spawned thread's, even in debug builds.
+pub const MAX_CALL_DEPTH: usize = 256;
@@ -0,0 +1,825 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::compiler::vm::bytecode::ConstantKey;
+use crate::error::{CalcError, Span};
+use crate::program::check_variables;
+use crate::val::Val;
+use crate::{Compile, Node, Operator, Result, Spanned};
+
+/// A register in the current call frame. A function's arguments are its
+/// first registers, and the main program keeps each `let` binding in one.
+pub type Reg = u16;
+
+/// Where an instruction reads a value from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Reg(Reg),
+    /// An index into the constant pool.
+    Const(u16),
+}
+
+/// A three-address instruction. Jump targets are indexes into the
+/// instructions of the same function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instr {
+    Move {
+        dst: Reg,
+        src: Operand,
+    },
+    Add {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Sub {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Mul {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Div {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    FloorDiv {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Mod {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Pow {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Equal {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    NotEqual {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Less {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    LessEqual {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Greater {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    GreaterEqual {
+        dst: Reg,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Plus {
+        dst: Reg,
+        src: Operand,
+    },
+    Minus {
+        dst: Reg,
+        src: Operand,
+    },
+    Not {
+        dst: Reg,
+        src: Operand,
+    },
+    Jump {
+        target: u32,
+    },
+    JumpIfFalse {
+        cond: Operand,
+        target: u32,
+    },
+    JumpIfTrue {
+        cond: Operand,
+        target: u32,
+    },
+    /// Calls a function whose arguments are in `args` and the registers
+    /// after it. The callee's registers start at `args`, and its result is
+    /// written to `dst`.
+    Call {
+        dst: Reg,
+        function: u16,
+        args: Reg,
+    },
+    /// Ends the function, or the program when executed by the main program.
+    Return {
+        src: Operand,
+    },
+}
+
+impl Instr {
+    fn binary(op: Operator, dst: Reg, lhs: Operand, rhs: Operand) -> Result<Instr> {
+        Ok(match op {
+            Operator::Plus => Instr::Add { dst, lhs, rhs },
+            Operator::Minus => Instr::Sub { dst, lhs, rhs },
+            Operator::Multiply => Instr::Mul { dst, lhs, rhs },
+            Operator::Divide => Instr::Div { dst, lhs, rhs },
+            Operator::FloorDivide => Instr::FloorDiv { dst, lhs, rhs },
+            Operator::Modulo => Instr::Mod { dst, lhs, rhs },
+            Operator::Power => Instr::Pow { dst, lhs, rhs },
+            Operator::Equal => Instr::Equal { dst, lhs, rhs },
+            Operator::NotEqual => Instr::NotEqual { dst, lhs, rhs },
+            Operator::Less => Instr::Less { dst, lhs, rhs },
+            Operator::LessEqual => Instr::LessEqual { dst, lhs, rhs },
+            Operator::Greater => Instr::Greater { dst, lhs, rhs },
+            Operator::GreaterEqual => Instr::GreaterEqual { dst, lhs, rhs },
+            _ => {
+                return Err(CalcError::internal(format!(
+                    "`{}` is not a binary operator",
+                    op
+                )));
+            }
+        })
+    }
+
+    fn unary(op: Operator, dst: Reg, src: Operand) -> Result<Instr> {
+        Ok(match op {
+            Operator::Plus => Instr::Plus { dst, src },
+            Operator::Minus => Instr::Minus { dst, src },
+            Operator::Not => Instr::Not { dst, src },
+            _ => {
+                return Err(CalcError::internal(format!(
+                    "`{}` is not a unary operator",
+                    op
+                )));
+            }
+        })
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operand::Reg(reg) => write!(f, "r{}", reg),
+            Operand::Const(idx) => write!(f, "k{}", idx),
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Instr::Move { dst, src } => return write!(f, "move r{}, {}", dst, src),
+            Instr::Plus { dst, src } => return write!(f, "plus r{}, {}", dst, src),
+            Instr::Minus { dst, src } => return write!(f, "minus r{}, {}", dst, src),
+            Instr::Not { dst, src } => return write!(f, "not r{}, {}", dst, src),
+            Instr::Jump { target } => return write!(f, "jmp {}", target),
+            Instr::JumpIfFalse { cond, target } => return write!(f, "jmpf {}, {}", cond, target),
+            Instr::JumpIfTrue { cond, target } => return write!(f, "jmpt {}, {}", cond, target),
+            Instr::Call {
+                dst,
+                function,
+                args,
+            } => return write!(f, "call r{}, {}, r{}", dst, function, args),
+            Instr::Return { src } => return write!(f, "ret {}", src),
+            Instr::Add { .. } => "add",
+            Instr::Sub { .. } => "sub",
+            Instr::Mul { .. } => "mul",
+            Instr::Div { .. } => "div",
+            Instr::FloorDiv { .. } => "floordiv",
+            Instr::Mod { .. } => "mod",
+            Instr::Pow { .. } => "pow",
+            Instr::Equal { .. } => "eq",
+            Instr::NotEqual { .. } => "ne",
+            Instr::Less { .. } => "lt",
+            Instr::LessEqual { .. } => "le",
+            Instr::Greater { .. } => "gt",
+            Instr::GreaterEqual { .. } => "ge",
+        };
+        match self {
+            Instr::Add { dst, lhs, rhs }
+            | Instr::Sub { dst, lhs, rhs }
+            | Instr::Mul { dst, lhs, rhs }
+            | Instr::Div { dst, lhs, rhs }
+            | Instr::FloorDiv { dst, lhs, rhs }
+            | Instr::Mod { dst, lhs, rhs }
+            | Instr::Pow { dst, lhs, rhs }
+            | Instr::Equal { dst, lhs, rhs }
+            | Instr::NotEqual { dst, lhs, rhs }
+            | Instr::Less { dst, lhs, rhs }
+            | Instr::LessEqual { dst, lhs, rhs }
+            | Instr::Greater { dst, lhs, rhs }
+            | Instr::GreaterEqual { dst, lhs, rhs } => {
+                write!(f, "{} r{}, {}, {}", name, dst, lhs, rhs)
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The main program or a function, with the number of registers its call
+/// frame needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Chunk {
+    pub(crate) name: String,
+    pub(crate) arity: usize,
+    pub(crate) registers: usize,
+    pub(crate) instructions: Vec<Instr>,
+    /// The span of source each instruction was compiled from, which errors
+    /// it raises point at. Instructions that cannot fail have an empty span.
+    pub(crate) spans: Vec<Span>,
+}
+
+/// A program compiled for the register VM. It can only be built by
+/// [`Compiler`], so every register, constant, function and jump target it
+/// refers to exists and every function ends with `Return`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Code {
+    pub(crate) main: Chunk,
+    pub(crate) constants: Vec<Val>,
+    pub(crate) functions: Vec<Chunk>,
+}
+
+impl Code {
+    /// Lists the main program and then every function, one instruction per
+    /// line such as `0002 add r2, r0, k1 (1)`, with constants resolved.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        for (idx, chunk) in std::iter::once(&self.main)
+            .chain(&self.functions)
+            .enumerate()
+        {
+            if idx > 0 {
+                out.push('\n');
+                out.push_str(&format!("== {}/{} ", chunk.name, chunk.arity));
+            } else {
+                out.push_str("== main ");
+            }
+            out.push_str(&format!("({} registers) ==\n", chunk.registers));
+            for (offset, instr) in chunk.instructions.iter().enumerate() {
+                out.push_str(&format!(
+                    "{:04} {}{}\n",
+                    offset,
+                    instr,
+                    self.comment(*instr)
+                ));
+            }
+        }
+        out
+    }
+
+    fn comment(&self, instr: Instr) -> String {
+        let operands = match instr {
+            Instr::Move { src, .. }
+            | Instr::Plus { src, .. }
+            | Instr::Minus { src, .. }
+            | Instr::Not { src, .. }
+            | Instr::Return { src } => vec![src],
+            Instr::JumpIfFalse { cond, .. } | Instr::JumpIfTrue { cond, .. } => vec![cond],
+            Instr::Call { function, .. } => {
+                return format!(" ({})", self.functions[function as usize].name);
+            }
+            Instr::Jump { .. } => vec![],
+            Instr::Add { lhs, rhs, .. }
+            | Instr::Sub { lhs, rhs, .. }
+            | Instr::Mul { lhs, rhs, .. }
+            | Instr::Div { lhs, rhs, .. }
+            | Instr::FloorDiv { lhs, rhs, .. }
+            | Instr::Mod { lhs, rhs, .. }
+            | Instr::Pow { lhs, rhs, .. }
+            | Instr::Equal { lhs, rhs, .. }
+            | Instr::NotEqual { lhs, rhs, .. }
+            | Instr::Less { lhs, rhs, .. }
+            | Instr::LessEqual { lhs, rhs, .. }
+            | Instr::Greater { lhs, rhs, .. }
+            | Instr::GreaterEqual { lhs, rhs, .. } => vec![lhs, rhs],
+        };
+        let constants: Vec<String> = operands
+            .into_iter()
+            .filter_map(|operand| match operand {
+                Operand::Const(idx) => Some(match self.constants[idx as usize] {
+                    Val::Float(f) => format!("{:?}", f.into_inner()),
+                    val => val.to_string(),
+                }),
+                Operand::Reg(_) => None,
+            })
+            .collect();
+        if constants.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", constants.join(", "))
+        }
+    }
+}
+
+/// Compiles the AST to register code.
+///
+/// Registers are allocated like a stack while walking the tree: an
+/// expression gets the register its value must end up in, and the operands
+/// it computes take the next free ones, which are released once the
+/// expression's instruction is emitted. Constants and variables are read
+/// where they are, so they take no instruction or register of their own.
+#[derive(Debug)]
+pub struct Compiler {
+    code: Code,
+    constants: HashMap<ConstantKey, u16>, // index of each value in the constant pool
+    globals: HashMap<String, Reg>,        // register of each `let` binding in the main program
+    functions: HashMap<String, (u16, usize)>, // index into the function table and arity
+    locals: Option<Vec<String>>,          // parameters of the function being compiled
+    instructions: Vec<Instr>,             // instructions of the chunk being compiled
+    spans: Vec<Span>,                     // span of each of those instructions
+    next: usize,                          // first free register
+    registers: usize,                     // registers the chunk being compiled needs
+}
+
+impl Compile for Compiler {
+    type Output = Code;
+
+    fn from_ast(ast: Vec<Spanned<Node>>) -> Result<Self::Output> {
+        Compiler::compile(ast, &[])
+    }
+}
+
+impl Compiler {
+    /// Compiles a program in which `variables` may be read without being
+    /// bound by `let`. They are the first registers of the main program, in
+    /// order, and are given their values by [`RegisterVM::run_with`](crate::RegisterVM::run_with).
+    pub fn compile(ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Code> {
+        check_variables(variables)?;
+        let mut compiler = Compiler {
+            code: Code {
+                main: Chunk {
+                    name: "main".to_string(),
+                    arity: 0,
+                    registers: 0,
+                    instructions: Vec::new(),
+                    spans: Vec::new(),
+                },
+                constants: Vec::new(),
+                functions: Vec::new(),
+            },
+            constants: HashMap::new(),
+            globals: HashMap::new(),
+            functions: HashMap::new(),
+            locals: None,
+            instructions: Vec::new(),
+            spans: Vec::new(),
+            next: 0,
+            registers: 0,
+        };
+        for name in variables {
+            let reg = compiler.alloc()?;
+            compiler.globals.insert(name.to_string(), reg);
+        }
+        // function definitions are hoisted so they can be called from anywhere
+        let (definitions, statements): (Vec<_>, Vec<_>) = ast
+            .into_iter()
+            .partition(|node| matches!(node.node, Node::FnDef { .. }));
+        for node in &definitions {
+            if let Node::FnDef { name, params, .. } = &node.node {
+                compiler.declare_function(name, params.len())?;
+            }
+        }
+
+        let len = statements.len();
+        for (idx, node) in statements.into_iter().enumerate() {
+            if idx + 1 == len {
+                compiler.compile_return(node)?;
+            } else if let Node::Let { name, value } = node.node {
+                compiler.compile_let(name, *value, node.span)?;
+            } else {
+                // the result is dropped, but the statement can still fail
+                compiler.operand(node, None)?;
+                compiler.next = compiler.globals.len();
+            }
+        }
+        compiler.code.main.registers = compiler.registers;
+        compiler.code.main.instructions = std::mem::take(&mut compiler.instructions);
+        compiler.code.main.spans = std::mem::take(&mut compiler.spans);
+
+        for node in definitions {
+            if let Node::FnDef { name, params, body } = node.node {
+                compiler.compile_function(name, params, *body)?;
+            }
+        }
+        Ok(compiler.code)
+    }
+    fn declare_function(&mut self, name: &str, arity: usize) -> Result<()> {
+        if self.functions.len() > u16::MAX as usize {
+            return Err(CalcError::limit(format!(
+                "too many functions, the limit is {}",
+                u16::MAX as usize + 1
+            )));
+        }
+        let idx = self.functions.len() as u16;
+        self.functions.insert(name.to_string(), (idx, arity));
+        Ok(())
+    }
+
+    fn compile_function(
+        &mut self,
+        name: String,
+        params: Vec<String>,
+        body: Spanned<Node>,
+    ) -> Result<()> {
+        let arity = params.len();
+        if arity > Reg::MAX as usize + 1 {
+            return Err(too_many_registers());
+        }
+        self.next = arity;
+        self.registers = arity;
+        self.locals = Some(params);
+        self.compile_return(body)?;
+        self.locals = None;
+        self.code.functions.push(Chunk {
+            name,
+            arity,
+            registers: self.registers,
+            instructions: std::mem::take(&mut self.instructions),
+            spans: std::mem::take(&mut self.spans),
+        });
+        Ok(())
+    }
+
+    /// Compiles `node` so that its value is returned, giving each branch of
+    /// an `if` its own `Return` instead of joining them in a register first.
+    fn compile_return(&mut self, node: Spanned<Node>) -> Result<()> {
+        let mark = self.next;
+        let span = node.span;
+        match node.node {
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let cond = self.operand(*cond, None)?;
+                let jump_to_else =
+                    self.add_instruction_at(Instr::JumpIfFalse { cond, target: 0 }, span);
+                self.next = mark;
+                self.compile_return(*then_branch)?;
+                self.patch_jump(jump_to_else);
+                self.compile_return(*else_branch)?;
+            }
+            Node::Let { name, value } if self.locals.is_none() => {
+                let reg = self.compile_let(name, *value, span)?;
+                self.add_instruction(Instr::Return {
+                    src: Operand::Reg(reg),
+                });
+            }
+            node => {
+                let src = self.operand(Spanned::new(node, span), None)?;
+                self.add_instruction(Instr::Return { src });
+            }
+        }
+        self.next = mark;
+        Ok(())
+    }
+
+    /// Binds `name` to a register of the main program, which it keeps for
+    /// the rest of the program. `span` is that of the whole `let`.
+    fn compile_let(&mut self, name: String, value: Spanned<Node>, span: Span) -> Result<Reg> {
+        if self.locals.is_some() {
+            return Err(CalcError::type_error(format!(
+                "`let {}` can only be used at the top level",
+                name
+            ))
+            .at(span));
+        }
+        match self.globals.get(&name) {
+            // the value can read the previous binding, as in `let x = x + 1`,
+            // so it is computed elsewhere and then moved into place
+            Some(&reg) => {
+                let src = self.operand(value, None)?;
+                self.add_instruction(Instr::Move { dst: reg, src });
+                self.next = self.globals.len();
+                Ok(reg)
+            }
+            // the name is bound after its value is compiled, so that the
+            // value cannot refer to it
+            None => {
+                let reg = self.alloc()?;
+                self.compile_into(value, reg)?;
+                self.globals.insert(name, reg);
+                Ok(reg)
+            }
+        }
+    }
+
+    /// Returns where the value of `node` can be read. Constants and
+    /// variables are read in place; anything else is computed into
+    /// `scratch`, or into a newly allocated register that the caller frees
+    /// by resetting `next`.
+    fn operand(&mut self, node: Spanned<Node>, scratch: Option<Reg>) -> Result<Operand> {
+        match node.node {
+            Node::Val(val) => Ok(Operand::Const(self.add_constant(val)?)),
+            Node::Ident(name) => Ok(Operand::Reg(
+                self.variable(&name).map_err(|err| err.at(node.span))?,
+            )),
+            _ => {
+                let reg = match scratch {
+                    Some(reg) => reg,
+                    None => self.alloc()?,
+                };
+                self.compile_into(node, reg)?;
+                Ok(Operand::Reg(reg))
+            }
+        }
+    }
+
+    /// Compiles `node` so that its value ends up in `dst`. `dst` also holds
+    /// intermediate results on the way, so `node` must not read it. Every
+    /// register allocated here is free again afterwards.
+    fn compile_into(&mut self, node: Spanned<Node>, dst: Reg) -> Result<()> {
+        let mark = self.next;
+        let span = node.span;
+        match node.node {
+            node @ (Node::Val(_) | Node::Ident(_)) => {
+                let src = self.operand(Spanned::new(node, span), None)?;
+                self.add_instruction(Instr::Move { dst, src });
+            }
+            Node::Let { name, .. } => {
+                return Err(CalcError::type_error(format!(
+                    "`let {}` can only be used at the top level",
+                    name
+                ))
+                .at(span));
+            }
+            Node::FnDef { name, .. } => {
+                return Err(CalcError::type_error(format!(
+                    "function `{}` can only be defined at the top level",
+                    name
+                ))
+                .at(span));
+            }
+            Node::Call { name, args } => {
+                let Some(&(function, arity)) = self.functions.get(&name) else {
+                    return Err(
+                        CalcError::type_error(format!("undefined function `{}`", name)).at(span),
+                    );
+                };
+                if arity != args.len() {
+                    return Err(CalcError::type_error(format!(
+                        "function `{}` expects {} arguments but got {}",
+                        name,
+                        arity,
+                        args.len()
+                    ))
+                    .at(span));
+                }
+                // the arguments go in consecutive registers, which become the
+                // first registers of the callee's frame
+                let first = Reg::try_from(self.next).map_err(|_| too_many_registers())?;
+                for arg in args {
+                    let reg = self.alloc()?;
+                    self.compile_into(arg, reg)?;
+                }
+                self.add_instruction_at(
+                    Instr::Call {
+                        dst,
+                        function,
+                        args: first,
+                    },
+                    span,
+                );
+            }
+            Node::UnaryExpr { op, child } => {
+                let src = self.operand(*child, Some(dst))?;
+                self.add_instruction_at(Instr::unary(op, dst, src)?, span);
+            }
+            Node::BinaryExpr {
+                op: op @ (Operator::And | Operator::Or),
+                lhs,
+                rhs,
+            } => {
+                // both operands jump to the short-circuit result when they decide
+                // the outcome; falling through both yields the opposite value
+                let short_circuit = op == Operator::Or;
+                let jump = |cond| match op {
+                    Operator::Or => Instr::JumpIfTrue { cond, target: 0 },
+                    _ => Instr::JumpIfFalse { cond, target: 0 },
+                };
+                let cond = self.operand(*lhs, Some(dst))?;
+                let lhs_jump = self.add_instruction_at(jump(cond), span);
+                let cond = self.operand(*rhs, Some(dst))?;
+                let rhs_jump = self.add_instruction_at(jump(cond), span);
+                let src = Operand::Const(self.add_constant(Val::Bool(!short_circuit))?);
+                self.add_instruction(Instr::Move { dst, src });
+                let jump_to_end = self.add_instruction(Instr::Jump { target: 0 });
+                self.patch_jump(lhs_jump);
+                self.patch_jump(rhs_jump);
+                let src = Operand::Const(self.add_constant(Val::Bool(short_circuit))?);
+                self.add_instruction(Instr::Move { dst, src });
+                self.patch_jump(jump_to_end);
+            }
+            Node::BinaryExpr { op, lhs, rhs } => {
+                let lhs = self.operand(*lhs, Some(dst))?;
+                let rhs = self.operand(*rhs, None)?;
+                self.add_instruction_at(Instr::binary(op, dst, lhs, rhs)?, span);
+            }
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let cond = self.operand(*cond, Some(dst))?;
+                let jump_to_else =
+                    self.add_instruction_at(Instr::JumpIfFalse { cond, target: 0 }, span);
+                self.compile_into(*then_branch, dst)?;
+                let jump_to_end = self.add_instruction(Instr::Jump { target: 0 });
+                self.patch_jump(jump_to_else);
+                self.compile_into(*else_branch, dst)?;
+                self.patch_jump(jump_to_end);
+            }
+            Node::Error => return Err(CalcError::unparsed().at(span)),
+        }
+        self.next = mark;
+        Ok(())
+    }
+
+    fn variable(&self, name: &str) -> Result<Reg> {
+        let reg = match &self.locals {
+            Some(locals) => locals
+                .iter()
+                .position(|local| local == name)
+                .map(|idx| idx as Reg),
+            None => self.globals.get(name).copied(),
+        };
+        reg.ok_or_else(|| CalcError::type_error(format!("undefined variable `{}`", name)))
+    }
+
+    fn alloc(&mut self) -> Result<Reg> {
+        let reg = Reg::try_from(self.next).map_err(|_| too_many_registers())?;
+        self.next += 1;
+        self.registers = self.registers.max(self.next);
+        Ok(reg)
+    }
+
+    /// Returns the index of `val` in the constant pool, adding it unless it
+    /// is already there.
+    fn add_constant(&mut self, val: Val) -> Result<u16> {
+        if let Some(&idx) = self.constants.get(&val.into()) {
+            return Ok(idx);
+        }
+        let idx = u16::try_from(self.code.constants.len()).map_err(|_| {
+            CalcError::limit(format!(
+                "too many constants, the limit is {}",
+                u16::MAX as usize + 1
+            ))
+        })?;
+        self.code.constants.push(val);
+        self.constants.insert(val.into(), idx);
+        Ok(idx)
+    }
+
+    fn add_instruction(&mut self, instr: Instr) -> usize {
+        self.add_instruction_at(instr, Span::default())
+    }
+
+    /// Adds an instruction that can fail at run time, recording `span` as
+    /// where its errors come from.
+    fn add_instruction_at(&mut self, instr: Instr, span: Span) -> usize {
+        self.instructions.push(instr);
+        self.spans.push(span);
+        self.instructions.len() - 1
+    }
+
+    /// Points the jump at `position` to the next instruction to be added.
+    fn patch_jump(&mut self, position: usize) {
+        let next = self.instructions.len() as u32;
+        match &mut self.instructions[position] {
+            Instr::Jump { target }
+            | Instr::JumpIfFalse { target, .. }
+            | Instr::JumpIfTrue { target, .. } => *target = next,
+            instr => unreachable!("{} is not a jump", instr),
+        }
+    }
+}
+
+fn too_many_registers() -> CalcError {
+    CalcError::limit(format!(
+        "expression is too large to compile, a call frame is limited to {} registers",
+        Reg::MAX as usize + 1
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn compile(source: &str) -> Code {
+        Compiler::from_ast(parse(source).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn three_address_instructions() {
+        let code = compile("fn f(a, b) = (a + 1) * (b - 2); f(3, 4)");
+        let r = Operand::Reg;
+        let k = Operand::Const;
+        assert_eq!(
+            code.functions[0].instructions,
+            vec![
+                Instr::Add {
+                    dst: 2,
+                    lhs: r(0),
+                    rhs: k(2)
+                },
+                Instr::Sub {
+                    dst: 3,
+                    lhs: r(1),
+                    rhs: k(3)
+                },
+                Instr::Mul {
+                    dst: 2,
+                    lhs: r(2),
+                    rhs: r(3)
+                },
+                Instr::Return { src: r(2) },
+            ]
+        );
+        assert_eq!(code.functions[0].registers, 4);
+        assert_eq!(
+            code.main.instructions,
+            vec![
+                Instr::Move { dst: 1, src: k(0) },
+                Instr::Move { dst: 2, src: k(1) },
+                Instr::Call {
+                    dst: 0,
+                    function: 0,
+                    args: 1
+                },
+                Instr::Return { src: r(0) },
+            ]
+        );
+    }
+
+    #[test]
+    fn register_allocation() {
+        // a left-leaning chain reuses the register it builds its result in
+        let chain = (2..100).fold("x".to_string(), |acc, n| format!("{} * {}", acc, n));
+        let code = compile(&format!("fn f(x) = {}; f(1)", chain));
+        assert_eq!(code.functions[0].registers, 2);
+        assert_eq!(code.functions[0].instructions.len(), 99);
+
+        // each nested right operand holds one more value
+        let code = compile("fn f(x) = x - (x - (x - (x - 1))); f(1)");
+        assert_eq!(code.functions[0].registers, 5);
+
+        // bindings keep their registers, temporaries are reused per statement
+        let code = compile("let a = 1; let b = (a + 1) * (a + 2); let c = a + b; a * (b - c)");
+        assert_eq!(code.main.registers, 5);
+        assert_eq!(code.main.instructions.len(), 8);
+    }
+
+    #[test]
+    fn disassemble() {
+        let code = compile("fn sq(x) = x * x; let y = 1.5; if y < 2 then sq(y) else -y");
+        assert_eq!(
+            code.disassemble(),
+            "== main (3 registers) ==\n\
+             0000 move r0, k0 (1.5)\n\
+             0001 lt r1, r0, k1 (2)\n\
+             0002 jmpf r1, 6\n\
+             0003 move r2, r0\n\
+             0004 call r1, 0, r2 (sq)\n\
+             0005 ret r1\n\
+             0006 minus r1, r0\n\
+             0007 ret r1\n\
+             \n\
+             == sq/1 (2 registers) ==\n\
+             0000 mul r1, r0, r0\n\
+             0001 ret r1\n"
+        );
+    }
+
+    #[test]
+    fn compile_errors() {
+        let err = |src| {
+            Compiler::from_ast(parse(src).unwrap())
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(err("let x = x + 1; x"), "undefined variable `x`");
+        assert_eq!(err("f(1)"), "undefined function `f`");
+        assert_eq!(
+            err("fn f(a, b) = a; f(1)"),
+            "function `f` expects 2 arguments but got 1"
+        );
+        assert_eq!(
+            err("let y = 1; fn f(x) = x + y; f(1)"),
+            "undefined variable `y`"
+        );
+    }
+}
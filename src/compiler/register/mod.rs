@@ -0,0 +1,13 @@
+//! A register machine, the alternative to the stack VM in `compiler::vm`.
+//!
+//! Each instruction names the registers it reads and the one it writes, such
+//! as `add r2, r0, k1`, so `(a + 1) * (b - 2)` runs as three instructions
+//! instead of the seven pushes, pops and operators of the stack VM.
+
+pub mod code;
+pub mod vm;
+
+pub use crate::compiler::register::{
+    code::{Code, Compiler, Instr, Operand, Reg},
+    vm::RegisterVM,
+};
@@ -0,0 +1,346 @@
+use std::cell::RefCell;
+
+use crate::compiler::MAX_CALL_DEPTH;
+use crate::compiler::register::code::{Chunk, Code, Compiler, Instr, Operand, Reg};
+use crate::error::{CalcError, RuntimeError};
+use crate::program::{Executable, Prepare};
+use crate::trace::{self, Category, Level};
+use crate::val::Val;
+use crate::{Compile, Node, Operator, Result, Spanned};
+
+pub struct RegisterVM {
+    code: Code,
+    registers: Vec<Val>,
+    frames: Vec<Frame>,
+}
+
+/// Where to resume the caller once a function returns.
+struct Frame {
+    function: Option<usize>, // `None` for the main program
+    ip: usize,
+    base: usize, // index of the caller's first register
+    dst: Reg,    // caller's register that receives the result
+}
+
+impl RegisterVM {
+    pub fn new(code: Code) -> Self {
+        // a callee's frame starts inside its caller's, so the frames of the
+        // deepest chain of calls fit in the main program's registers plus
+        // the largest frame for every call
+        let largest = code
+            .functions
+            .iter()
+            .map(|function| function.registers)
+            .max()
+            .unwrap_or(0);
+        let len = code.main.registers + largest * MAX_CALL_DEPTH;
+        Self {
+            code,
+            registers: vec![Val::Int(0); len],
+            frames: Vec::new(),
+        }
+    }
+
+    /// Runs the program and returns the value of its last statement.
+    pub fn run(&mut self) -> Result<Val> {
+        let Self {
+            code,
+            registers,
+            frames,
+        } = self;
+        if code.main.instructions.is_empty() {
+            return Err(CalcError::type_error("cannot evaluate an empty program"));
+        }
+        frames.clear();
+        let constants = code.constants.as_slice();
+        let mut chunk: &Chunk = &code.main;
+        let mut function = None; // function being executed, `None` for the main program
+        let mut ip = 0; // instruction pointer
+        let mut base = 0; // index of the current frame's first register
+
+        macro_rules! get {
+            ($operand:expr) => {
+                match $operand {
+                    Operand::Reg(reg) => registers[base + reg as usize],
+                    Operand::Const(idx) => constants[idx as usize],
+                }
+            };
+        }
+        macro_rules! set {
+            ($dst:expr, $val:expr) => {
+                registers[base + $dst as usize] = $val
+            };
+        }
+        // points an error raised by the current instruction at its source
+        macro_rules! located {
+            ($result:expr) => {
+                $result.map_err(|err| err.at(chunk.spans[ip - 1]))?
+            };
+        }
+        macro_rules! binary {
+            ($op:expr, $dst:expr, $lhs:expr, $rhs:expr) => {{
+                let val = located!(get!($lhs).fast_binary($op, get!($rhs)));
+                set!($dst, val);
+            }};
+        }
+        macro_rules! unary {
+            ($op:expr, $dst:expr, $src:expr) => {{
+                let val = located!(get!($src).unary($op));
+                set!($dst, val);
+            }};
+        }
+
+        loop {
+            // every chunk ends with `Return` and every jump lands inside it
+            let instr = chunk.instructions[ip];
+            ip += 1;
+            match instr {
+                Instr::Move { dst, src } => set!(dst, get!(src)),
+                Instr::Add { dst, lhs, rhs } => binary!(Operator::Plus, dst, lhs, rhs),
+                Instr::Sub { dst, lhs, rhs } => binary!(Operator::Minus, dst, lhs, rhs),
+                Instr::Mul { dst, lhs, rhs } => binary!(Operator::Multiply, dst, lhs, rhs),
+                Instr::Div { dst, lhs, rhs } => binary!(Operator::Divide, dst, lhs, rhs),
+                Instr::FloorDiv { dst, lhs, rhs } => {
+                    binary!(Operator::FloorDivide, dst, lhs, rhs)
+                }
+                Instr::Mod { dst, lhs, rhs } => binary!(Operator::Modulo, dst, lhs, rhs),
+                Instr::Pow { dst, lhs, rhs } => binary!(Operator::Power, dst, lhs, rhs),
+                Instr::Equal { dst, lhs, rhs } => binary!(Operator::Equal, dst, lhs, rhs),
+                Instr::NotEqual { dst, lhs, rhs } => binary!(Operator::NotEqual, dst, lhs, rhs),
+                Instr::Less { dst, lhs, rhs } => binary!(Operator::Less, dst, lhs, rhs),
+                Instr::LessEqual { dst, lhs, rhs } => {
+                    binary!(Operator::LessEqual, dst, lhs, rhs)
+                }
+                Instr::Greater { dst, lhs, rhs } => binary!(Operator::Greater, dst, lhs, rhs),
+                Instr::GreaterEqual { dst, lhs, rhs } => {
+                    binary!(Operator::GreaterEqual, dst, lhs, rhs)
+                }
+                Instr::Plus { dst, src } => unary!(Operator::Plus, dst, src),
+                Instr::Minus { dst, src } => unary!(Operator::Minus, dst, src),
+                Instr::Not { dst, src } => unary!(Operator::Not, dst, src),
+                Instr::Jump { target } => ip = target as usize,
+                Instr::JumpIfFalse { cond, target } => {
+                    if !located!(get!(cond).expect_bool()) {
+                        ip = target as usize;
+                    }
+                }
+                Instr::JumpIfTrue { cond, target } => {
+                    if located!(get!(cond).expect_bool()) {
+                        ip = target as usize;
+                    }
+                }
+                Instr::Call {
+                    dst,
+                    function: idx,
+                    args,
+                } => {
+                    if frames.len() >= MAX_CALL_DEPTH {
+                        let err = CalcError::runtime(RuntimeError::CallDepthExceeded);
+                        return Err(err.at(chunk.spans[ip - 1]));
+                    }
+                    frames.push(Frame {
+                        function,
+                        ip,
+                        base,
+                        dst,
+                    });
+                    function = Some(idx as usize);
+                    chunk = &code.functions[idx as usize];
+                    base += args as usize;
+                    ip = 0;
+                }
+                Instr::Return { src } => {
+                    let val = get!(src);
+                    let Some(frame) = frames.pop() else {
+                        return Ok(val);
+                    };
+                    function = frame.function;
+                    chunk = match function {
+                        Some(idx) => &code.functions[idx],
+                        None => &code.main,
+                    };
+                    ip = frame.ip;
+                    base = frame.base;
+                    set!(frame.dst, val);
+                }
+            }
+        }
+    }
+
+    /// Runs the program with `args` in the first registers of the main
+    /// program, which hold the free variables of [`Compiler::compile`].
+    pub fn run_with(&mut self, args: &[Val]) -> Result<Val> {
+        if args.len() > self.code.main.registers {
+            return Err(CalcError::type_error(format!(
+                "program has {} registers but got {} values",
+                self.code.main.registers,
+                args.len()
+            )));
+        }
+        self.registers[..args.len()].copy_from_slice(args);
+        self.run()
+    }
+
+    /// Runs compiled code on a new VM, returning the value of its last statement.
+    pub fn eval(code: Code) -> Result<Val> {
+        RegisterVM::new(code).run()
+    }
+}
+
+impl Compile for RegisterVM {
+    type Output = Val;
+
+    fn from_ast(ast: Vec<Spanned<Node>>) -> Result<Self::Output> {
+        RegisterVM::eval(compile(ast, &[])?)
+    }
+}
+
+/// Compiles a program to register code, tracing the result.
+fn compile(ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Code> {
+    let code = Compiler::compile(ast, variables)?;
+    trace::emit(Category::Bytecode, Level::Info, || code.disassemble());
+    Ok(code)
+}
+
+impl Prepare for RegisterVM {
+    fn prepare(ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Box<dyn Executable>> {
+        Ok(Box::new(RefCell::new(RegisterVM::new(compile(
+            ast, variables,
+        )?))))
+    }
+}
+
+impl Executable for RefCell<RegisterVM> {
+    fn eval(&self, args: &[Val]) -> Result<Val> {
+        self.borrow_mut().run_with(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Span;
+    use crate::parser::parse;
+
+    fn eval(source: &str) -> Result<Val> {
+        RegisterVM::from_ast(parse(source).unwrap())
+    }
+
+    #[test]
+    fn arithmetic() {
+        let tests = [
+            ("1 + 2 * 3", Val::Int(7)),
+            ("(1 + 2) * 3", Val::Int(9)),
+            ("10 - 7 // 2 * 2", Val::Int(4)),
+            ("-7 % 2", Val::Int(1)),
+            ("7 / 2", Val::Float(3.5.into())),
+            ("1 + 2.5", Val::Float(3.5.into())),
+            ("--3", Val::Int(3)),
+            ("1 < 2 && !(2.5 >= 3)", Val::Bool(true)),
+            ("1 > 2 || 1 == 1.0", Val::Bool(true)),
+        ];
+        for (source, expected) in tests {
+            assert_eq!(
+                eval(source).unwrap(),
+                expected,
+                "Failed on input: {}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn bindings() {
+        assert_eq!(
+            eval("let x = 2; let y = x * x; y - x").unwrap(),
+            Val::Int(2)
+        );
+        // the new value reads the old binding before replacing it
+        assert_eq!(
+            eval("let x = 3; let x = x * 2 + x; x").unwrap(),
+            Val::Int(9)
+        );
+        assert_eq!(
+            eval("let x = 3; let x = if x > 1 then x - 1 else x; x").unwrap(),
+            Val::Int(2)
+        );
+        assert_eq!(eval("let x = 1; x + 1; let y = 5").unwrap(), Val::Int(5));
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(
+            eval("fn fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2); fib(15)").unwrap(),
+            Val::Int(610)
+        );
+        // each call's frame overlaps the registers its caller has free
+        assert_eq!(
+            eval(
+                "fn add(a, b) = a + b
+                 fn f(x) = add(x, 1) * add(x * 2, add(x, 3)) - x
+                 f(2) + f(3)"
+            )
+            .unwrap(),
+            Val::Int(3 * 9 - 2 + 4 * 12 - 3)
+        );
+        assert_eq!(
+            eval("fn f(n) = if n == 0 then 0 else 1 + f(n - 1); f(255)").unwrap(),
+            Val::Int(255)
+        );
+    }
+
+    #[test]
+    fn short_circuit() {
+        let prelude = "fn loop(x) = loop(x)\n";
+        for (source, expected) in [
+            ("false && loop(1)", false),
+            ("true || loop(1)", true),
+            ("let t = true; t && (t || loop(1))", true),
+        ] {
+            let val = eval(&format!("{}{}", prelude, source)).unwrap();
+            assert_eq!(val, Val::Bool(expected), "Failed on input: {}", source);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let err = |source| eval(source).unwrap_err();
+        assert_eq!(
+            err("fn f(n) = 1 + f(n); f(1)"),
+            CalcError::runtime(RuntimeError::CallDepthExceeded).at(Span::new(14, 18))
+        );
+        assert_eq!(
+            err("1 // 0"),
+            CalcError::runtime(RuntimeError::DivisionByZero).at(Span::new(0, 6))
+        );
+        assert_eq!(
+            err("2147483647 + 1"),
+            CalcError::runtime(RuntimeError::Overflow).at(Span::new(0, 14))
+        );
+        assert_eq!(
+            err("let x = 2; if x then 1 else 2").span(),
+            Span::new(11, 29)
+        );
+        assert_eq!(err("true && 1").to_string(), "expected a bool, found int");
+        assert_eq!(
+            err("if 1 then 2 else 3").to_string(),
+            "expected a bool, found int"
+        );
+        assert_eq!(err("-true").to_string(), "cannot apply `-` to bool");
+        assert_eq!(
+            err("fn f(x) = x").to_string(),
+            "cannot evaluate an empty program"
+        );
+    }
+
+    #[test]
+    fn rerun() {
+        let code = Compiler::from_ast(
+            parse("fn f(n) = if n == 0 then 7 else f(n - 1); f(3) + 1").unwrap(),
+        )
+        .unwrap();
+        let mut vm = RegisterVM::new(code);
+        assert_eq!(vm.run().unwrap(), Val::Int(8));
+        assert_eq!(vm.run().unwrap(), Val::Int(8));
+    }
+}
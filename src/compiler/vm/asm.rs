@@ -0,0 +1,535 @@
+//! A text assembler for VM bytecode, so that opcodes can be tested without
+//! going through the parser and `bytecode::Interpreter`.
+//!
+//! It reads the output of [`Bytecode::disassemble`] back into the same
+//! bytecode, and also accepts a shorter form that is easier to write by hand:
+//!
+//! ```text
+//! ; comments run from `;` to the end of the line
+//! const 1.5
+//! const 2
+//! call half
+//! jumpiftrue done
+//! minus
+//! done:
+//!
+//! == half/1 ==
+//! getlocal 0
+//! const 2.0
+//! div
+//! return
+//! ```
+//!
+//! - Instructions are written with their `OpCode` name, such as `OpConstant`,
+//!   or a short lowercase name such as `const`; see [`MNEMONICS`].
+//! - A leading offset, as printed by the disassembler, is ignored.
+//! - `const VALUE` adds `VALUE` to the constant pool, while `OpConstant IDX (VALUE)`
+//!   places it at index `IDX`. `constwide` and `OpConstantWide` do the same
+//!   with a 24-bit index. `2` is an int, `2.0`, `inf` and `NaN` are floats
+//!   and `true` and `false` are bools.
+//! - `call` takes the name of a function or its index in the function table.
+//! - Jumps take an absolute offset or a label defined with `name:`.
+//! - `== name/arity ==` starts a function and `== main ==` returns to the main
+//!   program.
+
+use std::collections::HashMap;
+
+use ordered_float::OrderedFloat;
+
+use crate::Result;
+use crate::compiler::vm::bytecode::{Function, LineTable};
+use crate::compiler::vm::opcode::MAX_CONSTANTS;
+use crate::compiler::vm::{Bytecode, OpCode, make_op};
+use crate::error::{CalcError, Span};
+use crate::val::Val;
+
+/// Short names accepted besides each opcode's full name.
+pub const MNEMONICS: [(&str, OpCode); 27] = [
+    ("const", OpCode::OpConstant(0)),
+    ("constwide", OpCode::OpConstantWide(0)),
+    ("pop", OpCode::OpPop),
+    ("add", OpCode::OpAdd),
+    ("sub", OpCode::OpSub),
+    ("mul", OpCode::OpMul),
+    ("div", OpCode::OpDiv),
+    ("floordiv", OpCode::OpFloorDiv),
+    ("mod", OpCode::OpMod),
+    ("pow", OpCode::OpPow),
+    ("plus", OpCode::OpPlus),
+    ("minus", OpCode::OpMinus),
+    ("getglobal", OpCode::OpGetGlobal(0)),
+    ("setglobal", OpCode::OpSetGlobal(0)),
+    ("getlocal", OpCode::OpGetLocal(0)),
+    ("call", OpCode::OpCall(0)),
+    ("return", OpCode::OpReturn),
+    ("eq", OpCode::OpEqual),
+    ("ne", OpCode::OpNotEqual),
+    ("lt", OpCode::OpLess),
+    ("le", OpCode::OpLessEqual),
+    ("gt", OpCode::OpGreater),
+    ("ge", OpCode::OpGreaterEqual),
+    ("not", OpCode::OpNot),
+    ("jump", OpCode::OpJump(0)),
+    ("jumpiffalse", OpCode::OpJumpIfFalse(0)),
+    ("jumpiftrue", OpCode::OpJumpIfTrue(0)),
+];
+
+/// Assembles `source` into bytecode. Errors are parse errors pointing at the
+/// offending token.
+pub fn assemble(source: &str) -> Result<Bytecode> {
+    let mut assembler = Assembler::default();
+    let mut line_start = 0;
+    for line in source.split_inclusive('\n') {
+        assembler.line(line, line_start)?;
+        line_start += line.len();
+    }
+    assembler.finish()
+}
+
+/// A word of a line and where it starts in the source.
+#[derive(Clone, Copy)]
+struct Token<'a> {
+    text: &'a str,
+    span: Span,
+}
+
+impl Token<'_> {
+    fn error(&self, message: impl Into<String>) -> CalcError {
+        CalcError::parse(message).at(self.span)
+    }
+}
+
+/// An operand that can only be filled in once the whole file has been read.
+enum Fixup {
+    Label(String),
+    Function(String),
+    Literal(Val),
+}
+
+#[derive(Default)]
+struct Section {
+    name: Option<(String, u8)>, // `None` for the main program
+    instructions: Vec<u8>,
+    labels: HashMap<String, usize>,
+    // position of the operand to patch, how to resolve it and the token it came from
+    fixups: Vec<(usize, Fixup, Span)>,
+}
+
+#[derive(Default)]
+struct Assembler {
+    sections: Vec<Section>, // the main program followed by each function
+    current: usize,
+    constants: Vec<Option<Val>>,
+}
+
+fn parse_val(token: Token) -> Result<Val> {
+    match token.text {
+        "true" => Ok(Val::Bool(true)),
+        "false" => Ok(Val::Bool(false)),
+        text => text
+            .parse::<i32>()
+            .map(Val::Int)
+            .or_else(|_| text.parse::<f32>().map(|f| Val::Float(OrderedFloat(f))))
+            .map_err(|_| token.error(format!("`{}` is not a value", text))),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(token: Token) -> Result<T> {
+    token
+        .text
+        .parse()
+        .map_err(|_| token.error(format!("`{}` is not a valid operand", token.text)))
+}
+
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl Assembler {
+    fn section(&mut self) -> &mut Section {
+        if self.sections.is_empty() {
+            self.sections.push(Section::default());
+        }
+        &mut self.sections[self.current]
+    }
+
+    fn line(&mut self, line: &str, line_start: usize) -> Result<()> {
+        let code = line.split(';').next().unwrap_or("");
+        let mut tokens = Vec::new();
+        let mut rest = code;
+        while let Some(start) = rest.find(|c: char| !c.is_whitespace()) {
+            let len = rest[start..]
+                .find(char::is_whitespace)
+                .unwrap_or(rest.len() - start);
+            let offset = line_start + (code.len() - rest.len()) + start;
+            tokens.push(Token {
+                text: &rest[start..start + len],
+                span: Span::new(offset, offset + len),
+            });
+            rest = &rest[start + len..];
+        }
+
+        match tokens.as_slice() {
+            [] => Ok(()),
+            [first, name, last] if first.text == "==" && last.text == "==" => self.header(*name),
+            [label] if label.text.ends_with(':') => self.label(*label),
+            // the offset the disassembler prints before each instruction
+            [offset, rest @ ..] if offset.text.bytes().all(|b| b.is_ascii_digit()) => match rest {
+                [] => Err(offset.error("expected an instruction after the offset")),
+                _ => self.instruction(rest),
+            },
+            _ => self.instruction(&tokens),
+        }
+    }
+
+    fn header(&mut self, name: Token) -> Result<()> {
+        if name.text == "main" {
+            self.section();
+            self.current = 0;
+            return Ok(());
+        }
+        let (function, arity) = name
+            .text
+            .split_once('/')
+            .filter(|(function, _)| is_name(function))
+            .ok_or_else(|| name.error("expected `main` or `name/arity`"))?;
+        let arity = arity
+            .parse()
+            .map_err(|_| name.error(format!("`{}` is not a valid arity", arity)))?;
+        self.section();
+        if self
+            .sections
+            .iter()
+            .any(|section| matches!(&section.name, Some((existing, _)) if existing == function))
+        {
+            return Err(name.error(format!("function `{}` is already defined", function)));
+        }
+        self.sections.push(Section {
+            name: Some((function.to_string(), arity)),
+            ..Section::default()
+        });
+        self.current = self.sections.len() - 1;
+        Ok(())
+    }
+
+    fn label(&mut self, token: Token) -> Result<()> {
+        let name = &token.text[..token.text.len() - 1];
+        if !is_name(name) {
+            return Err(token.error(format!("`{}` is not a valid label", name)));
+        }
+        let section = self.section();
+        let offset = section.instructions.len();
+        if section.labels.insert(name.to_string(), offset).is_some() {
+            return Err(token.error(format!("label `{}` is already defined", name)));
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, tokens: &[Token]) -> Result<()> {
+        let mnemonic = tokens[0];
+        let template = MNEMONICS
+            .iter()
+            .find(|(short, op)| {
+                mnemonic.text.eq_ignore_ascii_case(short)
+                    || mnemonic.text.eq_ignore_ascii_case(op.name())
+            })
+            .map(|(_, op)| *op)
+            .ok_or_else(|| mnemonic.error(format!("unknown instruction `{}`", mnemonic.text)))?;
+
+        // the disassembler explains some operands in parentheses
+        let (operands, note) = match tokens {
+            [_, operands @ .., note] if note.text.starts_with('(') => {
+                let text = note
+                    .text
+                    .strip_prefix('(')
+                    .and_then(|text| text.strip_suffix(')'))
+                    .ok_or_else(|| note.error("expected `)`"))?;
+                let inner = Token {
+                    text,
+                    span: Span::new(note.span.start + 1, note.span.end - 1),
+                };
+                (operands, Some(inner))
+            }
+            [_, operands @ ..] => (operands, None),
+            [] => unreachable!(),
+        };
+
+        let operand = match (template.operand(), operands) {
+            (None, []) => None,
+            (None, [extra, ..]) => {
+                return Err(extra.error(format!("`{}` takes no operand", template.name())));
+            }
+            (Some(_), [operand]) => Some(*operand),
+            (Some(_), [_, extra, ..]) => {
+                return Err(extra.error("expected the end of the line"));
+            }
+            (Some(_), []) => {
+                return Err(mnemonic.error(format!("`{}` needs an operand", template.name())));
+            }
+        };
+
+        let position = self.section().instructions.len() + 1;
+        let mut fixup = None;
+        let op = match (template, operand) {
+            (_, None) => template,
+            (OpCode::OpConstant(_), Some(operand)) => match note {
+                Some(value) => {
+                    let idx: u16 = parse_number(operand)?;
+                    self.define_constant(idx as usize, parse_val(value)?, value)?;
+                    OpCode::OpConstant(idx)
+                }
+                None => {
+                    fixup = Some(Fixup::Literal(parse_val(operand)?));
+                    OpCode::OpConstant(0)
+                }
+            },
+            (OpCode::OpConstantWide(_), Some(operand)) => match note {
+                Some(value) => {
+                    let idx: u32 = parse_number(operand)?;
+                    if idx as usize >= MAX_CONSTANTS {
+                        return Err(
+                            operand.error(format!("`{}` is not a valid operand", operand.text))
+                        );
+                    }
+                    self.define_constant(idx as usize, parse_val(value)?, value)?;
+                    OpCode::OpConstantWide(idx)
+                }
+                None => {
+                    fixup = Some(Fixup::Literal(parse_val(operand)?));
+                    OpCode::OpConstantWide(0)
+                }
+            },
+            (OpCode::OpGetLocal(_), Some(operand)) => OpCode::OpGetLocal(parse_number(operand)?),
+            (OpCode::OpGetGlobal(_), Some(operand)) => OpCode::OpGetGlobal(parse_number(operand)?),
+            (OpCode::OpSetGlobal(_), Some(operand)) => OpCode::OpSetGlobal(parse_number(operand)?),
+            (OpCode::OpCall(_), Some(operand)) if is_name(operand.text) => {
+                fixup = Some(Fixup::Function(operand.text.to_string()));
+                OpCode::OpCall(0)
+            }
+            (OpCode::OpCall(_), Some(operand)) => OpCode::OpCall(parse_number(operand)?),
+            (
+                OpCode::OpJump(_) | OpCode::OpJumpIfFalse(_) | OpCode::OpJumpIfTrue(_),
+                Some(operand),
+            ) => {
+                let target = if is_name(operand.text) {
+                    fixup = Some(Fixup::Label(operand.text.to_string()));
+                    0
+                } else {
+                    parse_number(operand)?
+                };
+                match template {
+                    OpCode::OpJump(_) => OpCode::OpJump(target),
+                    OpCode::OpJumpIfFalse(_) => OpCode::OpJumpIfFalse(target),
+                    _ => OpCode::OpJumpIfTrue(target),
+                }
+            }
+            (_, Some(_)) => unreachable!("every opcode with an operand is handled above"),
+        };
+
+        let span = operand.map_or(mnemonic.span, |operand| operand.span);
+        let section = self.section();
+        if let Some(fixup) = fixup {
+            section.fixups.push((position, fixup, span));
+        }
+        section.instructions.extend(make_op(op));
+        Ok(())
+    }
+
+    fn define_constant(&mut self, idx: usize, val: Val, token: Token) -> Result<()> {
+        if idx >= self.constants.len() {
+            self.constants.resize(idx + 1, None);
+        }
+        match self.constants[idx] {
+            Some(existing) if existing != val => Err(token.error(format!(
+                "constant {} is already defined as {}",
+                idx, existing
+            ))),
+            _ => {
+                self.constants[idx] = Some(val);
+                Ok(())
+            }
+        }
+    }
+
+    fn finish(mut self) -> Result<Bytecode> {
+        self.section();
+        let indexes: HashMap<String, usize> = self
+            .sections
+            .iter()
+            .skip(1)
+            .enumerate()
+            .filter_map(|(idx, section)| Some((section.name.as_ref()?.0.clone(), idx)))
+            .collect();
+
+        let mut constants = self
+            .constants
+            .iter()
+            .enumerate()
+            .map(|(idx, val)| {
+                val.ok_or_else(|| CalcError::parse(format!("constant {} is never defined", idx)))
+            })
+            .collect::<Result<Vec<Val>>>()?;
+
+        let mut sections = std::mem::take(&mut self.sections);
+        for section in &mut sections {
+            for (position, fixup, span) in std::mem::take(&mut section.fixups) {
+                let operand = match fixup {
+                    Fixup::Label(name) => *section.labels.get(&name).ok_or_else(|| {
+                        CalcError::parse(format!("label `{}` is not defined", name)).at(span)
+                    })?,
+                    Fixup::Function(name) => *indexes.get(&name).ok_or_else(|| {
+                        CalcError::parse(format!("function `{}` is not defined", name)).at(span)
+                    })?,
+                    Fixup::Literal(val) => {
+                        constants.push(val);
+                        constants.len() - 1
+                    }
+                };
+                // the operand follows the opcode and is 2 bytes wide, or 3 for
+                // `OpConstantWide`
+                let width = OpCode::decode(&section.instructions, position - 1)?.1 - 1;
+                let bytes = (operand as u32).to_be_bytes();
+                if operand >> (8 * width) != 0 {
+                    return Err(CalcError::parse(format!(
+                        "operand {} does not fit in {} bits",
+                        operand,
+                        8 * width
+                    ))
+                    .at(span));
+                }
+                section.instructions[position..position + width]
+                    .copy_from_slice(&bytes[4 - width..]);
+            }
+        }
+
+        let mut sections = sections.into_iter();
+        let main = sections.next().unwrap_or_default();
+        Ok(Bytecode {
+            instructions: main.instructions,
+            constants,
+            functions: sections
+                .map(|section| {
+                    let (name, arity) = section.name.unwrap_or_default();
+                    Function {
+                        name,
+                        arity,
+                        instructions: section.instructions,
+                        lines: LineTable::default(),
+                    }
+                })
+                .collect(),
+            lines: LineTable::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compile;
+    use crate::VM;
+    use crate::compiler::vm::bytecode::Interpreter;
+
+    #[test]
+    fn round_trip() {
+        for src in [
+            "1 + 2 * 3",
+            "let x = 3.14; let y = -x / 2; x // y % 1.5",
+            "true && !false || 1 >= 2.0",
+            "fn fact(n) = if n <= 1 then 1 else n * fact(n - 1); fact(5) != 120",
+            "f(1, 2); fn f(a, b) = g(b) - a; fn g(x) = x * 2.0",
+        ] {
+            let bytecode = Interpreter::from_source(src).unwrap();
+            assert_eq!(
+                assemble(&bytecode.disassemble()).unwrap(),
+                bytecode,
+                "Failed on input: {}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn hand_written() {
+        let bytecode = assemble(
+            "; negate half of 3 unless the flag is set
+            const 3
+            call half
+            const false
+            jumpiftrue done
+            minus
+            done:
+
+            == half/1 ==
+            getlocal 0
+            const 2.0
+            OpDiv ; full names work too
+            return
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            bytecode.constants,
+            vec![Val::Int(3), Val::Bool(false), Val::Float(OrderedFloat(2.0))]
+        );
+        assert_eq!(
+            bytecode.disassemble(),
+            "== main ==
+0000 OpConstant 0 (3)
+0003 OpCall 0 (half)
+0006 OpConstant 1 (false)
+0009 OpJumpIfTrue 13
+0012 OpMinus
+
+== half/1 ==
+0000 OpGetLocal 0
+0002 OpConstant 2 (2.0)
+0005 OpDiv
+0006 OpReturn
+"
+        );
+        assert_eq!(VM::eval(bytecode), Ok(Val::Float(OrderedFloat(-1.5))));
+    }
+
+    #[test]
+    fn errors() {
+        let err = |src| assemble(src).unwrap_err();
+        assert_eq!(
+            err("const 1\nfrob"),
+            CalcError::parse("unknown instruction `frob`").at(Span::new(8, 12))
+        );
+        assert_eq!(
+            err("add 1"),
+            CalcError::parse("`OpAdd` takes no operand").at(Span::new(4, 5))
+        );
+        assert_eq!(
+            err("const"),
+            CalcError::parse("`OpConstant` needs an operand").at(Span::new(0, 5))
+        );
+        assert_eq!(
+            err("const x"),
+            CalcError::parse("`x` is not a value").at(Span::new(6, 7))
+        );
+        assert_eq!(
+            err("jump nowhere"),
+            CalcError::parse("label `nowhere` is not defined").at(Span::new(5, 12))
+        );
+        assert_eq!(
+            err("call f"),
+            CalcError::parse("function `f` is not defined").at(Span::new(5, 6))
+        );
+        assert_eq!(
+            err("getlocal 256"),
+            CalcError::parse("`256` is not a valid operand").at(Span::new(9, 12))
+        );
+        assert_eq!(
+            err("OpConstant 1 (2)"),
+            CalcError::parse("constant 0 is never defined")
+        );
+        assert_eq!(
+            err("== f/1 ==\nreturn\n== f/2 ==\n"),
+            CalcError::parse("function `f` is already defined").at(Span::new(20, 23))
+        );
+    }
+}
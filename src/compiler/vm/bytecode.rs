@@ -1,11 +1,17 @@
-use crate::compiler::vm::{make_op, OpCode};
+use std::collections::HashMap;
+
+use crate::compiler::vm::{OpCode, make_op};
+use crate::error::{CalcError, Span};
+use crate::program::check_variables;
 use crate::val::Val;
-use crate::{Compile, Node, Operator};
+use crate::{Compile, Node, Operator, Result, Spanned};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Bytecode {
     pub instructions: Vec<u8>,
-    pub constants: Vec<Node>,
+    pub constants: Vec<Val>,
+    pub functions: Vec<Function>,
+    pub lines: LineTable,
 }
 
 impl Bytecode {
@@ -13,88 +19,471 @@ impl Bytecode {
         Self {
             instructions: Vec::new(),
             constants: Vec::new(),
+            functions: Vec::new(),
+            lines: LineTable::default(),
+        }
+    }
+
+    /// Decodes the main program and then every function into one line per
+    /// instruction, such as `0000 OpConstant 0 (3.14)`. Offsets restart at zero
+    /// in each function because every function has its own instruction stream.
+    /// Constants and call targets are resolved next to their operand.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::from("== main ==\n");
+        self.disassemble_instructions(&self.instructions, &mut out);
+        for function in &self.functions {
+            out.push_str(&format!("\n== {}/{} ==\n", function.name, function.arity));
+            self.disassemble_instructions(&function.instructions, &mut out);
+        }
+        out
+    }
+
+    fn disassemble_instructions(&self, instructions: &[u8], out: &mut String) {
+        let mut offset = 0;
+        while offset < instructions.len() {
+            let (op, size) = match OpCode::decode(instructions, offset) {
+                Ok(decoded) => decoded,
+                Err(err) => {
+                    // the rest of the stream cannot be decoded reliably
+                    out.push_str(&format!("{:04} {}\n", offset, err));
+                    return;
+                }
+            };
+            let comment = match op {
+                OpCode::OpConstant(_) | OpCode::OpConstantWide(_) => {
+                    match op.constant_index().and_then(|idx| self.constants.get(idx)) {
+                        Some(Val::Float(f)) => format!(" ({:?})", f.into_inner()),
+                        Some(constant) => format!(" ({})", constant),
+                        None => " (missing)".to_string(),
+                    }
+                }
+                OpCode::OpCall(idx) => match self.functions.get(idx as usize) {
+                    Some(function) => format!(" ({})", function.name),
+                    None => " (missing)".to_string(),
+                },
+                _ => String::new(),
+            };
+            out.push_str(&format!("{:04} {}{}\n", offset, op, comment));
+            offset += size;
+        }
+    }
+}
+
+/// Maps the offset of each instruction that can fail at run time, such as an
+/// `OpDiv` or an `OpCall`, to the span of source it was compiled from, so that
+/// the VM can point its errors at the offending subexpression. Bytecode that
+/// was assembled or loaded from a file has an empty table, and its errors have
+/// no span.
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    entries: Vec<(usize, Span)>, // sorted by offset
+}
+
+impl LineTable {
+    /// Records the span of the instruction at `offset`, which must come after
+    /// every offset recorded so far.
+    pub fn push(&mut self, offset: usize, span: Span) {
+        debug_assert!(self.entries.last().is_none_or(|&(last, _)| last < offset));
+        self.entries.push((offset, span));
+    }
+
+    /// The span of the instruction at `offset`, if it was recorded.
+    pub fn span(&self, offset: usize) -> Option<Span> {
+        let idx = self
+            .entries
+            .binary_search_by_key(&offset, |&(offset, _)| offset)
+            .ok()?;
+        Some(self.entries[idx].1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, Span)> + '_ {
+        self.entries.iter().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl FromIterator<(usize, Span)> for LineTable {
+    fn from_iter<I: IntoIterator<Item = (usize, Span)>>(iter: I) -> Self {
+        let mut table = LineTable::default();
+        for (offset, span) in iter {
+            table.push(offset, span);
+        }
+        table
+    }
+}
+
+/// Like spans on the AST, the line table takes no part in comparisons, so
+/// compiled bytecode equals the same bytecode assembled or loaded from a file.
+impl PartialEq for LineTable {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for LineTable {}
+
+/// Identifies a constant by its exact value, so that the pool holds each
+/// value once. Floats are compared by their bits because `Val` equality
+/// treats `0.0` and `-0.0` as the same value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum ConstantKey {
+    Int(i32),
+    Float(u32),
+    Bool(bool),
+}
+
+impl From<Val> for ConstantKey {
+    fn from(val: Val) -> Self {
+        match val {
+            Val::Int(n) => ConstantKey::Int(n),
+            Val::Float(f) => ConstantKey::Float(f.into_inner().to_bits()),
+            Val::Bool(b) => ConstantKey::Bool(b),
         }
     }
 }
 
+/// A compiled function body. Its arguments sit at the bottom of its call frame
+/// and are read with `OpGetLocal`; the body ends with `OpReturn`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Function {
+    pub name: String,
+    pub arity: u8,
+    pub instructions: Vec<u8>,
+    pub lines: LineTable,
+}
+
 #[derive(Debug)]
 pub struct Interpreter {
     bytecode: Bytecode,
+    constants: HashMap<ConstantKey, usize>, // index of each value in the constant pool
+    globals: HashMap<String, u16>,
+    functions: HashMap<String, (u16, usize)>, // index into the function table and arity
+    locals: Option<Vec<String>>,              // parameters of the function being compiled
 }
 
 impl Compile for Interpreter {
     type Output = Bytecode;
 
-    fn from_ast(ast: Vec<Node>) -> Self::Output {
+    fn from_ast(ast: Vec<Spanned<Node>>) -> Result<Self::Output> {
+        Interpreter::compile(ast, &[])
+    }
+}
+
+impl Interpreter {
+    /// Compiles a program in which `variables` may be read without being
+    /// bound by `let`. They are the first globals, in order, so the VM must
+    /// be given their values with [`VM::run_with`](crate::VM::run_with).
+    pub fn compile(ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Bytecode> {
+        check_variables(variables)?;
         let mut interpreter = Interpreter {
             bytecode: Bytecode::new(),
+            constants: HashMap::new(),
+            globals: HashMap::new(),
+            functions: HashMap::new(),
+            locals: None,
         };
-        let len = ast.len();
-        for (idx, node) in ast.into_iter().enumerate() {
-            println!("compiling node {:?}", node);
-            interpreter.interpret_node(node);
+        for name in variables {
+            interpreter.global_index(name.to_string())?;
+        }
+        // function definitions are hoisted so they can be called from anywhere
+        let (definitions, statements): (Vec<_>, Vec<_>) = ast
+            .into_iter()
+            .partition(|node| matches!(node.node, Node::FnDef { .. }));
+        for node in &definitions {
+            if let Node::FnDef { name, params, .. } = &node.node {
+                interpreter.declare_function(name, params.len())?;
+            }
+        }
+
+        let len = statements.len();
+        for (idx, node) in statements.into_iter().enumerate() {
+            interpreter.interpret_node(node)?;
             // pop one element from the stack after each expression statement
             // to clean up, except for the last one so it can be inspected
-            if idx < len - 1 {
+            if idx + 1 < len {
                 interpreter.add_instruction(OpCode::OpPop);
             }
         }
-        interpreter.bytecode
-    }
-}
 
-impl Interpreter {
-    fn add_constant(&mut self, node: Node) -> u16 {
-        self.bytecode.constants.push(node);
-        (self.bytecode.constants.len() - 1) as u16 // cast to u16 because that is the size of our constant pool index
+        for node in definitions {
+            if let Node::FnDef { name, params, body } = node.node {
+                interpreter.compile_function(name, params, *body)?;
+            }
+        }
+        Ok(interpreter.bytecode)
+    }
+    /// Returns the instruction that loads `val`, adding it to the constant
+    /// pool unless it is already there.
+    fn add_constant(&mut self, val: Val) -> Result<OpCode> {
+        let idx = match self.constants.get(&val.into()) {
+            Some(&idx) => idx,
+            None => {
+                let idx = self.bytecode.constants.len();
+                // fails once the pool is full, before the value is added
+                OpCode::constant(idx)?;
+                self.bytecode.constants.push(val);
+                self.constants.insert(val.into(), idx);
+                idx
+            }
+        };
+        OpCode::constant(idx)
     }
 
-    fn add_instruction(&mut self, op_code: OpCode) -> u16 {
-        let position_of_new_instruction = self.bytecode.instructions.len() as u16;
+    fn add_instruction(&mut self, op_code: OpCode) -> usize {
+        let position_of_new_instruction = self.bytecode.instructions.len();
         self.bytecode.instructions.extend(make_op(op_code));
-        println!(
-            "added instructions {:?} from opcode {:?}",
-            self.bytecode.instructions,
-            op_code.clone()
-        );
         position_of_new_instruction
     }
 
-    fn interpret_node(&mut self, node: Node) {
-        match node {
-            Node::Val(Val::Int(num)) => {
-                let const_index = self.add_constant(Node::Val(Val::Int(num)));
-                self.add_instruction(OpCode::OpConstant(const_index));
+    /// Adds an instruction that can fail at run time, recording `span` as
+    /// where its errors come from.
+    fn add_instruction_at(&mut self, op_code: OpCode, span: Span) -> usize {
+        let position = self.add_instruction(op_code);
+        self.bytecode.lines.push(position, span);
+        position
+    }
+
+    /// Points the jump emitted at `position` to the next instruction to be added.
+    fn patch_jump(&mut self, position: usize) -> Result<()> {
+        let target = self.bytecode.instructions.len();
+        if target > u16::MAX as usize {
+            return Err(CalcError::limit(format!(
+                "program is too large to compile, jumps are limited to {} bytes",
+                u16::MAX
+            )));
+        }
+        let [hi, lo] = (target as u16).to_be_bytes();
+        self.bytecode.instructions[position + 1] = hi;
+        self.bytecode.instructions[position + 2] = lo;
+        Ok(())
+    }
+
+    /// Returns the index of the global `name`, adding it unless it is
+    /// already there.
+    fn global_index(&mut self, name: String) -> Result<u16> {
+        if let Some(&idx) = self.globals.get(&name) {
+            return Ok(idx);
+        }
+        let idx = u16::try_from(self.globals.len()).map_err(|_| {
+            CalcError::limit(format!(
+                "too many globals, the limit is {}",
+                u16::MAX as usize + 1
+            ))
+        })?;
+        self.globals.insert(name, idx);
+        Ok(idx)
+    }
+
+    fn declare_function(&mut self, name: &str, arity: usize) -> Result<()> {
+        if arity > u8::MAX as usize {
+            return Err(CalcError::limit(format!(
+                "function `{}` has more than {} parameters",
+                name,
+                u8::MAX
+            )));
+        }
+        if self.functions.len() > u16::MAX as usize {
+            return Err(CalcError::limit(format!(
+                "too many functions, the limit is {}",
+                u16::MAX as usize + 1
+            )));
+        }
+        let idx = self.functions.len() as u16;
+        self.functions.insert(name.to_string(), (idx, arity));
+        Ok(())
+    }
+
+    fn compile_function(
+        &mut self,
+        name: String,
+        params: Vec<String>,
+        body: Spanned<Node>,
+    ) -> Result<()> {
+        // the body is compiled into a fresh instruction stream, with its own
+        // line table, which is then moved into the function table
+        let main = std::mem::take(&mut self.bytecode.instructions);
+        let main_lines = std::mem::take(&mut self.bytecode.lines);
+        let arity = params.len() as u8;
+        self.locals = Some(params);
+        let compiled = self.interpret_node(body);
+        self.add_instruction(OpCode::OpReturn);
+        self.locals = None;
+        let instructions = std::mem::replace(&mut self.bytecode.instructions, main);
+        let lines = std::mem::replace(&mut self.bytecode.lines, main_lines);
+        compiled?;
+
+        self.bytecode.functions.push(Function {
+            name,
+            arity,
+            instructions,
+            lines,
+        });
+        Ok(())
+    }
+
+    /// Compiles `node`. Errors raised by the node itself, while compiling or
+    /// while running, point at its span.
+    fn interpret_node(&mut self, node: Spanned<Node>) -> Result<()> {
+        let span = node.span;
+        match node.node {
+            Node::Val(val) => {
+                let load = self.add_constant(val)?;
+                self.add_instruction(load);
             }
-            Node::Val(Val::Float(f)) => {
-                let const_idx = self.add_constant(Node::Val(Val::Float(f)));
-                self.add_instruction(OpCode::OpConstant(const_idx));
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                self.interpret_node(*cond)?;
+                let jump_to_else = self.add_instruction_at(OpCode::OpJumpIfFalse(u16::MAX), span);
+                self.interpret_node(*then_branch)?;
+                let jump_to_end = self.add_instruction(OpCode::OpJump(u16::MAX));
+                self.patch_jump(jump_to_else)?;
+                self.interpret_node(*else_branch)?;
+                self.patch_jump(jump_to_end)?;
+            }
+            Node::BinaryExpr {
+                op: op @ (Operator::And | Operator::Or),
+                lhs,
+                rhs,
+            } => {
+                // both operands jump to the short-circuit result when they decide
+                // the outcome; falling through both yields the opposite value
+                let short_circuit = op == Operator::Or;
+                let jump_op = |target| match op {
+                    Operator::Or => OpCode::OpJumpIfTrue(target),
+                    _ => OpCode::OpJumpIfFalse(target),
+                };
+                self.interpret_node(*lhs)?;
+                let lhs_jump = self.add_instruction_at(jump_op(u16::MAX), span);
+                self.interpret_node(*rhs)?;
+                let rhs_jump = self.add_instruction_at(jump_op(u16::MAX), span);
+                let load = self.add_constant(Val::Bool(!short_circuit))?;
+                self.add_instruction(load);
+                let jump_to_end = self.add_instruction(OpCode::OpJump(u16::MAX));
+                self.patch_jump(lhs_jump)?;
+                self.patch_jump(rhs_jump)?;
+                let load = self.add_constant(Val::Bool(short_circuit))?;
+                self.add_instruction(load);
+                self.patch_jump(jump_to_end)?;
+            }
+            Node::Ident(name) => match &self.locals {
+                Some(locals) => match locals.iter().position(|local| *local == name) {
+                    Some(idx) => {
+                        self.add_instruction(OpCode::OpGetLocal(idx as u8));
+                    }
+                    None => {
+                        return Err(CalcError::type_error(format!(
+                            "undefined variable `{}`",
+                            name
+                        ))
+                        .at(span));
+                    }
+                },
+                None => match self.globals.get(&name) {
+                    Some(&idx) => {
+                        self.add_instruction(OpCode::OpGetGlobal(idx));
+                    }
+                    None => {
+                        return Err(CalcError::type_error(format!(
+                            "undefined variable `{}`",
+                            name
+                        ))
+                        .at(span));
+                    }
+                },
+            },
+            Node::FnDef { name, .. } => {
+                return Err(CalcError::type_error(format!(
+                    "function `{}` can only be defined at the top level",
+                    name
+                ))
+                .at(span));
+            }
+            Node::Call { name, args } => {
+                let Some(&(idx, arity)) = self.functions.get(&name) else {
+                    return Err(
+                        CalcError::type_error(format!("undefined function `{}`", name)).at(span),
+                    );
+                };
+                if arity != args.len() {
+                    return Err(CalcError::type_error(format!(
+                        "function `{}` expects {} arguments but got {}",
+                        name,
+                        arity,
+                        args.len()
+                    ))
+                    .at(span));
+                }
+                for arg in args {
+                    self.interpret_node(arg)?;
+                }
+                self.add_instruction_at(OpCode::OpCall(idx), span);
+            }
+            Node::Let { name, value } => {
+                // the value is compiled before the name is bound so that
+                // `let x = x + 1` refers to the previous binding
+                self.interpret_node(*value)?;
+                let idx = self.global_index(name).map_err(|err| err.at(span))?;
+                self.add_instruction(OpCode::OpSetGlobal(idx));
+                // a let statement evaluates to the bound value
+                self.add_instruction(OpCode::OpGetGlobal(idx));
             }
             Node::UnaryExpr { op, child } => {
-                self.interpret_node(*child);
-                match op {
-                    Operator::Plus => self.add_instruction(OpCode::OpPlus),
-                    Operator::Minus => self.add_instruction(OpCode::OpMinus),
-                    _ => unreachable!("Invalid Unary Operator {:#?}", op),
+                self.interpret_node(*child)?;
+                let op_code = match op {
+                    Operator::Plus => OpCode::OpPlus,
+                    Operator::Minus => OpCode::OpMinus,
+                    Operator::Not => OpCode::OpNot,
+                    _ => {
+                        return Err(CalcError::internal(format!(
+                            "`{}` is not a unary operator",
+                            op
+                        )));
+                    }
                 };
+                self.add_instruction_at(op_code, span);
             }
             Node::BinaryExpr { op, lhs, rhs } => {
-                self.interpret_node(*lhs);
-                self.interpret_node(*rhs);
-                match op {
-                    Operator::Plus => self.add_instruction(OpCode::OpAdd),
-                    Operator::Minus => self.add_instruction(OpCode::OpSub),
-                    Operator::Multiply => self.add_instruction(OpCode::OpMul),
-                    Operator::Divide => self.add_instruction(OpCode::OpDiv),
+                self.interpret_node(*lhs)?;
+                self.interpret_node(*rhs)?;
+                let op_code = match op {
+                    Operator::Plus => OpCode::OpAdd,
+                    Operator::Minus => OpCode::OpSub,
+                    Operator::Multiply => OpCode::OpMul,
+                    Operator::Divide => OpCode::OpDiv,
+                    Operator::FloorDivide => OpCode::OpFloorDiv,
+                    Operator::Modulo => OpCode::OpMod,
+                    Operator::Power => OpCode::OpPow,
+                    Operator::Equal => OpCode::OpEqual,
+                    Operator::NotEqual => OpCode::OpNotEqual,
+                    Operator::Less => OpCode::OpLess,
+                    Operator::LessEqual => OpCode::OpLessEqual,
+                    Operator::Greater => OpCode::OpGreater,
+                    Operator::GreaterEqual => OpCode::OpGreaterEqual,
+                    _ => {
+                        return Err(CalcError::internal(format!(
+                            "`{}` is not a binary operator",
+                            op
+                        )));
+                    }
                 };
+                self.add_instruction_at(op_code, span);
             }
+            Node::Error => return Err(CalcError::unparsed().at(span)),
         };
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::parser::parse;
 
     #[test]
     fn unary_int_ops() {
@@ -104,7 +493,7 @@ mod tests {
 
     fn unary_template(op: &str, opcode: OpCode, _expected: i32) {
         let input = format!("{}1;", op);
-        let bytecode = Interpreter::from_source(&input);
+        let bytecode = Interpreter::from_ast(parse(&input).unwrap()).unwrap();
 
         let expected_instructions = vec![OpCode::OpConstant(0), opcode]
             .into_iter()
@@ -114,7 +503,9 @@ mod tests {
         assert_eq!(
             Bytecode {
                 instructions: expected_instructions,
-                constants: vec![Node::Val(Val::Int(1))]
+                constants: vec![Val::Int(1)],
+                functions: vec![],
+                lines: LineTable::default(),
             },
             bytecode
         );
@@ -123,7 +514,7 @@ mod tests {
     #[test]
     fn infix_float_ops() {
         let input = "6.7 + 2.25;";
-        let bytecode = Interpreter::from_source(input);
+        let bytecode = Interpreter::from_ast(parse(input).unwrap()).unwrap();
 
         let expected_instructions =
             vec![OpCode::OpConstant(0), OpCode::OpConstant(1), OpCode::OpAdd]
@@ -134,12 +525,217 @@ mod tests {
         assert_eq!(
             Bytecode {
                 instructions: expected_instructions,
-                constants: vec![
-                    Node::Val(Val::Float(6.7.into())),
-                    Node::Val(Val::Float(2.25.into())),
-                ]
+                constants: vec![Val::Float(6.7.into()), Val::Float(2.25.into()),],
+                functions: vec![],
+                lines: LineTable::default(),
+            },
+            bytecode
+        );
+    }
+
+    #[test]
+    fn constant_interning() {
+        let bytecode = Interpreter::from_ast(parse("1 + 1 + 1").unwrap()).unwrap();
+        let expected_instructions: Vec<u8> = vec![
+            OpCode::OpConstant(0),
+            OpCode::OpConstant(0),
+            OpCode::OpAdd,
+            OpCode::OpConstant(0),
+            OpCode::OpAdd,
+        ]
+        .into_iter()
+        .flat_map(make_op)
+        .collect();
+        assert_eq!(bytecode.instructions, expected_instructions);
+        assert_eq!(bytecode.constants, vec![Val::Int(1)]);
+
+        // `0.0 == -0.0`, but they are different constants
+        let ast = crate::optimize(parse("0.0; 1; 0.0; -0.0").unwrap());
+        let bytecode = Interpreter::from_ast(ast).unwrap();
+        assert_eq!(bytecode.constants.len(), 3);
+    }
+
+    #[test]
+    fn let_bindings() {
+        let bytecode = Interpreter::from_source("let x = 2; x").unwrap();
+
+        let expected_instructions = vec![
+            OpCode::OpConstant(0),
+            OpCode::OpSetGlobal(0),
+            OpCode::OpGetGlobal(0),
+            OpCode::OpPop,
+            OpCode::OpGetGlobal(0),
+        ]
+        .into_iter()
+        .flat_map(make_op)
+        .collect();
+
+        assert_eq!(
+            Bytecode {
+                instructions: expected_instructions,
+                constants: vec![Val::Int(2)],
+                functions: vec![],
+                lines: LineTable::default(),
+            },
+            bytecode
+        );
+    }
+
+    #[test]
+    fn too_many_globals() {
+        let source: String = (0..=u16::MAX as usize + 1)
+            .map(|idx| format!("let v{} = {};", idx, idx))
+            .collect();
+        let err = Interpreter::from_source(&source).unwrap_err();
+        assert_eq!(err.to_string(), "too many globals, the limit is 65536");
+        let span = err.span();
+        assert_eq!(&source[span.start..span.end], "let v65536 = 65536");
+    }
+
+    #[test]
+    fn undefined_variable() {
+        let err = Interpreter::from_source("x + 1").unwrap_err();
+        assert_eq!(err.to_string(), "undefined variable `x`");
+    }
+
+    #[test]
+    fn functions() {
+        let bytecode = Interpreter::from_source("fn area(w, h) = w * h; area(3, 4)").unwrap();
+
+        let expected_instructions = vec![
+            OpCode::OpConstant(0),
+            OpCode::OpConstant(1),
+            OpCode::OpCall(0),
+        ]
+        .into_iter()
+        .flat_map(make_op)
+        .collect();
+        let expected_body = vec![
+            OpCode::OpGetLocal(0),
+            OpCode::OpGetLocal(1),
+            OpCode::OpMul,
+            OpCode::OpReturn,
+        ]
+        .into_iter()
+        .flat_map(make_op)
+        .collect();
+
+        assert_eq!(
+            Bytecode {
+                instructions: expected_instructions,
+                constants: vec![Val::Int(3), Val::Int(4)],
+                functions: vec![Function {
+                    name: "area".to_string(),
+                    arity: 2,
+                    instructions: expected_body,
+                    lines: LineTable::default(),
+                }],
+                lines: LineTable::default(),
             },
             bytecode
         );
     }
+
+    #[test]
+    fn function_errors() {
+        let err = |src| Interpreter::from_source(src).unwrap_err().to_string();
+        assert_eq!(err("f(1)"), "undefined function `f`");
+        assert_eq!(
+            err("fn f(a, b) = a; f(1)"),
+            "function `f` expects 2 arguments but got 1"
+        );
+        assert_eq!(
+            err("let y = 1; fn f(x) = x + y; f(1)"),
+            "undefined variable `y`"
+        );
+    }
+
+    #[test]
+    fn if_expression() {
+        let bytecode = Interpreter::from_ast(parse("if true then 1 else 2").unwrap()).unwrap();
+
+        let expected_instructions = vec![
+            OpCode::OpConstant(0),     // 0000
+            OpCode::OpJumpIfFalse(12), // 0003
+            OpCode::OpConstant(1),     // 0006
+            OpCode::OpJump(15),        // 0009
+            OpCode::OpConstant(2),     // 0012
+        ]
+        .into_iter()
+        .flat_map(make_op)
+        .collect::<Vec<u8>>();
+
+        assert_eq!(expected_instructions, bytecode.instructions);
+    }
+
+    #[test]
+    fn disassemble() {
+        let bytecode =
+            Interpreter::from_source("fn half(x) = x / 2.0; let y = half(3.14); y > 1").unwrap();
+        assert_eq!(
+            bytecode.disassemble(),
+            "== main ==
+0000 OpConstant 0 (3.14)
+0003 OpCall 0 (half)
+0006 OpSetGlobal 0
+0009 OpGetGlobal 0
+0012 OpPop
+0013 OpGetGlobal 0
+0016 OpConstant 1 (1)
+0019 OpGreater
+
+== half/1 ==
+0000 OpGetLocal 0
+0002 OpConstant 2 (2.0)
+0005 OpDiv
+0006 OpReturn
+"
+        );
+
+        let malformed = Bytecode {
+            instructions: vec![0x02, 0xFF, 0x02],
+            constants: vec![],
+            functions: vec![],
+            lines: LineTable::default(),
+        };
+        assert_eq!(
+            malformed.disassemble(),
+            "== main ==\n0000 OpPop\n0001 internal error: unknown instruction 0xff\n"
+        );
+    }
+
+    #[test]
+    fn short_circuit() {
+        let bytecode =
+            Interpreter::from_source("a() && b(); fn a() = true; fn b() = false").unwrap();
+
+        let expected_instructions = vec![
+            OpCode::OpCall(0),         // 0000
+            OpCode::OpJumpIfFalse(18), // 0003
+            OpCode::OpCall(1),         // 0006
+            OpCode::OpJumpIfFalse(18), // 0009
+            OpCode::OpConstant(0),     // 0012
+            OpCode::OpJump(21),        // 0015
+            OpCode::OpConstant(1),     // 0018
+        ]
+        .into_iter()
+        .flat_map(make_op)
+        .collect::<Vec<u8>>();
+
+        assert_eq!(expected_instructions, bytecode.instructions);
+        assert_eq!([Val::Bool(true), Val::Bool(false)], bytecode.constants[..2]);
+    }
+
+    #[test]
+    fn line_table() {
+        let bytecode = Interpreter::from_source("let x = 2; 1 + x // 0").unwrap();
+        // only the instructions that can fail are recorded
+        let lines: Vec<_> = bytecode.lines.iter().collect();
+        assert_eq!(
+            lines,
+            vec![(19, Span::new(15, 21)), (20, Span::new(11, 21))]
+        );
+        assert_eq!(bytecode.lines.span(20), Some(Span::new(11, 21)));
+        assert_eq!(bytecode.lines.span(0), None);
+    }
 }
@@ -1,9 +1,13 @@
+pub mod asm;
 pub mod bytecode;
 pub mod opcode;
+pub mod peephole;
+pub mod serialize;
+pub mod verify;
 #[allow(clippy::module_inception)]
 pub mod vm;
 
 pub use crate::compiler::vm::{
     bytecode::Bytecode,
-    opcode::{make_op, OpCode},
+    opcode::{OpCode, make_op},
 };
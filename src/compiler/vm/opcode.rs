@@ -1,3 +1,8 @@
+use std::fmt;
+
+use crate::error::CalcError;
+use crate::{Operator, Result};
+
 #[derive(Debug, Copy, Clone)]
 // ANCHOR: vm_opcode
 pub enum OpCode {
@@ -7,11 +12,34 @@ pub enum OpCode {
     OpSub,
     OpMul,
     OpDiv,
+    OpFloorDiv,
+    OpMod,
+    OpPow,
     OpPlus,
     OpMinus,
+    OpGetGlobal(u16), // index into the globals table
+    OpSetGlobal(u16), // pops the top of the stack into a global
+    OpGetLocal(u8),   // index of a parameter in the current call frame
+    OpCall(u16),      // index into the function table
+    OpReturn,
+    OpEqual,
+    OpNotEqual,
+    OpLess,
+    OpLessEqual,
+    OpGreater,
+    OpGreaterEqual,
+    OpNot,
+    OpJump(u16),         // absolute offset within the current instruction stream
+    OpJumpIfFalse(u16),  // pops a bool and jumps if it is false
+    OpJumpIfTrue(u16),   // pops a bool and jumps if it is true
+    OpConstantWide(u32), // pointer to constant table past u16::MAX, 24 bits wide
 }
 // ANCHOR_END: vm_opcode
 
+/// The number of constants a program can hold; `OpConstantWide` addresses
+/// them with a 24-bit operand.
+pub const MAX_CONSTANTS: usize = 1 << 24;
+
 fn convert_u16_to_two_u8s(integer: u16) -> [u8; 2] {
     [(integer >> 8) as u8, integer as u8]
 }
@@ -20,23 +48,221 @@ pub fn convert_two_u8s_to_usize(int1: u8, int2: u8) -> usize {
     ((int1 as usize) << 8) | int2 as usize
 }
 
+fn make_two_byte_op(code: u8, data: u8) -> Vec<u8> {
+    vec![code, data]
+}
+
 fn make_three_byte_op(code: u8, data: u16) -> Vec<u8> {
     let mut output = vec![code];
     output.extend(&convert_u16_to_two_u8s(data));
     output
 }
 
+fn make_four_byte_op(code: u8, data: u32) -> Vec<u8> {
+    debug_assert!((data as usize) < MAX_CONSTANTS);
+    let [_, hi, mid, lo] = data.to_be_bytes();
+    vec![code, hi, mid, lo]
+}
+
 pub fn make_op(op: OpCode) -> Vec<u8> {
     match op {
         // ANCHOR: vm_make_op
         OpCode::OpConstant(arg) => make_three_byte_op(0x01, arg),
-        OpCode::OpPop => vec![0x02],   // decimal repr is 2
-        OpCode::OpAdd => vec![0x03],   // decimal repr is 3
-        OpCode::OpSub => vec![0x04],   // decimal repr is 4
-        OpCode::OpMul => vec![0x05],   // decimal repr is 5
-        OpCode::OpDiv => vec![0x06],   // decimal repr is 6
-        OpCode::OpPlus => vec![0x0A],  // decimal repr is 10
-        OpCode::OpMinus => vec![0x0B], // decimal repr is 11
+        OpCode::OpPop => vec![0x02],      // decimal repr is 2
+        OpCode::OpAdd => vec![0x03],      // decimal repr is 3
+        OpCode::OpSub => vec![0x04],      // decimal repr is 4
+        OpCode::OpMul => vec![0x05],      // decimal repr is 5
+        OpCode::OpDiv => vec![0x06],      // decimal repr is 6
+        OpCode::OpFloorDiv => vec![0x07], // decimal repr is 7
+        OpCode::OpMod => vec![0x08],      // decimal repr is 8
+        OpCode::OpPow => vec![0x09],      // decimal repr is 9
+        OpCode::OpPlus => vec![0x0A],     // decimal repr is 10
+        OpCode::OpMinus => vec![0x0B],    // decimal repr is 11
+        OpCode::OpGetGlobal(arg) => make_three_byte_op(0x0C, arg),
+        OpCode::OpSetGlobal(arg) => make_three_byte_op(0x0D, arg),
+        OpCode::OpGetLocal(arg) => make_two_byte_op(0x0E, arg),
+        OpCode::OpCall(arg) => make_three_byte_op(0x0F, arg),
+        OpCode::OpReturn => vec![0x10],       // decimal repr is 16
+        OpCode::OpEqual => vec![0x11],        // decimal repr is 17
+        OpCode::OpNotEqual => vec![0x12],     // decimal repr is 18
+        OpCode::OpLess => vec![0x13],         // decimal repr is 19
+        OpCode::OpLessEqual => vec![0x14],    // decimal repr is 20
+        OpCode::OpGreater => vec![0x15],      // decimal repr is 21
+        OpCode::OpGreaterEqual => vec![0x16], // decimal repr is 22
+        OpCode::OpNot => vec![0x17],          // decimal repr is 23
+        OpCode::OpJump(arg) => make_three_byte_op(0x18, arg),
+        OpCode::OpJumpIfFalse(arg) => make_three_byte_op(0x19, arg),
+        OpCode::OpJumpIfTrue(arg) => make_three_byte_op(0x1A, arg),
+        OpCode::OpConstantWide(arg) => make_four_byte_op(0x1B, arg),
+    }
+}
+
+impl OpCode {
+    /// Loads constant `idx`, using `OpConstantWide` when the index does not
+    /// fit in 16 bits.
+    pub fn constant(idx: usize) -> Result<OpCode> {
+        match u16::try_from(idx) {
+            Ok(idx) => Ok(OpCode::OpConstant(idx)),
+            Err(_) if idx < MAX_CONSTANTS => Ok(OpCode::OpConstantWide(idx as u32)),
+            Err(_) => Err(CalcError::limit(format!(
+                "too many constants, the limit is {}",
+                MAX_CONSTANTS
+            ))),
+        }
+    }
+
+    /// The index of the constant an `OpConstant` or `OpConstantWide` loads.
+    pub fn constant_index(&self) -> Option<usize> {
+        match *self {
+            OpCode::OpConstant(idx) => Some(idx as usize),
+            OpCode::OpConstantWide(idx) => Some(idx as usize),
+            _ => None,
+        }
+    }
+
+    /// Decodes the instruction starting at `offset`, returning it with its
+    /// length in bytes.
+    pub fn decode(instructions: &[u8], offset: usize) -> Result<(OpCode, usize)> {
+        let byte = |at: usize| {
+            instructions
+                .get(offset + at)
+                .copied()
+                .ok_or_else(|| CalcError::internal("instruction is missing its operand"))
+        };
+        let u16_operand = || -> Result<u16> { Ok(u16::from_be_bytes([byte(1)?, byte(2)?])) };
+        let op = match byte(0)? {
+            0x01 => OpCode::OpConstant(u16_operand()?),
+            0x02 => OpCode::OpPop,
+            0x03 => OpCode::OpAdd,
+            0x04 => OpCode::OpSub,
+            0x05 => OpCode::OpMul,
+            0x06 => OpCode::OpDiv,
+            0x07 => OpCode::OpFloorDiv,
+            0x08 => OpCode::OpMod,
+            0x09 => OpCode::OpPow,
+            0x0A => OpCode::OpPlus,
+            0x0B => OpCode::OpMinus,
+            0x0C => OpCode::OpGetGlobal(u16_operand()?),
+            0x0D => OpCode::OpSetGlobal(u16_operand()?),
+            0x0E => OpCode::OpGetLocal(byte(1)?),
+            0x0F => OpCode::OpCall(u16_operand()?),
+            0x10 => OpCode::OpReturn,
+            0x11 => OpCode::OpEqual,
+            0x12 => OpCode::OpNotEqual,
+            0x13 => OpCode::OpLess,
+            0x14 => OpCode::OpLessEqual,
+            0x15 => OpCode::OpGreater,
+            0x16 => OpCode::OpGreaterEqual,
+            0x17 => OpCode::OpNot,
+            0x18 => OpCode::OpJump(u16_operand()?),
+            0x19 => OpCode::OpJumpIfFalse(u16_operand()?),
+            0x1A => OpCode::OpJumpIfTrue(u16_operand()?),
+            0x1B => OpCode::OpConstantWide(u32::from_be_bytes([0, byte(1)?, byte(2)?, byte(3)?])),
+            code => {
+                return Err(CalcError::internal(format!(
+                    "unknown instruction {:#04x}",
+                    code
+                )));
+            }
+        };
+        Ok((op, op.size()))
+    }
+
+    /// Size of the encoded instruction in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            OpCode::OpGetLocal(_) => 2,
+            OpCode::OpConstantWide(_) => 4,
+            _ if self.operand().is_some() => 3,
+            _ => 1,
+        }
+    }
+
+    pub fn operand(&self) -> Option<u32> {
+        match *self {
+            OpCode::OpConstant(arg)
+            | OpCode::OpGetGlobal(arg)
+            | OpCode::OpSetGlobal(arg)
+            | OpCode::OpCall(arg)
+            | OpCode::OpJump(arg)
+            | OpCode::OpJumpIfFalse(arg)
+            | OpCode::OpJumpIfTrue(arg) => Some(arg as u32),
+            OpCode::OpGetLocal(arg) => Some(arg as u32),
+            OpCode::OpConstantWide(arg) => Some(arg),
+            _ => None,
+        }
+    }
+
+    /// The operator a binary instruction applies to the top two values.
+    pub fn binary_operator(&self) -> Option<Operator> {
+        match self {
+            OpCode::OpAdd => Some(Operator::Plus),
+            OpCode::OpSub => Some(Operator::Minus),
+            OpCode::OpMul => Some(Operator::Multiply),
+            OpCode::OpDiv => Some(Operator::Divide),
+            OpCode::OpFloorDiv => Some(Operator::FloorDivide),
+            OpCode::OpMod => Some(Operator::Modulo),
+            OpCode::OpPow => Some(Operator::Power),
+            OpCode::OpEqual => Some(Operator::Equal),
+            OpCode::OpNotEqual => Some(Operator::NotEqual),
+            OpCode::OpLess => Some(Operator::Less),
+            OpCode::OpLessEqual => Some(Operator::LessEqual),
+            OpCode::OpGreater => Some(Operator::Greater),
+            OpCode::OpGreaterEqual => Some(Operator::GreaterEqual),
+            _ => None,
+        }
+    }
+
+    /// The operator a unary instruction applies to the top value.
+    pub fn unary_operator(&self) -> Option<Operator> {
+        match self {
+            OpCode::OpPlus => Some(Operator::Plus),
+            OpCode::OpMinus => Some(Operator::Minus),
+            OpCode::OpNot => Some(Operator::Not),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            OpCode::OpConstant(_) => "OpConstant",
+            OpCode::OpPop => "OpPop",
+            OpCode::OpAdd => "OpAdd",
+            OpCode::OpSub => "OpSub",
+            OpCode::OpMul => "OpMul",
+            OpCode::OpDiv => "OpDiv",
+            OpCode::OpFloorDiv => "OpFloorDiv",
+            OpCode::OpMod => "OpMod",
+            OpCode::OpPow => "OpPow",
+            OpCode::OpPlus => "OpPlus",
+            OpCode::OpMinus => "OpMinus",
+            OpCode::OpGetGlobal(_) => "OpGetGlobal",
+            OpCode::OpSetGlobal(_) => "OpSetGlobal",
+            OpCode::OpGetLocal(_) => "OpGetLocal",
+            OpCode::OpCall(_) => "OpCall",
+            OpCode::OpReturn => "OpReturn",
+            OpCode::OpEqual => "OpEqual",
+            OpCode::OpNotEqual => "OpNotEqual",
+            OpCode::OpLess => "OpLess",
+            OpCode::OpLessEqual => "OpLessEqual",
+            OpCode::OpGreater => "OpGreater",
+            OpCode::OpGreaterEqual => "OpGreaterEqual",
+            OpCode::OpNot => "OpNot",
+            OpCode::OpJump(_) => "OpJump",
+            OpCode::OpJumpIfFalse(_) => "OpJumpIfFalse",
+            OpCode::OpJumpIfTrue(_) => "OpJumpIfTrue",
+            OpCode::OpConstantWide(_) => "OpConstantWide",
+        }
+    }
+}
+
+/// Prints the mnemonic followed by the operand, if any, e.g. `OpConstant 0`.
+impl fmt::Display for OpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.operand() {
+            Some(arg) => write!(f, "{} {}", self.name(), arg),
+            None => write!(f, "{}", self.name()),
+        }
     }
 }
 
@@ -49,6 +275,17 @@ mod tests {
         assert_eq!(vec![0x01, 255, 254], make_op(OpCode::OpConstant(65534)));
     }
 
+    #[test]
+    fn make_op_constant_wide() {
+        assert_eq!(vec![0x1B, 1, 0, 0], make_op(OpCode::OpConstantWide(65536)));
+        assert_eq!(OpCode::constant(65535).unwrap().size(), 3);
+        assert_eq!(OpCode::constant(65536).unwrap().size(), 4);
+        assert_eq!(
+            OpCode::constant(MAX_CONSTANTS).unwrap_err().to_string(),
+            "too many constants, the limit is 16777216"
+        );
+    }
+
     #[test]
     fn make_op_pop() {
         assert_eq!(vec![0x02], make_op(OpCode::OpPop));
@@ -58,4 +295,65 @@ mod tests {
     fn make_op_add() {
         assert_eq!(vec![0x03], make_op(OpCode::OpAdd));
     }
+
+    #[test]
+    fn make_op_division() {
+        assert_eq!(vec![0x06], make_op(OpCode::OpDiv));
+        assert_eq!(vec![0x07], make_op(OpCode::OpFloorDiv));
+        assert_eq!(vec![0x08], make_op(OpCode::OpMod));
+    }
+
+    #[test]
+    fn make_op_globals() {
+        assert_eq!(vec![0x0C, 0, 3], make_op(OpCode::OpGetGlobal(3)));
+        assert_eq!(vec![0x0D, 1, 0], make_op(OpCode::OpSetGlobal(256)));
+    }
+
+    #[test]
+    fn make_op_calls() {
+        assert_eq!(vec![0x0E, 2], make_op(OpCode::OpGetLocal(2)));
+        assert_eq!(vec![0x0F, 0, 1], make_op(OpCode::OpCall(1)));
+        assert_eq!(vec![0x10], make_op(OpCode::OpReturn));
+    }
+
+    #[test]
+    fn decode_round_trip() {
+        let ops = [
+            OpCode::OpConstant(65534),
+            OpCode::OpGetLocal(2),
+            OpCode::OpCall(1),
+            OpCode::OpMod,
+            OpCode::OpJumpIfTrue(258),
+            OpCode::OpConstantWide(MAX_CONSTANTS as u32 - 1),
+        ];
+        let instructions: Vec<u8> = ops.iter().copied().flat_map(make_op).collect();
+        let mut offset = 0;
+        for op in ops {
+            let (decoded, len) = OpCode::decode(&instructions, offset).unwrap();
+            assert_eq!(make_op(decoded), make_op(op));
+            assert_eq!(len, make_op(op).len());
+            offset += len;
+        }
+        assert!(OpCode::decode(&[0x01, 0], 0).is_err());
+        assert!(OpCode::decode(&[0x1B, 0, 0], 0).is_err());
+        assert!(OpCode::decode(&[0xFF], 0).is_err());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(OpCode::OpConstant(3).to_string(), "OpConstant 3");
+        assert_eq!(OpCode::OpGetLocal(1).to_string(), "OpGetLocal 1");
+        assert_eq!(OpCode::OpAdd.to_string(), "OpAdd");
+        assert_eq!(
+            OpCode::OpConstantWide(70000).to_string(),
+            "OpConstantWide 70000"
+        );
+    }
+
+    #[test]
+    fn make_op_jumps() {
+        assert_eq!(vec![0x18, 0, 7], make_op(OpCode::OpJump(7)));
+        assert_eq!(vec![0x19, 1, 2], make_op(OpCode::OpJumpIfFalse(258)));
+        assert_eq!(vec![0x1A, 0, 0], make_op(OpCode::OpJumpIfTrue(0)));
+    }
 }
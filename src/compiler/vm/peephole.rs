@@ -0,0 +1,320 @@
+//! A peephole pass over compiled bytecode. It looks at a few instructions at
+//! a time and replaces them with shorter ones that leave the same values on
+//! the stack:
+//!
+//! - `OpConstant a; OpConstant b; OpAdd` and the other binary operators become
+//!   `OpConstant (a + b)`, so whole constant expressions collapse
+//! - `OpConstant a; OpMinus` becomes `OpConstant (-a)`, and likewise for
+//!   `OpPlus` and `OpNot`
+//! - `OpPlus` after an instruction that always leaves a number is removed
+//!
+//! Operations that would fail, like dividing by zero, are left for the VM to
+//! report. Instructions that a jump lands on are never merged into the
+//! instruction before them, since the stack there depends on where execution
+//! came from. Jump targets and the line table are rewritten for the shorter
+//! code, and constants that are no longer used are dropped from the pool.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::Result;
+use crate::compiler::vm::bytecode::{ConstantKey, LineTable};
+use crate::compiler::vm::verify::verify;
+use crate::compiler::vm::{Bytecode, OpCode, make_op};
+use crate::error::CalcError;
+use crate::val::Val;
+
+impl Bytecode {
+    /// Optimizes the main program and every function, returning how many
+    /// bytes of instructions were saved. The bytecode is verified first, so
+    /// every constant and jump target the pass follows exists.
+    pub fn peephole(&mut self) -> Result<usize> {
+        verify(self)?;
+        let before = self.code_size();
+        (self.instructions, self.lines) =
+            self.peephole_stream(&self.instructions.clone(), &self.lines.clone())?;
+        for idx in 0..self.functions.len() {
+            let function = &self.functions[idx];
+            let (code, lines) =
+                self.peephole_stream(&function.instructions.clone(), &function.lines.clone())?;
+            self.functions[idx].instructions = code;
+            self.functions[idx].lines = lines;
+        }
+        self.compact_constants()?;
+        Ok(before - self.code_size())
+    }
+
+    fn code_size(&self) -> usize {
+        self.instructions.len()
+            + self
+                .functions
+                .iter()
+                .map(|function| function.instructions.len())
+                .sum::<usize>()
+    }
+
+    fn peephole_stream(&mut self, code: &[u8], lines: &LineTable) -> Result<(Vec<u8>, LineTable)> {
+        let mut ops = Vec::new();
+        let mut offset = 0;
+        while offset < code.len() {
+            let (op, size) = OpCode::decode(code, offset)?;
+            ops.push((offset, op));
+            offset += size;
+        }
+        let targets: HashSet<usize> = ops.iter().filter_map(|(_, op)| jump_target(*op)).collect();
+
+        // each instruction keeps the offset it had before the pass, which is
+        // how jumps find it again afterwards
+        let mut out: Vec<(usize, OpCode)> = Vec::with_capacity(ops.len());
+        for op in ops {
+            out.push(op);
+            while self.reduce(&mut out, &targets) {}
+        }
+
+        let mut new_offsets = HashMap::new();
+        let mut len = 0;
+        for (offset, op) in &out {
+            new_offsets.insert(*offset, len);
+            len += op.size();
+        }
+        new_offsets.insert(code.len(), len);
+        let mut optimized = Vec::with_capacity(len);
+        for (offset, op) in out {
+            let op = match op {
+                OpCode::OpJump(target)
+                | OpCode::OpJumpIfFalse(target)
+                | OpCode::OpJumpIfTrue(target) => {
+                    let target = *new_offsets.get(&(target as usize)).ok_or_else(|| {
+                        CalcError::internal(format!(
+                            "jump at offset {} does not land on an instruction",
+                            offset
+                        ))
+                    })? as u16;
+                    match op {
+                        OpCode::OpJump(_) => OpCode::OpJump(target),
+                        OpCode::OpJumpIfFalse(_) => OpCode::OpJumpIfFalse(target),
+                        _ => OpCode::OpJumpIfTrue(target),
+                    }
+                }
+                op => op,
+            };
+            optimized.extend(make_op(op));
+        }
+        // folded instructions lose their line entry, which is harmless because
+        // an instruction is only folded when it cannot fail
+        let lines = lines
+            .iter()
+            .filter_map(|(offset, span)| Some((*new_offsets.get(&offset)?, span)))
+            .collect();
+        Ok((optimized, lines))
+    }
+
+    /// Rewrites the instructions at the end of `out` once, if any rule
+    /// applies.
+    fn reduce(&mut self, out: &mut Vec<(usize, OpCode)>, targets: &HashSet<usize>) -> bool {
+        let len = out.len();
+        if len >= 3
+            && !targets.contains(&out[len - 2].0)
+            && !targets.contains(&out[len - 1].0)
+            && let (Some(lhs), Some(rhs), Some(operator)) = (
+                out[len - 3].1.constant_index(),
+                out[len - 2].1.constant_index(),
+                out[len - 1].1.binary_operator(),
+            )
+            && let Ok(val) = self.constants[lhs].binary(operator, self.constants[rhs])
+            && let Some(load) = self.push_constant(val)
+        {
+            let offset = out[len - 3].0;
+            out.truncate(len - 3);
+            out.push((offset, load));
+            return true;
+        }
+        if len < 2 || targets.contains(&out[len - 1].0) {
+            return false;
+        }
+        let ((offset, prev), op) = (out[len - 2], out[len - 1].1);
+        if let (Some(idx), Some(operator)) = (prev.constant_index(), op.unary_operator())
+            && let Ok(val) = self.constants[idx].unary(operator)
+            && let Some(load) = self.push_constant(val)
+        {
+            out.truncate(len - 2);
+            out.push((offset, load));
+            return true;
+        }
+        if matches!(op, OpCode::OpPlus) && self.leaves_number(prev) {
+            out.pop();
+            return true;
+        }
+        false
+    }
+
+    /// Whether `op` always leaves a number on the stack when it succeeds.
+    fn leaves_number(&self, op: OpCode) -> bool {
+        match op {
+            OpCode::OpConstant(_) | OpCode::OpConstantWide(_) => matches!(
+                op.constant_index().map(|idx| self.constants[idx]),
+                Some(Val::Int(_) | Val::Float(_))
+            ),
+            OpCode::OpAdd
+            | OpCode::OpSub
+            | OpCode::OpMul
+            | OpCode::OpDiv
+            | OpCode::OpFloorDiv
+            | OpCode::OpMod
+            | OpCode::OpPow
+            | OpCode::OpPlus
+            | OpCode::OpMinus => true,
+            _ => false,
+        }
+    }
+
+    // folded values are appended, duplicates and all, and deduplicated by
+    // `compact_constants` once every stream is done; a full pool leaves the
+    // instructions unfolded
+    fn push_constant(&mut self, val: Val) -> Option<OpCode> {
+        let load = OpCode::constant(self.constants.len()).ok()?;
+        self.constants.push(val);
+        Some(load)
+    }
+
+    /// Rebuilds the constant pool with only the constants the code uses, each
+    /// stored once. Constants loaded by `OpConstant` are numbered first so
+    /// that their indexes still fit in its 16-bit operand, then those only
+    /// `OpConstantWide` loads.
+    fn compact_constants(&mut self) -> Result<()> {
+        let old = std::mem::take(&mut self.constants);
+        let mut indexes: HashMap<ConstantKey, usize> = HashMap::new();
+        for wide in [false, true] {
+            let streams = std::iter::once(&mut self.instructions).chain(
+                self.functions
+                    .iter_mut()
+                    .map(|function| &mut function.instructions),
+            );
+            for code in streams {
+                let mut offset = 0;
+                while offset < code.len() {
+                    let (op, size) = OpCode::decode(code, offset)?;
+                    if let Some(idx) = op.constant_index()
+                        && matches!(op, OpCode::OpConstantWide(_)) == wide
+                    {
+                        let val = old[idx];
+                        let next = self.constants.len();
+                        let idx = *indexes.entry(val.into()).or_insert_with(|| {
+                            self.constants.push(val);
+                            next
+                        });
+                        // at most 65536 constants are loaded by `OpConstant`, so its
+                        // new indexes still fit and every operand keeps its width
+                        let operand = (idx as u32).to_be_bytes();
+                        code[offset + 1..offset + size].copy_from_slice(&operand[5 - size..]);
+                    }
+                    offset += size;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn jump_target(op: OpCode) -> Option<usize> {
+    match op {
+        OpCode::OpJump(target) | OpCode::OpJumpIfFalse(target) | OpCode::OpJumpIfTrue(target) => {
+            Some(target as usize)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compile;
+    use crate::compiler::vm::bytecode::Interpreter;
+    use crate::compiler::vm::vm::VM;
+    use crate::error::Span;
+    use crate::parser::parse;
+
+    fn compile(source: &str) -> Bytecode {
+        Interpreter::from_ast(parse(source).unwrap()).unwrap()
+    }
+
+    fn optimized(source: &str) -> (Bytecode, usize) {
+        let mut bytecode = compile(source);
+        let saved = bytecode.peephole().unwrap();
+        (bytecode, saved)
+    }
+
+    #[test]
+    fn folds_constants() {
+        let (bytecode, saved) = optimized("1 + 2 * 3 - -4");
+        assert_eq!(bytecode.instructions, make_op(OpCode::OpConstant(0)));
+        assert_eq!(bytecode.constants, vec![Val::Int(11)]);
+        assert_eq!(saved, compile("1 + 2 * 3 - -4").instructions.len() - 3);
+
+        let (bytecode, _) = optimized("!(1 < 2.5); +-7 // 2");
+        assert_eq!(bytecode.constants, vec![Val::Bool(false), Val::Int(-4)]);
+    }
+
+    #[test]
+    fn removes_no_op_plus() {
+        let (bytecode, saved) = optimized("fn f(x) = +(x * 2); f(1)");
+        assert_eq!(saved, 1);
+        assert!(!bytecode.functions[0].instructions.contains(&0x0A));
+        // `x` could be a bool, which `+` rejects
+        let (_, saved) = optimized("fn f(x) = +x; f(1)");
+        assert_eq!(saved, 0);
+    }
+
+    #[test]
+    fn keeps_failing_operations() {
+        for source in ["1 // 0", "2147483647 + 1", "-true", "1 + true"] {
+            let (bytecode, saved) = optimized(source);
+            assert_eq!(saved, 0, "Failed on input: {}", source);
+            assert_eq!(
+                VM::eval(bytecode).unwrap_err(),
+                VM::eval(compile(source)).unwrap_err()
+            );
+        }
+    }
+
+    #[test]
+    fn remaps_lines() {
+        // folding `1 + 2` moves the division, whose span must move with it
+        let (bytecode, saved) = optimized("let x = 0; (1 + 2) // x");
+        assert!(saved > 0);
+        assert_eq!(VM::eval(bytecode).unwrap_err().span(), Span::new(11, 23));
+    }
+
+    #[test]
+    fn rewrites_jumps() {
+        for source in [
+            "if 1 < 2 then 3 * 4 else -5",
+            "let x = 2; if x > 1 then 2 + 2 else 1 - 3",
+            "fn f(n) = if n < 1 then -(1 + 1) else f(n - 1) + 1 * 2; f(3)",
+            "true && false || !(1 == 1)",
+            "let a = 1; !(a < 2 && a > 0) || 1 + 1 == 2",
+        ] {
+            let (bytecode, saved) = optimized(source);
+            assert!(saved > 0, "Failed on input: {}", source);
+            assert_eq!(
+                VM::eval(bytecode).unwrap(),
+                VM::eval(compile(source)).unwrap(),
+                "Failed on input: {}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_jump_targets() {
+        // the OpMinus after the if is where both branches meet, so it
+        // cannot be folded into the constant of the else branch
+        let (bytecode, _) = optimized("let a = true; -(if a then 1 else 2)");
+        assert!(bytecode.instructions.contains(&0x0B));
+    }
+
+    #[test]
+    fn drops_unused_constants() {
+        let (bytecode, _) = optimized("fn f(x) = x + 1 * 1; 2 * 3 + f(1)");
+        assert_eq!(bytecode.constants, vec![Val::Int(6), Val::Int(1)]);
+    }
+}
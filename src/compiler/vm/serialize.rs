@@ -0,0 +1,299 @@
+//! The `.calcb` file format, which stores compiled [`Bytecode`] so a program
+//! can be compiled once and run many times.
+//!
+//! Every number is big-endian, like instruction operands:
+//!
+//! ```text
+//! magic        4 bytes   "CLCB"
+//! version      u16       FORMAT_VERSION
+//! body length  u32       size of the body in bytes
+//! body
+//!   constants    u32 count, then per constant a tag byte and its payload:
+//!                0 = int (i32), 1 = float (f32 bits), 2 = bool (u8)
+//!   instructions u32 length, then the main instruction stream
+//!   functions    u32 count, then per function its name (u16 length and
+//!                UTF-8 bytes), its arity (u8) and its instructions (u32
+//!                length and bytes)
+//! checksum     u32       FNV-1a hash of the body
+//! ```
+//!
+//! The line table is not stored, since the source it points into is not
+//! either, so errors from a loaded program have no span.
+
+use ordered_float::OrderedFloat;
+
+use crate::Result;
+use crate::compiler::vm::Bytecode;
+use crate::compiler::vm::bytecode::{Function, LineTable};
+use crate::error::CalcError;
+use crate::val::Val;
+
+pub const MAGIC: [u8; 4] = *b"CLCB";
+
+/// Bumped whenever the layout or the instruction set changes incompatibly.
+pub const FORMAT_VERSION: u16 = 1;
+
+const HEADER_LEN: usize = MAGIC.len() + 2 + 4;
+const CHECKSUM_LEN: usize = 4;
+
+const TAG_INT: u8 = 0;
+const TAG_FLOAT: u8 = 1;
+const TAG_BOOL: u8 = 2;
+
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+fn write_len(out: &mut Vec<u8>, len: usize) -> Result<()> {
+    let len = u32::try_from(len).map_err(|_| {
+        CalcError::limit(format!(
+            "cannot store a length of {} in a .calcb file, the limit is {}",
+            len,
+            u32::MAX
+        ))
+    })?;
+    out.extend(len.to_be_bytes());
+    Ok(())
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    write_len(out, bytes.len())?;
+    out.extend(bytes);
+    Ok(())
+}
+
+impl Bytecode {
+    /// Encodes the bytecode as a `.calcb` file, failing if a count or a
+    /// function name is too long for its length prefix.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        write_len(&mut body, self.constants.len())?;
+        for constant in &self.constants {
+            match constant {
+                Val::Int(n) => {
+                    body.push(TAG_INT);
+                    body.extend(n.to_be_bytes());
+                }
+                Val::Float(f) => {
+                    body.push(TAG_FLOAT);
+                    body.extend(f.into_inner().to_bits().to_be_bytes());
+                }
+                Val::Bool(b) => body.extend([TAG_BOOL, *b as u8]),
+            }
+        }
+        write_bytes(&mut body, &self.instructions)?;
+        write_len(&mut body, self.functions.len())?;
+        for function in &self.functions {
+            let name_len = u16::try_from(function.name.len()).map_err(|_| {
+                CalcError::limit(format!(
+                    "cannot store a function name of {} bytes in a .calcb file, the limit is {}",
+                    function.name.len(),
+                    u16::MAX
+                ))
+            })?;
+            body.extend(name_len.to_be_bytes());
+            body.extend(function.name.as_bytes());
+            body.push(function.arity);
+            write_bytes(&mut body, &function.instructions)?;
+        }
+
+        let mut out = Vec::with_capacity(HEADER_LEN + body.len() + CHECKSUM_LEN);
+        out.extend(MAGIC);
+        out.extend(FORMAT_VERSION.to_be_bytes());
+        write_len(&mut out, body.len())?;
+        out.extend(&body);
+        out.extend(checksum(&body).to_be_bytes());
+        Ok(out)
+    }
+
+    /// Decodes a `.calcb` file, rejecting it if it is not one, was written by
+    /// an incompatible version, is truncated or fails its checksum.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Bytecode> {
+        if !bytes.starts_with(&MAGIC) {
+            return Err(CalcError::internal("not a .calcb file"));
+        }
+        let mut reader = Reader {
+            bytes,
+            pos: MAGIC.len(),
+        };
+        let version = reader.u16()?;
+        if version != FORMAT_VERSION {
+            return Err(CalcError::internal(format!(
+                "unsupported .calcb version {}, expected version {}",
+                version, FORMAT_VERSION
+            )));
+        }
+        let body_len = reader.u32()? as usize;
+        let body = reader.take(body_len)?;
+        let expected = u32::from_be_bytes(reader.take(CHECKSUM_LEN)?.try_into().unwrap());
+        if reader.pos != bytes.len() {
+            return Err(CalcError::internal(
+                "unexpected data after the end of the .calcb file",
+            ));
+        }
+        if checksum(body) != expected {
+            return Err(CalcError::internal(
+                "checksum mismatch, the .calcb file is corrupted",
+            ));
+        }
+
+        let mut reader = Reader {
+            bytes: body,
+            pos: 0,
+        };
+        let bytecode = reader.bytecode()?;
+        if reader.pos != body.len() {
+            return Err(CalcError::internal(
+                "unexpected data after the end of the .calcb file",
+            ));
+        }
+        Ok(bytecode)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.bytes.len())
+            .ok_or_else(|| CalcError::internal("the .calcb file is truncated"))?;
+        let bytes = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn constant(&mut self) -> Result<Val> {
+        match self.u8()? {
+            TAG_INT => Ok(Val::Int(self.u32()? as i32)),
+            TAG_FLOAT => Ok(Val::Float(OrderedFloat(f32::from_bits(self.u32()?)))),
+            TAG_BOOL => match self.u8()? {
+                0 => Ok(Val::Bool(false)),
+                1 => Ok(Val::Bool(true)),
+                byte => Err(CalcError::internal(format!("invalid bool {}", byte))),
+            },
+            tag => Err(CalcError::internal(format!("unknown constant tag {}", tag))),
+        }
+    }
+
+    fn bytecode(&mut self) -> Result<Bytecode> {
+        // collecting through `Result` does not preallocate, so a corrupt count
+        // fails on the first missing item instead of allocating up front
+        let count = self.u32()?;
+        let constants = (0..count).map(|_| self.constant()).collect::<Result<_>>()?;
+        let instructions = self.bytes()?;
+        let count = self.u32()?;
+        let functions = (0..count)
+            .map(|_| {
+                let len = self.u16()? as usize;
+                let name = String::from_utf8(self.take(len)?.to_vec())
+                    .map_err(|_| CalcError::internal("function name is not valid UTF-8"))?;
+                Ok(Function {
+                    name,
+                    arity: self.u8()?,
+                    instructions: self.bytes()?,
+                    lines: LineTable::default(),
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Bytecode {
+            instructions,
+            constants,
+            functions,
+            lines: LineTable::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compile;
+    use crate::compiler::vm::bytecode::Interpreter;
+
+    fn sample() -> Bytecode {
+        Interpreter::from_source(
+            "fn fact(n) = if n <= 1 then 1 else n * fact(n - 1); let x = -2.5; fact(5) > x && true",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytecode = sample();
+        let bytes = bytecode.to_bytes().unwrap();
+        assert!(bytes.starts_with(b"CLCB\x00\x01"));
+        assert_eq!(Bytecode::from_bytes(&bytes).unwrap(), bytecode);
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let bytes = sample().to_bytes().unwrap();
+        let err = |bytes: &[u8]| Bytecode::from_bytes(bytes).unwrap_err().to_string();
+
+        assert_eq!(err(b"fn f(x) = x"), "internal error: not a .calcb file");
+        assert_eq!(err(&bytes[..3]), "internal error: not a .calcb file");
+        for len in [5, HEADER_LEN, bytes.len() / 2, bytes.len() - 1] {
+            assert_eq!(
+                err(&bytes[..len]),
+                "internal error: the .calcb file is truncated",
+                "Failed on length: {}",
+                len
+            );
+        }
+
+        let mut newer = bytes.clone();
+        newer[5] = 2;
+        assert_eq!(
+            err(&newer),
+            "internal error: unsupported .calcb version 2, expected version 1"
+        );
+
+        let mut corrupted = bytes.clone();
+        corrupted[HEADER_LEN + 5] ^= 0xFF;
+        assert_eq!(
+            err(&corrupted),
+            "internal error: checksum mismatch, the .calcb file is corrupted"
+        );
+
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert!(Bytecode::from_bytes(&longer).is_err());
+    }
+
+    #[test]
+    fn rejects_long_names() {
+        let mut bytecode = sample();
+        bytecode.functions[0].name = "f".repeat(u16::MAX as usize);
+        assert!(bytecode.to_bytes().is_ok());
+        bytecode.functions[0].name.push('f');
+        assert_eq!(
+            bytecode.to_bytes().unwrap_err().to_string(),
+            "cannot store a function name of 65536 bytes in a .calcb file, the limit is 65535"
+        );
+    }
+}
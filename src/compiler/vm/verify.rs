@@ -0,0 +1,345 @@
+//! Checks bytecode before the VM runs it, so that malformed or hostile input
+//! such as a `.calcb` file is rejected up front instead of crashing the VM.
+//!
+//! Every instruction stream is decoded in full and each instruction is then
+//! interpreted abstractly, tracking only how many values are on the stack.
+//! Verified bytecode has no unknown opcodes or truncated operands, refers only
+//! to constants, functions and parameters that exist, jumps only forward to
+//! the start of an instruction, never pops more than its call frame holds,
+//! reaches every instruction with the same stack depth along every path, and
+//! returns from every function. The language has no loops, so
+//! allowing only forward jumps also means verified code cannot hang.
+
+use crate::Result;
+use crate::compiler::vm::{Bytecode, OpCode};
+use crate::error::CalcError;
+
+/// The most stack slots a single call frame uses, counting a function's
+/// arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Depth {
+    pub main: usize,
+    pub function: usize, // the deepest of every function, 0 without any
+}
+
+/// Verifies `bytecode` and returns how deep each call frame gets. Calls nest
+/// at most `MAX_CALL_DEPTH` deep, which bounds the whole stack.
+pub fn verify(bytecode: &Bytecode) -> Result<Depth> {
+    let main = Verifier {
+        bytecode,
+        code: &bytecode.instructions,
+        function: None,
+    }
+    .run()?;
+    let mut function = 0;
+    for (idx, code) in bytecode.functions.iter().enumerate() {
+        let depth = Verifier {
+            bytecode,
+            code: &code.instructions,
+            function: Some(idx),
+        }
+        .run()?;
+        function = function.max(depth);
+    }
+    Ok(Depth { main, function })
+}
+
+struct Verifier<'a> {
+    bytecode: &'a Bytecode,
+    code: &'a [u8],
+    function: Option<usize>, // `None` for the main program
+}
+
+impl Verifier<'_> {
+    fn error(&self, offset: usize, message: impl std::fmt::Display) -> CalcError {
+        let location = match self.function {
+            Some(idx) => format!("function `{}`", self.bytecode.functions[idx].name),
+            None => "the main program".to_string(),
+        };
+        CalcError::internal(format!(
+            "invalid bytecode: {} at offset {} in {}",
+            message, offset, location
+        ))
+    }
+
+    fn arity(&self) -> usize {
+        self.function
+            .map_or(0, |idx| self.bytecode.functions[idx].arity as usize)
+    }
+
+    /// Decodes every instruction, checking the operands that can be checked
+    /// on their own. Returns the instruction starting at each offset.
+    fn decode(&self) -> Result<Vec<Option<OpCode>>> {
+        let mut ops = vec![None; self.code.len()];
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let (op, size) = OpCode::decode(self.code, offset).map_err(|err| match err {
+                CalcError::Internal { message, .. } => self.error(offset, message),
+                err => err,
+            })?;
+            if let Some(idx) = op.constant_index()
+                && idx >= self.bytecode.constants.len()
+            {
+                return Err(self.error(offset, format!("constant {} does not exist", idx)));
+            }
+            match op {
+                OpCode::OpCall(idx) if idx as usize >= self.bytecode.functions.len() => {
+                    return Err(self.error(offset, format!("function {} does not exist", idx)));
+                }
+                OpCode::OpGetLocal(_) | OpCode::OpReturn if self.function.is_none() => {
+                    return Err(self.error(offset, format!("{} outside of a function", op)));
+                }
+                OpCode::OpGetLocal(idx) if idx as usize >= self.arity() => {
+                    return Err(self.error(offset, format!("local {} does not exist", idx)));
+                }
+                _ => {}
+            }
+            ops[offset] = Some(op);
+            offset += size;
+        }
+        Ok(ops)
+    }
+
+    /// How many values `op` pops and pushes.
+    fn stack_effect(&self, op: OpCode) -> (usize, usize) {
+        match op {
+            OpCode::OpConstant(_)
+            | OpCode::OpConstantWide(_)
+            | OpCode::OpGetGlobal(_)
+            | OpCode::OpGetLocal(_) => (0, 1),
+            OpCode::OpPop
+            | OpCode::OpSetGlobal(_)
+            | OpCode::OpJumpIfFalse(_)
+            | OpCode::OpJumpIfTrue(_) => (1, 0),
+            OpCode::OpPlus | OpCode::OpMinus | OpCode::OpNot => (1, 1),
+            OpCode::OpCall(idx) => (self.bytecode.functions[idx as usize].arity as usize, 1),
+            OpCode::OpReturn => (1, 0),
+            OpCode::OpJump(_) => (0, 0),
+            // binary operators
+            _ => (2, 1),
+        }
+    }
+
+    /// Follows every path through the code, recording the stack depth on
+    /// entry to each instruction. Returns the deepest the frame gets.
+    fn run(&self) -> Result<usize> {
+        let ops = self.decode()?;
+        // depths are relative to the frame, so a function's arguments are not
+        // counted and cannot be popped
+        let mut depths: Vec<Option<usize>> = vec![None; self.code.len() + 1];
+        let mut pending = vec![(0, 0)];
+        let mut max_depth = 0;
+        while let Some((offset, depth)) = pending.pop() {
+            match depths[offset] {
+                Some(seen) if seen == depth => continue,
+                Some(seen) => {
+                    return Err(self.error(
+                        offset,
+                        format!(
+                            "stack depth is {} along one path and {} along another",
+                            seen, depth
+                        ),
+                    ));
+                }
+                None => depths[offset] = Some(depth),
+            }
+            if offset == self.code.len() {
+                if self.function.is_some() {
+                    return Err(self.error(offset, "execution runs past the end without OpReturn"));
+                }
+                continue;
+            }
+
+            let op = ops[offset].expect("offsets on the worklist start an instruction");
+            let (pops, pushes) = self.stack_effect(op);
+            let depth = depth
+                .checked_sub(pops)
+                .ok_or_else(|| self.error(offset, format!("{} underflows the stack", op)))?
+                + pushes;
+            max_depth = max_depth.max(depth);
+
+            let next = offset + op.size();
+            let target = match op {
+                OpCode::OpJump(target)
+                | OpCode::OpJumpIfFalse(target)
+                | OpCode::OpJumpIfTrue(target) => {
+                    let target = target as usize;
+                    if target <= offset {
+                        return Err(
+                            self.error(offset, format!("jump to {} does not go forward", target))
+                        );
+                    }
+                    if target != self.code.len() && ops.get(target).is_none_or(Option::is_none) {
+                        return Err(self.error(
+                            offset,
+                            format!("jump to {} is not the start of an instruction", target),
+                        ));
+                    }
+                    Some(target)
+                }
+                _ => None,
+            };
+            match op {
+                OpCode::OpReturn => {}
+                OpCode::OpJump(_) => pending.extend(target.map(|target| (target, depth))),
+                _ => {
+                    pending.push((next, depth));
+                    pending.extend(target.map(|target| (target, depth)));
+                }
+            }
+        }
+        Ok(max_depth + self.arity())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Compile;
+    use crate::compiler::vm::bytecode::{Function, Interpreter, LineTable};
+    use crate::compiler::vm::make_op;
+    use crate::parser::parse;
+    use crate::val::Val;
+
+    fn code(ops: &[OpCode]) -> Vec<u8> {
+        ops.iter().copied().flat_map(make_op).collect()
+    }
+
+    fn err(instructions: Vec<u8>, functions: Vec<Function>) -> String {
+        verify(&Bytecode {
+            instructions,
+            constants: vec![Val::Int(1), Val::Bool(true)],
+            functions,
+            lines: LineTable::default(),
+        })
+        .unwrap_err()
+        .to_string()
+    }
+
+    #[test]
+    fn max_depth() {
+        let depth = |src| verify(&Interpreter::from_ast(parse(src).unwrap()).unwrap()).unwrap();
+        let main = |main| Depth { main, function: 0 };
+        assert_eq!(depth("1"), main(1));
+        assert_eq!(depth("1 + 2 * 3"), main(3));
+        assert_eq!(depth("1 + 2; 3"), main(2));
+        assert_eq!(depth("if true then 1 else 2 + 3"), main(2));
+        // the function frame holds its 3 arguments and then `c * (a + b)`
+        assert_eq!(
+            depth("fn f(a, b, c) = c * (a + b); f(1, 2, 3)"),
+            Depth {
+                main: 3,
+                function: 6
+            }
+        );
+        assert_eq!(
+            depth("fn fact(n) = if n <= 1 then 1 else n * fact(n - 1); fact(5)"),
+            Depth {
+                main: 1,
+                function: 4
+            }
+        );
+    }
+
+    #[test]
+    fn accepts_compiled_programs() {
+        for src in [
+            "let x = 2; x * x",
+            "true && false || !true",
+            "fn fib(n) = if n < 2 then n else fib(n - 1) + fib(n - 2); fib(10)",
+            "fn f() = 1",
+        ] {
+            let bytecode = Interpreter::from_source(src).unwrap();
+            assert!(verify(&bytecode).is_ok(), "Failed on input: {}", src);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_code() {
+        let prefix = "internal error: invalid bytecode: ";
+        let tests = [
+            (
+                vec![0xFF],
+                "unknown instruction 0xff at offset 0 in the main program",
+            ),
+            (
+                vec![0x01, 0x00],
+                "instruction is missing its operand at offset 0 in the main program",
+            ),
+            (
+                code(&[OpCode::OpConstant(7)]),
+                "constant 7 does not exist at offset 0 in the main program",
+            ),
+            (
+                code(&[OpCode::OpConstantWide(70000)]),
+                "constant 70000 does not exist at offset 0 in the main program",
+            ),
+            (
+                code(&[OpCode::OpCall(0)]),
+                "function 0 does not exist at offset 0 in the main program",
+            ),
+            (
+                code(&[OpCode::OpGetLocal(0)]),
+                "OpGetLocal 0 outside of a function at offset 0 in the main program",
+            ),
+            (
+                code(&[OpCode::OpConstant(0), OpCode::OpAdd]),
+                "OpAdd underflows the stack at offset 3 in the main program",
+            ),
+            (
+                code(&[OpCode::OpJump(1), OpCode::OpPop]),
+                "jump to 1 is not the start of an instruction at offset 0 in the main program",
+            ),
+            (
+                code(&[OpCode::OpConstant(0), OpCode::OpJump(0)]),
+                "jump to 0 does not go forward at offset 3 in the main program",
+            ),
+            (
+                code(&[OpCode::OpJump(4)]),
+                "jump to 4 is not the start of an instruction at offset 0 in the main program",
+            ),
+            (
+                // the then branch leaves one value and the else branch none
+                code(&[
+                    OpCode::OpConstant(1),
+                    OpCode::OpJumpIfFalse(9),
+                    OpCode::OpConstant(0),
+                ]),
+                "stack depth is 0 along one path and 1 along another at offset 9 in the main program",
+            ),
+        ];
+        for (instructions, expected) in tests {
+            assert_eq!(err(instructions, vec![]), format!("{}{}", prefix, expected));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_functions() {
+        let function = |instructions| Function {
+            name: "f".to_string(),
+            arity: 1,
+            instructions,
+            lines: LineTable::default(),
+        };
+        let call = code(&[OpCode::OpConstant(0), OpCode::OpCall(0)]);
+        assert_eq!(
+            err(call.clone(), vec![function(code(&[OpCode::OpGetLocal(0)]))]),
+            "internal error: invalid bytecode: execution runs past the end without OpReturn at offset 2 in function `f`"
+        );
+        assert_eq!(
+            err(
+                call.clone(),
+                vec![function(code(&[OpCode::OpGetLocal(1), OpCode::OpReturn]))]
+            ),
+            "internal error: invalid bytecode: local 1 does not exist at offset 0 in function `f`"
+        );
+        // the argument belongs to the caller's stack, not to the body
+        assert_eq!(
+            err(
+                call,
+                vec![function(code(&[OpCode::OpPop, OpCode::OpReturn]))]
+            ),
+            "internal error: invalid bytecode: OpPop underflows the stack at offset 0 in function `f`"
+        );
+    }
+}
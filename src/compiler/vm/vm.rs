@@ -1,182 +1,372 @@
-use ordered_float::OrderedFloat;
+use std::cell::RefCell;
 
+use crate::compiler::MAX_CALL_DEPTH;
+use crate::compiler::vm::Bytecode;
 use crate::compiler::vm::bytecode::Interpreter as BytecodeInterpreter;
 use crate::compiler::vm::opcode::*;
-use crate::compiler::vm::Bytecode;
+use crate::compiler::vm::verify::verify;
+use crate::error::{CalcError, RuntimeError};
+use crate::program::{Executable, Prepare};
+use crate::trace::{self, Category, Level};
 use crate::val::Val;
-use crate::{Compile, Node, Result};
+use crate::{Compile, Node, Operator, Result, Spanned};
 
 pub struct VM {
     bytecode: Bytecode,
-    stack: Vec<Node>,
+    stack: Vec<Val>,
+    stack_bound: usize, // the most values one `run` can push, from the verifier
+    globals: Vec<Val>,
+    popped: Vec<Val>,
+    frames: Vec<Frame>,
+}
+
+/// Where to resume the caller once a function returns.
+struct Frame {
+    function: Option<usize>, // `None` for the main program
+    ip: usize,
+    base: usize, // stack index of the callee's first argument
 }
 
 impl VM {
-    pub fn new(bytecode: Bytecode) -> Self {
-        Self {
+    /// Verifies the bytecode before accepting it, so that `run` can skip the
+    /// bounds checks the verifier has already proven unnecessary.
+    pub fn new(bytecode: Bytecode) -> Result<Self> {
+        let depth = verify(&bytecode)?;
+        // calls nest at most `MAX_CALL_DEPTH` function frames on top of the
+        // main program's
+        let stack_bound = depth.main + MAX_CALL_DEPTH * depth.function;
+        Ok(Self {
             bytecode,
-            stack: Vec::new(),
+            stack: Vec::with_capacity(stack_bound),
+            stack_bound,
+            globals: Vec::new(),
+            popped: Vec::new(),
+            frames: Vec::new(),
+        })
+    }
+
+    fn code(&self, function: Option<usize>) -> &[u8] {
+        match function {
+            Some(idx) => {
+                debug_assert!(idx < self.bytecode.functions.len());
+                // SAFETY: `verify` checked that every OpCall names a function
+                unsafe { &self.bytecode.functions.get_unchecked(idx).instructions }
+            }
+            None => &self.bytecode.instructions,
         }
     }
-    pub fn run(&mut self) {
+
+    /// Points an error raised by the instruction at `offset` at the source it
+    /// was compiled from, if the line table records it.
+    #[cold]
+    fn locate(&self, err: CalcError, function: Option<usize>, offset: usize) -> CalcError {
+        let lines = match function {
+            Some(idx) => &self.bytecode.functions[idx].lines,
+            None => &self.bytecode.lines,
+        };
+        match lines.span(offset) {
+            Some(span) => err.at(span),
+            None => err,
+        }
+    }
+
+    fn read_u8(&self, function: Option<usize>, at: usize) -> u8 {
+        let code = self.code(function);
+        debug_assert!(at < code.len());
+        // SAFETY: `verify` decoded every instruction, so no operand is truncated
+        unsafe { *code.get_unchecked(at) }
+    }
+
+    fn read_u16(&self, function: Option<usize>, at: usize) -> usize {
+        convert_two_u8s_to_usize(self.read_u8(function, at), self.read_u8(function, at + 1))
+    }
+
+    fn read_u24(&self, function: Option<usize>, at: usize) -> usize {
+        ((self.read_u8(function, at) as usize) << 16) | self.read_u16(function, at + 1)
+    }
+
+    /// Runs the verified bytecode. Opcodes, operands, constants, call targets
+    /// and parameters are read, and the stack is pushed and popped, without
+    /// bounds checks; debug builds still assert them. Values are still checked,
+    /// since their types are only known at run time.
+    pub fn run(&mut self) -> Result<()> {
+        // `push` relies on this room; it is already there unless `run` is
+        // called again on a stack that still holds values
+        self.stack.reserve(self.stack_bound);
+        self.frames.clear();
         let mut ip = 0; // instruction pointer
-        while ip < self.bytecode.instructions.len() {
+        let mut function = None; // function being executed, `None` for the main program
+        let mut base = 0; // stack index of the current function's first argument
+        while ip < self.code(function).len() {
             let inst_addr = ip;
             ip += 1;
 
-            match self.bytecode.instructions[inst_addr] {
-                0x01 => {
-                    //OpConst
-                    let const_idx = convert_two_u8s_to_usize(
-                        self.bytecode.instructions[ip],
-                        self.bytecode.instructions[ip + 1],
-                    );
-                    ip += 2;
-                    self.push(self.bytecode.constants[const_idx].clone());
+            match self.read_u8(function, inst_addr) {
+                op @ (0x01 | 0x1B) => {
+                    //OpConst, OpConstantWide
+                    let const_idx = if op == 0x01 {
+                        self.read_u16(function, ip)
+                    } else {
+                        self.read_u24(function, ip)
+                    };
+                    ip += if op == 0x01 { 2 } else { 3 };
+                    debug_assert!(const_idx < self.bytecode.constants.len());
+                    // SAFETY: `verify` checked every constant index
+                    let val = unsafe { *self.bytecode.constants.get_unchecked(const_idx) };
+                    self.push(val);
                 }
                 0x02 => {
                     //OpPop
-                    self.pop();
+                    // only emitted between statements, so keep the statement's result
+                    let val = self.pop();
+                    self.popped.push(val);
                 }
-                0x03 => {
-                    // OpAdd
-                    match (self.pop(), self.pop()) {
-                        (Node::Val(Val::Int(rhs)), Node::Val(Val::Int(lhs))) => {
-                            self.push(Node::Val(Val::Int(lhs + rhs)))
-                        }
-                        (Node::Val(Val::Float(rhs)), Node::Val(Val::Float(lhs))) => {
-                            self.push(Node::Val(Val::Float(lhs + rhs)))
-                        }
-                        (Node::Val(Val::Int(rhs)), Node::Val(Val::Float(lhs))) => {
-                            self.push(Node::Val(Val::Float(lhs + OrderedFloat(rhs as f32))))
-                        }
-                        (Node::Val(Val::Float(rhs)), Node::Val(Val::Int(lhs))) => {
-                            self.push(Node::Val(Val::Float(OrderedFloat(lhs as f32) + rhs)))
-                        }
-                        _ => panic!("Unknown types to OpAdd"),
-                    }
+                op @ (0x03..=0x09 | 0x11..=0x16) => {
+                    // OpAdd, OpSub, OpMul, OpDiv, OpFloorDiv, OpMod, OpPow,
+                    // OpEqual, OpNotEqual, OpLess, OpLessEqual, OpGreater, OpGreaterEqual
+                    let rhs = self.pop();
+                    let lhs = self.pop();
+                    let operator = match op {
+                        0x03 => Operator::Plus,
+                        0x04 => Operator::Minus,
+                        0x05 => Operator::Multiply,
+                        0x06 => Operator::Divide,
+                        0x07 => Operator::FloorDivide,
+                        0x08 => Operator::Modulo,
+                        0x09 => Operator::Power,
+                        0x11 => Operator::Equal,
+                        0x12 => Operator::NotEqual,
+                        0x13 => Operator::Less,
+                        0x14 => Operator::LessEqual,
+                        0x15 => Operator::Greater,
+                        _ => Operator::GreaterEqual,
+                    };
+                    let val = lhs
+                        .fast_binary(operator, rhs)
+                        .map_err(|err| self.locate(err, function, inst_addr))?;
+                    self.push(val);
                 }
-                0x04 => {
-                    // OpSub
-                    match (self.pop(), self.pop()) {
-                        (Node::Val(Val::Int(rhs)), Node::Val(Val::Int(lhs))) => {
-                            self.push(Node::Val(Val::Int(lhs - rhs)))
-                        }
-                        (Node::Val(Val::Float(rhs)), Node::Val(Val::Float(lhs))) => {
-                            self.push(Node::Val(Val::Float(lhs - rhs)))
-                        }
-                        (Node::Val(Val::Int(rhs)), Node::Val(Val::Float(lhs))) => {
-                            self.push(Node::Val(Val::Float(lhs - OrderedFloat(rhs as f32))))
-                        }
-                        (Node::Val(Val::Float(rhs)), Node::Val(Val::Int(lhs))) => {
-                            self.push(Node::Val(Val::Float(OrderedFloat(lhs as f32) - rhs)))
-                        }
-                        _ => panic!("Unknown types to OpSub"),
-                    }
+                op @ (0x0A | 0x0B | 0x17) => {
+                    // OpPlus, OpMinus, OpNot
+                    let operator = match op {
+                        0x0A => Operator::Plus,
+                        0x0B => Operator::Minus,
+                        _ => Operator::Not,
+                    };
+                    let val = self.pop();
+                    let val = val
+                        .unary(operator)
+                        .map_err(|err| self.locate(err, function, inst_addr))?;
+                    self.push(val);
                 }
-                0x05 => {
-                    // OpMul
-                    match (self.pop(), self.pop()) {
-                        (Node::Val(Val::Int(rhs)), Node::Val(Val::Int(lhs))) => {
-                            self.push(Node::Val(Val::Int(lhs * rhs)))
-                        }
-                        (Node::Val(Val::Float(rhs)), Node::Val(Val::Float(lhs))) => {
-                            self.push(Node::Val(Val::Float(lhs * rhs)))
-                        }
-                        (Node::Val(Val::Int(rhs)), Node::Val(Val::Float(lhs))) => {
-                            self.push(Node::Val(Val::Float(lhs * OrderedFloat(rhs as f32))))
-                        }
-                        (Node::Val(Val::Float(rhs)), Node::Val(Val::Int(lhs))) => {
-                            self.push(Node::Val(Val::Float(OrderedFloat(lhs as f32) * rhs)))
-                        }
-                        _ => panic!("Unknown types to OpMul"),
-                    }
+                0x0C => {
+                    // OpGetGlobal
+                    let global_idx = self.read_u16(function, ip);
+                    ip += 2;
+                    let val = *self.globals.get(global_idx).ok_or_else(|| {
+                        CalcError::internal(format!("global {} is not set", global_idx))
+                    })?;
+                    self.push(val);
                 }
-                0x06 => {
-                    // OpDiv
-                    match (self.pop(), self.pop()) {
-                        (Node::Val(Val::Int(rhs)), Node::Val(Val::Int(lhs))) => {
-                            self.push(Node::Val(Val::Int(lhs / rhs)))
-                        }
-                        (Node::Val(Val::Float(rhs)), Node::Val(Val::Float(lhs))) => {
-                            self.push(Node::Val(Val::Float(lhs / rhs)))
-                        }
-                        (Node::Val(Val::Int(rhs)), Node::Val(Val::Float(lhs))) => {
-                            self.push(Node::Val(Val::Float(lhs / OrderedFloat(rhs as f32))))
-                        }
-                        (Node::Val(Val::Float(rhs)), Node::Val(Val::Int(lhs))) => {
-                            self.push(Node::Val(Val::Float(OrderedFloat(lhs as f32) / rhs)))
-                        }
-                        _ => panic!("Unknown types to OpDiv"),
+                0x0D => {
+                    // OpSetGlobal
+                    let global_idx = self.read_u16(function, ip);
+                    ip += 2;
+                    let val = self.pop();
+                    if global_idx >= self.globals.len() {
+                        self.globals.resize(global_idx + 1, Val::Int(0));
                     }
+                    self.globals[global_idx] = val;
                 }
-                0x0A => {
-                    // OpPlus
-                    match self.pop() {
-                        Node::Val(Val::Int(num)) => self.push(Node::Val(Val::Int(num))),
-                        Node::Val(Val::Float(num)) => self.push(Node::Val(Val::Float(num))),
-                        _ => panic!("Unknown arg type to OpPlus"),
+                0x0E => {
+                    // OpGetLocal
+                    let local_idx = self.read_u8(function, ip) as usize;
+                    ip += 1;
+                    debug_assert!(base + local_idx < self.stack.len());
+                    // SAFETY: `verify` checked that the index is below the arity,
+                    // and the caller pushed that many arguments
+                    let val = unsafe { *self.stack.get_unchecked(base + local_idx) };
+                    self.push(val);
+                }
+                0x0F => {
+                    // OpCall
+                    let function_idx = self.read_u16(function, ip);
+                    ip += 2;
+                    debug_assert!(function_idx < self.bytecode.functions.len());
+                    // SAFETY: `verify` checked every OpCall target
+                    let arity = unsafe { self.bytecode.functions.get_unchecked(function_idx).arity }
+                        as usize;
+                    if self.frames.len() >= MAX_CALL_DEPTH {
+                        let err = CalcError::runtime(RuntimeError::CallDepthExceeded);
+                        return Err(self.locate(err, function, inst_addr));
                     }
+                    self.frames.push(Frame { function, ip, base });
+                    // `verify` proved the arguments are on the stack
+                    base = self.stack.len() - arity;
+                    function = Some(function_idx);
+                    ip = 0;
+                }
+                0x10 => {
+                    // OpReturn
+                    let ret = self.pop();
+                    // drop the arguments along with anything the body left behind
+                    self.stack.truncate(base);
+                    self.push(ret);
+                    let frame = self
+                        .frames
+                        .pop()
+                        .ok_or_else(|| CalcError::internal("return outside of a function"))?;
+                    function = frame.function;
+                    ip = frame.ip;
+                    base = frame.base;
                 }
-                0x0B => {
-                    // OpMinus
-                    match self.pop() {
-                        Node::Val(Val::Int(num)) => self.push(Node::Val(Val::Int(-num))),
-                        Node::Val(Val::Float(num)) => self.push(Node::Val(Val::Float(-num))),
-                        _ => panic!("Unknown arg type to OpMinus"),
+                0x18 => {
+                    // OpJump
+                    ip = self.read_u16(function, ip);
+                }
+                op @ (0x19 | 0x1A) => {
+                    // OpJumpIfFalse, OpJumpIfTrue
+                    let target = self.read_u16(function, ip);
+                    ip += 2;
+                    let cond = match self.pop() {
+                        Val::Bool(b) => b,
+                        val => {
+                            let err = CalcError::type_error(format!(
+                                "expected a bool, found {}",
+                                val.type_name()
+                            ));
+                            return Err(self.locate(err, function, inst_addr));
+                        }
+                    };
+                    if cond == (op == 0x1A) {
+                        ip = target;
                     }
                 }
-                _ => panic!("Unknown instruction"),
+                op => {
+                    return Err(CalcError::internal(format!(
+                        "unknown instruction {:#04x} at offset {}",
+                        op, inst_addr
+                    )));
+                }
             }
         }
+        Ok(())
     }
 
-    pub fn push(&mut self, node: Node) {
-        self.stack.push(node);
+    #[inline(always)]
+    fn push(&mut self, val: Val) {
+        let len = self.stack.len();
+        debug_assert!(len < self.stack.capacity());
+        // SAFETY: `run` reserved room for the most values verified code can
+        // push: the main program's frame and at most `MAX_CALL_DEPTH` function
+        // frames, each no deeper than the verifier found
+        unsafe {
+            self.stack.as_mut_ptr().add(len).write(val);
+            self.stack.set_len(len + 1);
+        }
     }
 
-    pub fn pop(&mut self) -> Node {
-        // ignoring stack underflow
-        let node = self.stack.pop().expect("Stack Underflow");
-        node
+    #[inline(always)]
+    fn pop(&mut self) -> Val {
+        debug_assert!(!self.stack.is_empty());
+        // SAFETY: `verify` proved that no instruction pops more values than
+        // its call frame holds
+        unsafe { self.stack.pop().unwrap_unchecked() }
     }
-    pub fn pop_last(&self) -> &Node {
-        &self.stack.last().expect("Empty Stack")
+
+    /// Runs the program from the start with `globals` as the values of its
+    /// first globals, such as the free variables of
+    /// [`BytecodeInterpreter::compile`], returning the value of the last statement.
+    pub fn run_with(&mut self, globals: &[Val]) -> Result<Val> {
+        self.stack.clear();
+        self.popped.clear();
+        self.globals.clear();
+        self.globals.extend_from_slice(globals);
+        self.run()?;
+        self.peek()
+            .ok_or_else(|| CalcError::type_error("cannot evaluate an empty program"))
+    }
+
+    /// Results of every statement executed so far: the values popped between
+    /// statements followed by the one left on top of the stack.
+    pub fn statement_results(&self) -> Vec<Val> {
+        self.popped
+            .iter()
+            .chain(self.stack.last())
+            .copied()
+            .collect()
+    }
+
+    /// Runs compiled bytecode, such as a loaded `.calcb` file, returning the
+    /// value of the last statement.
+    pub fn eval(bytecode: Bytecode) -> Result<Val> {
+        let mut vm = VM::new(bytecode)?;
+        vm.run()?;
+        vm.peek()
+            .ok_or_else(|| CalcError::type_error("cannot evaluate an empty program"))
+    }
+
+    /// Compiles and runs every top-level statement, returning the result of each one.
+    ///
+    /// Only this backend and the tree-walking interpreter, with
+    /// [`Interpreter::eval_statements`](crate::Interpreter::eval_statements),
+    /// return per-statement results; [`Backend`](crate::Backend) and the
+    /// other backends return the last statement's.
+    pub fn eval_statements(ast: Vec<Spanned<Node>>) -> Result<Vec<Val>> {
+        let bytecode = compile(ast, &[])?;
+        let mut vm = VM::new(bytecode)?;
+        vm.run()?;
+        Ok(vm.statement_results())
     }
 
     pub fn peek(&self) -> Option<Val> {
-        self.stack.last().map(|node| match node {
-            Node::Val(v) => v.clone(),
-            _ => panic!("Top of the stack is not a Val"),
-        })
+        self.stack.last().copied()
     }
 }
 
 impl Compile for VM {
-    type Output = Result<i32>;
-
-    fn from_ast(ast: Vec<Node>) -> Self::Output {
-        let bytecode = BytecodeInterpreter::from_ast(ast);
-        let mut vm = VM::new(bytecode);
-        vm.run();
-        match vm.pop_last() {
-            Node::Val(Val::Int(n)) => Ok(*n),
-            _ => Err(anyhow::anyhow!("Expected integer result")),
-        }
+    type Output = Val;
+
+    fn from_ast(ast: Vec<Spanned<Node>>) -> Result<Self::Output> {
+        VM::eval(compile(ast, &[])?)
+    }
+}
+
+/// Compiles a program to bytecode and runs the peephole optimizer over it,
+/// tracing the result.
+fn compile(ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Bytecode> {
+    let mut bytecode = BytecodeInterpreter::compile(ast, variables)?;
+    let saved = bytecode.peephole()?;
+    trace::emit(Category::Bytecode, Level::Debug, || {
+        format!("peephole optimizer saved {} bytes", saved)
+    });
+    trace::emit(Category::Bytecode, Level::Info, || bytecode.disassemble());
+    Ok(bytecode)
+}
+
+impl Prepare for VM {
+    fn prepare(ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Box<dyn Executable>> {
+        Ok(Box::new(RefCell::new(VM::new(compile(ast, variables)?)?)))
+    }
+}
+
+impl Executable for RefCell<VM> {
+    fn eval(&self, args: &[Val]) -> Result<Val> {
+        self.borrow_mut().run_with(args)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::compiler::vm::bytecode::Interpreter;
     use crate::Compile;
+    use crate::compiler::vm::bytecode::Interpreter;
+    use crate::error::Span;
 
     fn assert_peek(source: &str, expected: Node) {
-        let byte_code = Interpreter::from_source(source);
-        println!("byte code: {:?}", byte_code);
-        let mut vm = VM::new(byte_code);
-        vm.run();
+        let byte_code = Interpreter::from_source(source).unwrap();
+        let mut vm = VM::new(byte_code).unwrap();
+        vm.run().unwrap();
 
         let expected_val = match expected {
             Node::Val(v) => v,
@@ -208,7 +398,10 @@ mod tests {
         assert_peek("1 + 2;", Node::Val(Val::Int(3)));
         assert_peek("5 - 3;", Node::Val(Val::Int(2)));
         assert_peek("4 * 3;", Node::Val(Val::Int(12)));
-        assert_peek("8 / 2;", Node::Val(Val::Int(4)));
+        assert_peek("8 / 2;", Node::Val(Val::Float(4.0.into())));
+        assert_peek("7 // 2;", Node::Val(Val::Int(3)));
+        assert_peek("-7 // 2;", Node::Val(Val::Int(-4)));
+        assert_peek("-7 % 2;", Node::Val(Val::Int(1)));
     }
 
     #[test]
@@ -231,7 +424,9 @@ mod tests {
     fn operator_precedence() {
         assert_peek("1 + 2 * 3;", Node::Val(Val::Int(7)));
         assert_peek("(1 + 2) * 3;", Node::Val(Val::Int(9)));
-        assert_peek("10 - 4 / 2;", Node::Val(Val::Int(8)));
+        assert_peek("10 - 4 / 2;", Node::Val(Val::Float(8.0.into())));
+        assert_peek("10 - 7 // 2 * 2;", Node::Val(Val::Int(4)));
+        assert_peek("1 + 7 % 4 * 2;", Node::Val(Val::Int(7)));
     }
 
     #[test]
@@ -242,12 +437,281 @@ mod tests {
     }
 
     #[test]
-    fn test_debug_binary() {
-        let input = "1 + 2;";
-        let ast = crate::parser::parse(input).unwrap();
-        println!("AST: {:#?}", ast);
+    fn disassemble_binary() {
+        // compiled as written, before the optimizers fold it
+        let ast = crate::parser::parse("1 + 2;").unwrap();
+        let bytecode = Interpreter::from_ast(ast).unwrap();
+        assert_eq!(
+            bytecode.disassemble(),
+            "== main ==\n0000 OpConstant 0 (1)\n0003 OpConstant 1 (2)\n0006 OpAdd\n"
+        );
+        assert_eq!(VM::eval(bytecode), Ok(Val::Int(3)));
+    }
+
+    #[test]
+    fn stack_bound() {
+        // a deep main program does not make every nested call frame as deep
+        let ast = crate::parser::parse("fn f() = 1; 1 + (2 + (3 + f()))").unwrap();
+        let vm = VM::new(Interpreter::from_ast(ast).unwrap()).unwrap();
+        assert_eq!(vm.stack_bound, 4 + MAX_CALL_DEPTH);
+        let ast = crate::parser::parse("1 + (2 + 3)").unwrap();
+        let vm = VM::new(Interpreter::from_ast(ast).unwrap()).unwrap();
+        assert_eq!(vm.stack_bound, 3);
+    }
+
+    #[test]
+    fn let_bindings() {
+        assert_peek("let x = 2 * 3; x + 1", Node::Val(Val::Int(7)));
+        assert_peek(
+            "let x = 1.5; let y = x * 2; y - x",
+            Node::Val(Val::Float(1.5.into())),
+        );
+        assert_peek("let x = 1; let x = x + 1; x", Node::Val(Val::Int(2)));
+        assert_eq!(
+            VM::from_source("let a = 4; let b = a * a; b").unwrap(),
+            Val::Int(16)
+        );
+    }
+
+    #[test]
+    fn multiple_statements() {
+        assert_peek("1 + 1; 2 * 5", Node::Val(Val::Int(10)));
+        assert_peek("let x = 2\nlet y = 3\n\nx * y\n", Node::Val(Val::Int(6)));
+        assert!(VM::from_ast(vec![]).is_err());
+    }
+
+    #[test]
+    fn statement_results() {
+        let ast = crate::parser::parse("let x = 4; x * 2; 1.5").unwrap();
+        assert_eq!(
+            VM::eval_statements(ast).unwrap(),
+            vec![Val::Int(4), Val::Int(8), Val::Float(1.5.into())]
+        );
+    }
+
+    #[test]
+    fn functions() {
+        assert_peek("fn area(w, h) = w * h; area(3, 4)", Node::Val(Val::Int(12)));
+        assert_peek(
+            "fn sq(x) = x * x\nfn hyp2(a, b) = sq(a) + sq(b)\nhyp2(3, 4.0)",
+            Node::Val(Val::Float(25.0.into())),
+        );
+        assert_peek(
+            "fn one() = 1; let x = 2; x - one() + x",
+            Node::Val(Val::Int(3)),
+        );
+        let ast =
+            crate::parser::parse("double(2); fn double(x) = x * 2; let x = 1; double(x)").unwrap();
+        assert_eq!(
+            VM::eval_statements(ast).unwrap(),
+            vec![Val::Int(4), Val::Int(1), Val::Int(2)]
+        );
+    }
+
+    #[test]
+    fn unbounded_recursion() {
+        assert_eq!(
+            VM::from_source("fn f(x) = f(x); f(1)"),
+            Err(CalcError::runtime(RuntimeError::CallDepthExceeded).at(Span::new(10, 14)))
+        );
+    }
+
+    #[test]
+    fn assembled_opcodes() {
+        let run = |src| VM::eval(crate::compiler::vm::asm::assemble(src).unwrap());
+        assert_eq!(
+            run("const 1.5\nconst 2\nadd\nminus"),
+            Ok(Val::Float((-3.5).into()))
+        );
+        assert_eq!(run("const 7\nconst -2\nfloordiv"), Ok(Val::Int(-4)));
+        assert_eq!(run("const 7\nconst -2\nmod"), Ok(Val::Int(-1)));
+        assert_eq!(run("const true\nnot"), Ok(Val::Bool(false)));
+        assert_eq!(
+            run("const 1\nsetglobal 0\ngetglobal 0\ngetglobal 0\nmul"),
+            Ok(Val::Int(1))
+        );
+        // OpPop keeps the popped value as a statement result
+        assert_eq!(run("const 1\npop\nconst 2"), Ok(Val::Int(2)));
+        assert_eq!(
+            run("const 1\nconst 2\ncall sub\n== sub/2 ==\ngetlocal 0\ngetlocal 1\nsub\nreturn"),
+            Ok(Val::Int(-1))
+        );
+        assert_eq!(
+            run("const 1\nconst true\nminus"),
+            Err(CalcError::type_error("cannot apply `-` to bool"))
+        );
+        assert_eq!(
+            run("OpConstantWide 0 (3)\nconstwide 2.5\nadd"),
+            Ok(Val::Float(5.5.into()))
+        );
+    }
+
+    #[test]
+    fn stack_stays_preallocated() {
+        // every frame holds a partly evaluated expression at the deepest call
+        let source = "fn f(n) = if n == 0 then 7 else 1 + (2 - (3 - f(n - 1))); f(255)";
+        let mut vm = VM::new(Interpreter::from_source(source).unwrap()).unwrap();
+        let capacity = vm.stack.capacity();
+        assert!(capacity >= vm.stack_bound);
+        vm.run().unwrap();
+        assert_eq!(vm.stack.capacity(), capacity, "the stack was reallocated");
+        // running again starts on top of the previous result
+        vm.run().unwrap();
+        assert_eq!(vm.statement_results(), vec![Val::Int(7)]);
+        assert_eq!(vm.stack, vec![Val::Int(7), Val::Int(7)]);
+        assert_eq!(
+            VM::from_source("fn f(n) = 1 + f(n); f(1)").unwrap_err(),
+            CalcError::runtime(RuntimeError::CallDepthExceeded).at(Span::new(14, 18))
+        );
+    }
+
+    #[test]
+    fn wide_constants() {
+        // every literal is a new constant, so the last ones need OpConstantWide
+        let mut source: String = (0..70_000).map(|n| format!("{};", n)).collect();
+        source.push_str("69999 + 1.5 * 2");
+        let ast = crate::parser::parse(&source).unwrap();
+        let bytecode = Interpreter::from_ast(ast.clone()).unwrap();
+        assert_eq!(bytecode.constants.len(), 70_001);
+        assert!(
+            bytecode
+                .disassemble()
+                .contains("OpConstantWide 69999 (69999)")
+        );
+        assert_eq!(VM::eval(bytecode).unwrap(), Val::Float(70002.0.into()));
+        // the peephole pass folds `1.5 * 2` and renumbers the pool
+        assert_eq!(VM::from_ast(ast).unwrap(), Val::Float(70002.0.into()));
+    }
+
+    #[test]
+    fn results_keep_their_type() {
+        assert_eq!(VM::from_source("1.5 * 2").unwrap(), Val::Float(3.0.into()));
+        assert_eq!(VM::from_source("1 < 2").unwrap(), Val::Bool(true));
+        // the same programs give the same value in the tree-walking interpreter
+        for src in [
+            "1 + 2 * 3",
+            "let x = 1.5; x * x - 1",
+            "fn f(x) = x < 2; f(1.5)",
+        ] {
+            assert_eq!(
+                VM::from_source(src).unwrap(),
+                crate::Interpreter::from_source(src).unwrap(),
+                "Failed on input: {}",
+                src
+            );
+        }
+    }
 
-        let bytecode = Interpreter::from_source(input);
-        println!("Bytecode: {:#?}", bytecode);
+    #[test]
+    fn booleans() {
+        assert_peek("true", Node::Val(Val::Bool(true)));
+        assert_peek("!true", Node::Val(Val::Bool(false)));
+        assert_peek("1 < 2", Node::Val(Val::Bool(true)));
+        assert_peek("2 <= 2.0", Node::Val(Val::Bool(true)));
+        assert_peek("1.5 > 2", Node::Val(Val::Bool(false)));
+        assert_peek("3 >= 4", Node::Val(Val::Bool(false)));
+        assert_peek("1 == 1.0", Node::Val(Val::Bool(true)));
+        assert_peek("true != false", Node::Val(Val::Bool(true)));
+        assert_peek("1 < 2 && 2 < 3", Node::Val(Val::Bool(true)));
+        assert_peek("1 > 2 || 2 > 3", Node::Val(Val::Bool(false)));
+        assert_peek("false || true", Node::Val(Val::Bool(true)));
+        assert_peek("true && false", Node::Val(Val::Bool(false)));
+    }
+
+    #[test]
+    fn if_expressions() {
+        assert_peek(
+            "let x = -3; if x < 0 then -x else x",
+            Node::Val(Val::Int(3)),
+        );
+        assert_peek(
+            "fn fact(n) = if n <= 1 then 1 else n * fact(n - 1); fact(10)",
+            Node::Val(Val::Int(3628800)),
+        );
+        assert_peek(
+            "if false then 1 else if true then 2 else 3",
+            Node::Val(Val::Int(2)),
+        );
+    }
+
+    #[test]
+    fn short_circuit() {
+        // `loop` never returns, so evaluating it would exceed the call depth
+        let prelude = "fn loop(x) = loop(x)\n";
+        assert_peek(
+            &format!("{}false && loop(1)", prelude),
+            Node::Val(Val::Bool(false)),
+        );
+        assert_peek(
+            &format!("{}true || loop(1)", prelude),
+            Node::Val(Val::Bool(true)),
+        );
+        assert_peek(
+            &format!("{}if true then 1 < 2 else loop(1)", prelude),
+            Node::Val(Val::Bool(true)),
+        );
+    }
+
+    #[test]
+    fn non_bool_condition() {
+        let err = VM::from_source("true && 1").unwrap_err();
+        assert_eq!(err.to_string(), "expected a bool, found int");
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let err = |src| VM::from_source(src).unwrap_err();
+        assert_eq!(
+            err("1 / 0"),
+            CalcError::runtime(RuntimeError::DivisionByZero).at(Span::new(0, 5))
+        );
+        assert_eq!(
+            err("1 % 0"),
+            CalcError::runtime(RuntimeError::DivisionByZero).at(Span::new(0, 5))
+        );
+        assert_eq!(
+            err("-2147483647 - 2"),
+            CalcError::runtime(RuntimeError::Overflow).at(Span::new(0, 15))
+        );
+        assert_eq!(
+            err("fn f(x) = x * x; f(65536)"),
+            CalcError::runtime(RuntimeError::Overflow).at(Span::new(10, 15))
+        );
+        assert_eq!(err("!1").to_string(), "cannot apply `!` to int");
+        // the span is that of the subexpression that failed
+        assert_eq!(err("1 + (2 // 0)").span(), Span::new(5, 11));
+        assert_eq!(
+            err("let t = true; if 1 then t else t").span(),
+            Span::new(14, 32)
+        );
+    }
+
+    #[test]
+    fn malformed_bytecode() {
+        // rejected by the verifier before anything runs
+        let new = |instructions: Vec<u8>| {
+            VM::new(Bytecode {
+                instructions,
+                constants: vec![Val::Int(1)],
+                functions: vec![],
+                lines: Default::default(),
+            })
+            .err()
+            .unwrap()
+        };
+        assert_eq!(
+            new(vec![0x03]).to_string(),
+            "internal error: invalid bytecode: OpAdd underflows the stack at offset 0 in the main program"
+        );
+        assert_eq!(
+            new(vec![0xFF]).to_string(),
+            "internal error: invalid bytecode: unknown instruction 0xff at offset 0 in the main program"
+        );
+        assert!(matches!(new(vec![0x01, 0x00]), CalcError::Internal { .. }));
+        assert!(matches!(
+            new(vec![0x01, 0x00, 0x05]),
+            CalcError::Internal { .. }
+        ));
+        assert!(matches!(new(vec![0x10]), CalcError::Internal { .. }));
     }
 }
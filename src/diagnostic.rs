@@ -0,0 +1,326 @@
+//! Rendering of errors the way a compiler shows them: a header with the error
+//! code, the line of source the error points at with its span underlined, and
+//! notes that explain it.
+//!
+//! ```
+//! use calculator::{Backend, Diagnostic, Registry, Renderer};
+//!
+//! let source = "let zero = 0\n10 / zero";
+//! let err = Registry::new().default_backend().eval(source).unwrap_err();
+//! let rendered = Renderer::plain().render(&Diagnostic::from(&err), "example.calc", source);
+//! assert_eq!(
+//!     rendered,
+//!     "\
+//! error[E0003]: division by zero
+//!  --> example.calc:2:1
+//!   |
+//! 2 | 10 / zero
+//!   | ^^^^^^^^^ this divides by zero
+//!   |
+//!   = note: dividing by the float `0.0` gives `inf` or `NaN` instead
+//! "
+//! );
+//! ```
+
+use std::fmt::Write;
+use std::io::IsTerminal;
+
+use crate::compiler::MAX_CALL_DEPTH;
+use crate::error::{CalcError, RuntimeError, Span};
+
+/// An error ready to be shown to a person.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Identifies the kind of error, such as `E0001`.
+    pub code: &'static str,
+    pub message: String,
+    pub span: Span,
+    /// Shown next to the underlined span.
+    pub label: Option<String>,
+    /// Shown below the source, each as `note: ...`.
+    pub notes: Vec<String>,
+    /// A suggestion for fixing the error, shown as `help: ...`.
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(code: &'static str, message: impl Into<String>, span: Span) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            span,
+            label: None,
+            notes: vec![],
+            help: None,
+        }
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+}
+
+impl From<&CalcError> for Diagnostic {
+    fn from(err: &CalcError) -> Self {
+        let diagnostic = Diagnostic::new(err.code(), err.to_string(), err.span());
+        match err {
+            CalcError::Parse { .. } | CalcError::Type { .. } => diagnostic,
+            CalcError::Runtime { kind, .. } => match kind {
+                RuntimeError::DivisionByZero => diagnostic
+                    .with_label("this divides by zero")
+                    .with_note("dividing by the float `0.0` gives `inf` or `NaN` instead"),
+                RuntimeError::Overflow => diagnostic
+                    .with_label("this overflows")
+                    .with_note("ints are 32 bits, from -2147483648 to 2147483647"),
+                RuntimeError::CallDepthExceeded => diagnostic
+                    .with_label("this call nests too deep")
+                    .with_note(format!("calls nest at most {} deep", MAX_CALL_DEPTH))
+                    .with_help("check that the recursion reaches its base case"),
+                RuntimeError::NegativeExponent => diagnostic
+                    .with_label("this has a negative exponent")
+                    .with_help("make the base a float, such as `2.0 ^ -1`, to get a float"),
+            },
+            CalcError::Internal { .. } => {
+                diagnostic.with_note("this is a bug in calc rather than in the program")
+            }
+            CalcError::Limit { .. } => {
+                diagnostic.with_note("the program is valid, but too large for this backend")
+            }
+        }
+    }
+}
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// Turns diagnostics into text, with or without terminal colors.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Renderer {
+    color: bool,
+}
+
+impl Renderer {
+    pub fn plain() -> Self {
+        Self { color: false }
+    }
+
+    pub fn colored() -> Self {
+        Self { color: true }
+    }
+
+    /// Colors the output when stderr is a terminal and `NO_COLOR` is not set.
+    pub fn stderr() -> Self {
+        Self {
+            color: std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        }
+    }
+
+    /// Renders `diagnostic` against `source`, which was read from `name`.
+    pub fn render(&self, diagnostic: &Diagnostic, name: &str, source: &str) -> String {
+        let start = diagnostic.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |idx| idx + 1);
+        let line_end = source[start..]
+            .find('\n')
+            .map_or(source.len(), |idx| start + idx);
+        let line = source[line_start..line_end].trim_end_matches('\r');
+        let line_no = source[..start].matches('\n').count() + 1;
+        let column = source[line_start..start].chars().count() + 1;
+
+        // a span over several lines is underlined to the end of its first one
+        let end = diagnostic.span.end.clamp(start, line_start + line.len());
+        let indent = width(&source[line_start..start]);
+        let underline = width(&source[start..end]).max(1);
+
+        let gutter = line_no.to_string().len();
+        let mut out = self.header(diagnostic);
+        let _ = writeln!(
+            out,
+            "{:gutter$}{}-->{} {}:{}:{}",
+            "",
+            self.paint(BLUE),
+            self.paint(RESET),
+            name,
+            line_no,
+            column
+        );
+        let bar = format!("{}{:gutter$} |{}", self.paint(BLUE), "", self.paint(RESET));
+        let _ = writeln!(out, "{}", bar);
+        let _ = writeln!(
+            out,
+            "{}{:>gutter$} |{} {}",
+            self.paint(BLUE),
+            line_no,
+            self.paint(RESET),
+            line.replace('\t', "    ")
+        );
+        let label = diagnostic
+            .label
+            .as_ref()
+            .map_or(String::new(), |label| format!(" {}", label));
+        let _ = writeln!(
+            out,
+            "{} {:indent$}{}{}{}{}",
+            bar,
+            "",
+            self.paint(RED),
+            "^".repeat(underline),
+            label,
+            self.paint(RESET)
+        );
+        if !diagnostic.notes.is_empty() || diagnostic.help.is_some() {
+            let _ = writeln!(out, "{}", bar);
+        }
+        out + &self.footer(diagnostic, gutter)
+    }
+
+    /// Renders `diagnostic` without showing where it points, for errors in
+    /// programs whose source is not at hand, such as compiled bytecode.
+    pub fn render_without_source(&self, diagnostic: &Diagnostic) -> String {
+        self.header(diagnostic) + &self.footer(diagnostic, 0)
+    }
+
+    fn header(&self, diagnostic: &Diagnostic) -> String {
+        format!(
+            "{}error[{}]{}{}: {}{}\n",
+            self.paint(RED),
+            diagnostic.code,
+            self.paint(RESET),
+            self.paint(BOLD),
+            diagnostic.message,
+            self.paint(RESET)
+        )
+    }
+
+    fn footer(&self, diagnostic: &Diagnostic, gutter: usize) -> String {
+        let notes = diagnostic.notes.iter().map(|note| ("note", note));
+        let help = diagnostic.help.iter().map(|help| ("help", help));
+        let mut out = String::new();
+        for (kind, text) in notes.chain(help) {
+            let _ = writeln!(
+                out,
+                "{:gutter$} {}={} {}{}:{} {}",
+                "",
+                self.paint(BLUE),
+                self.paint(RESET),
+                self.paint(BOLD),
+                kind,
+                self.paint(RESET),
+                text
+            );
+        }
+        out
+    }
+
+    fn paint(&self, code: &'static str) -> &'static str {
+        if self.color { code } else { "" }
+    }
+}
+
+// how many columns `text` takes up once tabs are expanded to four spaces
+fn width(text: &str) -> usize {
+    text.chars().map(|c| if c == '\t' { 4 } else { 1 }).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn renders_parse_errors() {
+        let source = "let x = 1\nlet y 2\n";
+        let err = parse(source).unwrap_err();
+        assert_eq!(
+            Renderer::plain().render(&Diagnostic::from(&err), "test.calc", source),
+            "\
+error[E0001]: expected `=` after `y`
+ --> test.calc:2:7
+  |
+2 | let y 2
+  |       ^
+"
+        );
+
+        // at the end of the source the caret points past the last character
+        let err = parse("1 +").unwrap_err();
+        assert_eq!(
+            Renderer::plain().render(&Diagnostic::from(&err), "<repl>", "1 +"),
+            "\
+error[E0001]: expected a number, a name or `(` after `+`
+ --> <repl>:1:4
+  |
+1 | 1 +
+  |    ^
+"
+        );
+    }
+
+    #[test]
+    fn renders_notes_and_help() {
+        let source = "\n".repeat(9) + "fn f(n) = f(n)\nf(1)";
+        let diagnostic = Diagnostic::from(
+            &CalcError::runtime(RuntimeError::CallDepthExceeded).at(Span::new(19, 23)),
+        );
+        assert_eq!(
+            Renderer::plain().render(&diagnostic, "deep.calc", &source),
+            "\
+error[E0005]: maximum call depth of 256 exceeded
+  --> deep.calc:10:11
+   |
+10 | fn f(n) = f(n)
+   |           ^^^^ this call nests too deep
+   |
+   = note: calls nest at most 256 deep
+   = help: check that the recursion reaches its base case
+"
+        );
+        assert_eq!(
+            Renderer::plain().render_without_source(&diagnostic),
+            "\
+error[E0005]: maximum call depth of 256 exceeded
+ = note: calls nest at most 256 deep
+ = help: check that the recursion reaches its base case
+"
+        );
+
+        // a limit is not a bug in calc
+        let diagnostic =
+            Diagnostic::from(&CalcError::limit("too many globals, the limit is 65536"));
+        assert_eq!(
+            Renderer::plain().render_without_source(&diagnostic),
+            "\
+error[E0008]: too many globals, the limit is 65536
+ = note: the program is valid, but too large for this backend
+"
+        );
+    }
+
+    #[test]
+    fn renders_tabs_and_colors() {
+        let diagnostic = Diagnostic::new("E0002", "undefined variable `y`", Span::new(5, 6));
+        let rendered = Renderer::plain().render(&diagnostic, "tabs.calc", "\tx + y");
+        assert!(rendered.ends_with("1 |     x + y\n  |         ^\n"));
+
+        let colored = Renderer::colored().render(&diagnostic, "tabs.calc", "\tx + y");
+        assert!(colored.starts_with("\x1b[1;31merror[E0002]\x1b[0m"));
+        let stripped = colored
+            .replace(RED, "")
+            .replace(BLUE, "")
+            .replace(BOLD, "")
+            .replace(RESET, "");
+        assert_eq!(stripped, rendered);
+    }
+}
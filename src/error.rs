@@ -0,0 +1,233 @@
+use std::fmt;
+
+use crate::compiler::MAX_CALL_DEPTH;
+
+/// A range of byte offsets into the source, `start..end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+impl From<pest::Span<'_>> for Span {
+    fn from(span: pest::Span<'_>) -> Self {
+        Self::new(span.start(), span.end())
+    }
+}
+
+/// Ways a well-typed program can still fail while it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeError {
+    DivisionByZero,
+    /// An integer result does not fit in an `i32`.
+    Overflow,
+    CallDepthExceeded,
+    /// An int raised to a negative int power, which is not an int.
+    NegativeExponent,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::DivisionByZero => write!(f, "division by zero"),
+            RuntimeError::Overflow => write!(f, "integer overflow"),
+            RuntimeError::CallDepthExceeded => {
+                write!(f, "maximum call depth of {} exceeded", MAX_CALL_DEPTH)
+            }
+            RuntimeError::NegativeExponent => write!(f, "negative exponent in an int power"),
+        }
+    }
+}
+
+/// Error returned by the parser and by every backend.
+///
+/// The span points at the offending source: for an error raised while running
+/// a program, the subexpression whose evaluation failed. Bytecode assembled or
+/// loaded from a file has no spans, and its errors use an empty span at 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CalcError {
+    /// The source does not match the grammar, or breaks a rule the parser checks
+    /// such as duplicate function names.
+    Parse {
+        message: String,
+        span: Span,
+    },
+    /// The program uses a value of the wrong type, an unknown name or the wrong
+    /// number of arguments.
+    Type {
+        message: String,
+        span: Span,
+    },
+    Runtime {
+        kind: RuntimeError,
+        span: Span,
+    },
+    /// A failure that is not the program's fault: malformed bytecode, a
+    /// failure inside LLVM or a bug in calc.
+    Internal {
+        message: String,
+        span: Span,
+    },
+    /// The program is valid but too large for a backend, such as one with
+    /// more constants than its instructions can address.
+    Limit {
+        message: String,
+        span: Span,
+    },
+}
+
+impl CalcError {
+    pub fn parse(message: impl Into<String>) -> Self {
+        CalcError::Parse {
+            message: message.into(),
+            span: Span::default(),
+        }
+    }
+
+    pub fn type_error(message: impl Into<String>) -> Self {
+        CalcError::Type {
+            message: message.into(),
+            span: Span::default(),
+        }
+    }
+
+    pub fn runtime(kind: RuntimeError) -> Self {
+        CalcError::Runtime {
+            kind,
+            span: Span::default(),
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        CalcError::Internal {
+            message: message.into(),
+            span: Span::default(),
+        }
+    }
+
+    pub fn limit(message: impl Into<String>) -> Self {
+        CalcError::Limit {
+            message: message.into(),
+            span: Span::default(),
+        }
+    }
+
+    /// The error a backend raises on reaching a [`Node::Error`](crate::Node::Error),
+    /// a statement that did not parse.
+    pub(crate) fn unparsed() -> Self {
+        CalcError::parse("cannot run a statement that did not parse")
+    }
+
+    /// Returns the same error pointing at `span`.
+    pub fn at(mut self, span: Span) -> Self {
+        match &mut self {
+            CalcError::Parse { span: s, .. }
+            | CalcError::Type { span: s, .. }
+            | CalcError::Runtime { span: s, .. }
+            | CalcError::Internal { span: s, .. }
+            | CalcError::Limit { span: s, .. } => *s = span,
+        }
+        self
+    }
+
+    /// The code that identifies this kind of error in diagnostics, such as
+    /// `E0003` for division by zero.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CalcError::Parse { .. } => "E0001",
+            CalcError::Type { .. } => "E0002",
+            CalcError::Runtime { kind, .. } => match kind {
+                RuntimeError::DivisionByZero => "E0003",
+                RuntimeError::Overflow => "E0004",
+                RuntimeError::CallDepthExceeded => "E0005",
+                RuntimeError::NegativeExponent => "E0007",
+            },
+            CalcError::Internal { .. } => "E0006",
+            CalcError::Limit { .. } => "E0008",
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            CalcError::Parse { span, .. }
+            | CalcError::Type { span, .. }
+            | CalcError::Runtime { span, .. }
+            | CalcError::Internal { span, .. }
+            | CalcError::Limit { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalcError::Parse { message, .. }
+            | CalcError::Type { message, .. }
+            | CalcError::Limit { message, .. } => write!(f, "{}", message),
+            CalcError::Runtime { kind, .. } => write!(f, "{}", kind),
+            CalcError::Internal { message, .. } => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for CalcError {}
+
+#[cfg(feature = "jit")]
+impl From<inkwell::builder::BuilderError> for CalcError {
+    fn from(err: inkwell::builder::BuilderError) -> Self {
+        CalcError::internal(format!("LLVM builder failed: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spans() {
+        let err = CalcError::type_error("undefined variable `x`");
+        assert_eq!(err.span(), Span::default());
+        let err = err.at(Span::new(3, 4));
+        assert_eq!(err.span(), Span::new(3, 4));
+        assert_eq!(
+            err,
+            CalcError::Type {
+                message: "undefined variable `x`".to_string(),
+                span: Span::new(3, 4)
+            }
+        );
+    }
+
+    #[test]
+    fn messages() {
+        assert_eq!(
+            CalcError::runtime(RuntimeError::DivisionByZero).to_string(),
+            "division by zero"
+        );
+        assert_eq!(
+            CalcError::runtime(RuntimeError::CallDepthExceeded).to_string(),
+            "maximum call depth of 256 exceeded"
+        );
+        assert_eq!(
+            CalcError::internal("Stack Underflow").to_string(),
+            "internal error: Stack Underflow"
+        );
+        assert_eq!(CalcError::parse("expected `=`").code(), "E0001");
+        assert_eq!(CalcError::runtime(RuntimeError::Overflow).code(), "E0004");
+        assert_eq!(
+            CalcError::runtime(RuntimeError::NegativeExponent).code(),
+            "E0007"
+        );
+        assert_eq!(
+            CalcError::limit("too many globals, the limit is 65536").to_string(),
+            "too many globals, the limit is 65536"
+        );
+        assert_eq!(CalcError::limit("too many globals").code(), "E0008");
+    }
+}
@@ -1,27 +1,38 @@
 pub mod ast;
+pub mod backend;
 pub mod compiler;
+pub mod diagnostic;
+pub mod error;
+pub mod optimize;
 pub mod parser;
+pub mod pratt;
+pub mod program;
+pub mod trace;
 pub mod val;
 
-pub use crate::ast::{Node, Operator};
+pub use crate::ast::{Node, Operator, Spanned};
+pub use crate::backend::{Backend, Registry};
+pub use crate::compiler::closure::{self, Closure};
 pub use crate::compiler::interpreter::Interpreter;
 #[cfg(feature = "jit")]
 pub use crate::compiler::jit::Jit;
+pub use crate::compiler::register::{self, RegisterVM};
 pub use crate::compiler::vm::{self, vm::VM};
+pub use crate::diagnostic::{Diagnostic, Renderer};
+pub use crate::error::{CalcError, RuntimeError, Span};
+pub use crate::optimize::optimize;
+pub use crate::program::Program;
 
-pub type Result<T> = anyhow::Result<T>;
+pub type Result<T> = std::result::Result<T, CalcError>;
 
 // ANCHOR: compile_trait
 pub trait Compile {
     type Output;
 
-    fn from_ast(ast: Vec<Node>) -> Self::Output;
+    fn from_ast(ast: Vec<Spanned<Node>>) -> Result<Self::Output>;
 
-    fn from_source(source: &str) -> Self::Output {
-        println!("Compiling the source: {}", source);
-        let ast: Vec<Node> = parser::parse(source).unwrap();
-        println!("{:?}", ast);
-        Self::from_ast(ast)
+    fn from_source(source: &str) -> Result<Self::Output> {
+        Self::from_ast(optimize(parser::parse(source)?))
     }
 }
 // ANCHOR_END: compile_trait
@@ -1,10 +1,18 @@
 // This file is 100% slopGPT code
 
-use calculator::Compile;
+use calculator::trace::{self, Filter};
+use calculator::val::Val;
+use calculator::vm::bytecode::Interpreter as BytecodeCompiler;
+use calculator::vm::serialize::MAGIC;
+use calculator::vm::{Bytecode, asm};
+use calculator::{
+    Backend, CalcError, Compile, Diagnostic, Registry, Renderer, VM, optimize, parser,
+};
 use clap::{Parser, Subcommand};
 use rustyline::DefaultEditor;
 use rustyline::error::ReadlineError;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
 
 /// calc - A calculator language with multiple execution backends
@@ -18,124 +26,197 @@ struct Cli {
     /// Input file to execute (legacy mode, use 'calc run <file>' instead)
     #[arg(value_name = "FILE")]
     file: Option<String>,
+
+    /// Execution backend to use, see 'calc backends'
+    #[arg(short, long, global = true, value_name = "NAME")]
+    backend: Option<String>,
+
+    /// Run programs as written, without constant folding and simplification
+    #[arg(long, global = true)]
+    no_opt: bool,
+
+    /// Print what happens to a program to stderr: any of parse, ast, bytecode,
+    /// ir, exec or all, each optionally with :debug, such as 'bytecode,ir'
+    #[arg(long, global = true, value_name = "CATEGORIES")]
+    trace: Option<Filter>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Start interactive REPL with tree-walking interpreter
+    /// Start interactive REPL
     Repl,
 
-    /// Start interactive REPL with bytecode VM
-    #[cfg(feature = "vm")]
-    Vm,
-
-    /// Start interactive REPL with JIT compiler
-    #[cfg(feature = "jit")]
-    Jit,
-
-    /// Execute a calculator file
+    /// Execute a calculator file or a compiled .calcb file
     Run {
         /// Path to the calculator file to execute
         #[arg(value_name = "FILE")]
         file: String,
+    },
+
+    /// Compile a calculator file to VM bytecode that 'calc run' can execute
+    Compile {
+        /// Path to the calculator file to compile
+        #[arg(value_name = "FILE")]
+        file: String,
+
+        /// Where to write the bytecode, FILE with a .calcb extension by default
+        #[arg(short, long, value_name = "OUT")]
+        output: Option<PathBuf>,
+    },
+
+    /// Print the VM bytecode a calculator file compiles to, or a .calcb file holds
+    Disasm {
+        /// Path to the calculator or .calcb file to disassemble
+        #[arg(value_name = "FILE")]
+        file: String,
+    },
 
-        /// Use bytecode VM for execution
-        #[arg(short, long)]
-        #[cfg(feature = "vm")]
-        vm: bool,
+    /// Report every syntax error in a calculator file without running it
+    Check {
+        /// Path to the calculator file to check
+        #[arg(value_name = "FILE")]
+        file: String,
+    },
 
-        /// Use JIT compiler for execution
-        #[arg(short, long)]
-        #[cfg(feature = "jit")]
-        jit: bool,
+    /// Assemble a VM assembly file and run it
+    Asm {
+        /// Path to the .casm file to assemble
+        #[arg(value_name = "FILE")]
+        file: String,
     },
+
+    /// List the execution backends compiled into this binary
+    Backends,
 }
 
 fn main() {
     let cli = Cli::parse();
+    if let Some(filter) = cli.trace {
+        trace::set_tracer(filter, |event| eprintln!("{}", event));
+    }
+    let registry = Registry::new();
+    let backend = match &cli.backend {
+        Some(name) => match registry.get(name) {
+            Some(backend) => backend,
+            None => {
+                eprintln!(
+                    "Error: unknown backend '{}', available backends: {}",
+                    name,
+                    registry.names().join(", ")
+                );
+                process::exit(1);
+            }
+        },
+        None => registry.default_backend(),
+    };
 
     // Legacy mode: if a file is provided without subcommand, execute it
     if let Some(file) = cli.file {
-        // Use the default engine based on features (like old main.rs)
-        cfg_if::cfg_if! {
-            if #[cfg(feature = "jit")] {
-                run_file::<calculator::Jit>(&file);
-            }
-            else if #[cfg(feature = "vm")] {
-                run_file::<calculator::VM>(&file);
-            }
-            else {
-                run_file::<calculator::Interpreter>(&file);
-            }
-        }
+        run_file(backend, &file, cli.backend.is_some(), cli.no_opt);
         return;
     }
 
     // New subcommand mode
     match cli.command {
         Some(Commands::Repl) => {
-            println!("Starting calc REPL (interpreter mode)...");
-            println!();
-            run_repl::<calculator::Interpreter>();
-        }
-
-        #[cfg(feature = "vm")]
-        Some(Commands::Vm) => {
-            println!("Starting calc REPL (VM mode)...");
+            println!("Starting calc REPL ({})...", backend.description());
             println!();
-            run_repl::<calculator::VM>();
+            run_repl(backend, cli.no_opt);
         }
 
-        #[cfg(feature = "jit")]
-        Some(Commands::Jit) => {
-            println!("Starting calc REPL (JIT mode)...");
-            println!();
-            run_repl::<calculator::Jit>();
+        Some(Commands::Run { ref file }) => {
+            run_file(backend, file, cli.backend.is_some(), cli.no_opt)
         }
 
-        Some(Commands::Run { file, .. }) => {
-            #[cfg(all(feature = "vm", feature = "jit"))]
-            {
-                let use_vm = matches!(&cli.command, Some(Commands::Run { vm: true, .. }));
-                let use_jit = matches!(&cli.command, Some(Commands::Run { jit: true, .. }));
-
-                if use_vm && use_jit {
-                    eprintln!("Error: Cannot use both --vm and --jit flags");
+        Some(Commands::Compile {
+            ref file,
+            ref output,
+        }) => {
+            let source = read_file(file);
+            let bytecode = match compile_bytecode(&source, cli.no_opt) {
+                Ok((bytecode, saved)) => {
+                    if saved > 0 {
+                        println!("peephole optimizer saved {} bytes", saved);
+                    }
+                    bytecode
+                }
+                Err(e) => {
+                    report(&e, Some((file, &source)));
                     process::exit(1);
                 }
-
-                if use_vm {
-                    run_file::<calculator::VM>(&file);
-                } else if use_jit {
-                    run_file::<calculator::Jit>(&file);
-                } else {
-                    run_file::<calculator::Interpreter>(&file);
+            };
+            let output = output
+                .clone()
+                .unwrap_or_else(|| Path::new(file).with_extension("calcb"));
+            let bytes = match bytecode.to_bytes() {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    report(&e, None);
+                    process::exit(1);
                 }
+            };
+            if let Err(e) = fs::write(&output, bytes) {
+                eprintln!("Error writing file '{}': {}", output.display(), e);
+                process::exit(1);
             }
+        }
 
-            #[cfg(all(feature = "vm", not(feature = "jit")))]
-            {
-                let use_vm = matches!(&cli.command, Some(Commands::Run { vm: true, .. }));
-                if use_vm {
-                    run_file::<calculator::VM>(&file);
-                } else {
-                    run_file::<calculator::Interpreter>(&file);
+        Some(Commands::Disasm { ref file }) => {
+            let bytes = read_bytes(file);
+            let bytecode = if bytes.starts_with(&MAGIC) {
+                Bytecode::from_bytes(&bytes).map_err(|e| (e, None))
+            } else {
+                let source = into_source(file, bytes);
+                compile_bytecode(&source, cli.no_opt)
+                    .map(|(bytecode, _)| bytecode)
+                    .map_err(|e| (e, Some(source)))
+            };
+            match bytecode {
+                Ok(bytecode) => print!("{}", bytecode.disassemble()),
+                Err((e, source)) => {
+                    report(&e, source.as_deref().map(|source| (file.as_str(), source)));
+                    process::exit(1);
                 }
             }
+        }
+
+        Some(Commands::Check { ref file }) => {
+            let source = read_file(file);
+            let errors = parser::parse_with_recovery(&source).errors;
+            for e in &errors {
+                report(e, Some((file, &source)));
+                eprintln!();
+            }
+            if !errors.is_empty() {
+                let noun = if errors.len() == 1 { "error" } else { "errors" };
+                eprintln!("{}: {} {}", file, errors.len(), noun);
+                process::exit(1);
+            }
+        }
 
-            #[cfg(all(feature = "jit", not(feature = "vm")))]
-            {
-                let use_jit = matches!(&cli.command, Some(Commands::Run { jit: true, .. }));
-                if use_jit {
-                    run_file::<calculator::Jit>(&file);
-                } else {
-                    run_file::<calculator::Interpreter>(&file);
+        Some(Commands::Asm { ref file }) => {
+            let source = read_file(file);
+            let bytecode = match asm::assemble(&source) {
+                Ok(bytecode) => bytecode,
+                Err(e) => {
+                    report(&e, Some((file, &source)));
+                    process::exit(1);
+                }
+            };
+            // assembled bytecode has no line table to point back into the source
+            match VM::eval(bytecode) {
+                Ok(result) => println!("{}", result),
+                Err(e) => {
+                    report(&e, None);
+                    process::exit(1);
                 }
             }
+        }
 
-            #[cfg(not(any(feature = "vm", feature = "jit")))]
-            {
-                run_file::<calculator::Interpreter>(&file);
+        Some(Commands::Backends) => {
+            for (idx, backend) in registry.iter().enumerate() {
+                let default = if idx == 0 { " (default)" } else { "" };
+                println!("{:<8} {}{}", backend.name(), backend.description(), default);
             }
         }
 
@@ -149,11 +230,7 @@ fn main() {
     }
 }
 
-fn run_repl<T>()
-where
-    T: Compile,
-    T::Output: std::fmt::Debug,
-{
+fn run_repl(backend: &dyn Backend, no_opt: bool) {
     let mut rl = DefaultEditor::new().expect("Failed to create readline editor");
 
     // Try to load history
@@ -184,8 +261,10 @@ where
 
                 let _ = rl.add_history_entry(line);
 
-                let result = T::from_source(line);
-                println!("{:?}", result);
+                match eval(backend, line, no_opt) {
+                    Ok(result) => println!("{}", result),
+                    Err(e) => report(&e, Some(("<repl>", line))),
+                }
             }
             Err(ReadlineError::Interrupted) => {
                 println!("^C");
@@ -208,19 +287,86 @@ where
     }
 }
 
-fn run_file<T>(filename: &str)
-where
-    T: Compile,
-    T::Output: std::fmt::Debug,
-{
-    let source = match fs::read_to_string(filename) {
+/// Prints `err` to stderr as a diagnostic, showing the line of source it points
+/// at when the program's `(name, source)` is at hand.
+fn report(err: &CalcError, source: Option<(&str, &str)>) {
+    let diagnostic = Diagnostic::from(err);
+    let renderer = Renderer::stderr();
+    match source {
+        Some((name, source)) => eprint!("{}", renderer.render(&diagnostic, name, source)),
+        None => eprint!("{}", renderer.render_without_source(&diagnostic)),
+    }
+}
+
+fn eval(backend: &dyn Backend, source: &str, no_opt: bool) -> calculator::Result<Val> {
+    if no_opt {
+        backend.eval_ast(parser::parse(source)?)
+    } else {
+        backend.eval(source)
+    }
+}
+
+/// Compiles `source` the way the vm backend does, returning the bytecode and
+/// how many bytes the peephole optimizer saved.
+fn compile_bytecode(source: &str, no_opt: bool) -> calculator::Result<(Bytecode, usize)> {
+    let ast = parser::parse(source)?;
+    let mut bytecode = BytecodeCompiler::from_ast(if no_opt { ast } else { optimize(ast) })?;
+    let saved = bytecode.peephole()?;
+    Ok((bytecode, saved))
+}
+
+fn read_bytes(filename: &str) -> Vec<u8> {
+    match fs::read(filename) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Error reading file '{}': {}", filename, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn read_file(filename: &str) -> String {
+    into_source(filename, read_bytes(filename))
+}
+
+fn into_source(filename: &str, bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
         Ok(s) => s,
         Err(e) => {
             eprintln!("Error reading file '{}': {}", filename, e);
             process::exit(1);
         }
-    };
+    }
+}
+
+fn run_file(backend: &dyn Backend, filename: &str, explicit_backend: bool, no_opt: bool) {
+    let bytes = read_bytes(filename);
+
+    // compiled files are recognised by their header rather than their extension
+    if bytes.starts_with(&MAGIC) {
+        if explicit_backend && backend.name() != "vm" {
+            eprintln!(
+                "Error: '{}' is compiled bytecode and only runs on the vm backend",
+                filename
+            );
+            process::exit(1);
+        }
+        match Bytecode::from_bytes(&bytes).and_then(VM::eval) {
+            Ok(result) => println!("{}", result),
+            Err(e) => {
+                report(&e, None);
+                process::exit(1);
+            }
+        }
+        return;
+    }
 
-    let result = T::from_source(&source);
-    println!("{:?}", result);
+    let source = into_source(filename, bytes);
+    match eval(backend, &source, no_opt) {
+        Ok(result) => println!("{}", result),
+        Err(e) => {
+            report(&e, Some((filename, &source)));
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,410 @@
+//! A simplification pass over the AST, shared by every backend.
+//!
+//! A rewrite is only applied when it cannot change what a program does: the
+//! simplified program gives the same value or fails with the same error. So an
+//! expression that fails when evaluated, like `1 / 0`, is kept rather than
+//! folded, since it may sit in a branch that never runs. Identities such as
+//! `x * 1` are only applied when `x` is known to be a number, because with a
+//! bool `x` the original fails and the rewrite would not. For the same reason a
+//! branch that never runs is only dropped when every name in it is defined,
+//! since the backends reject an undefined name before running anything.
+
+use std::collections::HashMap;
+
+use crate::ast::{Node, Operator, Spanned};
+use crate::error::Span;
+use crate::trace;
+use crate::val::Val;
+
+/// Folds constant subexpressions and removes operations that cannot change
+/// their operand.
+pub fn optimize(ast: Vec<Spanned<Node>>) -> Vec<Spanned<Node>> {
+    let functions = ast
+        .iter()
+        .filter_map(|node| match &node.node {
+            Node::FnDef { name, params, .. } => Some((name.clone(), params.len())),
+            _ => None,
+        })
+        .collect();
+    let mut optimizer = Optimizer::new(&functions, None);
+    let ast = ast
+        .into_iter()
+        .map(|node| match node.node {
+            // a function body only sees its parameters, whose types are unknown
+            Node::FnDef { name, params, body } => {
+                let body = Box::new(Optimizer::new(&functions, Some(&params)).expr(*body).0);
+                Spanned::new(Node::FnDef { name, params, body }, node.span)
+            }
+            _ => optimizer.expr(node).0,
+        })
+        .collect::<Vec<_>>();
+    trace::ast("optimized", &ast);
+    ast
+}
+
+/// What an expression yields if it evaluates without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Int,
+    Float,
+    /// An int or a float.
+    Number,
+    Bool,
+    Unknown,
+}
+
+impl Kind {
+    fn of(val: Val) -> Self {
+        match val {
+            Val::Int(_) => Kind::Int,
+            Val::Float(_) => Kind::Float,
+            Val::Bool(_) => Kind::Bool,
+        }
+    }
+
+    fn is_number(self) -> bool {
+        matches!(self, Kind::Int | Kind::Float | Kind::Number)
+    }
+
+    fn join(self, other: Kind) -> Kind {
+        match (self, other) {
+            _ if self == other => self,
+            _ if self.is_number() && other.is_number() => Kind::Number,
+            _ => Kind::Unknown,
+        }
+    }
+
+    fn unary(op: Operator, child: Kind) -> Kind {
+        match (op, child) {
+            (Operator::Not, _) => Kind::Bool,
+            (_, Kind::Int | Kind::Float) => child,
+            _ => Kind::Number,
+        }
+    }
+
+    // arithmetic only ever succeeds with a number, even on operands of unknown type
+    fn binary(op: Operator, lhs: Kind, rhs: Kind) -> Kind {
+        match (op, lhs, rhs) {
+            _ if op.is_comparison() || matches!(op, Operator::And | Operator::Or) => Kind::Bool,
+            (Operator::Divide, _, _) => Kind::Float,
+            (_, Kind::Int, Kind::Int) => Kind::Int,
+            (_, Kind::Float, _) | (_, _, Kind::Float) => Kind::Float,
+            _ => Kind::Number,
+        }
+    }
+}
+
+struct Optimizer<'a> {
+    // what each global holds at this point of the program
+    vars: HashMap<String, Kind>,
+    functions: &'a HashMap<String, usize>, // arity of each function
+    params: Option<&'a [String]>,          // parameters of the function being optimized
+}
+
+impl<'a> Optimizer<'a> {
+    fn new(functions: &'a HashMap<String, usize>, params: Option<&'a [String]>) -> Self {
+        Self {
+            vars: HashMap::new(),
+            functions,
+            params,
+        }
+    }
+
+    /// Whether every name in `node` is defined, so that dropping it cannot
+    /// hide an error. Free variables of a [`crate::Program`] are not known
+    /// here, so a branch that reads one is kept.
+    fn resolves(&self, node: &Spanned<Node>) -> bool {
+        match &node.node {
+            Node::Val(_) => true,
+            Node::Ident(name) => match self.params {
+                Some(params) => params.contains(name),
+                None => self.vars.contains_key(name),
+            },
+            Node::Let { .. } | Node::FnDef { .. } | Node::Error => false,
+            Node::Call { name, args } => {
+                self.functions.get(name) == Some(&args.len())
+                    && args.iter().all(|arg| self.resolves(arg))
+            }
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => self.resolves(cond) && self.resolves(then_branch) && self.resolves(else_branch),
+            Node::UnaryExpr { child, .. } => self.resolves(child),
+            Node::BinaryExpr { lhs, rhs, .. } => self.resolves(lhs) && self.resolves(rhs),
+        }
+    }
+
+    /// Rewrites `node`, returning what it yields. A node folded to a constant
+    /// keeps the span of the expression it replaces, and an operand kept on
+    /// its own keeps its own span.
+    fn expr(&mut self, node: Spanned<Node>) -> (Spanned<Node>, Kind) {
+        let span = node.span;
+        let (node, kind) = match node.node {
+            Node::Val(val) => (Node::Val(val), Kind::of(val)),
+            Node::Ident(name) => {
+                let kind = self.vars.get(&name).copied().unwrap_or(Kind::Unknown);
+                (Node::Ident(name), kind)
+            }
+            Node::Let { name, value } => {
+                let (value, kind) = self.expr(*value);
+                self.vars.insert(name.clone(), kind);
+                let value = Box::new(value);
+                (Node::Let { name, value }, kind)
+            }
+            node @ (Node::FnDef { .. } | Node::Error) => (node, Kind::Unknown),
+            Node::Call { name, args } => {
+                let args = args.into_iter().map(|arg| self.expr(arg).0).collect();
+                (Node::Call { name, args }, Kind::Unknown)
+            }
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                let (cond, _) = self.expr(*cond);
+                let (then_branch, then_kind) = self.expr(*then_branch);
+                let (else_branch, else_kind) = self.expr(*else_branch);
+                match cond.node {
+                    Node::Val(Val::Bool(true)) if self.resolves(&else_branch) => {
+                        return (then_branch, then_kind);
+                    }
+                    Node::Val(Val::Bool(false)) if self.resolves(&then_branch) => {
+                        return (else_branch, else_kind);
+                    }
+                    _ => (
+                        Node::If {
+                            cond: Box::new(cond),
+                            then_branch: Box::new(then_branch),
+                            else_branch: Box::new(else_branch),
+                        },
+                        then_kind.join(else_kind),
+                    ),
+                }
+            }
+            Node::UnaryExpr { op, child } => {
+                let (child, kind) = self.expr(*child);
+                return (unary(op, child, kind, span), Kind::unary(op, kind));
+            }
+            Node::BinaryExpr { op, lhs, rhs } => {
+                let (lhs, lhs_kind) = self.expr(*lhs);
+                let (rhs, rhs_kind) = self.expr(*rhs);
+                let kind = Kind::binary(op, lhs_kind, rhs_kind);
+                let rhs_resolves = self.resolves(&rhs);
+                return match binary(op, lhs, lhs_kind, rhs, rhs_kind, rhs_resolves, span) {
+                    // a rewrite may keep just one operand, whose kind is more precise
+                    Rewrite::Operand(node, kind) => (node, kind),
+                    Rewrite::Node(node) => {
+                        let kind = match &node.node {
+                            Node::Val(val) => Kind::of(*val),
+                            _ => kind,
+                        };
+                        (node, kind)
+                    }
+                };
+            }
+        };
+        (Spanned::new(node, span), kind)
+    }
+}
+
+fn unary(op: Operator, child: Spanned<Node>, kind: Kind, span: Span) -> Spanned<Node> {
+    match (op, child.node) {
+        (_, Node::Val(val)) => match val.unary(op) {
+            Ok(val) => Spanned::new(Node::Val(val), span),
+            Err(_) => unary_node(op, Spanned::new(Node::Val(val), child.span), span),
+        },
+        (Operator::Plus, node) if kind.is_number() => Spanned::new(node, child.span),
+        // `--x` overflows when `x` is `i32::MIN`, so only floats lose both signs
+        (
+            Operator::Minus,
+            Node::UnaryExpr {
+                op: Operator::Minus,
+                child,
+            },
+        ) if kind == Kind::Float => *child,
+        (
+            Operator::Not,
+            Node::UnaryExpr {
+                op: Operator::Not,
+                child,
+            },
+        ) if kind == Kind::Bool => *child,
+        (op, node) => unary_node(op, Spanned::new(node, child.span), span),
+    }
+}
+
+fn unary_node(op: Operator, child: Spanned<Node>, span: Span) -> Spanned<Node> {
+    let child = Box::new(child);
+    Spanned::new(Node::UnaryExpr { op, child }, span)
+}
+
+enum Rewrite {
+    Operand(Spanned<Node>, Kind),
+    Node(Spanned<Node>),
+}
+
+fn binary(
+    op: Operator,
+    lhs: Spanned<Node>,
+    lhs_kind: Kind,
+    rhs: Spanned<Node>,
+    rhs_kind: Kind,
+    rhs_resolves: bool,
+    span: Span,
+) -> Rewrite {
+    match (op, &lhs.node, &rhs.node) {
+        (_, Node::Val(l), Node::Val(r)) => {
+            if let Ok(val) = l.binary(op, *r) {
+                return Rewrite::Node(Spanned::new(Node::Val(val), span));
+            }
+        }
+        // the rhs is never evaluated
+        (Operator::And, Node::Val(Val::Bool(false)), _)
+        | (Operator::Or, Node::Val(Val::Bool(true)), _)
+            if rhs_resolves =>
+        {
+            return Rewrite::Node(lhs);
+        }
+        // the constant side cannot change a bool on the other side
+        (Operator::And, Node::Val(Val::Bool(true)), _)
+        | (Operator::Or, Node::Val(Val::Bool(false)), _)
+            if rhs_kind == Kind::Bool =>
+        {
+            return Rewrite::Operand(rhs, rhs_kind);
+        }
+        (Operator::And, _, Node::Val(Val::Bool(true)))
+        | (Operator::Or, _, Node::Val(Val::Bool(false)))
+            if lhs_kind == Kind::Bool =>
+        {
+            return Rewrite::Operand(lhs, lhs_kind);
+        }
+        // `x * 1` and `1 * x` keep the int or float `x` unchanged
+        (Operator::Multiply, _, Node::Val(Val::Int(1))) if lhs_kind.is_number() => {
+            return Rewrite::Operand(lhs, lhs_kind);
+        }
+        (Operator::Multiply, Node::Val(Val::Int(1)), _) if rhs_kind.is_number() => {
+            return Rewrite::Operand(rhs, rhs_kind);
+        }
+        // adding 0 turns the float -0.0 into 0.0, so only ints are unchanged
+        (Operator::Plus, _, Node::Val(Val::Int(0))) if lhs_kind == Kind::Int => {
+            return Rewrite::Operand(lhs, lhs_kind);
+        }
+        (Operator::Plus, Node::Val(Val::Int(0)), _) if rhs_kind == Kind::Int => {
+            return Rewrite::Operand(rhs, rhs_kind);
+        }
+        (Operator::Minus, _, Node::Val(Val::Int(0))) if lhs_kind.is_number() => {
+            return Rewrite::Operand(lhs, lhs_kind);
+        }
+        (Operator::FloorDivide, _, Node::Val(Val::Int(1))) if lhs_kind == Kind::Int => {
+            return Rewrite::Operand(lhs, lhs_kind);
+        }
+        _ => {}
+    }
+    let (lhs, rhs) = (Box::new(lhs), Box::new(rhs));
+    Rewrite::Node(Spanned::new(Node::BinaryExpr { op, lhs, rhs }, span))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn optimized(source: &str) -> String {
+        optimize(parse(source).unwrap())
+            .iter()
+            .map(|node| node.to_string())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    #[test]
+    fn folds_constants() {
+        assert_eq!(optimized("2 * 3 + 1"), "7");
+        assert_eq!(optimized("7 / 2"), "3.5");
+        assert_eq!(optimized("-(1 + 2) * 2.0"), "-6");
+        assert_eq!(optimized("1 < 2 && !false"), "true");
+        assert_eq!(optimized("let x = 2 * 3; x + 1"), "let x = 6; x + 1");
+        assert_eq!(optimized("fn f(a) = (a - 1) * (4 // 3)"), "fn f(a) = a - 1");
+        assert_eq!(
+            optimized("fn f(a) = a; if 1 > 2 then f(1) else 3 % 2"),
+            "fn f(a) = a; 1"
+        );
+        assert_eq!(optimized("f(2 + 2)"), "f(4)");
+    }
+
+    #[test]
+    fn keeps_failing_expressions() {
+        assert_eq!(optimized("1 / 0"), "1 / 0");
+        assert_eq!(optimized("2147483647 + 1"), "2147483647 + 1");
+        assert_eq!(optimized("-true"), "-true");
+        assert_eq!(
+            optimized("if false then 1 // 0 else 2"),
+            "2",
+            "the failing branch never runs"
+        );
+        assert_eq!(optimized("if 1 then 2 else 3"), "if 1 then 2 else 3");
+    }
+
+    #[test]
+    fn keeps_branches_with_undefined_names() {
+        assert_eq!(
+            optimized("if false then zz else 1"),
+            "if false then zz else 1"
+        );
+        assert_eq!(
+            optimized("if true then 1 else f(2)"),
+            "if true then 1 else f(2)"
+        );
+        assert_eq!(
+            optimized("fn f(a) = a; if true then 1 else f(2, 3)"),
+            "fn f(a) = a; if true then 1 else f(2, 3)"
+        );
+        assert_eq!(optimized("fn f(a) = true || b"), "fn f(a) = true || b");
+        assert_eq!(
+            optimized("fn f(a) = false && g(a)"),
+            "fn f(a) = false && g(a)"
+        );
+        assert_eq!(
+            optimized("let x = 1; if true then 2 else x"),
+            "let x = 1; 2"
+        );
+    }
+
+    #[test]
+    fn removes_unary_operators() {
+        assert_eq!(optimized("let x = 1; +x"), "let x = 1; x");
+        assert_eq!(optimized("fn f(a) = +(a * 2)"), "fn f(a) = a * 2");
+        // `a` could be a bool, which `+` rejects
+        assert_eq!(optimized("fn f(a) = +a"), "fn f(a) = +a");
+        assert_eq!(optimized("let x = 1.5; --x"), "let x = 1.5; x");
+        // `--x` overflows for i32::MIN
+        assert_eq!(optimized("fn f(a) = --(a * 1)"), "fn f(a) = --a * 1");
+        assert_eq!(optimized("let b = 1 < 2; !!b"), "let b = true; b");
+        assert_eq!(optimized("fn f(a) = !!(a < 1)"), "fn f(a) = a < 1");
+    }
+
+    #[test]
+    fn identities() {
+        assert_eq!(optimized("fn f(a) = (a + 1) * 1"), "fn f(a) = a + 1");
+        assert_eq!(optimized("fn f(a) = 1 * (a / 2)"), "fn f(a) = a / 2");
+        assert_eq!(optimized("fn f(a) = a * 1"), "fn f(a) = a * 1");
+        assert_eq!(optimized("let x = 3; 0 + x - 0"), "let x = 3; x");
+        assert_eq!(optimized("let x = 3; x // 1"), "let x = 3; x");
+        // -0.0 + 0 is 0.0, and 1.5 // 1 is 1.0
+        assert_eq!(optimized("let x = 1.5; x + 0"), "let x = 1.5; x + 0");
+        assert_eq!(optimized("let x = 1.5; x // 1"), "let x = 1.5; x // 1");
+        assert_eq!(optimized("let x = 1.5; x - 0"), "let x = 1.5; x");
+        assert_eq!(
+            optimized("fn g(a) = a; fn f(a) = false && g(a)"),
+            "fn g(a) = a; fn f(a) = false"
+        );
+        assert_eq!(optimized("fn f(a) = a < 1 || false"), "fn f(a) = a < 1");
+        // `true && a` checks that `a` is a bool
+        assert_eq!(optimized("fn f(a) = true && a"), "fn f(a) = true && a");
+        assert_eq!(
+            optimized("let x = 1; let x = true; x * 1"),
+            "let x = 1; let x = true; x * 1"
+        );
+    }
+}
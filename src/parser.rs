@@ -1,111 +1,509 @@
-#![allow(clippy::upper_case_acronyms, clippy::result_large_err)]
+#![allow(clippy::upper_case_acronyms)]
+
+use std::collections::HashSet;
 
 use ordered_float::OrderedFloat;
 use pest::{self, Parser};
 
-use crate::ast::{Node, Operator};
+use crate::Result;
+use crate::ast::{Node, Operator, Spanned};
+use crate::error::{CalcError, Span};
+use crate::trace::{self, Category, Level};
 use crate::val::Val;
 
 #[derive(pest_derive::Parser)]
 #[grammar = "grammar.pest"]
 struct CalcParser;
 
-pub fn parse(source: &str) -> std::result::Result<Vec<Node>, pest::error::Error<Rule>> {
+/// Turns a pest error into one that says what was expected in words rather
+/// than grammar rules, such as "expected a number, a name or `(` after `+`",
+/// pointing at the token found instead.
+fn syntax_error(source: &str, err: pest::error::Error<Rule>) -> CalcError {
+    let Some(attempts) = err.parse_attempts() else {
+        let span = match err.location {
+            pest::error::InputLocation::Pos(pos) => Span::new(pos, pos),
+            pest::error::InputLocation::Span((start, end)) => Span::new(start, end),
+        };
+        return CalcError::parse(err.variant.message()).at(span);
+    };
+    let tokens: Vec<String> = attempts
+        .expected_tokens()
+        .iter()
+        .map(|token| token.to_string())
+        .collect();
+    let tokens: Vec<&str> = tokens.iter().map(String::as_str).collect();
+    expected(source, attempts.max_position, &tokens)
+}
+
+/// A syntax error at `pos` in `source`, where one of `tokens` was expected.
+/// The tokens are written the way pest writes them, such as `0..9` for a
+/// digit or `then` for the keyword.
+pub(crate) fn expected(source: &str, pos: usize, tokens: &[&str]) -> CalcError {
+    let mut message = format!("expected {}", describe_expected(tokens));
+    if let Some(previous) = previous_token(source, pos) {
+        message.push_str(&format!(" after `{}`", previous));
+    }
+    let found = token_len(&source[pos..]);
+    CalcError::parse(message).at(Span::new(pos, pos + found))
+}
+
+pub(crate) const OPERATORS: [&str; 15] = [
+    "+", "-", "*", "/", "//", "%", "^", "==", "!=", "<", "<=", ">", ">=", "&&", "||",
+];
+
+// the tokens pest could have accepted, grouped the way a person would say
+// them: the many ways an operand can start become "a number, a name or `(`".
+// Only an operand starts with `!`, while digits and `(` are also expected
+// right after a name, which could go on or be called
+fn describe_expected(tokens: &[&str]) -> String {
+    let has = |token: &str| tokens.contains(&token);
+    if has("let") {
+        return "a statement".to_string();
+    }
+    let operand = has("!");
+    let operator = tokens.iter().any(|t| OPERATORS.contains(t));
+    let mut items = vec![];
+    if operand {
+        items.push("a number".to_string());
+        if has("a..z") {
+            items.push("a name".to_string());
+        }
+    } else if operator {
+        items.push("an operator".to_string());
+    } else if has("0..9") {
+        items.push("a digit".to_string());
+    } else if has("a..z") {
+        items.push("a name".to_string());
+    }
+    for &token in tokens {
+        let skip = match token {
+            " " | "\t" | "\n" | "\r" | "\r\n" | "0..9" | "A..Z" | "a..z" | "_" => true,
+            // the rest of a literal, or the start of an operand
+            "." | "!" | "true" | "false" | "if" => true,
+            token if OPERATORS.contains(&token) => true,
+            _ => false,
+        };
+        if !skip {
+            items.push(format!("`{}`", token));
+        }
+    }
+    if has("\n") {
+        items.push("a new line".to_string());
+    }
+    match items.split_last() {
+        Some((last, [])) => last.clone(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => "something else".to_string(),
+    }
+}
+
+// the token that ends right before `pos` on the same line, if any
+fn previous_token(source: &str, pos: usize) -> Option<&str> {
+    let end = source[..pos].trim_end_matches([' ', '\t']).len();
+    let before = &source[..end];
+    let last = before.chars().next_back().filter(|&c| c != '\n')?;
+    let start = if last.is_alphanumeric() || last == '_' {
+        before
+            .trim_end_matches(|c: char| c.is_alphanumeric() || c == '_')
+            .len()
+    } else if OPERATORS
+        .iter()
+        .any(|op| op.len() == 2 && before.ends_with(op))
+    {
+        end - 2
+    } else {
+        end - last.len_utf8()
+    };
+    Some(&source[start..end])
+}
+
+// the length of the word or symbol `rest` starts with, 0 at the end of a line
+fn token_len(rest: &str) -> usize {
+    match rest.chars().next() {
+        None | Some('\n' | '\r') => 0,
+        Some(c) if c.is_alphanumeric() || c == '_' => rest
+            .find(|c: char| !c.is_alphanumeric() && c != '_')
+            .unwrap_or(rest.len()),
+        Some(c) => c.len_utf8(),
+    }
+}
+
+pub fn parse(source: &str) -> Result<Vec<Spanned<Node>>> {
+    trace::emit(Category::Parse, Level::Debug, || source.to_string());
+    let ast = parse_program(source)?;
+    trace::emit(Category::Parse, Level::Info, || {
+        format!(
+            "parsed {} statements from {} bytes",
+            ast.len(),
+            source.len()
+        )
+    });
+    trace::ast("parsed", &ast);
+    Ok(ast)
+}
+
+/// A partial AST, with an [`Node::Error`] in place of each statement that did
+/// not parse, and the errors that stopped those statements.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recovered {
+    pub ast: Vec<Spanned<Node>>,
+    pub errors: Vec<CalcError>,
+}
+
+/// Parses `source` like [`parse`], but rather than stop at the first error it
+/// resynchronizes at the next `;` or newline and carries on, so that every
+/// statement with an error is reported. The first error is the one `parse`
+/// returns.
+pub fn parse_with_recovery(source: &str) -> Recovered {
+    // most sources parse and need no recovery
+    let first = match parse(source) {
+        Ok(ast) => {
+            return Recovered {
+                ast,
+                errors: vec![],
+            };
+        }
+        Err(err) => err,
+    };
+
+    // statements never span a separator, so each can be parsed on its own
+    let mut statements = vec![];
+    let mut start = 0;
+    for (idx, c) in source.char_indices() {
+        if matches!(c, ';' | '\n' | '\r') {
+            statements.push((start, &source[start..=idx]));
+            start = idx + 1;
+        }
+    }
+    statements.push((start, &source[start..]));
+
     let mut ast = vec![];
-    let pairs = CalcParser::parse(Rule::Program, source)?;
-    for pair in pairs {
-        if let Rule::Expr = pair.as_rule() {
-            ast.push(build_ast_from_expr(pair));
+    let mut errors = vec![];
+    let mut functions = HashSet::new();
+    for (offset, text) in statements {
+        let trimmed = text.trim_end_matches([' ', '\t', ';', '\n', '\r']);
+        let body = trimmed.trim_start_matches([' ', '\t']);
+        if body.is_empty() {
+            continue;
         }
+        match parse_statement(text, &mut functions) {
+            Ok(mut node) => {
+                shift(&mut node, offset);
+                ast.push(node);
+            }
+            Err(err) => {
+                let span = err.span();
+                errors.push(err.at(Span::new(offset + span.start, offset + span.end)));
+                let start = offset + trimmed.len() - body.len();
+                ast.push(Spanned::new(
+                    Node::Error,
+                    Span::new(start, offset + trimmed.len()),
+                ));
+            }
+        }
+    }
+    // a source of nothing but separators has no statement to recover
+    if errors.is_empty() {
+        errors.push(first);
+    }
+    Recovered { ast, errors }
+}
+
+type Pair<'a> = pest::iterators::Pair<'a, Rule>;
+
+// the span of `pair` without the spaces after it, which pest includes in a
+// rule that ends with a repetition that matched nothing
+fn span_of(pair: &Pair) -> Span {
+    let span = pair.as_span();
+    let text = span.as_str().trim_end_matches([' ', '\t']);
+    Span::new(span.start(), span.start() + text.len())
+}
+
+fn parse_program(source: &str) -> Result<Vec<Spanned<Node>>> {
+    if cfg!(feature = "pratt") {
+        crate::pratt::parse(source)
+    } else {
+        parse_with_pest(source)
+    }
+}
+
+/// Parses `source` with the pest grammar, without tracing, whichever parser
+/// [`parse`] uses.
+pub fn parse_with_pest(source: &str) -> Result<Vec<Spanned<Node>>> {
+    let mut ast = vec![];
+    let mut functions = HashSet::new();
+    // makes pest record the tokens it expected, which `syntax_error` describes
+    pest::set_error_detail(true);
+    let pairs =
+        CalcParser::parse(Rule::Program, source).map_err(|err| syntax_error(source, err))?;
+    for pair in pairs {
+        ast.extend(build_ast_from_stmt(pair, &mut functions)?);
     }
     Ok(ast)
 }
 
-fn build_ast_from_expr(pair: pest::iterators::Pair<Rule>) -> Node {
+// parses the one statement in `text`, which may end with its separator
+fn parse_statement(text: &str, functions: &mut HashSet<String>) -> Result<Spanned<Node>> {
+    if cfg!(feature = "pratt") {
+        return crate::pratt::parse_statement(text, functions);
+    }
+    let pairs = CalcParser::parse(Rule::Statement, text).map_err(|err| syntax_error(text, err))?;
+    let mut ast = vec![];
+    for pair in pairs {
+        ast.extend(build_ast_from_stmt(pair, functions)?);
+    }
+    ast.pop()
+        .ok_or_else(|| CalcError::internal("statement without a node"))
+}
+
+// builds a top-level statement, rejecting a function defined twice; other
+// pairs, such as the end of input, build nothing
+fn build_ast_from_stmt(
+    pair: Pair,
+    functions: &mut HashSet<String>,
+) -> Result<Option<Spanned<Node>>> {
+    let node = match pair.as_rule() {
+        Rule::FnDef => {
+            let node = build_ast_from_fn_def(pair)?;
+            define_function(functions, &node)?;
+            node
+        }
+        Rule::Let => build_ast_from_let(pair)?,
+        Rule::Expr => build_ast_from_expr(pair)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(node))
+}
+
+/// Adds the function `node` defines to `functions`, failing when a function of
+/// that name is already defined.
+pub(crate) fn define_function(functions: &mut HashSet<String>, node: &Spanned<Node>) -> Result<()> {
+    if let Node::FnDef { name, .. } = &node.node
+        && !functions.insert(name.clone())
+    {
+        return Err(
+            CalcError::parse(format!("function `{}` is already defined", name)).at(node.span),
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn duplicate_parameter(param: &str, function: &str, span: Span) -> CalcError {
+    CalcError::parse(format!(
+        "duplicate parameter `{}` in function `{}`",
+        param, function
+    ))
+    .at(span)
+}
+
+pub(crate) fn int_literal(text: &str, span: Span) -> Result<i32> {
+    text.parse().map_err(|_| {
+        CalcError::parse(format!(
+            "integer literal `{}` does not fit in 32 bits",
+            text
+        ))
+        .at(span)
+    })
+}
+
+pub(crate) fn float_literal(text: &str, span: Span) -> Result<f32> {
+    text.parse()
+        .map_err(|_| CalcError::parse(format!("invalid float literal `{}`", text)).at(span))
+}
+
+// moves the spans of a statement parsed on its own to where it is in the source
+fn shift(node: &mut Spanned<Node>, offset: usize) {
+    node.span = Span::new(node.span.start + offset, node.span.end + offset);
+    match &mut node.node {
+        Node::Val(_) | Node::Ident(_) | Node::Error => {}
+        Node::Let { value: child, .. }
+        | Node::FnDef { body: child, .. }
+        | Node::UnaryExpr { child, .. } => shift(child, offset),
+        Node::Call { args, .. } => {
+            for arg in args {
+                shift(arg, offset);
+            }
+        }
+        Node::BinaryExpr { lhs, rhs, .. } => {
+            shift(lhs, offset);
+            shift(rhs, offset);
+        }
+        Node::If {
+            cond,
+            then_branch,
+            else_branch,
+        } => {
+            shift(cond, offset);
+            shift(then_branch, offset);
+            shift(else_branch, offset);
+        }
+    }
+}
+
+fn build_ast_from_let(pair: Pair) -> Result<Spanned<Node>> {
+    let span = span_of(&pair);
+    let mut pairs = pair.into_inner().skip_while(|p| p.as_rule() == Rule::LetKw);
+    let name = pairs.next().unwrap().as_str().to_string();
+    let value = build_ast_from_expr(pairs.next().unwrap())?;
+    let value = Box::new(value);
+    Ok(Spanned::new(Node::Let { name, value }, span))
+}
+
+fn build_ast_from_fn_def(pair: Pair) -> Result<Spanned<Node>> {
+    let span = span_of(&pair);
+    let mut pairs = pair.into_inner().skip_while(|p| p.as_rule() == Rule::FnKw);
+    let name = pairs.next().unwrap().as_str().to_string();
+    let mut params: Vec<String> = vec![];
+    for param in pairs.next().unwrap().into_inner() {
+        if params.iter().any(|p| p == param.as_str()) {
+            return Err(duplicate_parameter(param.as_str(), &name, span_of(&param)));
+        }
+        params.push(param.as_str().to_string());
+    }
+    let body = Box::new(build_ast_from_expr(pairs.next().unwrap())?);
+    Ok(Spanned::new(Node::FnDef { name, params, body }, span))
+}
+
+fn build_ast_from_call(pair: Pair) -> Result<Spanned<Node>> {
+    let span = span_of(&pair);
     let mut pairs = pair.into_inner();
-    let mut lhs = build_ast_from_term(pairs.next().unwrap());
+    let name = pairs.next().unwrap().as_str().to_string();
+    let args = pairs.map(build_ast_from_expr).collect::<Result<_>>()?;
+    Ok(Spanned::new(Node::Call { name, args }, span))
+}
 
-    while let Some(op) = pairs.next() {
-        let rhs = build_ast_from_term(pairs.next().unwrap());
-        lhs = parse_binary_expr(op, lhs, rhs);
+fn build_ast_from_expr(pair: Pair) -> Result<Spanned<Node>> {
+    let inner = pair.into_inner().next().unwrap();
+    match inner.as_rule() {
+        Rule::IfExpr => build_ast_from_if(inner),
+        _ => build_ast_from_infix(inner),
     }
-    lhs
 }
 
-fn build_ast_from_term(pair: pest::iterators::Pair<Rule>) -> Node {
+fn build_ast_from_if(pair: Pair) -> Result<Spanned<Node>> {
+    let span = span_of(&pair);
+    let mut exprs = pair
+        .into_inner()
+        .filter(|p| p.as_rule() == Rule::Expr)
+        .map(build_ast_from_expr);
+    let node = Node::If {
+        cond: Box::new(exprs.next().unwrap()?),
+        then_branch: Box::new(exprs.next().unwrap()?),
+        else_branch: Box::new(exprs.next().unwrap()?),
+    };
+    Ok(Spanned::new(node, span))
+}
+
+// builds one precedence level (`OrExpr` down to `Term`) as a left-associative
+// chain; each operation spans from the start of the chain to the end of its
+// rhs, parentheses included
+fn build_ast_from_infix(pair: Pair) -> Result<Spanned<Node>> {
+    let start = pair.as_span().start();
     let mut pairs = pair.into_inner();
-    let mut lhs = build_ast_from_factor(pairs.next().unwrap());
+    let mut lhs = build_ast_from_operand(pairs.next().unwrap())?;
 
     while let Some(op) = pairs.next() {
-        let rhs = build_ast_from_factor(pairs.next().unwrap());
-        lhs = parse_binary_expr(op, lhs, rhs);
+        let rhs_pair = pairs.next().unwrap();
+        let span = Span::new(start, span_of(&rhs_pair).end);
+        let rhs = build_ast_from_operand(rhs_pair)?;
+        lhs = Spanned::new(parse_binary_expr(op, lhs, rhs), span);
+    }
+    Ok(lhs)
+}
+
+fn build_ast_from_operand(pair: Pair) -> Result<Spanned<Node>> {
+    match pair.as_rule() {
+        Rule::Factor => build_ast_from_factor(pair),
+        _ => build_ast_from_infix(pair),
     }
-    lhs
 }
 
-fn build_ast_from_factor(pair: pest::iterators::Pair<Rule>) -> Node {
+fn build_ast_from_factor(pair: Pair) -> Result<Spanned<Node>> {
     match pair.as_rule() {
         Rule::Factor => {
             let inner = pair.into_inner().next().unwrap();
             build_ast_from_factor(inner)
         }
         Rule::UnaryExpr => {
+            let span = span_of(&pair);
             let mut inner = pair.into_inner();
             let op_pair = inner.next().unwrap();
             let child = inner.next().unwrap();
-            let child_node = build_ast_from_factor(child);
-
-            let op_node = Node::UnaryExpr {
-                op: match op_pair.as_str() {
-                    "+" => Operator::Plus,
-                    "-" => Operator::Minus,
-                    _ => unreachable!(),
-                },
-                child: Box::new(child_node),
-            };
-            op_node
+            let child_node = build_ast_from_factor(child)?;
+            Ok(Spanned::new(parse_unary_expr(op_pair, child_node), span))
+        }
+        // `a ^ b` spans the whole `Power`, and `b` is a `Factor`, which makes
+        // `^` right-associative
+        Rule::Power => {
+            let span = span_of(&pair);
+            let mut inner = pair.into_inner();
+            let base = build_ast_from_factor(inner.next().unwrap())?;
+            match (inner.next(), inner.next()) {
+                (Some(op), Some(exponent)) => {
+                    let exponent = build_ast_from_factor(exponent)?;
+                    Ok(Spanned::new(parse_binary_expr(op, base, exponent), span))
+                }
+                _ => Ok(base),
+            }
         }
         Rule::Primary => {
             let inner = pair.into_inner().next().unwrap();
             build_ast_from_primary(inner)
         }
-        rule => build_ast_from_primary(pair),
+        _ => build_ast_from_primary(pair),
     }
 }
 
-fn build_ast_from_primary(pair: pest::iterators::Pair<Rule>) -> Node {
-    match pair.as_rule() {
-        Rule::Int => {
-            let int: i32 = pair.as_str().parse().unwrap();
-            Node::Val(Val::Int(int))
+fn build_ast_from_primary(pair: Pair) -> Result<Spanned<Node>> {
+    let span = span_of(&pair);
+    let node = match pair.as_rule() {
+        Rule::Int => Node::Val(Val::Int(int_literal(pair.as_str(), span)?)),
+        Rule::Float => Node::Val(Val::Float(OrderedFloat(float_literal(
+            pair.as_str(),
+            span,
+        )?))),
+        Rule::Bool => Node::Val(Val::Bool(pair.as_str() == "true")),
+        Rule::Ident => Node::Ident(pair.as_str().to_string()),
+        Rule::Call => return build_ast_from_call(pair),
+        // a parenthesized expression keeps the span of what is inside
+        Rule::Expr => return build_ast_from_expr(pair),
+        unknown => {
+            return Err(CalcError::internal(format!("unexpected primary {:?}", unknown)).at(span));
         }
-        Rule::Float => {
-            let num: f32 = pair.as_str().parse().expect("Invalid Float Parsing");
-            Node::Val(Val::Float(OrderedFloat(num)))
-        }
-        Rule::Expr => build_ast_from_expr(pair),
-        unknown => panic!("Unknown primary: {:?}", unknown),
-    }
+    };
+    Ok(Spanned::new(node, span))
 }
 
-fn parse_unary_expr(pair: pest::iterators::Pair<Rule>, child: Node) -> Node {
+fn parse_unary_expr(pair: Pair, child: Spanned<Node>) -> Node {
     Node::UnaryExpr {
         op: match pair.as_str() {
             "+" => Operator::Plus,
             "-" => Operator::Minus,
+            "!" => Operator::Not,
             _ => unreachable!(),
         },
         child: Box::new(child),
     }
 }
 
-fn parse_binary_expr(pair: pest::iterators::Pair<Rule>, lhs: Node, rhs: Node) -> Node {
+fn parse_binary_expr(pair: Pair, lhs: Spanned<Node>, rhs: Spanned<Node>) -> Node {
     Node::BinaryExpr {
         op: match pair.as_str() {
             "+" => Operator::Plus,
             "-" => Operator::Minus,
             "*" => Operator::Multiply,
             "/" => Operator::Divide,
+            "//" => Operator::FloorDivide,
+            "%" => Operator::Modulo,
+            "^" => Operator::Power,
+            "==" => Operator::Equal,
+            "!=" => Operator::NotEqual,
+            "<" => Operator::Less,
+            "<=" => Operator::LessEqual,
+            ">" => Operator::Greater,
+            ">=" => Operator::GreaterEqual,
+            "&&" => Operator::And,
+            "||" => Operator::Or,
             _ => unreachable!("Unrecognised Operator"),
         },
         lhs: Box::new(lhs),
@@ -121,17 +519,18 @@ mod tests {
     fn test_simple_integer() {
         let result = parse("42").unwrap();
         assert_eq!(result.len(), 1);
-        match &result[0] {
+        match &result[0].node {
             Node::Val(Val::Int(n)) => assert_eq!(*n, 42),
             _ => panic!("Expected Int"),
         }
     }
 
     #[test]
+    #[allow(clippy::approx_constant)]
     fn test_simple_float() {
         let result = parse("3.14").unwrap();
         assert_eq!(result.len(), 1);
-        match &result[0] {
+        match &result[0].node {
             Node::Val(Val::Float(n)) => assert_eq!(n.0, 3.14),
             _ => panic!("Expected Float"),
         }
@@ -141,11 +540,11 @@ mod tests {
     fn test_addition() {
         let result = parse("1 + 2").unwrap();
         assert_eq!(result.len(), 1);
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, lhs, rhs } => {
                 assert_eq!(*op, Operator::Plus);
-                assert!(matches!(**lhs, Node::Val(Val::Int(1))));
-                assert!(matches!(**rhs, Node::Val(Val::Int(2))));
+                assert!(matches!(lhs.node, Node::Val(Val::Int(1))));
+                assert!(matches!(rhs.node, Node::Val(Val::Int(2))));
             }
             _ => panic!("Expected BinaryExpr"),
         }
@@ -155,7 +554,7 @@ mod tests {
     fn test_subtraction() {
         let result = parse("10 - 3").unwrap();
         assert_eq!(result.len(), 1);
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, .. } => assert_eq!(*op, Operator::Minus),
             _ => panic!("Expected BinaryExpr"),
         }
@@ -165,7 +564,7 @@ mod tests {
     fn test_multiplication() {
         let result = parse("4 * 5").unwrap();
         assert_eq!(result.len(), 1);
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, .. } => assert_eq!(*op, Operator::Multiply),
             _ => panic!("Expected BinaryExpr"),
         }
@@ -175,20 +574,45 @@ mod tests {
     fn test_division() {
         let result = parse("20 / 4").unwrap();
         assert_eq!(result.len(), 1);
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, .. } => assert_eq!(*op, Operator::Divide),
             _ => panic!("Expected BinaryExpr"),
         }
     }
 
+    #[test]
+    fn test_floor_division_and_modulo() {
+        // 1 + 7 // 2 % 3 should parse as 1 + ((7 // 2) % 3)
+        let result = parse("1 + 7 // 2 % 3").unwrap();
+        match &result[0].node {
+            Node::BinaryExpr { op, rhs, .. } => {
+                assert_eq!(*op, Operator::Plus);
+                match &rhs.node {
+                    Node::BinaryExpr { op, lhs, .. } => {
+                        assert_eq!(*op, Operator::Modulo);
+                        assert!(matches!(
+                            lhs.node,
+                            Node::BinaryExpr {
+                                op: Operator::FloorDivide,
+                                ..
+                            }
+                        ));
+                    }
+                    _ => panic!("Expected nested modulo"),
+                }
+            }
+            _ => panic!("Expected BinaryExpr"),
+        }
+    }
+
     #[test]
     fn test_unary_minus() {
         let result = parse("-5").unwrap();
         assert_eq!(result.len(), 1);
-        match &result[0] {
+        match &result[0].node {
             Node::UnaryExpr { op, child } => {
                 assert_eq!(*op, Operator::Minus);
-                assert!(matches!(**child, Node::Val(Val::Int(5))));
+                assert!(matches!(child.node, Node::Val(Val::Int(5))));
             }
             _ => panic!("Expected UnaryExpr"),
         }
@@ -198,7 +622,7 @@ mod tests {
     fn test_unary_plus() {
         let result = parse("+3").unwrap();
         assert_eq!(result.len(), 1);
-        match &result[0] {
+        match &result[0].node {
             Node::UnaryExpr { op, .. } => assert_eq!(*op, Operator::Plus),
             _ => panic!("Expected UnaryExpr"),
         }
@@ -208,15 +632,15 @@ mod tests {
     fn test_precedence_multiply_before_add() {
         // 2 + 3 * 4 should parse as 2 + (3 * 4)
         let result = parse("2 + 3 * 4").unwrap();
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, lhs, rhs } => {
                 assert_eq!(*op, Operator::Plus);
-                assert!(matches!(**lhs, Node::Val(Val::Int(2))));
-                match &**rhs {
+                assert!(matches!(lhs.node, Node::Val(Val::Int(2))));
+                match &rhs.node {
                     Node::BinaryExpr { op, lhs, rhs } => {
                         assert_eq!(*op, Operator::Multiply);
-                        assert!(matches!(**lhs, Node::Val(Val::Int(3))));
-                        assert!(matches!(**rhs, Node::Val(Val::Int(4))));
+                        assert!(matches!(lhs.node, Node::Val(Val::Int(3))));
+                        assert!(matches!(rhs.node, Node::Val(Val::Int(4))));
                     }
                     _ => panic!("Expected nested multiplication"),
                 }
@@ -229,11 +653,11 @@ mod tests {
     fn test_precedence_divide_before_subtract() {
         // 10 - 6 / 2 should parse as 10 - (6 / 2)
         let result = parse("10 - 6 / 2").unwrap();
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, lhs, rhs } => {
                 assert_eq!(*op, Operator::Minus);
-                assert!(matches!(**lhs, Node::Val(Val::Int(10))));
-                match &**rhs {
+                assert!(matches!(lhs.node, Node::Val(Val::Int(10))));
+                match &rhs.node {
                     Node::BinaryExpr { op, .. } => {
                         assert_eq!(*op, Operator::Divide);
                     }
@@ -248,15 +672,15 @@ mod tests {
     fn test_left_associativity_addition() {
         // 1 + 2 + 3 should parse as (1 + 2) + 3
         let result = parse("1 + 2 + 3").unwrap();
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, lhs, rhs } => {
                 assert_eq!(*op, Operator::Plus);
-                assert!(matches!(**rhs, Node::Val(Val::Int(3))));
-                match &**lhs {
+                assert!(matches!(rhs.node, Node::Val(Val::Int(3))));
+                match &lhs.node {
                     Node::BinaryExpr { op, lhs, rhs } => {
                         assert_eq!(*op, Operator::Plus);
-                        assert!(matches!(**lhs, Node::Val(Val::Int(1))));
-                        assert!(matches!(**rhs, Node::Val(Val::Int(2))));
+                        assert!(matches!(lhs.node, Node::Val(Val::Int(1))));
+                        assert!(matches!(rhs.node, Node::Val(Val::Int(2))));
                     }
                     _ => panic!("Expected nested addition"),
                 }
@@ -269,11 +693,11 @@ mod tests {
     fn test_left_associativity_multiplication() {
         // 2 * 3 * 4 should parse as (2 * 3) * 4
         let result = parse("2 * 3 * 4").unwrap();
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, lhs, rhs } => {
                 assert_eq!(*op, Operator::Multiply);
-                assert!(matches!(**rhs, Node::Val(Val::Int(4))));
-                match &**lhs {
+                assert!(matches!(rhs.node, Node::Val(Val::Int(4))));
+                match &lhs.node {
                     Node::BinaryExpr { op, .. } => {
                         assert_eq!(*op, Operator::Multiply);
                     }
@@ -284,19 +708,51 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_power() {
+        let pow = |lhs: Node, rhs: Node| Node::BinaryExpr {
+            op: Operator::Power,
+            lhs: Box::new(lhs.into()),
+            rhs: Box::new(rhs.into()),
+        };
+        let int = |n| Node::Val(Val::Int(n));
+        let minus = |child: Node| Node::UnaryExpr {
+            op: Operator::Minus,
+            child: Box::new(child.into()),
+        };
+
+        // 2 ^ 3 ^ 2 should parse as 2 ^ (3 ^ 2)
+        let result = parse("2 ^ 3 ^ 2").unwrap();
+        assert_eq!(result[0].node, pow(int(2), pow(int(3), int(2))));
+        assert_eq!(result[0].span, Span::new(0, 9));
+
+        // -2 ^ 2 should parse as -(2 ^ 2), and the exponent may be negated
+        let result = parse("-2 ^ -2").unwrap();
+        assert_eq!(result[0].node, minus(pow(int(2), minus(int(2)))));
+
+        // 2 * 3 ^ 2 should parse as 2 * (3 ^ 2)
+        let result = parse("2 * 3 ^ 2").unwrap();
+        let Node::BinaryExpr { op, rhs, .. } = &result[0].node else {
+            panic!("expected a product, got {:?}", result[0]);
+        };
+        assert_eq!(*op, Operator::Multiply);
+        assert_eq!(rhs.node, pow(int(3), int(2)));
+        assert_eq!(rhs.span, Span::new(4, 9));
+    }
+
     #[test]
     fn test_parentheses_override_precedence() {
         // (2 + 3) * 4 should parse as (2 + 3) * 4
         let result = parse("(2 + 3) * 4").unwrap();
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, lhs, rhs } => {
                 assert_eq!(*op, Operator::Multiply);
-                assert!(matches!(**rhs, Node::Val(Val::Int(4))));
-                match &**lhs {
+                assert!(matches!(rhs.node, Node::Val(Val::Int(4))));
+                match &lhs.node {
                     Node::BinaryExpr { op, lhs, rhs } => {
                         assert_eq!(*op, Operator::Plus);
-                        assert!(matches!(**lhs, Node::Val(Val::Int(2))));
-                        assert!(matches!(**rhs, Node::Val(Val::Int(3))));
+                        assert!(matches!(lhs.node, Node::Val(Val::Int(2))));
+                        assert!(matches!(rhs.node, Node::Val(Val::Int(3))));
                     }
                     _ => panic!("Expected nested addition"),
                 }
@@ -308,7 +764,7 @@ mod tests {
     #[test]
     fn test_nested_parentheses() {
         let result = parse("((1 + 2))").unwrap();
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, .. } => assert_eq!(*op, Operator::Plus),
             _ => panic!("Expected BinaryExpr"),
         }
@@ -318,11 +774,11 @@ mod tests {
     fn test_unary_with_binary() {
         // -1 + 2
         let result = parse("-1 + 2").unwrap();
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, lhs, rhs } => {
                 assert_eq!(*op, Operator::Plus);
-                assert!(matches!(**lhs, Node::UnaryExpr { .. }));
-                assert!(matches!(**rhs, Node::Val(Val::Int(2))));
+                assert!(matches!(lhs.node, Node::UnaryExpr { .. }));
+                assert!(matches!(rhs.node, Node::Val(Val::Int(2))));
             }
             _ => panic!("Expected BinaryExpr"),
         }
@@ -332,11 +788,11 @@ mod tests {
     fn test_unary_in_middle() {
         // 5 + -3
         let result = parse("5 + -3").unwrap();
-        match &result[0] {
+        match &result[0].node {
             Node::BinaryExpr { op, lhs, rhs } => {
                 assert_eq!(*op, Operator::Plus);
-                assert!(matches!(**lhs, Node::Val(Val::Int(5))));
-                assert!(matches!(**rhs, Node::UnaryExpr { .. }));
+                assert!(matches!(lhs.node, Node::Val(Val::Int(5))));
+                assert!(matches!(rhs.node, Node::UnaryExpr { .. }));
             }
             _ => panic!("Expected BinaryExpr"),
         }
@@ -347,14 +803,14 @@ mod tests {
         let result = parse("3.14 * 2.0 + 1.5 / 3.0").unwrap();
         assert_eq!(result.len(), 1);
         // Just verify it parses without panicking
-        assert!(matches!(result[0], Node::BinaryExpr { .. }));
+        assert!(matches!(result[0].node, Node::BinaryExpr { .. }));
     }
 
     #[test]
     fn test_complex_expression() {
         let result = parse("10 + 2 * (6 - 4) / 2").unwrap();
         assert_eq!(result.len(), 1);
-        assert!(matches!(result[0], Node::BinaryExpr { .. }));
+        assert!(matches!(result[0].node, Node::BinaryExpr { .. }));
     }
 
     #[test]
@@ -364,9 +820,9 @@ mod tests {
         let result3 = parse("1  +  2").unwrap();
 
         // All should parse to the same structure
-        assert!(matches!(result1[0], Node::BinaryExpr { .. }));
-        assert!(matches!(result2[0], Node::BinaryExpr { .. }));
-        assert!(matches!(result3[0], Node::BinaryExpr { .. }));
+        assert!(matches!(result1[0].node, Node::BinaryExpr { .. }));
+        assert!(matches!(result2[0].node, Node::BinaryExpr { .. }));
+        assert!(matches!(result3[0].node, Node::BinaryExpr { .. }));
     }
 
     #[test]
@@ -386,6 +842,372 @@ mod tests {
     fn test_multiple_operators() {
         let result = parse("1 + 2 - 3 * 4 / 5").unwrap();
         assert_eq!(result.len(), 1);
-        assert!(matches!(result[0], Node::BinaryExpr { .. }));
+        assert!(matches!(result[0].node, Node::BinaryExpr { .. }));
+    }
+
+    #[test]
+    fn test_let_binding() {
+        let result = parse("let x = 2 * 3; x + 1").unwrap();
+        assert_eq!(result.len(), 2);
+        match &result[0].node {
+            Node::Let { name, value } => {
+                assert_eq!(name, "x");
+                assert!(matches!(value.node, Node::BinaryExpr { .. }));
+            }
+            _ => panic!("Expected Let"),
+        }
+        match &result[1].node {
+            Node::BinaryExpr { lhs, .. } => {
+                assert!(matches!(&lhs.node, Node::Ident(name) if name == "x"));
+            }
+            _ => panic!("Expected BinaryExpr"),
+        }
+    }
+
+    #[test]
+    fn test_identifiers() {
+        let result = parse("_tmp1 * letter").unwrap();
+        match &result[0].node {
+            Node::BinaryExpr { lhs, rhs, .. } => {
+                assert!(matches!(&lhs.node, Node::Ident(name) if name == "_tmp1"));
+                assert!(matches!(&rhs.node, Node::Ident(name) if name == "letter"));
+            }
+            _ => panic!("Expected BinaryExpr"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_let() {
+        assert!(parse("let = 1; 2").is_err());
+        assert!(parse("let let = 1; 2").is_err());
+        assert!(parse("let 1x = 1; 2").is_err());
+        assert!(parse("let x = 1 2").is_err());
+    }
+
+    #[test]
+    fn test_statement_separators() {
+        let result = parse("let x = 1; x + 1\nx * 2\r\n\n3;").unwrap();
+        assert_eq!(result.len(), 4);
+        assert!(matches!(result[0].node, Node::Let { .. }));
+        assert!(matches!(result[3].node, Node::Val(Val::Int(3))));
+
+        let result = parse("\n\n;1;;\n").unwrap();
+        assert_eq!(result.len(), 1);
+    }
+
+    #[test]
+    fn test_invalid_statements() {
+        assert!(parse(";").is_err());
+        assert!(parse("\n").is_err());
+        assert!(parse("1 +\n2").is_err());
+    }
+
+    #[test]
+    fn test_function_definition() {
+        let result = parse("fn area(w, h) = w * h; area(3, 4)").unwrap();
+        assert_eq!(result.len(), 2);
+        match &result[0].node {
+            Node::FnDef { name, params, body } => {
+                assert_eq!(name, "area");
+                assert_eq!(params, &["w", "h"]);
+                assert!(matches!(body.node, Node::BinaryExpr { .. }));
+            }
+            _ => panic!("Expected FnDef"),
+        }
+        match &result[1].node {
+            Node::Call { name, args } => {
+                assert_eq!(name, "area");
+                assert_eq!(args, &[Node::Val(Val::Int(3)), Node::Val(Val::Int(4))]);
+            }
+            _ => panic!("Expected Call"),
+        }
+    }
+
+    #[test]
+    fn test_calls_in_expressions() {
+        let result = parse("fn one() = 1; 2 * one() + f(1 + 2, g(x))").unwrap();
+        match &result[0].node {
+            Node::FnDef { params, .. } => assert!(params.is_empty()),
+            _ => panic!("Expected FnDef"),
+        }
+        match &result[1].node {
+            Node::BinaryExpr { op, rhs, .. } => {
+                assert_eq!(*op, Operator::Plus);
+                match &rhs.node {
+                    Node::Call { name, args } => {
+                        assert_eq!(name, "f");
+                        assert_eq!(args.len(), 2);
+                        assert!(matches!(&args[1].node, Node::Call { name, .. } if name == "g"));
+                    }
+                    _ => panic!("Expected Call"),
+                }
+            }
+            _ => panic!("Expected BinaryExpr"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_functions() {
+        assert!(parse("fn f(x, x) = x; 1").is_err());
+        assert!(parse("fn f(x) = x; fn f(y) = y; 1").is_err());
+        assert!(parse("fn fn(x) = x; 1").is_err());
+        assert!(parse("fn f(x = x; 1").is_err());
+        assert!(parse("1 + fn f(x) = x").is_err());
+        assert!(parse("f(1,)").is_err());
+    }
+
+    #[test]
+    fn test_bools() {
+        let result = parse("true; false; !true").unwrap();
+        assert_eq!(result[0], Node::Val(Val::Bool(true)));
+        assert_eq!(result[1], Node::Val(Val::Bool(false)));
+        match &result[2].node {
+            Node::UnaryExpr { op, child } => {
+                assert_eq!(*op, Operator::Not);
+                assert!(matches!(child.node, Node::Val(Val::Bool(true))));
+            }
+            _ => panic!("Expected UnaryExpr"),
+        }
+        assert!(
+            matches!(&parse("trueish").unwrap()[0].node, Node::Ident(name) if name == "trueish")
+        );
+    }
+
+    #[test]
+    fn test_comparison_precedence() {
+        // 1 + 2 < 4 == true should parse as ((1 + 2) < 4) == true
+        let result = parse("1 + 2 < 4 == true").unwrap();
+        match &result[0].node {
+            Node::BinaryExpr { op, lhs, rhs } => {
+                assert_eq!(*op, Operator::Equal);
+                assert!(matches!(rhs.node, Node::Val(Val::Bool(true))));
+                match &lhs.node {
+                    Node::BinaryExpr { op, lhs, .. } => {
+                        assert_eq!(*op, Operator::Less);
+                        assert!(matches!(
+                            lhs.node,
+                            Node::BinaryExpr {
+                                op: Operator::Plus,
+                                ..
+                            }
+                        ));
+                    }
+                    _ => panic!("Expected nested comparison"),
+                }
+            }
+            _ => panic!("Expected BinaryExpr"),
+        }
+    }
+
+    #[test]
+    fn test_logical_precedence() {
+        // a || b && c should parse as a || (b && c)
+        let result = parse("a || b && !c").unwrap();
+        match &result[0].node {
+            Node::BinaryExpr { op, lhs, rhs } => {
+                assert_eq!(*op, Operator::Or);
+                assert!(matches!(lhs.node, Node::Ident(_)));
+                assert!(matches!(
+                    rhs.node,
+                    Node::BinaryExpr {
+                        op: Operator::And,
+                        ..
+                    }
+                ));
+            }
+            _ => panic!("Expected BinaryExpr"),
+        }
+        for (src, op) in [
+            ("1 != 2", Operator::NotEqual),
+            ("1 <= 2", Operator::LessEqual),
+            ("1 >= 2", Operator::GreaterEqual),
+            ("1 > 2", Operator::Greater),
+        ] {
+            assert!(
+                matches!(parse(src).unwrap()[0].node, Node::BinaryExpr { op: o, .. } if o == op)
+            );
+        }
+    }
+
+    #[test]
+    fn test_if_expression() {
+        let result = parse("if x > 0 then x else -x").unwrap();
+        match &result[0].node {
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                assert!(matches!(
+                    cond.node,
+                    Node::BinaryExpr {
+                        op: Operator::Greater,
+                        ..
+                    }
+                ));
+                assert!(matches!(then_branch.node, Node::Ident(_)));
+                assert!(matches!(else_branch.node, Node::UnaryExpr { .. }));
+            }
+            _ => panic!("Expected If"),
+        }
+        // the else branch extends as far as possible
+        let result = parse("if a then 1 else 2 + 3").unwrap();
+        assert!(matches!(
+            &result[0].node,
+            Node::If { else_branch, .. } if matches!(else_branch.node, Node::BinaryExpr { .. })
+        ));
+        assert!(parse("1 + (if a then 1 else 2)").is_ok());
+    }
+
+    #[test]
+    fn test_invalid_if() {
+        assert!(parse("if a then 1").is_err());
+        assert!(parse("if a 1 else 2").is_err());
+        assert!(parse("ifa then 1 else 2").is_err());
+        assert!(parse("1 + if a then 1 else 2").is_err());
+        assert!(parse("let then = 1; then").is_err());
+    }
+
+    #[test]
+    fn test_error_spans() {
+        let err = parse("1 + ").unwrap_err();
+        assert!(matches!(err, CalcError::Parse { .. }));
+        assert_eq!(err.span(), Span::new(4, 4));
+
+        let err = parse("fn f(x, x) = x; 1").unwrap_err();
+        assert_eq!(err.to_string(), "duplicate parameter `x` in function `f`");
+        assert_eq!(err.span(), Span::new(8, 9));
+
+        let err = parse("1 + 3000000000").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "integer literal `3000000000` does not fit in 32 bits"
+        );
+        assert_eq!(err.span(), Span::new(4, 14));
+    }
+
+    #[test]
+    fn test_error_messages() {
+        let message = |source| parse(source).unwrap_err().to_string();
+        assert_eq!(
+            message("1 + "),
+            "expected a number, a name or `(` after `+`"
+        );
+        assert_eq!(message("(1 + 2"), "expected an operator or `)` after `2`");
+        assert_eq!(message("let x 3"), "expected `=` after `x`");
+        assert_eq!(message("fn (x) = 1"), "expected a name after `fn`");
+        assert_eq!(message("fn f(x = x"), "expected `)` or `,` after `x`");
+        assert_eq!(
+            message("if 1 then 2"),
+            "expected an operator or `else` after `2`"
+        );
+        assert_eq!(message("1."), "expected a digit after `.`");
+        assert_eq!(message(""), "expected a statement");
+        assert_eq!(
+            message("1 $ 2"),
+            "expected an operator, `;` or a new line after `1`"
+        );
+
+        // the span is the token found instead, or empty at the end of a line
+        let err = parse("let x = 1\nlet y 2\n").unwrap_err();
+        assert_eq!(err.span(), Span::new(16, 17));
+        assert_eq!(parse("1 +\n").unwrap_err().span(), Span::new(3, 3));
+    }
+
+    #[test]
+    fn test_recovery() {
+        let source = "let x = 1 +\nlet y = 2\nfn f(a = a; y * 3 $ 1\n\n  f(y)  ";
+        let recovered = parse_with_recovery(source);
+        assert_eq!(
+            recovered.ast,
+            vec![
+                Node::Error,
+                Node::Let {
+                    name: "y".to_string(),
+                    value: Node::Val(Val::Int(2)).into(),
+                },
+                Node::Error,
+                Node::Error,
+                Node::Call {
+                    name: "f".to_string(),
+                    args: vec![Node::Ident("y".to_string()).into()],
+                },
+            ]
+        );
+        let spans: Vec<_> = recovered.ast.iter().map(|node| node.span).collect();
+        assert_eq!(
+            spans,
+            vec![
+                Span::new(0, 11),
+                Span::new(12, 21),
+                Span::new(22, 32),
+                Span::new(34, 43),
+                Span::new(47, 51),
+            ]
+        );
+        let errors: Vec<_> = recovered
+            .errors
+            .iter()
+            .map(|err| (err.to_string(), err.span()))
+            .collect();
+        assert_eq!(
+            errors,
+            vec![
+                (
+                    "expected a number, a name or `(` after `+`".to_string(),
+                    Span::new(11, 11)
+                ),
+                (
+                    "expected `)` or `,` after `a`".to_string(),
+                    Span::new(29, 30)
+                ),
+                (
+                    "expected an operator, `;` or a new line after `3`".to_string(),
+                    Span::new(40, 41)
+                ),
+            ]
+        );
+        assert_eq!(recovered.errors[0], parse(source).unwrap_err());
+
+        // errors found after the grammar matched are recovered from too
+        let recovered = parse_with_recovery("fn f() = 1; fn f() = 2; 3000000000; f()");
+        assert_eq!(recovered.ast.len(), 4);
+        assert_eq!(recovered.errors.len(), 2);
+        assert_eq!(recovered.errors[0].span(), Span::new(12, 22));
+        assert_eq!(recovered.errors[1].span(), Span::new(24, 34));
+
+        let ok = parse_with_recovery("let a = 1; a");
+        assert_eq!(ok.ast, parse("let a = 1; a").unwrap());
+        assert!(ok.errors.is_empty());
+        assert_eq!(
+            parse_with_recovery(" ;\n").errors,
+            vec![parse(" ;\n").unwrap_err()]
+        );
+    }
+
+    #[test]
+    fn test_node_spans() {
+        let result = parse("let x = 1\n(x + 2) * -x").unwrap();
+        assert_eq!(result[0].span, Span::new(0, 9));
+        let product = &result[1];
+        assert_eq!(product.span, Span::new(10, 22));
+        let Node::BinaryExpr { lhs, rhs, .. } = &product.node else {
+            panic!("expected a product, got {:?}", product);
+        };
+        // a parenthesized expression keeps the span of what is inside
+        assert_eq!(lhs.span, Span::new(11, 16));
+        assert_eq!(rhs.span, Span::new(20, 22));
+
+        let result = parse("fn f(a) = a; f(1, 2)").unwrap();
+        let Node::Call { args, .. } = &result[1].node else {
+            panic!("expected a call, got {:?}", result[1]);
+        };
+        assert_eq!(result[1].span, Span::new(13, 20));
+        assert_eq!(args[1].span, Span::new(18, 19));
+
+        // no span ends with the spaces after it
+        let result = parse("let b = 1 < 2  ; b ^ 2 ").unwrap();
+        assert_eq!(result[0].span, Span::new(0, 13));
+        assert_eq!(result[1].span, Span::new(17, 22));
     }
 }
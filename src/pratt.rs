@@ -0,0 +1,824 @@
+//! A hand-written parser for the language of `grammar.pest`, which builds the
+//! same AST with the same spans and reports the same syntax errors, except
+//! right after a keyword, where pest looks further ahead.
+//! Binary operators are parsed by precedence climbing over [`INFIX`], a table
+//! of how tightly each operator binds and which way it groups, rather than
+//! one grammar rule per precedence level.
+//!
+//! The pest parser is the default; building with `--features pratt` makes
+//! [`parser::parse`](crate::parser::parse) use this one instead, and
+//! `cargo bench --bench parser` compares the two.
+//!
+//! ```
+//! use calculator::{parser, pratt};
+//!
+//! let source = "let x = 2\n-x ^ 2 ^ 3 + (x * 4)";
+//! assert_eq!(pratt::parse(source), parser::parse_with_pest(source));
+//! ```
+
+use std::collections::HashSet;
+
+use ordered_float::OrderedFloat;
+
+use crate::Result;
+use crate::ast::{Node, Operator, Spanned};
+use crate::error::{CalcError, Span};
+use crate::parser::{self, OPERATORS};
+use crate::val::Val;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Assoc {
+    Left,
+    Right,
+}
+
+/// The binary operators, loosest first, with how tightly each binds and
+/// which way a chain of operators that bind as tightly groups: `1 - 2 - 3` is
+/// `(1 - 2) - 3`, while `2 ^ 3 ^ 2` is `2 ^ (3 ^ 2)`.
+const INFIX: [(&str, u8, Assoc, Operator); 15] = [
+    ("||", 1, Assoc::Left, Operator::Or),
+    ("&&", 2, Assoc::Left, Operator::And),
+    ("==", 3, Assoc::Left, Operator::Equal),
+    ("!=", 3, Assoc::Left, Operator::NotEqual),
+    ("<", 4, Assoc::Left, Operator::Less),
+    ("<=", 4, Assoc::Left, Operator::LessEqual),
+    (">", 4, Assoc::Left, Operator::Greater),
+    (">=", 4, Assoc::Left, Operator::GreaterEqual),
+    ("+", 5, Assoc::Left, Operator::Plus),
+    ("-", 5, Assoc::Left, Operator::Minus),
+    ("*", 6, Assoc::Left, Operator::Multiply),
+    ("/", 6, Assoc::Left, Operator::Divide),
+    ("//", 6, Assoc::Left, Operator::FloorDivide),
+    ("%", 6, Assoc::Left, Operator::Modulo),
+    ("^", 7, Assoc::Right, Operator::Power),
+];
+
+/// How tightly the operand of a unary operator binds: only `^` binds tighter,
+/// so `-2 ^ 2` is `-(2 ^ 2)` but `-2 * 3` is `(-2) * 3`.
+const PREFIX: u8 = 7;
+
+const KEYWORDS: [&str; 7] = ["let", "fn", "if", "then", "else", "true", "false"];
+
+// the tokens an operand may start with, written the way pest writes them
+const OPERAND: [&str; 10] = [
+    "!", "(", "+", "-", "0..9", "A..Z", "_", "a..z", "false", "true",
+];
+const NAME: [&str; 3] = ["A..Z", "_", "a..z"];
+const SEPARATORS: [&str; 4] = ["\n", "\r", "\r\n", ";"];
+
+/// Parses a program, failing at the first error, like
+/// [`parser::parse_with_pest`].
+pub fn parse(source: &str) -> Result<Vec<Spanned<Node>>> {
+    let mut functions = HashSet::new();
+    let mut parser = Parser::new(source, &mut functions);
+    let ast = parser.program();
+    parser.finish(ast)
+}
+
+// parses the one statement in `text`, which may end with its separator
+pub(crate) fn parse_statement(
+    text: &str,
+    functions: &mut HashSet<String>,
+) -> Result<Spanned<Node>> {
+    let mut parser = Parser::new(text, functions);
+    let node = parser.statement_alone();
+    parser.finish(node)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token<'a> {
+    Int(&'a str),
+    Float(&'a str),
+    Name(&'a str),
+    Keyword(&'a str),
+    Symbol(&'static str),
+    /// `;` or a line break.
+    Separator,
+    /// Digits and a `.` that no digit follows, which pest expects one after.
+    BadFloat,
+    Unknown,
+    End,
+}
+
+// every symbol, two-character ones first so that `//` is not read as `/`
+const SYMBOLS: [&str; 20] = [
+    "//", "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "^", "<", ">", "!", "(",
+    ")", ",", "=",
+];
+
+// the token that starts at or after `pos`, past any spaces and tabs
+fn lex(source: &str, pos: usize) -> (Token<'_>, Span) {
+    let bytes = source.as_bytes();
+    let start = pos
+        + bytes[pos..]
+            .iter()
+            .take_while(|&&b| b == b' ' || b == b'\t')
+            .count();
+    let rest = &source[start..];
+    let run = |from: usize, pred: fn(&u8) -> bool| {
+        from + rest.as_bytes()[from..]
+            .iter()
+            .take_while(|b| pred(b))
+            .count()
+    };
+    let (token, len) = match rest.as_bytes().first() {
+        None => (Token::End, 0),
+        Some(b'\r') if rest.starts_with("\r\n") => (Token::Separator, 2),
+        Some(b';' | b'\n' | b'\r') => (Token::Separator, 1),
+        Some(b) if b.is_ascii_digit() => {
+            let digits = run(0, u8::is_ascii_digit);
+            match rest.as_bytes().get(digits) {
+                Some(b'.') => {
+                    let end = run(digits + 1, u8::is_ascii_digit);
+                    if end == digits + 1 {
+                        (Token::BadFloat, end)
+                    } else {
+                        (Token::Float(&rest[..end]), end)
+                    }
+                }
+                _ => (Token::Int(&rest[..digits]), digits),
+            }
+        }
+        Some(b) if b.is_ascii_alphabetic() || *b == b'_' => {
+            let end = run(1, |b| b.is_ascii_alphanumeric() || *b == b'_');
+            let word = &rest[..end];
+            if KEYWORDS.contains(&word) {
+                (Token::Keyword(word), end)
+            } else {
+                (Token::Name(word), end)
+            }
+        }
+        Some(_) => match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+            Some(&symbol) => (Token::Symbol(symbol), symbol.len()),
+            None => (
+                Token::Unknown,
+                rest.chars().next().map_or(0, char::len_utf8),
+            ),
+        },
+    };
+    (token, Span::new(start, start + len))
+}
+
+/// What may follow an expression where it is parsed, which a syntax error
+/// after it lists along with the operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum End {
+    Statement,
+    Paren,
+    Arg,
+    Then,
+    Else,
+}
+
+impl End {
+    fn tokens(self) -> &'static [&'static str] {
+        match self {
+            End::Statement => &SEPARATORS,
+            End::Paren => &[")"],
+            End::Arg => &[")", ","],
+            End::Then => &["then"],
+            End::Else => &["else"],
+        }
+    }
+}
+
+/// A parsed expression, and the span it takes up in the expression around it,
+/// which includes the parentheses a parenthesized expression's node does not.
+struct Operand {
+    node: Spanned<Node>,
+    outer: Span,
+}
+
+impl Operand {
+    fn new(node: Node, span: Span) -> Self {
+        Self {
+            node: Spanned::new(node, span),
+            outer: span,
+        }
+    }
+}
+
+struct Parser<'a, 'f> {
+    source: &'a str,
+    token: Token<'a>,
+    span: Span,
+    // whether the last token was a name, which a `(` could have made a call
+    after_name: bool,
+    functions: &'f mut HashSet<String>,
+    // the first error found in source that matches the grammar, such as a
+    // literal too large, which pest only finds while building the AST, so
+    // that a syntax error later on is reported first
+    deferred: Option<CalcError>,
+}
+
+impl<'a, 'f> Parser<'a, 'f> {
+    fn new(source: &'a str, functions: &'f mut HashSet<String>) -> Self {
+        let (token, span) = lex(source, 0);
+        Self {
+            source,
+            token,
+            span,
+            after_name: false,
+            functions,
+            deferred: None,
+        }
+    }
+
+    fn finish<T>(self, result: Result<T>) -> Result<T> {
+        let value = result?;
+        match self.deferred {
+            Some(err) => Err(err),
+            None => Ok(value),
+        }
+    }
+
+    fn bump(&mut self) -> Span {
+        let span = self.span;
+        (self.token, self.span) = lex(self.source, span.end);
+        self.after_name = false;
+        span
+    }
+
+    fn defer(&mut self, err: CalcError) {
+        self.deferred.get_or_insert(err);
+    }
+
+    /// The syntax error at the current token, which was none of `tokens`.
+    fn fail(&self, tokens: &[&str]) -> CalcError {
+        let mut tokens = tokens.to_vec();
+        tokens.sort_unstable();
+        tokens.dedup();
+        parser::expected(self.source, self.span.start, &tokens)
+    }
+
+    /// The syntax error at the current token after an expression that `end`
+    /// should follow.
+    fn fail_after_expr(&self, end: End) -> CalcError {
+        let mut tokens = OPERATORS.to_vec();
+        tokens.extend_from_slice(end.tokens());
+        if self.after_name {
+            tokens.push("(");
+        }
+        self.fail(&tokens)
+    }
+
+    fn expect(&mut self, token: Token, expected: &[&str]) -> Result<Span> {
+        if self.token != token {
+            return Err(self.fail(expected));
+        }
+        Ok(self.bump())
+    }
+
+    // `=` after a name, which pest also finds at the start of `==`, and then
+    // expects an operand after
+    fn expect_equals(&mut self) -> Result<Span> {
+        if self.token == Token::Symbol("==") {
+            return Err(self.fail_in_symbol(&OPERAND));
+        }
+        self.expect(Token::Symbol("="), &["="])
+    }
+
+    // the syntax error right after the first character of a two-character
+    // symbol, where pest stops after reading it as a shorter one
+    fn fail_in_symbol(&self, tokens: &[&str]) -> CalcError {
+        parser::expected(self.source, self.span.start + 1, tokens)
+    }
+
+    fn expect_name(&mut self, also: &[&str]) -> Result<(&'a str, Span)> {
+        let Token::Name(name) = self.token else {
+            return Err(self.fail(&[&NAME[..], also].concat()));
+        };
+        Ok((name, self.bump()))
+    }
+
+    fn expect_end(&mut self, token: Token, end: End) -> Result<Span> {
+        if self.token != token {
+            return Err(self.fail_after_expr(end));
+        }
+        Ok(self.bump())
+    }
+
+    fn skip_separators(&mut self) {
+        while self.token == Token::Separator {
+            self.bump();
+        }
+    }
+
+    fn program(&mut self) -> Result<Vec<Spanned<Node>>> {
+        let mut ast = vec![];
+        self.skip_separators();
+        loop {
+            ast.push(self.statement()?);
+            match self.token {
+                Token::End => return Ok(ast),
+                Token::Separator => self.skip_separators(),
+                _ => return Err(self.fail_after_expr(End::Statement)),
+            }
+            if self.token == Token::End {
+                return Ok(ast);
+            }
+        }
+    }
+
+    fn statement_alone(&mut self) -> Result<Spanned<Node>> {
+        let node = self.statement()?;
+        match self.token {
+            Token::End => {}
+            Token::Separator => {
+                self.bump();
+                if self.token != Token::End {
+                    return Err(self.fail(&[]));
+                }
+            }
+            _ => return Err(self.fail_after_expr(End::Statement)),
+        }
+        Ok(node)
+    }
+
+    fn statement(&mut self) -> Result<Spanned<Node>> {
+        match self.token {
+            Token::Keyword("let") => self.let_binding(),
+            Token::Keyword("fn") => self.fn_def(),
+            _ if self.starts_expr() => Ok(self.expr()?.node),
+            _ => Err(self.fail(&[&OPERAND[..], &["fn", "if", "let"], &SEPARATORS].concat())),
+        }
+    }
+
+    fn let_binding(&mut self) -> Result<Spanned<Node>> {
+        let start = self.bump().start;
+        let (name, _) = self.expect_name(&[])?;
+        self.expect_equals()?;
+        let value = self.expr()?;
+        let node = Node::Let {
+            name: name.to_string(),
+            value: Box::new(value.node),
+        };
+        Ok(Spanned::new(node, Span::new(start, value.outer.end)))
+    }
+
+    fn fn_def(&mut self) -> Result<Spanned<Node>> {
+        let start = self.bump().start;
+        let (name, _) = self.expect_name(&[])?;
+        self.expect(Token::Symbol("("), &["("])?;
+        let mut params: Vec<String> = vec![];
+        if self.token != Token::Symbol(")") {
+            loop {
+                let also: &[&str] = if params.is_empty() { &[")"] } else { &[] };
+                let (param, span) = self.expect_name(also)?;
+                if params.iter().any(|p| p == param) {
+                    self.defer(parser::duplicate_parameter(param, name, span));
+                }
+                params.push(param.to_string());
+                match self.token {
+                    Token::Symbol(",") => self.bump(),
+                    Token::Symbol(")") => break,
+                    _ => return Err(self.fail(&[")", ","])),
+                };
+            }
+        }
+        self.bump();
+        self.expect_equals()?;
+        let body = self.expr()?;
+        let node = Spanned::new(
+            Node::FnDef {
+                name: name.to_string(),
+                params,
+                body: Box::new(body.node),
+            },
+            Span::new(start, body.outer.end),
+        );
+        // pest defines the function once the whole definition has built
+        if self.deferred.is_none()
+            && let Err(err) = parser::define_function(self.functions, &node)
+        {
+            self.defer(err);
+        }
+        Ok(node)
+    }
+
+    fn starts_expr(&self) -> bool {
+        match self.token {
+            Token::Int(_) | Token::Float(_) | Token::Name(_) | Token::BadFloat => true,
+            Token::Keyword(keyword) => matches!(keyword, "if" | "true" | "false"),
+            Token::Symbol(symbol) => matches!(symbol, "+" | "-" | "!" | "!=" | "("),
+            _ => false,
+        }
+    }
+
+    fn expr(&mut self) -> Result<Operand> {
+        if self.token == Token::Keyword("if") {
+            return self.if_expr();
+        }
+        self.binary(0)
+    }
+
+    fn if_expr(&mut self) -> Result<Operand> {
+        let start = self.bump().start;
+        let cond = self.expr()?;
+        self.expect_end(Token::Keyword("then"), End::Then)?;
+        let then_branch = self.expr()?;
+        self.expect_end(Token::Keyword("else"), End::Else)?;
+        let else_branch = self.expr()?;
+        let node = Node::If {
+            cond: Box::new(cond.node),
+            then_branch: Box::new(then_branch.node),
+            else_branch: Box::new(else_branch.node),
+        };
+        Ok(Operand::new(node, Span::new(start, else_branch.outer.end)))
+    }
+
+    /// Parses operands joined by operators that bind at least as tightly as
+    /// `min`. Each operation spans from the start of its lhs to the end of
+    /// its rhs, parentheses included.
+    fn binary(&mut self, min: u8) -> Result<Operand> {
+        let mut lhs = self.unary()?;
+        while let Token::Symbol(symbol) = self.token
+            && let Some(&(_, precedence, assoc, op)) =
+                INFIX.iter().find(|(infix, ..)| *infix == symbol)
+            && precedence >= min
+        {
+            self.bump();
+            let next = match assoc {
+                Assoc::Left => precedence + 1,
+                Assoc::Right => precedence,
+            };
+            let rhs = self.binary(next)?;
+            let span = Span::new(lhs.outer.start, rhs.outer.end);
+            let node = Node::BinaryExpr {
+                op,
+                lhs: Box::new(lhs.node),
+                rhs: Box::new(rhs.node),
+            };
+            lhs = Operand::new(node, span);
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Operand> {
+        let op = match self.token {
+            Token::Symbol("+") => Operator::Plus,
+            Token::Symbol("-") => Operator::Minus,
+            Token::Symbol("!") => Operator::Not,
+            Token::Symbol("!=") => return Err(self.fail_in_symbol(&OPERAND)),
+            _ => return self.primary(),
+        };
+        let start = self.bump().start;
+        let child = self.binary(PREFIX)?;
+        let node = Node::UnaryExpr {
+            op,
+            child: Box::new(child.node),
+        };
+        Ok(Operand::new(node, Span::new(start, child.outer.end)))
+    }
+
+    fn primary(&mut self) -> Result<Operand> {
+        let span = self.span;
+        let node = match self.token {
+            Token::Int(text) => {
+                let int = parser::int_literal(text, span).unwrap_or_else(|err| {
+                    self.defer(err);
+                    0
+                });
+                Node::Val(Val::Int(int))
+            }
+            Token::Float(text) => {
+                let float = parser::float_literal(text, span).unwrap_or_else(|err| {
+                    self.defer(err);
+                    0.0
+                });
+                Node::Val(Val::Float(OrderedFloat(float)))
+            }
+            // pest reads the digits as an int and expects more after the `.`
+            Token::BadFloat => return Err(parser::expected(self.source, span.end, &["0..9"])),
+            Token::Keyword("true") => Node::Val(Val::Bool(true)),
+            Token::Keyword("false") => Node::Val(Val::Bool(false)),
+            Token::Name(name) => {
+                self.bump();
+                if self.token == Token::Symbol("(") {
+                    return self.call(name, span.start);
+                }
+                self.after_name = true;
+                return Ok(Operand::new(Node::Ident(name.to_string()), span));
+            }
+            Token::Symbol("(") => {
+                self.bump();
+                let inner = self.expr()?;
+                let close = self.expect_end(Token::Symbol(")"), End::Paren)?;
+                // a parenthesized expression keeps the span of what is inside
+                return Ok(Operand {
+                    node: inner.node,
+                    outer: Span::new(span.start, close.end),
+                });
+            }
+            _ => return Err(self.fail(&OPERAND)),
+        };
+        self.bump();
+        Ok(Operand::new(node, span))
+    }
+
+    fn call(&mut self, name: &str, start: usize) -> Result<Operand> {
+        self.bump();
+        let mut args = vec![];
+        if self.token != Token::Symbol(")") {
+            if !self.starts_expr() {
+                return Err(self.fail(&[&OPERAND[..], &[")"]].concat()));
+            }
+            loop {
+                args.push(self.expr()?.node);
+                match self.token {
+                    Token::Symbol(",") => self.bump(),
+                    Token::Symbol(")") => break,
+                    _ => return Err(self.fail_after_expr(End::Arg)),
+                };
+            }
+        }
+        let close = self.bump();
+        let node = Node::Call {
+            name: name.to_string(),
+            args,
+        };
+        Ok(Operand::new(node, Span::new(start, close.end)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every span in the tree, in order, since `Spanned` compares without them
+    fn spans(node: &Spanned<Node>, out: &mut Vec<Span>) {
+        out.push(node.span);
+        match &node.node {
+            Node::Val(_) | Node::Ident(_) | Node::Error => {}
+            Node::Let { value: child, .. }
+            | Node::FnDef { body: child, .. }
+            | Node::UnaryExpr { child, .. } => spans(child, out),
+            Node::Call { args, .. } => args.iter().for_each(|arg| spans(arg, out)),
+            Node::BinaryExpr { lhs, rhs, .. } => {
+                spans(lhs, out);
+                spans(rhs, out);
+            }
+            Node::If {
+                cond,
+                then_branch,
+                else_branch,
+            } => {
+                spans(cond, out);
+                spans(then_branch, out);
+                spans(else_branch, out);
+            }
+        }
+    }
+
+    fn all_spans(ast: &[Spanned<Node>]) -> Vec<Span> {
+        let mut out = vec![];
+        ast.iter().for_each(|node| spans(node, &mut out));
+        out
+    }
+
+    // pratt and pest agree on `source`, spans and error messages included
+    fn agree(source: &str) {
+        let pratt = parse(source);
+        let pest = parser::parse_with_pest(source);
+        assert_eq!(pratt, pest, "parsing {:?}", source);
+        if let (Ok(pratt), Ok(pest)) = (&pratt, &pest) {
+            assert_eq!(all_spans(pratt), all_spans(pest), "spans of {:?}", source);
+        }
+        if let (Err(pratt), Err(pest)) = (&pratt, &pest) {
+            assert_eq!(pratt.span(), pest.span(), "error in {:?}", source);
+        }
+    }
+
+    const VALID: [&str; 22] = [
+        "1 + 2 * 3 - 4 / 5 // 6 % 7",
+        "2 ^ 3 ^ 2 * -2 ^ -2 ^ 2 + (2 ^ 3) ^ 2",
+        "-(1 + 2) * +3 - !true",
+        "--1 - -(-2)",
+        "1 < 2 == 3 >= 4 != (5 <= 6) && 7 > 8 || !false",
+        "a || b && c || d && e",
+        "let x = (1)\n(x)",
+        "let  y\t=  ( ( y ) )  ;  y",
+        "fn f(a, b) = a * b; f (1, 2) + f(f(1, 2), (3))",
+        "fn none() = 1\nnone()",
+        "if a then 1 else if b then 2 else 3",
+        "(if (a) then (1) else 2) + 1",
+        "if if a then b else c then 1 else 2 + 3",
+        "f(if a then 1 else 2, 3 ^ 2)",
+        ";;\n1;\n\r\n2\r3;;\n",
+        "3.25 * 00012 + 1.0",
+        "trueish + letter + if_ + _1",
+        "1.5 ^ 2 ^ 0.5",
+        "x ^ -y * z",
+        "-x ^ y ^ -z",
+        "fn f(x) = x ^ 2\nf(2) ^ f(3)",
+        "(((1)))",
+    ];
+
+    #[test]
+    fn matches_pest_on_valid_sources() {
+        for source in VALID {
+            assert!(parse(source).is_ok(), "{:?} did not parse", source);
+            agree(source);
+        }
+    }
+
+    #[test]
+    fn matches_pest_on_errors() {
+        let sources = [
+            "",
+            "  ",
+            ";",
+            "1 +",
+            "1 + ",
+            "1 +\n2",
+            "(1 + 2",
+            "1 2",
+            "1 $ 2",
+            "x $",
+            "x = 1",
+            "1.",
+            "1.x",
+            "1.5.",
+            "f(",
+            "f(1",
+            "f(1,",
+            "f(,)",
+            "f()$",
+            "(1)$",
+            "f(1)(2)",
+            "let x 3",
+            "let x = 1\nlet y 2\n",
+            "let x =",
+            "let 1 = 2",
+            "fn (x) = 1",
+            "fn f x",
+            "fn f(",
+            "fn f(a,",
+            "fn f(a)",
+            "fn f(x = x",
+            "fn f(1)",
+            "fn f(a) =",
+            "if",
+            "if 1",
+            "if 1 then",
+            "if 1 then 2",
+            "if 1 then 2 else",
+            "-",
+            "!",
+            "1 ^",
+            "2 ^ ^ 3",
+            "2 ^ 3 $",
+            "true(1)",
+            "1; $",
+            "1 & 2",
+            "1 + é",
+            "é",
+            ")",
+        ];
+        for source in sources {
+            assert!(parse(source).is_err(), "{:?} parsed", source);
+            agree(source);
+        }
+
+        // errors pest only finds once the grammar has matched
+        for source in [
+            "fn f(x, x) = x; 1",
+            "fn f(x) = x; fn f(y) = y; 1",
+            "1 + 3000000000",
+            "3000000000 + 4000000000",
+            "fn f(a, a) = 3000000000",
+            "fn f() = 3000000000; fn f() = 1",
+            "3000000000 +",
+        ] {
+            agree(source);
+        }
+    }
+
+    // pest looks past a keyword to check that it is not the start of a longer
+    // name, and reports errors after it where this parser reports them at it
+    #[test]
+    fn differs_from_pest_after_keywords() {
+        let message = |source| parse(source).unwrap_err().to_string();
+        assert_eq!(message("let"), "expected a name after `let`");
+        assert_eq!(message("let x"), "expected `=` after `x`");
+        assert_eq!(
+            message("1 + if a then 1 else 2"),
+            "expected a number, a name or `(` after `+`"
+        );
+        assert_eq!(
+            message("1 + fn f(x) = x"),
+            "expected a number, a name or `(` after `+`"
+        );
+        assert!(parser::parse_with_pest("let").is_err());
+    }
+
+    // every statement and every way to cut it short, which recovery reparses
+    #[test]
+    fn matches_pest_on_prefixes() {
+        for source in VALID {
+            for end in (0..=source.len()).filter(|&end| source.is_char_boundary(end)) {
+                let prefix = &source[..end];
+                let (pratt, pest) = (parse(prefix), parser::parse_with_pest(prefix));
+                assert_eq!(pratt.is_ok(), pest.is_ok(), "parsing {:?}", prefix);
+                if let (Ok(pratt), Ok(pest)) = (&pratt, &pest) {
+                    assert_eq!(pratt, pest, "parsing {:?}", prefix);
+                    assert_eq!(all_spans(pratt), all_spans(pest), "spans of {:?}", prefix);
+                }
+            }
+        }
+    }
+
+    // pest checks that a keyword or a name is not the start of a longer name,
+    // and when nothing else matches, reports an error after the word where it
+    // expected a character of that longer name, such as "expected a digit
+    // after `if`" for `1 + if`; this parser says what it expected at the word
+    fn looked_past_word(err: &CalcError) -> bool {
+        let message = err.to_string();
+        message.contains("a digit") || message.contains("something else")
+    }
+
+    // each valid source with one character taken out or one token put in
+    #[test]
+    fn matches_pest_on_mutations() {
+        let insertions = [
+            " ", "(", ")", "^", "-", "!", ";", "\n", "x", "1", ".", ",", "if ",
+        ];
+        let mut mismatches = vec![];
+        for source in VALID {
+            let cuts = (0..source.len()).filter(|&at| source.is_char_boundary(at));
+            for at in cuts {
+                let mut mutations = vec![format!("{}{}", &source[..at], &source[at + 1..])];
+                for insertion in insertions {
+                    mutations.push(format!("{}{}{}", &source[..at], insertion, &source[at..]));
+                }
+                for mutated in mutations {
+                    let (pratt, pest) = (parse(&mutated), parser::parse_with_pest(&mutated));
+                    let agree = match (&pratt, &pest) {
+                        (Ok(pratt), Ok(pest)) => {
+                            pratt == pest && all_spans(pratt) == all_spans(pest)
+                        }
+                        (Err(pratt), Err(pest)) => {
+                            pratt == pest
+                                || (looked_past_word(pest)
+                                    && pratt.span().start <= pest.span().start)
+                        }
+                        _ => false,
+                    };
+                    if !agree {
+                        mismatches.push(format!(
+                            "{:?}\n  pratt {:?}\n  pest  {:?}",
+                            mutated, pratt, pest
+                        ));
+                    }
+                }
+            }
+        }
+        assert!(
+            mismatches.is_empty(),
+            "{} mismatches:\n{}",
+            mismatches.len(),
+            mismatches.join("\n")
+        );
+    }
+
+    #[test]
+    fn binds_by_the_table() {
+        let parsed = |source: &str| parse(source).unwrap().remove(0).node;
+        // each operator binds tighter than the ones before it in the table
+        for pair in INFIX.windows(2) {
+            let [(loose, ..), (tight, ..)] = pair else {
+                unreachable!()
+            };
+            let source = format!("a {} b {} c", loose, tight);
+            let Node::BinaryExpr { rhs, .. } = parsed(&source) else {
+                panic!("{} did not parse to a binary expression", source);
+            };
+            let grouped = pair[0].1 == pair[1].1 && pair[0].2 == Assoc::Left;
+            assert_eq!(
+                matches!(rhs.node, Node::BinaryExpr { .. }),
+                !grouped,
+                "{}",
+                source
+            );
+        }
+        assert_eq!(parsed("2 ^ 3 ^ 2"), parsed("2 ^ (3 ^ 2)"));
+        assert_eq!(parsed("2 - 3 - 2"), parsed("(2 - 3) - 2"));
+        assert_eq!(parsed("-2 ^ 2"), parsed("-(2 ^ 2)"));
+        assert_eq!(parsed("-2 * 2"), parsed("(-2) * 2"));
+    }
+
+    #[test]
+    fn parses_statements_alone() {
+        let mut functions = HashSet::new();
+        let node = parse_statement("  fn f() = 1 ;", &mut functions).unwrap();
+        assert_eq!(node.span, Span::new(2, 12));
+        let err = parse_statement("fn f() = 2\n", &mut functions).unwrap_err();
+        assert_eq!(err.to_string(), "function `f` is already defined");
+        assert_eq!(err.span(), Span::new(0, 10));
+        // a definition with an error defines nothing
+        assert!(parse_statement("fn g(a = a;", &mut functions).is_err());
+        assert!(parse_statement("fn g(a) = 3000000000\r", &mut functions).is_err());
+        assert_eq!(functions.len(), 1);
+        assert!(parse_statement("g(1); 2", &mut functions).is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Programs that are compiled once and run many times. A program may read
+//! variables it never binds with `let`; each run gives them new values, so
+//! evaluating the same formula over and over parses and compiles it once:
+//!
+//! ```
+//! use calculator::{Program, Registry, val::Val};
+//!
+//! let registry = Registry::new();
+//! let backend = registry.get("closure").unwrap();
+//! let program = Program::new(backend, "let d = x - y; d * d", &["x", "y"]).unwrap();
+//! assert_eq!(program.eval(&[Val::Int(5), Val::Int(2)]), Ok(Val::Int(9)));
+//! assert_eq!(program.eval(&[Val::Int(1), Val::Int(4)]), Ok(Val::Int(9)));
+//! ```
+
+use crate::backend::Backend;
+use crate::error::CalcError;
+use crate::trace::{self, Category, Level};
+use crate::val::Val;
+use crate::{Node, Result, Spanned, optimize, parser};
+
+/// A program compiled by one backend, ready to run with values for its free
+/// variables.
+pub trait Executable {
+    /// Runs the program, returning the value of its last statement. `args`
+    /// holds one value per free variable, in the order they were declared.
+    fn eval(&self, args: &[Val]) -> Result<Val>;
+}
+
+/// A backend that can compile a program with free variables ahead of running it.
+pub trait Prepare {
+    fn prepare(ast: Vec<Spanned<Node>>, variables: &[&str]) -> Result<Box<dyn Executable>>;
+}
+
+/// A program compiled once by any [`Backend`], which can be evaluated
+/// repeatedly with different values for its free variables.
+pub struct Program {
+    backend: &'static str,
+    variables: Vec<String>,
+    executable: Box<dyn Executable>,
+}
+
+impl Program {
+    /// Parses, optimizes and compiles `source`, in which `variables` may be
+    /// read without being bound by `let`.
+    pub fn new(backend: &dyn Backend, source: &str, variables: &[&str]) -> Result<Self> {
+        Program::from_ast(backend, optimize(parser::parse(source)?), variables)
+    }
+
+    /// Compiles an AST as given, without optimizing it.
+    pub fn from_ast(
+        backend: &dyn Backend,
+        ast: Vec<Spanned<Node>>,
+        variables: &[&str],
+    ) -> Result<Self> {
+        check_variables(variables)?;
+        Ok(Self {
+            backend: backend.name(),
+            variables: variables.iter().map(|name| name.to_string()).collect(),
+            executable: backend.prepare(ast, variables)?,
+        })
+    }
+
+    /// Names of the free variables, in the order [`Program::eval`] takes their values.
+    pub fn variables(&self) -> &[String] {
+        &self.variables
+    }
+
+    /// Runs the program with `args` as the values of its free variables,
+    /// returning the value of its last statement.
+    pub fn eval(&self, args: &[Val]) -> Result<Val> {
+        if args.len() != self.variables.len() {
+            return Err(CalcError::type_error(format!(
+                "program expects {} variables but got {}",
+                self.variables.len(),
+                args.len()
+            )));
+        }
+        let result = self.executable.eval(args);
+        trace::emit(Category::Exec, Level::Debug, || {
+            let args: Vec<String> = (self.variables.iter())
+                .zip(args)
+                .map(|(name, val)| format!("{} = {}", name, val))
+                .collect();
+            match &result {
+                Ok(val) => format!("{}: {} with {}", self.backend, val, args.join(", ")),
+                Err(err) => format!("{}: error: {} with {}", self.backend, err, args.join(", ")),
+            }
+        });
+        result
+    }
+}
+
+/// Rejects a list of free variables that names the same one twice.
+pub(crate) fn check_variables(variables: &[&str]) -> Result<()> {
+    for (idx, name) in variables.iter().enumerate() {
+        if variables[..idx].contains(name) {
+            return Err(CalcError::type_error(format!(
+                "variable `{}` is given more than once",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Registry;
+    use crate::error::RuntimeError;
+    use crate::error::Span;
+
+    #[test]
+    fn every_backend() {
+        let registry = Registry::new();
+        let source = "fn sq(v) = v * v
+                      let d = x - y
+                      if flag then sq(d) else d / 2";
+        for backend in registry.iter() {
+            let program = Program::new(backend, source, &["x", "y", "flag"]).unwrap();
+            assert_eq!(program.variables(), ["x", "y", "flag"]);
+            let tests = [
+                ([Val::Int(5), Val::Int(2), Val::Bool(true)], Ok(Val::Int(9))),
+                (
+                    [Val::Int(1), Val::Int(4), Val::Bool(false)],
+                    Ok(Val::Float((-1.5).into())),
+                ),
+                (
+                    [Val::Float(2.5.into()), Val::Int(1), Val::Bool(true)],
+                    Ok(Val::Float(2.25.into())),
+                ),
+                (
+                    [Val::Int(i32::MIN), Val::Int(1), Val::Bool(true)],
+                    Err(CalcError::runtime(RuntimeError::Overflow).at(Span::new(47, 52))),
+                ),
+                // a run after an error starts afresh
+                ([Val::Int(3), Val::Int(3), Val::Bool(true)], Ok(Val::Int(0))),
+            ];
+            for (args, expected) in tests {
+                assert_eq!(
+                    program.eval(&args),
+                    expected,
+                    "Failed on backend {} with {:?}",
+                    backend.name(),
+                    args
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rebinding() {
+        let registry = Registry::new();
+        for backend in registry.iter() {
+            let program = Program::new(backend, "let x = x * 2; x + 1", &["x"]).unwrap();
+            for n in 0..3 {
+                assert_eq!(
+                    program.eval(&[Val::Int(n)]),
+                    Ok(Val::Int(n * 2 + 1)),
+                    "Failed on backend: {}",
+                    backend.name()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let registry = Registry::new();
+        for backend in registry.iter() {
+            let err = |source, variables: &[&str]| {
+                Program::new(backend, source, variables)
+                    .err()
+                    .map(|err| err.to_string())
+            };
+            assert_eq!(
+                err("x + y", &["x", "y", "x"]).as_deref(),
+                Some("variable `x` is given more than once"),
+                "Failed on backend: {}",
+                backend.name()
+            );
+            assert!(err("x +", &["x"]).is_some());
+
+            let program = Program::new(backend, "x // y", &["x", "y"]).unwrap();
+            assert_eq!(
+                program.eval(&[Val::Int(1)]).unwrap_err().to_string(),
+                "program expects 2 variables but got 1"
+            );
+            assert_eq!(
+                program.eval(&[Val::Int(1), Val::Int(0)]),
+                Err(CalcError::runtime(RuntimeError::DivisionByZero).at(Span::new(0, 6))),
+                "Failed on backend: {}",
+                backend.name()
+            );
+            assert_eq!(program.eval(&[Val::Int(7), Val::Int(2)]), Ok(Val::Int(3)));
+        }
+    }
+}
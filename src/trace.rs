@@ -0,0 +1,330 @@
+//! Tracing of what happens to a program on its way through the parser, the
+//! optimizer and a backend. Nothing is traced until a tracer is installed
+//! with [`set_tracer`]; the CLI installs one for `--trace=bytecode,ir`.
+//!
+//! ```
+//! use std::sync::{Arc, Mutex};
+//! use calculator::trace::{self, Category, Filter, Level};
+//! use calculator::{Backend, Registry};
+//!
+//! let events = Arc::new(Mutex::new(Vec::new()));
+//! let sink = Arc::clone(&events);
+//! trace::set_tracer(Filter::new().with(Category::Bytecode, Level::Info), move |event| {
+//!     sink.lock().unwrap().push(event.message.to_string());
+//! });
+//! Registry::new().get("vm").unwrap().eval("let x = 2; 1 + 2 * x").unwrap();
+//! trace::clear_tracer();
+//! assert!(events.lock().unwrap()[0].contains("OpGetGlobal"));
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::ast::{Node, Spanned};
+
+/// The stage of compilation or execution an event comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Category {
+    /// The source being parsed.
+    Parse,
+    /// The AST, as parsed and as optimized.
+    Ast,
+    /// Code compiled for the stack VM or the register VM.
+    Bytecode,
+    /// LLVM IR generated by the JIT.
+    Ir,
+    /// Programs run by a backend and their results.
+    Exec,
+}
+
+impl Category {
+    pub const ALL: [Category; 5] = [
+        Category::Parse,
+        Category::Ast,
+        Category::Bytecode,
+        Category::Ir,
+        Category::Exec,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Parse => "parse",
+            Category::Ast => "ast",
+            Category::Bytecode => "bytecode",
+            Category::Ir => "ir",
+            Category::Exec => "exec",
+        }
+    }
+}
+
+impl fmt::Display for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How much detail an event gives. `Debug` includes everything `Info` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Info = 1,
+    Debug = 2,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Info => write!(f, "info"),
+            Level::Debug => write!(f, "debug"),
+        }
+    }
+}
+
+/// The level each category is traced at, if at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Filter {
+    levels: [Option<Level>; Category::ALL.len()],
+}
+
+impl Filter {
+    /// A filter that traces nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A filter that traces every category at `level`.
+    pub fn all(level: Level) -> Self {
+        Self {
+            levels: [Some(level); Category::ALL.len()],
+        }
+    }
+
+    pub fn with(mut self, category: Category, level: Level) -> Self {
+        self.levels[category as usize] = Some(level);
+        self
+    }
+
+    pub fn level(&self, category: Category) -> Option<Level> {
+        self.levels[category as usize]
+    }
+}
+
+/// Parses a comma-separated list of categories, each optionally followed by
+/// `:info` or `:debug`, such as `bytecode,ir` or `all:debug`. A category
+/// without a level is traced at `info`.
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(spec: &str) -> std::result::Result<Self, Self::Err> {
+        let mut filter = Filter::new();
+        for item in spec
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+        {
+            let (name, level) = match item.split_once(':') {
+                Some((name, "info")) => (name, Level::Info),
+                Some((name, "debug")) => (name, Level::Debug),
+                Some((_, level)) => {
+                    return Err(format!(
+                        "unknown trace level `{}`, expected info or debug",
+                        level
+                    ));
+                }
+                None => (item, Level::Info),
+            };
+            if name == "all" {
+                for category in Category::ALL {
+                    filter = filter.with(category, level);
+                }
+                continue;
+            }
+            let Some(category) = Category::ALL.into_iter().find(|c| c.name() == name) else {
+                return Err(format!(
+                    "unknown trace category `{}`, expected parse, ast, bytecode, ir, exec or all",
+                    name
+                ));
+            };
+            filter = filter.with(category, level);
+        }
+        Ok(filter)
+    }
+}
+
+/// One traced event, passed to the tracer's callback.
+#[derive(Debug, Clone, Copy)]
+pub struct Event<'a> {
+    pub category: Category,
+    pub level: Level,
+    pub message: &'a str,
+}
+
+/// Shows the category before the message, and a message of several lines,
+/// such as a disassembly, below it.
+impl fmt::Display for Event<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = self.message.trim_end();
+        if message.contains('\n') {
+            write!(f, "[{}]\n{}", self.category, message)
+        } else {
+            write!(f, "[{}] {}", self.category, message)
+        }
+    }
+}
+
+type Callback = Arc<dyn Fn(&Event<'_>) + Send + Sync>;
+
+// the enabled level of each category, 0 when it is off, so that checking
+// whether to trace does not take the lock
+static LEVELS: [AtomicU8; Category::ALL.len()] = [const { AtomicU8::new(0) }; 5];
+static TRACER: RwLock<Option<Callback>> = RwLock::new(None);
+
+/// Sends the events `filter` lets through to `callback`, from any thread,
+/// replacing the previous tracer.
+pub fn set_tracer(filter: Filter, callback: impl Fn(&Event<'_>) + Send + Sync + 'static) {
+    *TRACER.write().unwrap_or_else(|err| err.into_inner()) = Some(Arc::new(callback));
+    for category in Category::ALL {
+        let level = filter.level(category).map_or(0, |level| level as u8);
+        LEVELS[category as usize].store(level, Ordering::Relaxed);
+    }
+}
+
+/// Removes the tracer, so that nothing is traced.
+pub fn clear_tracer() {
+    for level in &LEVELS {
+        level.store(0, Ordering::Relaxed);
+    }
+    *TRACER.write().unwrap_or_else(|err| err.into_inner()) = None;
+}
+
+/// Whether an event of `category` at `level` would reach the tracer.
+pub fn enabled(category: Category, level: Level) -> bool {
+    LEVELS[category as usize].load(Ordering::Relaxed) >= level as u8
+}
+
+/// Traces the message `message` builds, which it only does when the event is enabled.
+pub(crate) fn emit(category: Category, level: Level, message: impl FnOnce() -> String) {
+    if !enabled(category, level) {
+        return;
+    }
+    // the callback runs without the lock, so that it may trace itself
+    let callback = TRACER.read().unwrap_or_else(|err| err.into_inner()).clone();
+    if let Some(callback) = callback {
+        callback(&Event {
+            category,
+            level,
+            message: &message(),
+        });
+    }
+}
+
+/// Traces the AST as it is after `stage`: on one line at `Info`, and as an
+/// indented tree at `Debug`.
+pub(crate) fn ast(stage: &str, ast: &[Spanned<Node>]) {
+    if enabled(Category::Ast, Level::Debug) {
+        emit(Category::Ast, Level::Debug, || {
+            format!("{}: {:#?}", stage, ast)
+        });
+    } else {
+        emit(Category::Ast, Level::Info, || {
+            format!("{}: {:?}", stage, ast)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters() {
+        let filter: Filter = "bytecode, ir".parse().unwrap();
+        assert_eq!(
+            filter,
+            Filter::new()
+                .with(Category::Bytecode, Level::Info)
+                .with(Category::Ir, Level::Info)
+        );
+        assert_eq!("all:debug".parse(), Ok(Filter::all(Level::Debug)));
+        assert_eq!(
+            "all,ast:debug".parse(),
+            Ok(Filter::all(Level::Info).with(Category::Ast, Level::Debug))
+        );
+        assert_eq!("".parse(), Ok(Filter::new()));
+        assert_eq!(
+            "bytecode,asm".parse::<Filter>().unwrap_err(),
+            "unknown trace category `asm`, expected parse, ast, bytecode, ir, exec or all"
+        );
+        assert_eq!(
+            "ir:loud".parse::<Filter>().unwrap_err(),
+            "unknown trace level `loud`, expected info or debug"
+        );
+    }
+
+    #[test]
+    fn events() {
+        let event = Event {
+            category: Category::Exec,
+            level: Level::Info,
+            message: "interp: 3",
+        };
+        assert_eq!(event.to_string(), "[exec] interp: 3");
+        let event = Event {
+            category: Category::Bytecode,
+            level: Level::Info,
+            message: "0000 OpConstant 0\n0003 OpPop\n",
+        };
+        assert_eq!(
+            event.to_string(),
+            "[bytecode]\n0000 OpConstant 0\n0003 OpPop"
+        );
+    }
+
+    // the only test that installs a tracer, since it is shared by every thread
+    #[test]
+    fn tracer() {
+        use crate::Registry;
+        use std::sync::Mutex;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        let filter = Filter::new()
+            .with(Category::Ast, Level::Info)
+            .with(Category::Exec, Level::Info);
+        set_tracer(filter, move |event| {
+            sink.lock()
+                .unwrap()
+                .push((event.category, event.level, event.message.to_string()));
+        });
+        assert!(enabled(Category::Exec, Level::Info));
+        assert!(!enabled(Category::Exec, Level::Debug));
+        assert!(!enabled(Category::Bytecode, Level::Info));
+        let registry = Registry::new();
+        let backend = registry.get("reg").unwrap();
+        assert_eq!(
+            backend.eval("let traced = 4; traced * 2"),
+            Ok(crate::val::Val::Int(8))
+        );
+        clear_tracer();
+        assert!(!enabled(Category::Exec, Level::Info));
+
+        // other tests may run meanwhile, and their events pass the same filter
+        let events = events.lock().unwrap();
+        assert!(events.iter().all(|(category, level, _)| {
+            matches!(category, Category::Ast | Category::Exec) && *level == Level::Info
+        }));
+        let parsed = |stage: &str| {
+            events.iter().any(|(_, _, message)| {
+                message.starts_with(stage) && message.contains("Ident(\"traced\")")
+            })
+        };
+        assert!(parsed("parsed: ") && parsed("optimized: "));
+        assert!(
+            events
+                .iter()
+                .any(|(category, _, message)| *category == Category::Exec
+                    && message.starts_with("reg: 8 in "))
+        );
+    }
+}
@@ -2,20 +2,239 @@ use core::fmt;
 use ordered_float::{self, OrderedFloat};
 use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
 
+use crate::Result;
+use crate::ast::Operator;
+use crate::error::{CalcError, RuntimeError};
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Val {
     Int(i32),
     Float(OrderedFloat<f32>),
+    Bool(bool),
+}
+
+impl Val {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Val::Int(_) => "int",
+            Val::Float(_) => "float",
+            Val::Bool(_) => "bool",
+        }
+    }
+
+    /// Applies a comparison operator. Ints and floats compare by numeric value,
+    /// bools only support `==` and `!=`. Returns `None` if the operands cannot
+    /// be compared.
+    pub fn compare(self, op: Operator, rhs: Val) -> Option<bool> {
+        fn apply<T: PartialOrd>(op: Operator, a: T, b: T) -> bool {
+            match op {
+                Operator::Equal => a == b,
+                Operator::NotEqual => a != b,
+                Operator::Less => a < b,
+                Operator::LessEqual => a <= b,
+                Operator::Greater => a > b,
+                Operator::GreaterEqual => a >= b,
+                _ => unreachable!("{} is not a comparison operator", op),
+            }
+        }
+
+        match (self, rhs) {
+            (Val::Bool(a), Val::Bool(b)) => match op {
+                Operator::Equal | Operator::NotEqual => Some(apply(op, a, b)),
+                _ => None,
+            },
+            (Val::Bool(_), _) | (_, Val::Bool(_)) => None,
+            (Val::Int(a), Val::Int(b)) => Some(apply(op, a, b)),
+            (a, b) => Some(apply(op, a.as_f32(), b.as_f32())),
+        }
+    }
+
+    /// Applies a unary operator. Negating `i32::MIN` is an overflow error.
+    pub fn unary(self, op: Operator) -> Result<Val> {
+        match (op, self) {
+            (Operator::Not, Val::Bool(b)) => Ok(Val::Bool(!b)),
+            (Operator::Plus, Val::Int(_) | Val::Float(_)) => Ok(self),
+            (Operator::Minus, Val::Int(n)) => n
+                .checked_neg()
+                .map(Val::Int)
+                .ok_or(CalcError::runtime(RuntimeError::Overflow)),
+            (Operator::Minus, Val::Float(f)) => Ok(Val::Float(-f)),
+            _ => Err(CalcError::type_error(format!(
+                "cannot apply `{}` to {}",
+                op,
+                self.type_name()
+            ))),
+        }
+    }
+
+    /// Applies a binary operator. Mixing an int with a float gives a float, and
+    /// int arithmetic reports overflow instead of panicking.
+    /// `&&` and `||` evaluate both operands here; backends short-circuit themselves.
+    ///
+    /// Division is the same in every backend:
+    /// - `/` is true division and always gives a float, so `7 / 2` is `3.5`.
+    /// - `//` rounds the quotient down, so `-7 // 2` is `-4`. It gives an int when
+    ///   both operands are ints and a float otherwise.
+    /// - `%` is the remainder of `//`, so it takes the sign of the divisor:
+    ///   `-7 % 2` is `1` and `7 % -2` is `-1`. `a == (a // b) * b + a % b` holds.
+    /// - Dividing by the int `0` with any of the three is a division by zero
+    ///   error; dividing by the float `0.0` follows IEEE 754 and gives an
+    ///   infinity or NaN.
+    ///
+    /// `^` raises to a power. An int raised to an int is an int, so a negative
+    /// exponent is an error unless the base is a float.
+    pub fn binary(self, op: Operator, rhs: Val) -> Result<Val> {
+        let type_error = || {
+            CalcError::type_error(format!(
+                "cannot apply `{}` to {} and {}",
+                op,
+                self.type_name(),
+                rhs.type_name()
+            ))
+        };
+        if op.is_comparison() {
+            return self.compare(op, rhs).map(Val::Bool).ok_or_else(type_error);
+        }
+
+        match (self, rhs) {
+            (Val::Bool(a), Val::Bool(b)) => match op {
+                Operator::And => Ok(Val::Bool(a && b)),
+                Operator::Or => Ok(Val::Bool(a || b)),
+                _ => Err(type_error()),
+            },
+            (Val::Bool(_), _) | (_, Val::Bool(_)) => Err(type_error()),
+            (_, Val::Int(0)) if op.is_division() => {
+                Err(CalcError::runtime(RuntimeError::DivisionByZero))
+            }
+            (Val::Int(a), Val::Int(b)) => {
+                let result = match op {
+                    Operator::Plus => a.checked_add(b),
+                    Operator::Minus => a.checked_sub(b),
+                    Operator::Multiply => a.checked_mul(b),
+                    Operator::Divide => return Ok(Val::Float(OrderedFloat(a as f32 / b as f32))),
+                    // `checked_div` truncates towards zero, so step down when the
+                    // signs differ and the division is inexact
+                    Operator::FloorDivide => a.checked_div(b).map(|q| {
+                        if q * b != a && (a < 0) != (b < 0) {
+                            q - 1
+                        } else {
+                            q
+                        }
+                    }),
+                    // `wrapping_rem` only wraps for `i32::MIN % -1`, which is 0
+                    Operator::Modulo => {
+                        let r = a.wrapping_rem(b);
+                        Some(if r != 0 && (r < 0) != (b < 0) {
+                            r + b
+                        } else {
+                            r
+                        })
+                    }
+                    Operator::Power => match u32::try_from(b) {
+                        Ok(exponent) => a.checked_pow(exponent),
+                        Err(_) => return Err(CalcError::runtime(RuntimeError::NegativeExponent)),
+                    },
+                    _ => return Err(type_error()),
+                };
+                result
+                    .map(Val::Int)
+                    .ok_or(CalcError::runtime(RuntimeError::Overflow))
+            }
+            (a, b) => {
+                let (a, b) = (a.as_f32(), b.as_f32());
+                let result = match op {
+                    Operator::Plus => a + b,
+                    Operator::Minus => a - b,
+                    Operator::Multiply => a * b,
+                    Operator::Divide => a / b,
+                    Operator::FloorDivide => (a / b).floor(),
+                    Operator::Modulo => {
+                        let r = a % b;
+                        if r != 0.0 && (r < 0.0) != (b < 0.0) {
+                            r + b
+                        } else {
+                            r
+                        }
+                    }
+                    Operator::Power => a.powf(b),
+                    _ => return Err(type_error()),
+                };
+                Ok(Val::Float(OrderedFloat(result)))
+            }
+        }
+    }
+
+    /// Same as [`Val::binary`], but computes the common int and float cases
+    /// directly, for the VMs and the closure backend. It is always inlined,
+    /// so where `op` is a constant, as in the register VM, only the case for
+    /// that operator is left.
+    #[inline(always)]
+    pub(crate) fn fast_binary(self, op: Operator, rhs: Val) -> Result<Val> {
+        let val = match (self, rhs) {
+            (Val::Int(a), Val::Int(b)) => match op {
+                Operator::Plus => a.checked_add(b).map(Val::Int),
+                Operator::Minus => a.checked_sub(b).map(Val::Int),
+                Operator::Multiply => a.checked_mul(b).map(Val::Int),
+                Operator::Equal => Some(Val::Bool(a == b)),
+                Operator::NotEqual => Some(Val::Bool(a != b)),
+                Operator::Less => Some(Val::Bool(a < b)),
+                Operator::LessEqual => Some(Val::Bool(a <= b)),
+                Operator::Greater => Some(Val::Bool(a > b)),
+                Operator::GreaterEqual => Some(Val::Bool(a >= b)),
+                _ => None,
+            },
+            (Val::Float(a), Val::Float(b)) => {
+                // compare the raw floats, since `OrderedFloat` considers NaN equal to itself
+                let (a, b) = (a.into_inner(), b.into_inner());
+                match op {
+                    Operator::Plus => Some(Val::Float((a + b).into())),
+                    Operator::Minus => Some(Val::Float((a - b).into())),
+                    Operator::Multiply => Some(Val::Float((a * b).into())),
+                    Operator::Divide => Some(Val::Float((a / b).into())),
+                    Operator::Equal => Some(Val::Bool(a == b)),
+                    Operator::NotEqual => Some(Val::Bool(a != b)),
+                    Operator::Less => Some(Val::Bool(a < b)),
+                    Operator::LessEqual => Some(Val::Bool(a <= b)),
+                    Operator::Greater => Some(Val::Bool(a > b)),
+                    Operator::GreaterEqual => Some(Val::Bool(a >= b)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        match val {
+            Some(val) => Ok(val),
+            None => self.binary(op, rhs),
+        }
+    }
+
+    /// The condition of an `if` or an operand of `&&` and `||`, which must
+    /// be a bool.
+    pub(crate) fn expect_bool(self) -> Result<bool> {
+        match self {
+            Val::Bool(b) => Ok(b),
+            other => Err(CalcError::type_error(format!(
+                "expected a bool, found {}",
+                other.type_name()
+            ))),
+        }
+    }
+
+    fn as_f32(self) -> f32 {
+        match self {
+            Val::Int(n) => n as f32,
+            Val::Float(f) => f.into_inner(),
+            Val::Bool(_) => panic!("a bool is not a number"),
+        }
+    }
 }
 
 impl Neg for Val {
     type Output = Val;
 
     fn neg(self) -> Self {
-        match self {
-            Self::Float(f) => Val::Float(-f),
-            Self::Int(n) => Val::Int(-n),
-        }
+        self.unary(Operator::Minus)
+            .unwrap_or_else(|err| panic!("{}", err))
     }
 }
 
@@ -23,12 +242,8 @@ impl Add for Val {
     type Output = Val;
 
     fn add(self, other: Self) -> Self {
-        match (self, other) {
-            (Val::Int(a), Val::Int(b)) => Val::Int(a + b),
-            (Val::Float(a), Val::Float(b)) => Val::Float(a + b),
-            (Val::Float(a), Val::Int(b)) => Val::Float(a + (b as f32)),
-            (Val::Int(a), Val::Float(b)) => Val::Float(OrderedFloat(a as f32) + b),
-        }
+        self.binary(Operator::Plus, other)
+            .unwrap_or_else(|err| panic!("{}", err))
     }
 }
 
@@ -36,12 +251,8 @@ impl Sub for Val {
     type Output = Val;
 
     fn sub(self, other: Self) -> Self {
-        match (self, other) {
-            (Val::Int(a), Val::Int(b)) => Val::Int(a - b),
-            (Val::Float(a), Val::Float(b)) => Val::Float(a - b),
-            (Val::Float(a), Val::Int(b)) => Val::Float(a - (b as f32)),
-            (Val::Int(a), Val::Float(b)) => Val::Float(OrderedFloat(a as f32) - b),
-        }
+        self.binary(Operator::Minus, other)
+            .unwrap_or_else(|err| panic!("{}", err))
     }
 }
 
@@ -49,12 +260,8 @@ impl Mul for Val {
     type Output = Val;
 
     fn mul(self, other: Self) -> Self {
-        match (self, other) {
-            (Val::Int(a), Val::Int(b)) => Val::Int(a * b),
-            (Val::Float(a), Val::Float(b)) => Val::Float(a * b),
-            (Val::Float(a), Val::Int(b)) => Val::Float(a * (b as f32)),
-            (Val::Int(a), Val::Float(b)) => Val::Float(OrderedFloat(a as f32) * b),
-        }
+        self.binary(Operator::Multiply, other)
+            .unwrap_or_else(|err| panic!("{}", err))
     }
 }
 
@@ -62,12 +269,8 @@ impl Div for Val {
     type Output = Val;
 
     fn div(self, other: Self) -> Self {
-        match (self, other) {
-            (Val::Int(a), Val::Int(b)) => Val::Int(a / b),
-            (Val::Float(a), Val::Float(b)) => Val::Float(a / b),
-            (Val::Float(a), Val::Int(b)) => Val::Float(a / (b as f32)),
-            (Val::Int(a), Val::Float(b)) => Val::Float(OrderedFloat(a as f32) / b),
-        }
+        self.binary(Operator::Divide, other)
+            .unwrap_or_else(|err| panic!("{}", err))
     }
 }
 
@@ -79,7 +282,7 @@ impl AddAssign for Val {
 
 impl SubAssign for Val {
     fn sub_assign(&mut self, rhs: Val) {
-        *self = self.clone() - rhs;
+        *self = *self - rhs;
     }
 }
 
@@ -88,6 +291,103 @@ impl fmt::Display for Val {
         match self {
             Self::Int(n) => write!(f, "{}", n),
             Self::Float(n) => write!(f, "{}", n),
+            Self::Bool(b) => write!(f, "{}", b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        let int = Val::Int;
+        let float = |f: f32| Val::Float(OrderedFloat(f));
+        assert_eq!(int(7).binary(Operator::Divide, int(2)), Ok(float(3.5)));
+        assert_eq!(int(8).binary(Operator::Divide, int(2)), Ok(float(4.0)));
+        assert_eq!(int(1).binary(Operator::Plus, float(0.5)), Ok(float(1.5)));
+        assert_eq!(
+            float(3.0).binary(Operator::Multiply, int(2)),
+            Ok(float(6.0))
+        );
+        assert_eq!(
+            int(2).binary(Operator::Less, float(2.5)),
+            Ok(Val::Bool(true))
+        );
+        assert_eq!(int(2).unary(Operator::Minus), Ok(int(-2)));
+        assert_eq!(Val::Bool(true).unary(Operator::Not), Ok(Val::Bool(false)));
+    }
+
+    #[test]
+    fn division() {
+        let int = Val::Int;
+        let float = |f: f32| Val::Float(OrderedFloat(f));
+        let apply = |a: Val, op, b: Val| a.binary(op, b).unwrap();
+        for (a, b, quotient, remainder) in [
+            (7, 2, 3, 1),
+            (-7, 2, -4, 1),
+            (7, -2, -4, -1),
+            (-7, -2, 3, -1),
+            (6, 3, 2, 0),
+            (-6, 3, -2, 0),
+            (i32::MIN, -1, 0, 0),
+        ] {
+            if a != i32::MIN {
+                assert_eq!(apply(int(a), Operator::FloorDivide, int(b)), int(quotient));
+            }
+            assert_eq!(apply(int(a), Operator::Modulo, int(b)), int(remainder));
+        }
+        assert_eq!(
+            apply(float(-7.0), Operator::FloorDivide, int(2)),
+            float(-4.0)
+        );
+        assert_eq!(apply(float(-7.5), Operator::Modulo, int(2)), float(0.5));
+        assert_eq!(
+            apply(float(7.5), Operator::Modulo, float(-2.0)),
+            float(-0.5)
+        );
+        assert_eq!(
+            apply(int(1), Operator::Divide, float(0.0)),
+            float(f32::INFINITY)
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let runtime = |kind| Err(CalcError::runtime(kind));
+        assert_eq!(
+            Val::Int(1).binary(Operator::Divide, Val::Int(0)),
+            runtime(RuntimeError::DivisionByZero)
+        );
+        assert_eq!(
+            Val::Int(i32::MAX).binary(Operator::Plus, Val::Int(1)),
+            runtime(RuntimeError::Overflow)
+        );
+        assert_eq!(
+            Val::Int(i32::MIN).binary(Operator::FloorDivide, Val::Int(-1)),
+            runtime(RuntimeError::Overflow)
+        );
+        for op in [Operator::Divide, Operator::FloorDivide, Operator::Modulo] {
+            assert_eq!(
+                Val::Float(OrderedFloat(1.5)).binary(op, Val::Int(0)),
+                runtime(RuntimeError::DivisionByZero)
+            );
         }
+        assert_eq!(
+            Val::Int(i32::MIN).unary(Operator::Minus),
+            runtime(RuntimeError::Overflow)
+        );
+        assert_eq!(
+            Val::Bool(true)
+                .binary(Operator::Plus, Val::Int(1))
+                .unwrap_err()
+                .to_string(),
+            "cannot apply `+` to bool and int"
+        );
+        assert_eq!(
+            Val::Int(1).unary(Operator::Not).unwrap_err().to_string(),
+            "cannot apply `!` to int"
+        );
     }
 }
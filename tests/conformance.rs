@@ -0,0 +1,279 @@
+//! Runs every program in `tests/conformance/` through every backend compiled
+//! into this build, with and without the optimizer, and checks the result
+//! against the `.expected` file next to it.
+//!
+//! An `.expected` file holds one line, either a value:
+//!
+//! ```text
+//! float 3.5
+//! ```
+//!
+//! or an error, optionally with the span it points at and its message:
+//!
+//! ```text
+//! error runtime: division by zero
+//! error parse at 3..3
+//! error parse at 8..18: integer literal `3000000000` does not fit in 32 bits
+//! ```
+//!
+//! A backend with a limit the others do not have can expect something else,
+//! on a later line starting with its name, or with its name and `--no-opt` to
+//! expect it only when the program runs as written:
+//!
+//! ```text
+//! int 1
+//! vm: error limit: too many globals, the limit is 65536
+//! jit --no-opt: error type: cannot infer the return type of `loop`
+//! ```
+//!
+//! Lines starting with `#` are comments. Run with `--features jit` to include
+//! the JIT.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use calculator::val::Val;
+use calculator::{CalcError, Registry, Span, parser};
+
+enum Expected {
+    Value(String),
+    Error {
+        kind: String,
+        span: Option<Span>,
+        message: Option<String>,
+    },
+}
+
+/// What every backend should give, and what the backends or runs named in
+/// `overrides`, such as `vm` or `jit --no-opt`, give instead.
+struct Expectations {
+    default: Expected,
+    overrides: Vec<(String, Expected)>,
+}
+
+impl Expectations {
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        let default = Expected::parse(lines.next().ok_or("the file is empty")?)?;
+        let overrides = lines
+            .map(|line| {
+                let (backend, line) = line
+                    .split_once(": ")
+                    .ok_or_else(|| format!("expected `<backend>: ...`, found `{}`", line))?;
+                Ok((backend.to_string(), Expected::parse(line)?))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { default, overrides })
+    }
+
+    fn get(&self, run: &str) -> Option<&Expected> {
+        self.overrides
+            .iter()
+            .find(|(name, _)| name == run)
+            .map(|(_, expected)| expected)
+    }
+}
+
+impl Expected {
+    fn parse(line: &str) -> Result<Self, String> {
+        let Some(error) = line.strip_prefix("error ") else {
+            return Ok(Expected::Value(line.to_string()));
+        };
+        let (head, message) = match error.split_once(": ") {
+            Some((head, message)) => (head, Some(message.to_string())),
+            None => (error, None),
+        };
+        let (kind, span) = match head.split_once(" at ") {
+            Some((kind, span)) => {
+                let (start, end) = span
+                    .split_once("..")
+                    .ok_or_else(|| format!("bad span `{}`", span))?;
+                let offset = |n: &str| n.parse().map_err(|_| format!("bad span `{}`", span));
+                (kind, Some(Span::new(offset(start)?, offset(end)?)))
+            }
+            None => (head, None),
+        };
+        Ok(Expected::Error {
+            kind: kind.to_string(),
+            span,
+            message,
+        })
+    }
+
+    fn matches(&self, result: &Result<Val, CalcError>) -> bool {
+        match (self, result) {
+            (Expected::Value(expected), Ok(val)) => *expected == describe_val(val),
+            (
+                Expected::Error {
+                    kind,
+                    span,
+                    message,
+                },
+                Err(err),
+            ) => {
+                kind == error_kind(err)
+                    && span.is_none_or(|span| span == err.span())
+                    && message
+                        .as_ref()
+                        .is_none_or(|message| *message == err.to_string())
+            }
+            _ => false,
+        }
+    }
+}
+
+fn describe_val(val: &Val) -> String {
+    format!("{} {}", val.type_name(), val)
+}
+
+fn error_kind(err: &CalcError) -> &'static str {
+    match err {
+        CalcError::Parse { .. } => "parse",
+        CalcError::Type { .. } => "type",
+        CalcError::Runtime { .. } => "runtime",
+        CalcError::Internal { .. } => "internal",
+        CalcError::Limit { .. } => "limit",
+    }
+}
+
+fn describe(result: &Result<Val, CalcError>) -> String {
+    match result {
+        Ok(val) => describe_val(val),
+        Err(err) => {
+            let span = err.span();
+            format!(
+                "error {} at {}..{}: {}",
+                error_kind(err),
+                span.start,
+                span.end,
+                err
+            )
+        }
+    }
+}
+
+fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/conformance");
+    let mut programs: Vec<PathBuf> = fs::read_dir(&dir)
+        .unwrap_or_else(|err| panic!("cannot read {}: {}", dir.display(), err))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "calc"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+/// Checks one program against every backend, returning a report of each
+/// backend that got it wrong and of any disagreement between backends. Each
+/// backend runs the program both optimized and as written.
+fn check(path: &Path, registry: &Registry) -> Vec<String> {
+    let name = path.file_name().unwrap().to_string_lossy();
+    let source = fs::read_to_string(path).unwrap();
+    let expected_path = path.with_extension("expected");
+    let expected = match fs::read_to_string(&expected_path) {
+        Ok(text) => Expectations::parse(&text),
+        Err(err) => Err(format!("cannot read {}: {}", expected_path.display(), err)),
+    };
+    match expected {
+        Ok(expected) => check_source(&name, &source, &expected, registry),
+        Err(err) => vec![format!("{}: {}", name, err)],
+    }
+}
+
+/// Checks `source` as [`check`] does, for a program that is not in a file.
+fn check_source(
+    name: &str,
+    source: &str,
+    expected: &Expectations,
+    registry: &Registry,
+) -> Vec<String> {
+    // each backend or run that expects something else is checked on its own,
+    // the rest against the default expectation and against each other
+    let ast = parser::parse(source);
+    let group = |name: &str, run: &str| {
+        [run, name]
+            .into_iter()
+            .find(|key| expected.get(key).is_some())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let results: Vec<(String, String, Result<Val, CalcError>)> = registry
+        .iter()
+        .flat_map(|backend| {
+            let name = backend.name();
+            let unoptimized_run = format!("{} --no-opt", name);
+            let unoptimized = ast.clone().and_then(|ast| backend.eval_ast(ast));
+            [
+                (group(name, name), name.to_string(), backend.eval(source)),
+                (group(name, &unoptimized_run), unoptimized_run, unoptimized),
+            ]
+        })
+        .collect();
+    let mut failures: Vec<String> = results
+        .iter()
+        .filter(|(group, _, result)| {
+            let expected = expected.get(group).unwrap_or(&expected.default);
+            !expected.matches(result)
+        })
+        .map(|(_, backend, result)| format!("{}: {} gave `{}`", name, backend, describe(result)))
+        .collect();
+    let mut groups: Vec<&str> = results.iter().map(|(group, _, _)| group.as_str()).collect();
+    groups.sort_unstable();
+    groups.dedup();
+    for group in groups {
+        let results: Vec<_> = results.iter().filter(|result| result.0 == group).collect();
+        if results.iter().any(|(_, _, result)| *result != results[0].2) {
+            let all = results
+                .iter()
+                .map(|(_, backend, result)| format!("{} = `{}`", backend, describe(result)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            failures.push(format!("{}: backends disagree: {}", name, all));
+        }
+    }
+    failures
+}
+
+#[test]
+fn conformance() {
+    let programs = programs();
+    assert!(!programs.is_empty(), "no conformance programs found");
+    let registry = Registry::new();
+    let failures: Vec<String> = programs
+        .iter()
+        .flat_map(|path| check(path, &registry))
+        .collect();
+    assert!(
+        failures.is_empty(),
+        "{} conformance failures:\n{}",
+        failures.len(),
+        failures.join("\n")
+    );
+}
+
+/// A program with one more global than the VMs can number, generated since
+/// it is a megabyte of source.
+#[test]
+fn too_many_globals() {
+    let mut source = "let v0 = 1\n".to_string();
+    for idx in 1..=u16::MAX as usize + 1 {
+        source += &format!("let v{} = 0\n", idx);
+    }
+    source += "v0\n";
+    let start = source.find("let v65536").unwrap();
+    let end = start + "let v65536 = 0".len();
+    // the bytecode VM numbers globals with 16 bits, and the register VM keeps
+    // them in the registers of the main program, so both stop at 65,536
+    let expected = Expectations::parse(&format!(
+        "int 1
+vm: error limit at {}..{}: too many globals, the limit is 65536
+reg: error limit: expression is too large to compile, a call frame is limited to 65536 registers",
+        start, end
+    ))
+    .unwrap();
+    let failures = check_source("too_many_globals", &source, &expected, &Registry::new());
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}